@@ -0,0 +1,126 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use uefi::boot;
+
+/// The bootloader phases that get a TSC timestamp. The discriminant is the index of the phase
+/// in `BootTimestamps::tsc`, so the kernel's copy of this list has to stay in the same order.
+#[repr(usize)]
+#[derive(Copy, Clone, Debug)]
+pub enum BootPhase {
+    Entry = 0,
+    FsOpen,
+    KernelRead,
+    ElfParse,
+    SegmentLoad,
+    ExitBootServices,
+}
+
+pub const BOOT_PHASE_COUNT: usize = 6;
+
+impl BootPhase {
+    pub const ALL: [BootPhase; BOOT_PHASE_COUNT] = [
+        BootPhase::Entry,
+        BootPhase::FsOpen,
+        BootPhase::KernelRead,
+        BootPhase::ElfParse,
+        BootPhase::SegmentLoad,
+        BootPhase::ExitBootServices,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BootPhase::Entry => "bootloader entry",
+            BootPhase::FsOpen => "filesystem open",
+            BootPhase::KernelRead => "kernel read",
+            BootPhase::ElfParse => "elf parse",
+            BootPhase::SegmentLoad => "segment load",
+            BootPhase::ExitBootServices => "exit boot services",
+        }
+    }
+}
+
+/// The timestamps handed to the kernel through `KernelArgs`
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct BootTimestamps {
+    /// The TSC value at each `BootPhase`, or 0 if the phase was never reached
+    pub tsc: [u64; BOOT_PHASE_COUNT],
+
+    /// TSC ticks per second, calibrated against the firmware stall timer
+    pub tsc_hz: u64,
+}
+
+impl Default for BootTimestamps {
+    fn default() -> Self {
+        Self {
+            tsc: [0; BOOT_PHASE_COUNT],
+            tsc_hz: 0,
+        }
+    }
+}
+
+//how long to stall while calibrating the TSC. longer is more accurate, but it adds to boot time
+const CALIBRATION_US: usize = 10_000; //10ms
+
+static PHASE_TSC: [AtomicU64; BOOT_PHASE_COUNT] = [const { AtomicU64::new(0) }; BOOT_PHASE_COUNT];
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Records the current TSC value for the given phase
+pub fn stamp(phase: BootPhase) {
+    PHASE_TSC[phase as usize].store(rdtsc(), Ordering::Relaxed);
+}
+
+/// Measures the TSC frequency by counting ticks across a firmware stall. This has to run while
+/// boot services are still available.
+pub fn calibrate_tsc() -> u64 {
+    let start = rdtsc();
+    boot::stall(CALIBRATION_US);
+    let end = rdtsc();
+
+    let hz = (end - start) * (1_000_000 / CALIBRATION_US as u64);
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    hz
+}
+
+/// Returns a copy of every timestamp recorded so far
+pub fn snapshot() -> BootTimestamps {
+    let mut timestamps = BootTimestamps {
+        tsc_hz: TSC_HZ.load(Ordering::Relaxed),
+        ..Default::default()
+    };
+
+    for (i, tsc) in PHASE_TSC.iter().enumerate() {
+        timestamps.tsc[i] = tsc.load(Ordering::Relaxed);
+    }
+
+    timestamps
+}
+
+/// Converts a TSC delta to microseconds, using the calibrated frequency
+pub fn ticks_to_us(ticks: u64, tsc_hz: u64) -> u64 {
+    if tsc_hz == 0 {
+        return 0;
+    }
+
+    ((ticks as u128 * 1_000_000) / tsc_hz as u128) as u64
+}
+
+/// Logs the time spent between each recorded phase
+pub fn log_report(timestamps: &BootTimestamps) {
+    let entry = timestamps.tsc[BootPhase::Entry as usize];
+
+    for phase in BootPhase::ALL {
+        let tsc = timestamps.tsc[phase as usize];
+        if tsc == 0 {
+            continue;
+        }
+
+        log::info!("boot phase {:<20} +{} us",
+            phase.name(), ticks_to_us(tsc - entry, timestamps.tsc_hz)
+        );
+    }
+}
@@ -0,0 +1,53 @@
+use log::info;
+use uefi::boot::{self, OpenProtocolAttributes, OpenProtocolParams};
+use uefi::proto::console::gop::{GraphicsOutput, PixelFormat};
+
+use crate::kernel_args::{pixel_format, FramebufferInfo};
+
+// The framebuffer handed to the kernel for its text console. The firmware has already set a
+// graphics mode, so this only reads the current one back; the framebuffer stays where the
+// firmware put it after boot services exit.
+
+/// Returns the current GOP mode and framebuffer, or None if there is no GOP or it has no
+/// linear framebuffer
+pub fn query() -> Option<FramebufferInfo> {
+    let handle = boot::get_handle_for_protocol::<GraphicsOutput>().ok()?;
+
+    //opened without exclusive access, so the firmware's own text console keeps working
+    let mut gop = unsafe {
+        boot::open_protocol::<GraphicsOutput>(
+            OpenProtocolParams {
+                handle,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+        .ok()?
+    };
+
+    let mode = gop.current_mode_info();
+    let (width, height) = mode.resolution();
+    let (format, masks) = match mode.pixel_format() {
+        PixelFormat::Rgb => (pixel_format::RGB, None),
+        PixelFormat::Bgr => (pixel_format::BGR, None),
+        PixelFormat::Bitmask => (pixel_format::BITMASK, mode.pixel_bitmask()),
+        PixelFormat::BltOnly => {
+            info!("WARNING: the GOP has no linear framebuffer");
+            return None;
+        }
+    };
+
+    let mut framebuffer = gop.frame_buffer();
+    Some(FramebufferInfo {
+        base: framebuffer.as_mut_ptr() as usize,
+        size: framebuffer.size(),
+        width: width as u32,
+        height: height as u32,
+        stride: mode.stride() as u32,
+        format,
+        red_mask: masks.map_or(0, |m| m.red),
+        green_mask: masks.map_or(0, |m| m.green),
+        blue_mask: masks.map_or(0, |m| m.blue),
+    })
+}
@@ -1,7 +1,13 @@
 use core::ffi::c_void;
+use uefi::mem::memory_map::{MemoryAttribute, MemoryType};
 use uefi::table::cfg::{ConfigTableEntry, ACPI2_GUID, ACPI_GUID, SMBIOS3_GUID, SMBIOS_GUID};
-use uefi_raw::table::boot::{MemoryAttribute, MemoryType};
 
+use crate::boot_timing::BootTimestamps;
+
+// Everything in this file is read by the kernel through its own copy of these structs in
+// kernel/src/kernel_args.rs, so both are #[repr(C)] and have to be changed together.
+
+#[repr(C)]
 pub struct OSMemEntry {
     pub ty: MemoryType,
     pub base: usize,
@@ -9,6 +15,47 @@ pub struct OSMemEntry {
     pub att: MemoryAttribute,
 }
 
+/// The values of `FramebufferInfo::format`
+pub mod pixel_format {
+    /// There is no framebuffer
+    pub const NONE: u32 = 0;
+
+    /// 32 bit pixels with red in the lowest byte
+    pub const RGB: u32 = 1;
+
+    /// 32 bit pixels with blue in the lowest byte
+    pub const BGR: u32 = 2;
+
+    /// 32 bit pixels laid out by the color masks
+    pub const BITMASK: u32 = 3;
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FramebufferInfo {
+    /// The physical address of the framebuffer
+    pub base: usize,
+
+    /// The size in bytes of the framebuffer
+    pub size: usize,
+
+    /// The visible size in pixels
+    pub width: u32,
+    pub height: u32,
+
+    /// The number of pixels from the start of one line to the start of the next
+    pub stride: u32,
+
+    /// One of the `pixel_format` values
+    pub format: u32,
+
+    /// Which bits of a pixel hold each color, for `pixel_format::BITMASK`
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+}
+
+#[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct KernelArgs {
     /// The physical address of the ACPI RSDP
@@ -31,6 +78,30 @@ pub struct KernelArgs {
 
     /// The number of entries in the slice pointed at by memmap_ptr
     memmap_entries: usize,
+
+    /// TSC timestamps of each bootloader phase
+    boot_timing: BootTimestamps,
+
+    /// The pointer to the persistent crash store region
+    pstore_ptr: *mut c_void,
+
+    /// The size in bytes of the region pointed at by pstore_ptr
+    pstore_size: usize,
+
+    /// The physical address of the first page the kernel image was loaded into
+    kernel_base: usize,
+
+    /// The size in bytes of the loaded kernel image, rounded up to whole pages
+    kernel_size: usize,
+
+    /// The physical address of the bottom of the kernel stack
+    stack_base: usize,
+
+    /// The size in bytes of the kernel stack
+    stack_size: usize,
+
+    /// The GOP framebuffer, with a format of `pixel_format::NONE` if there is none
+    framebuffer: FramebufferInfo,
 }
 
 // Initially populate an empty struct with every value set to 0. We cannot derive this
@@ -45,6 +116,14 @@ impl Default for KernelArgs {
             pcie_ptr: core::ptr::null_mut(),
             memmap_ptr: core::ptr::null_mut(),
             memmap_entries: 0,
+            boot_timing: BootTimestamps::default(),
+            pstore_ptr: core::ptr::null_mut(),
+            pstore_size: 0,
+            kernel_base: 0,
+            kernel_size: 0,
+            stack_base: 0,
+            stack_size: 0,
+            framebuffer: FramebufferInfo { format: pixel_format::NONE, ..FramebufferInfo::default() },
         }
     }
 }
@@ -123,4 +202,32 @@ impl KernelArgs {
     pub fn get_memmap_entries(&self) -> usize {
         self.memmap_entries
     }
+
+    /// Sets the bootloader phase timestamps
+    pub fn set_boot_timing(&mut self, timing: BootTimestamps) {
+        self.boot_timing = timing;
+    }
+
+    /// Sets the crash store pointer and size
+    pub fn set_pstore(&mut self, ptr: *mut c_void, size: usize) {
+        self.pstore_ptr = ptr;
+        self.pstore_size = size;
+    }
+
+    /// Sets the physical range the kernel image was loaded into
+    pub fn set_kernel_image(&mut self, base: usize, size: usize) {
+        self.kernel_base = base;
+        self.kernel_size = size;
+    }
+
+    /// Sets the physical range of the kernel stack
+    pub fn set_stack(&mut self, base: usize, size: usize) {
+        self.stack_base = base;
+        self.stack_size = size;
+    }
+
+    /// Sets the GOP framebuffer
+    pub fn set_framebuffer(&mut self, framebuffer: FramebufferInfo) {
+        self.framebuffer = framebuffer;
+    }
 }
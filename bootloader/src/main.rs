@@ -18,10 +18,18 @@ use uefi::allocator::Allocator;
 #[global_allocator]
 static ALLOCATOR: Allocator = Allocator;
 
+mod boot_timing;
+mod framebuffer;
+mod kernel_args;
+mod memmap;
+mod pstore;
 mod serial_output;
 
 use serial_output::SerialPort;
+use boot_timing::BootPhase;
+use kernel_args::KernelArgs;
 use log::info;
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use uefi::boot::MemoryType;
 use uefi::prelude::*;
@@ -31,12 +39,22 @@ use uefi::proto::media::fs::SimpleFileSystem;
 use uefi::boot::{self, ScopedProtocol};
 use uefi::Error;
 use core::arch::asm;
+use core::fmt::Write;
 
 const KERNEL_LOCATION: &str = "\\EFI\\router_os\\kernel.bin";
 const KERNEL_STACK_SIZE: usize = 8 * 1024 * 1024; //8MB
 
+//if loading the kernel takes longer than this, the firmware resets the machine. the firmware
+//disarms the watchdog when boot services exit, and the kernel arms its own.
+const BOOT_WATCHDOG_SECS: usize = 60;
+
+//watchdog codes up to 0xFFFF are reserved for the firmware
+const BOOT_WATCHDOG_CODE: u64 = 0x1_0000;
+
 #[entry]
 fn main() -> Status {
+    boot_timing::stamp(BootPhase::Entry);
+
     uefi::helpers::init().expect("uefi helper functions could not be initialized");
 
     //initialize the serial port to get output to a the host    
@@ -44,6 +62,21 @@ fn main() -> Status {
     port.init();
     
     info!("Hello world!");
+
+    //arm the firmware watchdog so a hang while loading the kernel resets the router
+    if let Err(e) = boot::set_watchdog_timer(BOOT_WATCHDOG_SECS, BOOT_WATCHDOG_CODE, None) {
+        info!("WARNING: could not arm the boot watchdog: {:?}", e);
+    }
+
+    //calibrate the TSC against the firmware timer so the boot timestamps can be converted to time
+    let tsc_hz = boot_timing::calibrate_tsc();
+    info!("TSC frequency: {} Hz", tsc_hz);
+
+    //reserve the crash store, and report any crash the kernel recorded there before the reboot
+    let pstore_region = pstore::reserve_region();
+    if let Some(record) = pstore_region.and_then(pstore::previous_crash) {
+        pstore::log_crash(record);
+    }
     
     //attempt to convert the kernel location to a cstring.
     let path: CString16 = CString16::try_from(KERNEL_LOCATION).expect("kernel location could not be determined");
@@ -60,16 +93,42 @@ fn main() -> Status {
     info!("Kernel file loaded: {} bytes", buffer.len());
 
     //allocate memory for the kernel, and get the address
-    if let Some(kernel_addr) = allocate_kernel_mem(&buffer) {
+    if let Some(kernel) = allocate_kernel_mem(&buffer) {
+        let kernel_addr = kernel.entry;
         info!("Kernel address: {:p}", kernel_addr);
     
         //allocation was good, initialize the stack
         match setup_kernel_stack() {
             Ok(stack_ptr) => unsafe {
-                let entry_fn: extern "C" fn() -> ! = core::mem::transmute(kernel_addr);
-
-                //load the stack pointer into the rsp
-                asm!("mov rsp, {}", in(reg) stack_ptr);
+                //the kernel arguments are allocated as LOADER_DATA, which stays valid after
+                //boot services exit
+                let args: &'static mut KernelArgs = Box::leak(Box::new(KernelArgs::default()));
+                //the table is only lent to an Fn closure, so copy it out before filling in args
+                let cfg_tables: Vec<_> = uefi::system::with_config_table(|cfg_tables| cfg_tables.to_vec());
+                args.populate_from_cfg_table(&cfg_tables);
+                if let Some(region) = pstore_region {
+                    args.set_pstore(region as *mut core::ffi::c_void, pstore::PSTORE_SIZE);
+                }
+                args.set_kernel_image(kernel.base, kernel.size);
+                args.set_stack(stack_ptr as usize - KERNEL_STACK_SIZE, KERNEL_STACK_SIZE);
+                if let Some(framebuffer) = framebuffer::query() {
+                    info!("Framebuffer: {}x{} at {:#x}", framebuffer.width, framebuffer.height, framebuffer.base);
+                    args.set_framebuffer(framebuffer);
+                }
+
+                //the memory map is only final once boot services exit, but its buffer has to be
+                //allocated before then
+                let memmap_buffer = match memmap::MemMapBuffer::allocate() {
+                    Ok(buffer) => buffer,
+                    Err(e) => {
+                        info!("ERROR: could not allocate the memory map buffer: {:?}", e);
+                        return Status::LOAD_ERROR;
+                    }
+                };
+                info!("Memory map buffer has room for {} entries", memmap_buffer.capacity);
+                info!("Kernel args: {:?}", args);
+
+                boot_timing::log_report(&boot_timing::snapshot());
 
                 // let rsp: u64;
                 // asm!("mov {}, rsp", out(reg) rsp);
@@ -94,9 +153,23 @@ fn main() -> Status {
 
                 //exit the boot services and enter into the entry function
                 info!("Entering entry function now...");
-                let _mem = boot::exit_boot_services(MemoryType::LOADER_DATA);
+                let mem = boot::exit_boot_services(MemoryType::LOADER_DATA);
+                boot_timing::stamp(BootPhase::ExitBootServices);
+                let memmap_entries = match memmap_buffer.fill(&mem) {
+                    Ok(count) => count,
+                    Err(total) => {
+                        //only the serial port is left once boot services are gone. the entries
+                        //dropped are memory the kernel won't know about, so it never uses it.
+                        let _ = writeln!(port, "WARNING: the memory map has {} entries, only the first {} are passed on",
+                            total, memmap_buffer.capacity
+                        );
+                        memmap_buffer.capacity
+                    }
+                };
+                args.set_memmap(memmap_buffer.as_ptr(), memmap_entries);
+                args.set_boot_timing(boot_timing::snapshot());
                 
-                entry_fn();
+                jump_to_kernel(kernel_addr, stack_ptr, args);
             },
             Err(err) => {
                 info!("ERROR could not setup the kernel stack: {:?}", err.data());
@@ -111,18 +184,47 @@ fn main() -> Status {
 
 }
 
+/// Switches to the kernel's stack and calls its entry function. The kernel's `_start` uses the
+/// System V calling convention, so the pointer to the kernel arguments goes in rdi. This is all
+/// done in one asm block, as nothing on the old stack can be touched once rsp has moved.
+unsafe fn jump_to_kernel(entry: *const u8, stack_top: *mut u8, args: &'static KernelArgs) -> ! {
+    unsafe {
+        asm!(
+            "mov rsp, {stack}",
+            "xor rbp, rbp",
+            "call {entry}",
+            "ud2",
+            stack = in(reg) stack_top,
+            entry = in(reg) entry,
+            in("rdi") args as *const KernelArgs,
+            options(noreturn)
+        )
+    }
+}
+
 fn read_in_kernel(path: CString16) -> Result<Vec<u8>, Error> {
     //open the filesystem to the root
     let fs_handle: ScopedProtocol<SimpleFileSystem> = boot::get_image_file_system(boot::image_handle())?;
     let mut fs: FileSystem = FileSystem::new(fs_handle);
+    boot_timing::stamp(BootPhase::FsOpen);
 
     //attempt to open the kernel binary
     let buffer: Vec<u8>  = fs.read(path.as_ref()).expect("Kernel could not be read into the buffer.");
+    boot_timing::stamp(BootPhase::KernelRead);
 
     Ok(buffer)
 }
 
-fn allocate_kernel_mem(buffer: &[u8]) -> Option<*const u8> {
+/// Where the kernel was loaded, and where to jump to start it
+struct LoadedKernel {
+    entry: *const u8,
+
+    /// The physical range the loaded segments cover, in whole pages
+    base: usize,
+    size: usize,
+}
+
+fn allocate_kernel_mem(buffer: &[u8]) -> Option<LoadedKernel> {
     
     if let Some(header) = parse_elf_header(buffer) {
         //load the segments into memory
@@ -130,7 +232,11 @@ fn allocate_kernel_mem(buffer: &[u8]) -> Option<*const u8> {
             buffer,
             header
         ) {
-            Ok(_) => Some(header.e_entry as *const u8), //return the entry function address
+            Ok((base, end)) => Some(LoadedKernel {
+                entry: header.e_entry as *const u8, //the entry function address
+                base,
+                size: end - base,
+            }),
             Err(e) => {
                 info!("Error allocating kernel memory: {}", e);
                 return None;
@@ -191,7 +297,7 @@ struct Elf64Phdr {
 }
 
 // Function to parse program headers and load segments
-fn load_segments(elf_data: &[u8], e_header: &Elf64Ehdr) -> Result<(), uefi::Error> {
+fn load_segments(elf_data: &[u8], e_header: &Elf64Ehdr) -> Result<(usize, usize), uefi::Error> {
 
     // Loop through all program headers
     info!("number of program headers: {}", e_header.e_phnum);
@@ -199,16 +305,18 @@ fn load_segments(elf_data: &[u8], e_header: &Elf64Ehdr) -> Result<(), uefi::Erro
     //parse the program headers
     //if None was returned, abort
     if let Some(p_headers) = parse_program_headers(elf_data, e_header) {
+        boot_timing::stamp(BootPhase::ElfParse);
 
         match load_elf_segments(elf_data, p_headers) {
-            Ok(_) => (),
-            Err(msg) => return Err(msg)
+            Ok(range) => {
+                boot_timing::stamp(BootPhase::SegmentLoad);
+                Ok(range)
+            }
+            Err(msg) => Err(msg)
         }
     } else {
-        return Err(uefi::Error::new(Status::LOAD_ERROR, ()));
+        Err(uefi::Error::new(Status::LOAD_ERROR, ()))
     }
-
-    Ok(())
 }
 
 fn parse_program_headers<'a>(buffer: &'a [u8], e_header: &'a Elf64Ehdr) -> Option<&'a [Elf64Phdr]> {
@@ -235,7 +343,11 @@ fn parse_program_headers<'a>(buffer: &'a [u8], e_header: &'a Elf64Ehdr) -> Optio
 const PAGE_SIZE: usize = 4096;
 const LOAD_SEGMENT_TYPE: u32 = 1;
 
-fn load_elf_segments(buffer: &[u8], ph_table: &[Elf64Phdr]) -> Result<(), uefi::Error> {
+//loads every LOAD segment, and returns the page aligned start and end of the memory they cover
+fn load_elf_segments(buffer: &[u8], ph_table: &[Elf64Phdr]) -> Result<(usize, usize), uefi::Error> {
+    let mut image_start = usize::MAX;
+    let mut image_end = 0;
+
     //loop through the program headers found and display their information
     //this will be where we would actually load the segments into memory
     for (i, ph) in ph_table.iter().enumerate() {
@@ -266,6 +378,8 @@ fn load_elf_segments(buffer: &[u8], ph_table: &[Elf64Phdr]) -> Result<(), uefi::
 
         //this will be a whole number, as the start and end have been aligned to the page size
         let num_pages = (page_aligned_end - page_aligned_start) / PAGE_SIZE;
+        image_start = image_start.min(page_aligned_start);
+        image_end = image_end.max(page_aligned_end);
 
         let allocated_addr = match boot::allocate_pages(
             boot::AllocateType::Address(page_aligned_start as u64),
@@ -313,8 +427,12 @@ fn load_elf_segments(buffer: &[u8], ph_table: &[Elf64Phdr]) -> Result<(), uefi::
         );
     }
 
-    //return 
-    Ok(())
+    if image_start > image_end {
+        info!("ERROR: the kernel has no loadable segments");
+        return Err(uefi::Error::new(Status::LOAD_ERROR, ()));
+    }
+
+    Ok((image_start, image_end))
 }
 
 
@@ -374,6 +492,7 @@ fn setup_kernel_stack() -> Result<*mut u8, uefi::Error> {
 //     };
 
 //     info!("Hello world!");
+
 //     boot::stall(20_000_000);
 
 //     let mut karg = KernelArgs::default();
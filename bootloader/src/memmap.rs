@@ -0,0 +1,67 @@
+use uefi::boot::{self, AllocateType, MemoryType};
+use uefi::mem::memory_map::{MemoryMap, MemoryMapOwned};
+
+use crate::kernel_args::OSMemEntry;
+
+// The memory map handed to the kernel. Nothing can be allocated once boot services have exited,
+// so the buffer is allocated first with room to spare, and filled in from the final map that
+// exit_boot_services returns. The allocation itself can split entries, so the map is read again
+// afterwards and the buffer made bigger until the spare room is still there.
+
+const PAGE_SIZE: usize = 4096;
+
+//allocating the buffer, and anything else done before exiting, can split a few more entries
+const SPARE_ENTRIES: usize = 32;
+
+pub struct MemMapBuffer {
+    ptr: *mut OSMemEntry,
+
+    /// How many entries fit in the buffer
+    pub capacity: usize,
+}
+
+impl MemMapBuffer {
+    /// Allocates a LOADER_DATA buffer big enough for the current memory map, plus some spare
+    pub fn allocate() -> Result<Self, uefi::Error> {
+        let mut entries = boot::memory_map(MemoryType::LOADER_DATA)?.len() + SPARE_ENTRIES;
+        loop {
+            let pages = (entries * size_of::<OSMemEntry>()).div_ceil(PAGE_SIZE);
+            let ptr = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)?;
+            let capacity = pages * PAGE_SIZE / size_of::<OSMemEntry>();
+
+            entries = boot::memory_map(MemoryType::LOADER_DATA)?.len() + SPARE_ENTRIES;
+            if entries <= capacity {
+                return Ok(MemMapBuffer { ptr: ptr.as_ptr() as *mut OSMemEntry, capacity });
+            }
+            unsafe { boot::free_pages(ptr, pages)? };
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut OSMemEntry {
+        self.ptr
+    }
+
+    /// Copies the final memory map into the buffer, and returns the entry count for
+    /// `KernelArgs::set_memmap`. If the map doesn't fit, the entries that do are still copied and
+    /// the error is how many there were in all.
+    pub fn fill(&self, map: &MemoryMapOwned) -> Result<usize, usize> {
+        let mut count = 0;
+
+        for (i, desc) in map.entries().take(self.capacity).enumerate() {
+            unsafe {
+                self.ptr.add(i).write(OSMemEntry {
+                    ty: desc.ty,
+                    base: desc.phys_start as usize,
+                    pages: desc.page_count as usize,
+                    att: desc.att,
+                });
+            }
+            count += 1;
+        }
+
+        if map.len() > count {
+            return Err(map.len());
+        }
+        Ok(count)
+    }
+}
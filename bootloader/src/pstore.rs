@@ -0,0 +1,173 @@
+use log::info;
+use uefi::boot::{self, MemoryType};
+
+// The persistent crash store. The kernel's panic handler writes a `CrashRecord` into a fixed
+// physical region, which survives a warm reboot. The layout here has to match
+// kernel/src/pstore.rs.
+
+/// The physical address of the crash store. This needs to be the same on every boot.
+pub const PSTORE_ADDR: u64 = 0x0300_0000; //48MB
+pub const PSTORE_PAGES: usize = 2;
+pub const PSTORE_SIZE: usize = PSTORE_PAGES * 4096;
+
+pub const CRASH_MAGIC: u64 = u64::from_le_bytes(*b"RTRCRASH");
+pub const CRASH_VERSION: u32 = 1;
+
+pub const CRASH_FILE_LEN: usize = 128;
+pub const CRASH_MESSAGE_LEN: usize = 512;
+pub const CRASH_LOG_LEN: usize = 4096;
+
+/// The register state at the time of the crash
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct CrashRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+#[repr(C)]
+pub struct CrashRecord {
+    pub magic: u64,
+    pub version: u32,
+
+    /// FNV-1a checksum of the whole record, calculated with this field set to 0
+    pub checksum: u32,
+
+    /// The TSC value when the crash was recorded
+    pub tsc: u64,
+
+    pub regs: CrashRegisters,
+    pub line: u32,
+    pub column: u32,
+    pub file_len: u32,
+    pub message_len: u32,
+    pub log_len: u32,
+    pub file: [u8; CRASH_FILE_LEN],
+    pub message: [u8; CRASH_MESSAGE_LEN],
+
+    /// The last bytes of the kernel's log ring, oldest first
+    pub log: [u8; CRASH_LOG_LEN],
+}
+
+const _: () = assert!(core::mem::size_of::<CrashRecord>() <= PSTORE_SIZE);
+
+impl CrashRecord {
+    /// Checks the magic, version and checksum of the record
+    pub fn is_valid(&self) -> bool {
+        self.magic == CRASH_MAGIC
+            && self.version == CRASH_VERSION
+            && self.file_len as usize <= CRASH_FILE_LEN
+            && self.message_len as usize <= CRASH_MESSAGE_LEN
+            && self.log_len as usize <= CRASH_LOG_LEN
+            && self.checksum == self.calculate_checksum()
+    }
+
+    pub fn calculate_checksum(&self) -> u32 {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                core::mem::size_of::<Self>()
+            )
+        };
+
+        //the checksum field is skipped, so the value can be stored inside the data it covers
+        let checksum_start = core::mem::offset_of!(CrashRecord, checksum);
+        let checksum_end = checksum_start + core::mem::size_of::<u32>();
+
+        let mut hash: u32 = 0x811c9dc5;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let byte = if (checksum_start..checksum_end).contains(&i) { 0 } else { byte };
+            hash ^= byte as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+
+        hash
+    }
+
+    pub fn file(&self) -> &str {
+        as_str(&self.file[..self.file_len as usize])
+    }
+
+    pub fn message(&self) -> &str {
+        as_str(&self.message[..self.message_len as usize])
+    }
+
+    pub fn log(&self) -> &str {
+        as_str(&self.log[..self.log_len as usize])
+    }
+}
+
+//the kernel truncates strings at byte boundaries, so cut back to the last valid character
+fn as_str(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => unsafe { core::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) },
+    }
+}
+
+/// Reserves the crash store region so neither the firmware nor the kernel's frame allocator
+/// hand it out. Returns the address of the region, or None if it could not be reserved.
+pub fn reserve_region() -> Option<*mut u8> {
+    match boot::allocate_pages(
+        boot::AllocateType::Address(PSTORE_ADDR),
+        MemoryType::RESERVED,
+        PSTORE_PAGES
+    ) {
+        Ok(addr) => Some(addr.as_ptr()),
+        Err(e) => {
+            info!("WARNING: crash store at {:#x} could not be reserved: {:?}", PSTORE_ADDR, e);
+            None
+        }
+    }
+}
+
+/// Returns the crash record left in the region by the last boot, if there is one
+pub fn previous_crash(region: *mut u8) -> Option<&'static CrashRecord> {
+    let record = unsafe { &*(region as *const CrashRecord) };
+
+    if record.is_valid() {
+        Some(record)
+    } else {
+        None
+    }
+}
+
+/// Prints a crash record on the serial console
+pub fn log_crash(record: &CrashRecord) {
+    let regs = &record.regs;
+
+    info!("==== kernel crash record from the previous boot ====");
+    info!("panicked at {}:{}:{}: {}", record.file(), record.line, record.column, record.message());
+    info!("RIP {:#018x} RSP {:#018x} RBP {:#018x} RFLAGS {:#018x}", regs.rip, regs.rsp, regs.rbp, regs.rflags);
+    info!("RAX {:#018x} RBX {:#018x} RCX {:#018x} RDX {:#018x}", regs.rax, regs.rbx, regs.rcx, regs.rdx);
+    info!("RSI {:#018x} RDI {:#018x} R8  {:#018x} R9  {:#018x}", regs.rsi, regs.rdi, regs.r8, regs.r9);
+    info!("R10 {:#018x} R11 {:#018x} R12 {:#018x} R13 {:#018x}", regs.r10, regs.r11, regs.r12, regs.r13);
+    info!("R14 {:#018x} R15 {:#018x}", regs.r14, regs.r15);
+    info!("CR0 {:#018x} CR2 {:#018x} CR3 {:#018x} CR4 {:#018x}", regs.cr0, regs.cr2, regs.cr3, regs.cr4);
+
+    info!("---- log tail ----");
+    for line in record.log().lines() {
+        info!("{}", line);
+    }
+    info!("==== end of crash record ====");
+}
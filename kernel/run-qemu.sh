@@ -0,0 +1,67 @@
+#!/bin/bash
+
+# Boots a kernel binary in QEMU behind the real bootloader. Cargo runs this for the kernel
+# target (see .cargo/config.toml), so `cargo run` boots the kernel and `cargo test` boots the
+# test kernel. It uses TCG, so KVM is not needed.
+#
+# A test kernel reports on the serial port, which goes to stdout, and exits through QEMU's
+# isa-debug-exit device. QEMU exits with (code << 1) | 1, so the kernel's success code 0x10
+# comes out as 33, which is turned into 0 here. Anything else, including QEMU being killed after
+# TEST_TIMEOUT seconds, fails the run. The test kernel gets a virtio NIC on QEMU's user network,
+# which the network tests use as eth0.
+#
+# OVMF_CODE and OVMF_VARS point at the firmware, and default to the copies in the bootloader
+# directory.
+
+set -eu
+
+KERNEL="$1"
+KERNEL_DIR="$(cd "$(dirname "$0")" && pwd)"
+BOOTLOADER_DIR="$KERNEL_DIR/../bootloader"
+OVMF_CODE="${OVMF_CODE:-$BOOTLOADER_DIR/OVMF_CODE.fd}"
+OVMF_VARS="${OVMF_VARS:-$BOOTLOADER_DIR/OVMF_VARS.fd}"
+TEST_TIMEOUT="${TEST_TIMEOUT:-300}"
+
+QEMU_SUCCESS=33
+
+if ! command -v qemu-system-x86_64 > /dev/null; then
+    echo "qemu-system-x86_64 is not installed, so the kernel can't be booted" >&2
+    exit 1
+fi
+
+#build the bootloader, and lay out an ESP with it and the kernel in a scratch directory
+(cd "$BOOTLOADER_DIR" && cargo build --release --quiet)
+ESP="$(mktemp -d)"
+trap 'rm -rf "$ESP"' EXIT
+mkdir -p "$ESP/EFI/BOOT" "$ESP/EFI/router_os"
+cp "$BOOTLOADER_DIR/target/x86_64-unknown-uefi/release/bootloader.efi" "$ESP/EFI/BOOT/bootx64.efi"
+cp "$KERNEL" "$ESP/EFI/router_os/kernel.bin"
+
+QEMU_ARGS=(
+    -m 512M
+    -drive if=pflash,format=raw,readonly=on,file="$OVMF_CODE"
+    -drive if=pflash,format=raw,readonly=on,file="$OVMF_VARS"
+    -drive format=raw,file=fat:rw:"$ESP"
+    -serial stdio
+)
+
+#cargo gives test binaries a hash suffix, which the one `cargo run` boots doesn't have
+if [[ "$(basename "$KERNEL")" =~ -[0-9a-f]{16}$ ]]; then
+    set +e
+    timeout --foreground "$TEST_TIMEOUT" qemu-system-x86_64 "${QEMU_ARGS[@]}" \
+        -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
+        -nic user,model=virtio-net-pci \
+        -display none \
+        -no-reboot
+    STATUS=$?
+    set -e
+
+    case "$STATUS" in
+        "$QEMU_SUCCESS") exit 0 ;;
+        124) echo "kernel tests timed out after $TEST_TIMEOUT seconds" >&2 ;;
+        *) echo "kernel tests failed (QEMU exit status $STATUS)" >&2 ;;
+    esac
+    exit 1
+fi
+
+exec qemu-system-x86_64 "${QEMU_ARGS[@]}"
@@ -0,0 +1,168 @@
+use core::ffi::c_void;
+use core::mem::size_of;
+use spin::Once;
+
+pub mod madt;
+
+/*
+ * Finds ACPI tables through the RSDP the bootloader took from the UEFI config table. The
+ * firmware's page tables identity map every table, so physical addresses are used as-is.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/RSDP
+ * https://wiki.osdev.org/RSDT
+ * ACPI 6.5, 5.2 "ACPI System Description Tables"
+ */
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+//the size of the ACPI 1.0 part of the RSDP, which is all the first checksum covers
+const RSDP_V1_SIZE: usize = 20;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The bootloader didn't find an RSDP
+    NoRsdp,
+
+    /// A table's signature is not what it was expected to be
+    BadSignature,
+
+    /// A table's bytes don't sum to zero
+    BadChecksum,
+
+    /// The table isn't in the RSDT/XSDT
+    NotFound,
+
+    /// The table is shorter than its fixed fields, or an entry runs past its end
+    Truncated,
+}
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+
+    //only valid from revision 2 up
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header every system description table starts with
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// The bytes of the table after the header
+    pub fn body(&self) -> &[u8] {
+        let length = self.length as usize;
+        unsafe {
+            let start = (self as *const Self as *const u8).add(size_of::<Self>());
+            core::slice::from_raw_parts(start, length.saturating_sub(size_of::<Self>()))
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        let length = self.length as usize;
+        length >= size_of::<Self>()
+            && checksum(unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, length) })
+    }
+}
+
+//the root table, and whether its entries are 64 bit (XSDT) or 32 bit (RSDT)
+struct RootTable {
+    header: &'static SdtHeader,
+    wide: bool,
+}
+
+static ROOT: Once<RootTable> = Once::new();
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Validates the RSDP and the RSDT or XSDT it points at. `version` is the one the bootloader
+/// passes along with the pointer: 2 means the XSDT can be used.
+pub fn init(rsdp: *const c_void, version: u8) -> Result<(), AcpiError> {
+    if rsdp.is_null() {
+        return Err(AcpiError::NoRsdp);
+    }
+
+    let rsdp = unsafe { &*(rsdp as *const Rsdp) };
+    if &rsdp.signature != RSDP_SIGNATURE {
+        return Err(AcpiError::BadSignature);
+    }
+
+    let bytes = unsafe { core::slice::from_raw_parts(rsdp as *const Rsdp as *const u8, RSDP_V1_SIZE) };
+    if !checksum(bytes) {
+        return Err(AcpiError::BadChecksum);
+    }
+
+    //prefer the XSDT, the RSDT can't point above 4 GiB
+    let (address, wide) = if version >= 2 && rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(rsdp as *const Rsdp as *const u8, size_of::<Rsdp>())
+        };
+        if !checksum(bytes) {
+            return Err(AcpiError::BadChecksum);
+        }
+        (rsdp.xsdt_address as usize, true)
+    } else {
+        (rsdp.rsdt_address as usize, false)
+    };
+
+    let header = unsafe { &*(address as *const SdtHeader) };
+    let expected = if wide { b"XSDT" } else { b"RSDT" };
+    if &header.signature != expected {
+        return Err(AcpiError::BadSignature);
+    }
+    if !header.is_valid() {
+        return Err(AcpiError::BadChecksum);
+    }
+
+    ROOT.call_once(|| RootTable { header, wide });
+    Ok(())
+}
+
+/// Finds the first table with the given signature, and checks its checksum
+pub fn find_table(signature: &[u8; 4]) -> Result<&'static SdtHeader, AcpiError> {
+    let root = ROOT.get().ok_or(AcpiError::NoRsdp)?;
+    let body = root.header.body();
+    let entry_size = if root.wide { 8 } else { 4 };
+
+    for entry in body.chunks_exact(entry_size) {
+        //the entries are only 4 byte aligned, even in the XSDT
+        let address = if root.wide {
+            u64::from_le_bytes(entry.try_into().unwrap()) as usize
+        } else {
+            u32::from_le_bytes(entry.try_into().unwrap()) as usize
+        };
+
+        let header = unsafe { &*(address as *const SdtHeader) };
+        if &header.signature != signature {
+            continue;
+        }
+
+        return if header.is_valid() {
+            Ok(header)
+        } else {
+            Err(AcpiError::BadChecksum)
+        };
+    }
+
+    Err(AcpiError::NotFound)
+}
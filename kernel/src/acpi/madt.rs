@@ -0,0 +1,257 @@
+use spin::Once;
+
+use super::AcpiError;
+use crate::interrupt::irq::{Polarity, Trigger};
+
+/*
+ * Parses the MADT ("APIC"), which lists the local APICs, the I/O APICs, and how the ISA IRQs
+ * are wired to them.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/MADT
+ * ACPI 6.5, 5.2.12 "Multiple APIC Description Table (MADT)"
+ */
+
+pub const MAX_CPUS: usize = 64;
+pub const MAX_IO_APICS: usize = 8;
+pub const MAX_OVERRIDES: usize = 16;
+pub const MAX_NMIS: usize = 8;
+
+//the ACPI processor UID that means "every processor" in a local APIC NMI entry
+pub const ALL_PROCESSORS: u32 = 0xFFFF_FFFF;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+const ENTRY_LOCAL_X2APIC_NMI: u8 = 10;
+
+//set in the MADT flags when the system also has dual 8259 PICs
+const FLAG_PCAT_COMPAT: u32 = 1 << 0;
+
+//set in a local APIC entry when the processor can be used
+const CPU_ENABLED: u32 = 1 << 0;
+const CPU_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Cpu {
+    /// The ACPI processor UID, which NMI entries refer to
+    pub processor_uid: u32,
+    pub apic_id: u32,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u32,
+
+    /// The first global system interrupt this I/O APIC handles
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that isn't identity mapped to the GSI of the same number, or that doesn't use the
+/// ISA default of active high, edge triggered
+#[derive(Copy, Clone, Debug)]
+pub struct SourceOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+/// A local APIC LINT pin that is wired to NMI
+#[derive(Copy, Clone, Debug)]
+pub struct LocalApicNmi {
+    pub processor_uid: u32,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+/// The parts of the MADT the interrupt code needs. Entries past the MAX_* limits are dropped.
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub pcat_compat: bool,
+
+    cpus: [Cpu; MAX_CPUS],
+    cpu_count: usize,
+    io_apics: [IoApicEntry; MAX_IO_APICS],
+    io_apic_count: usize,
+    overrides: [Option<SourceOverride>; MAX_OVERRIDES],
+    override_count: usize,
+    nmis: [Option<LocalApicNmi>; MAX_NMIS],
+    nmi_count: usize,
+}
+
+impl Madt {
+    /// The usable processors
+    pub fn cpus(&self) -> &[Cpu] {
+        &self.cpus[..self.cpu_count]
+    }
+
+    pub fn io_apics(&self) -> &[IoApicEntry] {
+        &self.io_apics[..self.io_apic_count]
+    }
+
+    pub fn overrides(&self) -> impl Iterator<Item = &SourceOverride> {
+        self.overrides[..self.override_count].iter().flatten()
+    }
+
+    pub fn nmis(&self) -> impl Iterator<Item = &LocalApicNmi> {
+        self.nmis[..self.nmi_count].iter().flatten()
+    }
+
+    /// Finds the override for an ISA IRQ, if there is one
+    pub fn source_override(&self, isa_irq: u8) -> Option<&SourceOverride> {
+        self.overrides().find(|o| o.isa_irq == isa_irq)
+    }
+}
+
+static MADT: Once<Madt> = Once::new();
+
+//the polarity and trigger bits of the MPS INTI flags. "conforms to the bus" means the ISA
+//default for everything the MADT describes.
+fn inti_flags(flags: u16) -> (Polarity, Trigger) {
+    let polarity = match flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b11 => Trigger::Level,
+        _ => Trigger::Edge,
+    };
+
+    (polarity, trigger)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn parse(body: &[u8]) -> Result<Madt, AcpiError> {
+    if body.len() < 8 {
+        return Err(AcpiError::Truncated);
+    }
+
+    let mut madt = Madt {
+        local_apic_address: read_u32(body, 0) as u64,
+        pcat_compat: read_u32(body, 4) & FLAG_PCAT_COMPAT != 0,
+        cpus: [Cpu::default(); MAX_CPUS],
+        cpu_count: 0,
+        io_apics: [IoApicEntry::default(); MAX_IO_APICS],
+        io_apic_count: 0,
+        overrides: [None; MAX_OVERRIDES],
+        override_count: 0,
+        nmis: [None; MAX_NMIS],
+        nmi_count: 0,
+    };
+
+    let mut offset = 8;
+    while offset + 2 <= body.len() {
+        let ty = body[offset];
+        let len = body[offset + 1] as usize;
+        if len < 2 || offset + len > body.len() {
+            return Err(AcpiError::Truncated);
+        }
+        let entry = &body[offset..offset + len];
+
+        match (ty, len) {
+            (ENTRY_LOCAL_APIC, 8..) => {
+                let flags = read_u32(entry, 4);
+                if flags & (CPU_ENABLED | CPU_ONLINE_CAPABLE) != 0 && madt.cpu_count < MAX_CPUS {
+                    madt.cpus[madt.cpu_count] = Cpu {
+                        processor_uid: entry[2] as u32,
+                        apic_id: entry[3] as u32,
+                    };
+                    madt.cpu_count += 1;
+                }
+            }
+            (ENTRY_LOCAL_X2APIC, 16..) => {
+                let flags = read_u32(entry, 8);
+                if flags & (CPU_ENABLED | CPU_ONLINE_CAPABLE) != 0 && madt.cpu_count < MAX_CPUS {
+                    madt.cpus[madt.cpu_count] = Cpu {
+                        processor_uid: read_u32(entry, 12),
+                        apic_id: read_u32(entry, 4),
+                    };
+                    madt.cpu_count += 1;
+                }
+            }
+            (ENTRY_IO_APIC, 12..) if madt.io_apic_count < MAX_IO_APICS => {
+                madt.io_apics[madt.io_apic_count] = IoApicEntry {
+                    id: entry[2],
+                    address: read_u32(entry, 4),
+                    gsi_base: read_u32(entry, 8),
+                };
+                madt.io_apic_count += 1;
+            }
+            (ENTRY_SOURCE_OVERRIDE, 10..) if madt.override_count < MAX_OVERRIDES => {
+                let (polarity, trigger) = inti_flags(read_u16(entry, 8));
+                madt.overrides[madt.override_count] = Some(SourceOverride {
+                    isa_irq: entry[3],
+                    gsi: read_u32(entry, 4),
+                    polarity,
+                    trigger,
+                });
+                madt.override_count += 1;
+            }
+            (ENTRY_LOCAL_APIC_NMI, 6..) if madt.nmi_count < MAX_NMIS => {
+                let (polarity, trigger) = inti_flags(read_u16(entry, 3));
+                let uid = match entry[2] {
+                    0xFF => ALL_PROCESSORS,
+                    uid => uid as u32,
+                };
+                madt.nmis[madt.nmi_count] = Some(LocalApicNmi {
+                    processor_uid: uid,
+                    lint: entry[5],
+                    polarity,
+                    trigger,
+                });
+                madt.nmi_count += 1;
+            }
+            (ENTRY_LOCAL_X2APIC_NMI, 12..) if madt.nmi_count < MAX_NMIS => {
+                let (polarity, trigger) = inti_flags(read_u16(entry, 2));
+                madt.nmis[madt.nmi_count] = Some(LocalApicNmi {
+                    processor_uid: read_u32(entry, 4),
+                    lint: entry[8],
+                    polarity,
+                    trigger,
+                });
+                madt.nmi_count += 1;
+            }
+            (ENTRY_LOCAL_APIC_ADDRESS, 12..) => {
+                madt.local_apic_address = read_u64(entry, 4);
+            }
+            _ => {}
+        }
+
+        offset += len;
+    }
+
+    Ok(madt)
+}
+
+/// Finds and parses the MADT. It is only parsed once, later calls return the same copy.
+pub fn init() -> Result<&'static Madt, AcpiError> {
+    if let Some(madt) = MADT.get() {
+        return Ok(madt);
+    }
+
+    let header = super::find_table(b"APIC")?;
+    let madt = parse(header.body())?;
+    Ok(MADT.call_once(|| madt))
+}
+
+/// The parsed MADT, if `init` succeeded
+pub fn get() -> Option<&'static Madt> {
+    MADT.get()
+}
@@ -0,0 +1,140 @@
+use core::arch::x86_64::_rdtsc;
+use core::fmt::{self, Write};
+use spin::Mutex;
+
+/// The bootloader phases that get a TSC timestamp. This mirrors `BootPhase` in
+/// bootloader/src/boot_timing.rs, and the discriminant is the index into `BootTimestamps::tsc`.
+#[repr(usize)]
+#[derive(Copy, Clone, Debug)]
+pub enum BootPhase {
+    Entry = 0,
+    FsOpen,
+    KernelRead,
+    ElfParse,
+    SegmentLoad,
+    ExitBootServices,
+}
+
+pub const BOOT_PHASE_COUNT: usize = 6;
+
+impl BootPhase {
+    pub const ALL: [BootPhase; BOOT_PHASE_COUNT] = [
+        BootPhase::Entry,
+        BootPhase::FsOpen,
+        BootPhase::KernelRead,
+        BootPhase::ElfParse,
+        BootPhase::SegmentLoad,
+        BootPhase::ExitBootServices,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BootPhase::Entry => "bootloader entry",
+            BootPhase::FsOpen => "filesystem open",
+            BootPhase::KernelRead => "kernel read",
+            BootPhase::ElfParse => "elf parse",
+            BootPhase::SegmentLoad => "segment load",
+            BootPhase::ExitBootServices => "exit boot services",
+        }
+    }
+}
+
+/// The timestamps handed over by the bootloader
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct BootTimestamps {
+    /// The TSC value at each `BootPhase`, or 0 if the phase was never reached
+    pub tsc: [u64; BOOT_PHASE_COUNT],
+
+    /// TSC ticks per second, calibrated by the bootloader against the firmware stall timer
+    pub tsc_hz: u64,
+}
+
+//the most kernel init stages that can be recorded. later stages are dropped.
+const MAX_KERNEL_STAGES: usize = 32;
+
+struct BootTimeLog {
+    loader: BootTimestamps,
+    stages: [(&'static str, u64); MAX_KERNEL_STAGES],
+    num_stages: usize,
+}
+
+static BOOT_TIME: Mutex<BootTimeLog> = Mutex::new(BootTimeLog {
+    loader: BootTimestamps {
+        tsc: [0; BOOT_PHASE_COUNT],
+        tsc_hz: 0,
+    },
+    stages: [("", 0); MAX_KERNEL_STAGES],
+    num_stages: 0,
+});
+
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Stores the bootloader's timestamps so they can be reported alongside the kernel's
+pub fn init(loader: &BootTimestamps) {
+    BOOT_TIME.lock().loader = *loader;
+}
+
+/// Records the current TSC value for a kernel init stage
+pub fn stage(name: &'static str) {
+    let tsc = rdtsc();
+    let mut log = BOOT_TIME.lock();
+
+    if log.num_stages < MAX_KERNEL_STAGES {
+        let i = log.num_stages;
+        log.stages[i] = (name, tsc);
+        log.num_stages += 1;
+    }
+}
+
+/// Returns the TSC frequency in Hz, or 0 if it was never calibrated
+pub fn tsc_hz() -> u64 {
+    BOOT_TIME.lock().loader.tsc_hz
+}
+
+/// Converts a TSC delta to microseconds, using the calibrated frequency
+pub fn ticks_to_us(ticks: u64, tsc_hz: u64) -> u64 {
+    if tsc_hz == 0 {
+        return 0;
+    }
+
+    ((ticks as u128 * 1_000_000) / tsc_hz as u128) as u64
+}
+
+/// Writes the time of every bootloader phase and kernel stage, relative to bootloader entry
+pub fn report(out: &mut impl Write) -> fmt::Result {
+    let log = BOOT_TIME.lock();
+    let hz = log.loader.tsc_hz;
+    let entry = log.loader.tsc[BootPhase::Entry as usize];
+
+    writeln!(out, "boot time report (TSC {} Hz)", hz)?;
+
+    //the time between each timestamp and the one before it
+    let mut last = entry;
+    let mut line = |out: &mut dyn Write, name: &str, tsc: u64| -> fmt::Result {
+        let result = writeln!(out, "  {:<24} +{:>8} us  (delta {:>8} us)",
+            name, ticks_to_us(tsc.saturating_sub(entry), hz), ticks_to_us(tsc.saturating_sub(last), hz)
+        );
+        last = tsc;
+        result
+    };
+
+    for phase in BootPhase::ALL {
+        let tsc = log.loader.tsc[phase as usize];
+        if tsc != 0 {
+            line(out, phase.name(), tsc)?;
+        }
+    }
+
+    //the kernel starts on the firmware's identity mapped page tables, so nothing is built
+    //between loading the segments and exiting boot services
+    writeln!(out, "  (no page table phase: the bootloader doesn't build any)")?;
+
+    for &(name, tsc) in &log.stages[..log.num_stages] {
+        line(out, name, tsc)?;
+    }
+
+    Ok(())
+}
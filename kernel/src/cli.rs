@@ -0,0 +1,934 @@
+use alloc::string::{String, ToString};
+use core::fmt::{self, Write};
+use core::net::{Ipv4Addr, Ipv6Addr};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use log::{info, LevelFilter};
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi;
+use crate::boot_time;
+use crate::console::{self, logger};
+use crate::drivers::net::{e1000, MacAddress, NetDevice, NetError, TxChecksum};
+use crate::drivers::watchdog;
+use crate::interrupt::entry::InterruptContext;
+use crate::interrupt::irq::{self, Irq};
+use crate::interrupt::{apic, exceptions, manager};
+use crate::kernel_args::memory_type;
+use crate::memory::frame::{self, FrameConstraints};
+use crate::memory::{heap, paging};
+use crate::net::interface::{self, Interface, InterfaceError, Ipv4Cidr, Ipv6Cidr};
+use crate::net::packet::{self, Packet};
+use crate::net::icmp;
+use crate::net::ipv4::{self, Ipv4Header};
+use crate::net::{arp, ethernet, SendError};
+use crate::panic::{self, PanicAction};
+use crate::pci::{self, driver::PciMatch, msi, PciAddress};
+use crate::pstore;
+use crate::serial::{self, SerialPort};
+use crate::time::{self, hpet, Instant};
+
+// The management console. It reads lines from COM1, looks the first word up in `COMMANDS` and
+// hands it the rest of the line. The main loop polls it, so a command runs with nothing else
+// going on.
+
+//the ISA IRQ of COM1
+const COM1_IRQ: u8 = 4;
+
+//the longest command line. anything typed past it is dropped.
+const MAX_LINE: usize = 80;
+
+const PROMPT: &str = "> ";
+
+//how far apart 'time timers' spreads its timers, in milliseconds
+const TIMER_SPREAD_MS: usize = 1000;
+
+static TIMERS_FIRED: AtomicUsize = AtomicUsize::new(0);
+
+//the IEEE ethertype for local experiments, which 'net send' uses for its test frame
+const ETHERTYPE_EXPERIMENTAL: u16 = 0x88B5;
+
+//the shortest Ethernet frame, without the FCS
+const MIN_FRAME_SIZE: usize = 60;
+
+//the requests 'ping' sends and their data bytes, unless told otherwise, and the most data that
+//fits in one IPv4 packet
+const PING_COUNT: u16 = 4;
+const PING_SIZE: usize = 56;
+const MAX_PING_SIZE: usize = 65535 - ipv4::HEADER_SIZE - icmp::HEADER_SIZE;
+
+//the IPv4 protocol number for experiments, which 'arp send' and 'ip send' use for their test
+//packets
+const IP_PROTOCOL_EXPERIMENTAL: u8 = 253;
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+
+    /// Runs the command with whatever followed its name on the line
+    run: fn(args: &str, out: &mut dyn Write) -> fmt::Result,
+}
+
+static COMMANDS: &[Command] = &[
+    Command { name: "help", help: "list the commands", run: help },
+    Command { name: "arp", help: "the neighbor cache, or 'arp add <if> <address> <mac>|del|send <if> <address>|flush <if>|proxy <if> on|off'", run: arp },
+    Command { name: "boot", help: "print the boot time report", run: |_, mut out| boot_time::report(&mut out) },
+    Command { name: "crash", help: "print the last crash record, or 'crash clear' to forget it", run: crash },
+    Command { name: "heap", help: "heap counters for each size class", run: heap },
+    Command { name: "ip", help: "IPv4 counters, or 'ip forward on|off|gateway <if> <address>|none|route|send <address>|ratelimit <per sec> <burst>|listen on|off'", run: ip },
+    Command { name: "irq", help: "interrupt controllers and counts, or 'irq mask|unmask <gsi>'", run: irq },
+    Command { name: "log", help: "log sinks and levels, or 'log level [module] <level>|clear <module>'", run: log },
+    Command { name: "mem", help: "memory counts, or 'mem map|alloc <count> [dma32]|free <hex address> [count]|translate <hex address>'", run: mem },
+    Command { name: "net", help: "interfaces, or 'net up|down|send|recv|counters <if>|addr <if> add|del <address>|rate|promisc|allmulti|multicast <if> ...'", run: net },
+    Command { name: "panic", help: "what happens after a panic, or 'panic halt|reboot <secs>'", run: panic },
+    Command { name: "pci", help: "PCI functions, or 'pci <bb:dd.f>|id <vendor>:<device>|class <class>.<subclass>[.<prog if>]|irq <bb:dd.f> <count>'", run: pci },
+    Command { name: "peek", help: "read the u32 at an address, 'peek <hex address>'", run: peek },
+    Command { name: "ping", help: "send echo requests, 'ping <address> [count] [size]'", run: ping },
+    Command { name: "time", help: "clock and tick status, or 'time sleep <ms>|timers <count>'", run: time },
+    Command { name: "watchdog", help: "watchdog status, or 'watchdog timeout <secs>|pet|stop'", run: watchdog },
+];
+
+struct Shell {
+    port: SerialPort,
+    line: [u8; MAX_LINE],
+    len: usize,
+}
+
+static SHELL: Mutex<Shell> = Mutex::new(Shell {
+    port: SerialPort::new(serial::COM1),
+    line: [0; MAX_LINE],
+    len: 0,
+});
+
+/// Takes an interrupt for every byte received, and prints the first prompt. The serial port and
+/// the interrupt controller have to be set up already.
+pub fn init() {
+    let mut shell = SHELL.lock();
+
+    //the interrupt only has to wake a halted CPU. `poll` reads the data.
+    match irq::route_irq(Irq::isa(COM1_IRQ), |_: &InterruptContext| {}) {
+        Ok(_) => shell.port.enable_receive_interrupt(),
+        Err(e) => {
+            let _ = writeln!(shell.port, "cli: no receive interrupt, falling back to polling: {:?}", e);
+        }
+    }
+
+    let _ = write!(shell.port, "\ntype 'help' for a list of commands\n{}", PROMPT);
+}
+
+/// Reads whatever has arrived on the serial port, and runs a command once a line is complete
+pub fn poll() {
+    let mut shell = SHELL.lock();
+    let Shell { port, line, len } = &mut *shell;
+
+    while let Some(byte) = port.read_byte() {
+        match byte {
+            b'\r' | b'\n' => {
+                let _ = port.write_str("\n");
+
+                //only printable ASCII is ever stored, so the line is always valid UTF-8
+                let text = core::str::from_utf8(&line[..*len]).unwrap_or("");
+                let _ = execute(text, port);
+
+                *len = 0;
+                let _ = port.write_str(PROMPT);
+            }
+
+            //backspace and delete both erase the last character
+            0x08 | 0x7f if *len > 0 => {
+                *len -= 1;
+                let _ = port.write_str("\x08 \x08");
+            }
+
+            0x20..=0x7e if *len < MAX_LINE => {
+                line[*len] = byte;
+                *len += 1;
+                port.write_byte(byte);
+            }
+
+            _ => {}
+        }
+    }
+}
+
+fn execute(text: &str, out: &mut dyn Write) -> fmt::Result {
+    let text = text.trim();
+    let (name, args) = text.split_once(' ').unwrap_or((text, ""));
+
+    if name.is_empty() {
+        return Ok(());
+    }
+
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(args.trim(), out),
+        None => writeln!(out, "unknown command '{}', try 'help'", name),
+    }
+}
+
+fn help(_args: &str, out: &mut dyn Write) -> fmt::Result {
+    for command in COMMANDS {
+        writeln!(out, "  {:<12} {}", command.name, command.help)?;
+    }
+
+    Ok(())
+}
+
+fn crash(args: &str, mut out: &mut dyn Write) -> fmt::Result {
+    match args {
+        "" => pstore::with_last_crash(|crash| match crash {
+            Some(crash) => crash.dump(&mut out),
+            None => writeln!(out, "no crash record"),
+        }),
+        "clear" => {
+            pstore::clear_last_crash();
+            writeln!(out, "crash record cleared")
+        }
+        _ => writeln!(out, "usage: crash [clear]"),
+    }
+}
+
+fn watchdog(args: &str, out: &mut dyn Write) -> fmt::Result {
+    let mut words = args.split_whitespace();
+
+    match (words.next(), words.next()) {
+        (None, _) => match watchdog::status() {
+            Some((name, timeout)) => {
+                writeln!(out, "{}: {} s timeout, last reset reason: {:?}",
+                    name, timeout, watchdog::last_reset_reason()
+                )
+            }
+            None => writeln!(out, "no watchdog running"),
+        },
+        (Some("timeout"), Some(secs)) => match secs.parse() {
+            Ok(secs) => match watchdog::set_timeout(secs) {
+                Ok(actual) => writeln!(out, "timeout set to {} s", actual),
+                Err(e) => writeln!(out, "could not set the timeout: {:?}", e),
+            },
+            Err(_) => writeln!(out, "'{}' is not a number of seconds", secs),
+        },
+        (Some("pet"), None) => {
+            watchdog::pet();
+            Ok(())
+        }
+        (Some("stop"), None) => match watchdog::stop() {
+            Ok(()) => writeln!(out, "watchdog stopped"),
+            Err(e) => writeln!(out, "could not stop the watchdog: {:?}", e),
+        },
+        _ => writeln!(out, "usage: watchdog [timeout <secs>|pet|stop]"),
+    }
+}
+
+fn timer_fired(_arg: usize) {
+    TIMERS_FIRED.fetch_add(1, Ordering::Relaxed);
+}
+
+fn time(args: &str, out: &mut dyn Write) -> fmt::Result {
+    let mut words = args.split_whitespace();
+
+    match (words.next(), words.next().map(str::parse::<usize>)) {
+        (None, _) => {
+            writeln!(out, "TSC at {} Hz, calibrated against the {}",
+                time::tsc_hz(), if hpet::is_present() { "HPET" } else { "PIT" }
+            )?;
+            writeln!(out, "{:?} tick at {} Hz, {} ticks so far", time::tick_source(), time::TICK_HZ, time::ticks())?;
+            writeln!(out, "{} timer(s) waiting", time::active_timers())
+        }
+        (Some("sleep"), Some(Ok(ms))) => {
+            let start = Instant::now();
+            time::sleep(Duration::from_millis(ms as u64));
+            writeln!(out, "slept for {:?}", start.elapsed())
+        }
+        (Some("timers"), Some(Ok(count))) => {
+            //spread the timers over the next second, and cancel every other one straight away
+            TIMERS_FIRED.store(0, Ordering::Relaxed);
+            let start = Instant::now();
+            let (mut added, mut cancelled) = (0, 0);
+            for i in 0..count {
+                let delay = Duration::from_millis((i % TIMER_SPREAD_MS) as u64 + 1);
+                match time::add_timer(delay, timer_fired, i) {
+                    Ok(handle) => {
+                        added += 1;
+                        if i % 2 == 1 && time::cancel_timer(handle) {
+                            cancelled += 1;
+                        }
+                    }
+                    Err(e) => {
+                        writeln!(out, "stopped after {} timers: {:?}", added, e)?;
+                        break;
+                    }
+                }
+            }
+            let took = start.elapsed();
+
+            time::sleep(Duration::from_millis(TIMER_SPREAD_MS as u64 + 100));
+            writeln!(out, "added {} timers in {:?} and cancelled {}. {} fired, {} still waiting",
+                added, took, cancelled, TIMERS_FIRED.load(Ordering::Relaxed), time::active_timers()
+            )
+        }
+        _ => writeln!(out, "usage: time [sleep <ms>|timers <count>]"),
+    }
+}
+
+fn log(args: &str, mut out: &mut dyn Write) -> fmt::Result {
+    let mut words = args.split_whitespace();
+
+    match (words.next(), words.next(), words.next(), words.next()) {
+        (None, _, _, _) => {
+            write!(out, "sinks:")?;
+            for name in console::sink_names() {
+                write!(out, " {}", name)?;
+            }
+            writeln!(out)?;
+            logger::report(&mut out)
+        }
+        (Some("level"), Some(level), None, _) => match level.parse::<LevelFilter>() {
+            Ok(level) => {
+                logger::set_level(level);
+                writeln!(out, "default level set to {}", level)
+            }
+            Err(_) => writeln!(out, "'{}' is not a log level", level),
+        },
+        (Some("level"), Some(module), Some(level), None) => match level.parse::<LevelFilter>() {
+            Ok(level) => match logger::set_module_level(module, level) {
+                Ok(()) => writeln!(out, "{} set to {}", module, level),
+                Err(e) => writeln!(out, "could not set the level of {}: {:?}", module, e),
+            },
+            Err(_) => writeln!(out, "'{}' is not a log level", level),
+        },
+        (Some("clear"), Some(module), None, _) => {
+            logger::clear_module_level(module);
+            writeln!(out, "{} is back to the default level", module)
+        }
+        _ => writeln!(out, "usage: log [level [module] <level>|clear <module>]"),
+    }
+}
+
+fn mem(args: &str, out: &mut dyn Write) -> fmt::Result {
+    let mut words = args.split_whitespace();
+
+    match (words.next(), words.next(), words.next()) {
+        (None, _, _) => {
+            let stats = frame::stats();
+            writeln!(out, "{} KiB free, {} KiB used, {} KiB in total",
+                stats.free * 4, stats.used * 4, stats.total * 4
+            )?;
+            writeln!(out, "{} KiB of boot services memory held back", stats.reclaimable * 4)?;
+            writeln!(out, "{} MiB of kernel address space free", paging::free_virtual_space() >> 20)
+        }
+        (Some("map"), None, _) => {
+            for entry in frame::memory_map() {
+                let end = entry.base + entry.pages * frame::FRAME_SIZE as usize;
+                writeln!(out, "  {:#014x}-{:#014x} {}", entry.base, end, memory_type::name(entry.ty))?;
+            }
+            Ok(())
+        }
+        (Some("alloc"), Some(count), constraints) => {
+            let constraints = match constraints {
+                None => FrameConstraints::ANY,
+                Some("dma32") => FrameConstraints::DMA32,
+                Some(_) => return writeln!(out, "usage: mem alloc <count> [dma32]"),
+            };
+            let result = match count.parse() {
+                Ok(1) if constraints == FrameConstraints::ANY => frame::alloc_frame(),
+                Ok(count) => frame::alloc_frames(count, constraints),
+                Err(_) => return writeln!(out, "'{}' is not a frame count", count),
+            };
+            match result {
+                Ok(frame) => writeln!(out, "allocated at {:#x}", frame.start_address()),
+                Err(e) => writeln!(out, "could not allocate: {:?}", e),
+            }
+        }
+        (Some("free"), Some(address), count) => {
+            let Ok(address) = u64::from_str_radix(address.trim_start_matches("0x"), 16) else {
+                return writeln!(out, "usage: mem free <hex address> [count]");
+            };
+            let frame = PhysFrame::containing_address(PhysAddr::new(address));
+            let result = match count.map(str::parse) {
+                None => frame::free_frame(frame),
+                Some(Ok(count)) => frame::free_frames(frame, count),
+                Some(Err(_)) => return writeln!(out, "usage: mem free <hex address> [count]"),
+            };
+            match result {
+                Ok(()) => writeln!(out, "freed"),
+                Err(e) => writeln!(out, "could not free: {:?}", e),
+            }
+        }
+        (Some("translate"), Some(address), None) => {
+            let Ok(address) = u64::from_str_radix(address.trim_start_matches("0x"), 16) else {
+                return writeln!(out, "usage: mem translate <hex address>");
+            };
+            match VirtAddr::try_new(address).ok().and_then(paging::translate) {
+                Some(phys) => writeln!(out, "{:#x} is mapped to {:#x}", address, phys),
+                None => writeln!(out, "{:#x} is not mapped", address),
+            }
+        }
+        _ => writeln!(out, "usage: mem [map|alloc <count> [dma32]|free <hex address> [count]|translate <hex address>]"),
+    }
+}
+
+fn parse_on_off(word: Option<&str>) -> Option<bool> {
+    match word {
+        Some("on") => Some(true),
+        Some("off") => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_mac(text: &str) -> Option<MacAddress> {
+    let mut mac = [0; 6];
+    let mut bytes = text.split(':');
+    for byte in mac.iter_mut() {
+        *byte = u8::from_str_radix(bytes.next()?, 16).ok()?;
+    }
+    bytes.next().is_none().then_some(MacAddress(mac))
+}
+
+//parses "address/prefix length", IPv4 or IPv6, and adds it to or removes it from an interface.
+//None if it isn't an address.
+fn change_address(interface: &'static Interface, add: bool, text: &str) -> Option<Result<String, InterfaceError>> {
+    let (address, prefix_len) = text.split_once('/').unwrap_or((text, ""));
+    let prefix_len = prefix_len.parse().map_err(|_| InterfaceError::InvalidPrefix);
+    let result = if let Ok(address) = address.parse::<Ipv4Addr>() {
+        if add {
+            prefix_len
+                .and_then(|prefix_len| Ipv4Cidr::new(address, prefix_len))
+                .and_then(|cidr| interface.add_ipv4(cidr).map(|()| cidr.to_string()))
+        } else {
+            interface.remove_ipv4(address).map(|cidr| cidr.to_string())
+        }
+    } else if let Ok(address) = address.parse::<Ipv6Addr>() {
+        if add {
+            prefix_len
+                .and_then(|prefix_len| Ipv6Cidr::new(address, prefix_len))
+                .and_then(|cidr| interface.add_ipv6(cidr).map(|()| cidr.to_string()))
+        } else {
+            interface.remove_ipv6(address).map(|cidr| cidr.to_string())
+        }
+    } else {
+        return None;
+    };
+    Some(result)
+}
+
+fn net(args: &str, mut out: &mut dyn Write) -> fmt::Result {
+    const USAGE: &str = "usage: net [up|down|send|recv|counters <if>|addr <if> add|del <address>[/<prefix>]|rate <if> <per sec>|promisc|allmulti <if> on|off|multicast <if> join|leave <mac>]";
+    let mut words = args.split_whitespace();
+
+    let (command, interface) = match (words.next(), words.next()) {
+        (None, _) => {
+            interface::report(&mut out)?;
+            ethernet::report(&mut out)?;
+            let pool = packet::stats();
+            return writeln!(out, "packet buffers: {} of {} free", pool.free, pool.buffers);
+        }
+        (Some(command), Some(name)) => match interface::by_name(name) {
+            Some(interface) => (command, interface),
+            None => return writeln!(out, "no interface {}", name),
+        },
+        _ => return writeln!(out, "{}", USAGE),
+    };
+    let device = interface.device();
+
+    //the e1000 driver has a few controls the other NICs don't
+    let e1000 = e1000::nics().into_iter().find(|nic| nic.pci().address == device.pci().address);
+
+    match (command, e1000) {
+        ("up" | "down", _) => {
+            interface.set_admin_up(command == "up");
+            writeln!(out, "{} is administratively {}", interface.name(), command)
+        }
+        ("addr", _) => {
+            let add = match words.next() {
+                Some("add") => true,
+                Some("del") => false,
+                _ => return writeln!(out, "{}", USAGE),
+            };
+            match words.next().and_then(|text| change_address(interface, add, text)) {
+                Some(Ok(cidr)) => {
+                    writeln!(out, "{} {} {} {}", if add { "added" } else { "removed" }, cidr, if add { "to" } else { "from" }, interface.name())
+                }
+                Some(Err(e)) => writeln!(out, "could not change the address: {:?}", e),
+                None => writeln!(out, "{}", USAGE),
+            }
+        }
+
+        //a broadcast frame whose payload starts with a checksum over itself, filled in by the NIC
+        //if it can and in software if not
+        ("send", _) => {
+            let mut frame = [0; MIN_FRAME_SIZE];
+            frame[0..6].copy_from_slice(&MacAddress::BROADCAST.0);
+            frame[6..12].copy_from_slice(&device.mac().0);
+            frame[12..14].copy_from_slice(&ETHERTYPE_EXPERIMENTAL.to_be_bytes());
+            frame[16..].iter_mut().enumerate().for_each(|(i, byte)| *byte = i as u8);
+
+            let result = Packet::from_slice(&frame)
+                .map_err(NetError::from)
+                .and_then(|packet| device.transmit(packet, Some(TxChecksum { start: 14, offset: 0 })));
+            match result {
+                Ok(()) => writeln!(out, "sent a {} byte test frame", frame.len()),
+                Err(e) => writeln!(out, "could not send: {:?}", e),
+            }
+        }
+        ("recv", _) => {
+            while let Some((packet, info)) = device.receive() {
+                let mut frame = [0; 14];
+                if packet.copy_to(&mut frame) < frame.len() {
+                    writeln!(out, "  runt frame of {} bytes", packet.len())?;
+                    continue;
+                }
+
+                let destination = MacAddress(frame[0..6].try_into().unwrap());
+                let source = MacAddress(frame[6..12].try_into().unwrap());
+                writeln!(out, "  {} -> {}{} type {:#06x}, {} bytes{}{}",
+                    source, destination, if destination.is_multicast() { " (multicast)" } else { "" },
+                    u16::from_be_bytes([frame[12], frame[13]]), packet.len(),
+                    if packet.is_chained() { " in several buffers" } else { "" },
+                    if info.checksum_valid { ", checksum checked" } else { "" }
+                )?;
+            }
+            Ok(())
+        }
+        ("promisc", _) => match parse_on_off(words.next()) {
+            Some(on) => {
+                interface.set_promiscuous(on);
+                writeln!(out, "promiscuous mode {}", if on { "on" } else { "off" })
+            }
+            None => writeln!(out, "{}", USAGE),
+        },
+        ("multicast", _) => match (words.next(), words.next().and_then(parse_mac)) {
+            (Some("join"), Some(group)) if group.is_multicast() => {
+                interface.join_multicast(group);
+                writeln!(out, "{} joined {}", interface.name(), group)
+            }
+            (Some("leave"), Some(group)) => {
+                interface.leave_multicast(group);
+                writeln!(out, "{} left {}", interface.name(), group)
+            }
+            _ => writeln!(out, "{}", USAGE),
+        },
+        ("counters", Some(nic)) => {
+            if let Some((speed, full_duplex)) = nic.link_speed() {
+                writeln!(out, "{} Mb/s {} duplex", speed, if full_duplex { "full" } else { "half" })?;
+            }
+            writeln!(out, "{} link change(s)", nic.link_changes())?;
+            writeln!(out, "{:#?}", nic.hardware_stats())
+        }
+        ("rate", Some(nic)) => match words.next().map(str::parse) {
+            Some(Ok(per_sec)) => {
+                nic.set_interrupt_rate(per_sec);
+                writeln!(out, "interrupts limited to {} a second (0 is no limit)", per_sec)
+            }
+            _ => writeln!(out, "{}", USAGE),
+        },
+        ("allmulti", Some(nic)) => match parse_on_off(words.next()) {
+            Some(on) => {
+                nic.set_all_multicast(on);
+                writeln!(out, "all multicast {}", if on { "on" } else { "off" })
+            }
+            None => writeln!(out, "{}", USAGE),
+        },
+        ("counters" | "rate" | "allmulti", None) => writeln!(out, "only e1000 NICs have that"),
+        _ => writeln!(out, "{}", USAGE),
+    }
+}
+
+//an IPv4 header with nothing after it, for the experimental protocol
+fn test_packet(source: Ipv4Addr, destination: Ipv4Addr) -> Result<Packet, NetError> {
+    let mut header = [0; 20];
+    header[0] = 0x45;
+    header[2..4].copy_from_slice(&20u16.to_be_bytes());
+    //don't fragment, and only one hop
+    header[6] = 0x40;
+    header[8] = 1;
+    header[9] = IP_PROTOCOL_EXPERIMENTAL;
+    header[12..16].copy_from_slice(&source.octets());
+    header[16..20].copy_from_slice(&destination.octets());
+
+    let mut packet = Packet::from_slice(&header)?;
+    let checksum = packet.checksum(0);
+    packet.data_mut()?[10..12].copy_from_slice(&checksum.to_be_bytes());
+    Ok(packet)
+}
+
+fn arp(args: &str, mut out: &mut dyn Write) -> fmt::Result {
+    const USAGE: &str = "usage: arp [add <if> <address> <mac>|del <if> <address>|send <if> <address>|flush <if>|proxy <if> on|off]";
+    let mut words = args.split_whitespace();
+
+    let (command, interface) = match (words.next(), words.next()) {
+        (None, _) => return arp::report(&mut out),
+        (Some(command), Some(name)) => match interface::by_name(name) {
+            Some(interface) => (command, interface),
+            None => return writeln!(out, "no interface {}", name),
+        },
+        _ => return writeln!(out, "{}", USAGE),
+    };
+
+    let word = words.next();
+    match (command, word.and_then(|word| word.parse::<Ipv4Addr>().ok())) {
+        ("add", Some(address)) => {
+            let Some(mac) = words.next().and_then(parse_mac) else {
+                return writeln!(out, "{}", USAGE);
+            };
+            match arp::add_static(interface, address, mac) {
+                Ok(()) => writeln!(out, "{} is {} on {}", address, mac, interface.name()),
+                Err(e) => writeln!(out, "could not add the entry: {:?}", e),
+            }
+        }
+        ("del", Some(address)) => match arp::remove(interface, address) {
+            Ok(()) => writeln!(out, "removed {}", address),
+            Err(e) => writeln!(out, "could not remove the entry: {:?}", e),
+        },
+
+        //resolves the neighbor if it has to, so the packet shows up queued in the table until
+        //the reply comes
+        ("send", Some(address)) => {
+            let addresses = interface.ipv4_addresses();
+            let Some(source) = addresses.iter().find(|cidr| cidr.contains(address)).or(addresses.first()) else {
+                return writeln!(out, "{} has no IPv4 address", interface.name());
+            };
+            let result = test_packet(source.address, address)
+                .map_err(SendError::from)
+                .and_then(|packet| arp::send_ipv4(interface, address, packet, None));
+            match result {
+                Ok(()) => writeln!(out, "sent a test packet to {}", address),
+                Err(e) => writeln!(out, "could not send: {:?}", e),
+            }
+        }
+        ("flush", _) if word.is_none() => {
+            arp::flush(interface);
+            writeln!(out, "forgot the neighbors on {}", interface.name())
+        }
+        ("proxy", _) => match parse_on_off(word) {
+            Some(on) => {
+                arp::set_proxy(interface, on);
+                writeln!(out, "proxy ARP {} on {}", if on { "on" } else { "off" }, interface.name())
+            }
+            None => writeln!(out, "{}", USAGE),
+        },
+        _ => writeln!(out, "{}", USAGE),
+    }
+}
+
+//logs the test packets that 'ip listen' takes
+fn log_test_packet(interface: &'static Interface, header: &Ipv4Header, packet: Packet) {
+    info!("ip: {} byte test packet from {} to {} on {}", packet.len(), header.source, header.destination, interface.name());
+}
+
+fn ip(args: &str, mut out: &mut dyn Write) -> fmt::Result {
+    const USAGE: &str = "usage: ip [forward on|off|gateway <if> <address>|gateway none|route <address>|send <address>|ratelimit <per sec> <burst>|listen on|off]";
+    let mut words = args.split_whitespace();
+
+    match (words.next(), words.next(), words.next()) {
+        (None, _, _) => {
+            ipv4::report(&mut out)?;
+            icmp::report(&mut out)
+        }
+        (Some("forward"), on, None) => match parse_on_off(on) {
+            Some(on) => {
+                ipv4::set_forwarding(on);
+                writeln!(out, "forwarding {}", if on { "on" } else { "off" })
+            }
+            None => writeln!(out, "{}", USAGE),
+        },
+        (Some("gateway"), Some("none"), None) => {
+            ipv4::set_default_gateway(None);
+            writeln!(out, "no default gateway")
+        }
+        (Some("gateway"), Some(name), Some(address)) => {
+            let Some(interface) = interface::by_name(name) else {
+                return writeln!(out, "no interface {}", name);
+            };
+            let Ok(address) = address.parse::<Ipv4Addr>() else {
+                return writeln!(out, "{}", USAGE);
+            };
+            ipv4::set_default_gateway(Some((interface, address)));
+            writeln!(out, "default gateway {} on {}", address, interface.name())
+        }
+        (Some("route"), Some(address), None) => {
+            let Ok(address) = address.parse::<Ipv4Addr>() else {
+                return writeln!(out, "{}", USAGE);
+            };
+            match ipv4::route(address) {
+                Some(route) => writeln!(out, "{} goes out of {} to {}", address, route.interface.name(), route.next_hop),
+                None => writeln!(out, "no route to {}", address),
+            }
+        }
+
+        //an empty packet of the experimental protocol. one for our own address is received
+        //straight away, and shows up in the log with 'ip listen on'.
+        (Some("send"), Some(address), None) => {
+            let Ok(address) = address.parse::<Ipv4Addr>() else {
+                return writeln!(out, "{}", USAGE);
+            };
+            let result = Packet::new()
+                .map_err(SendError::from)
+                .and_then(|packet| ipv4::send(None, address, IP_PROTOCOL_EXPERIMENTAL, ipv4::DEFAULT_TTL, packet, None));
+            match result {
+                Ok(()) => writeln!(out, "sent a test packet to {}", address),
+                Err(e) => writeln!(out, "could not send: {:?}", e),
+            }
+        }
+        (Some("ratelimit"), Some(rate), Some(burst)) => match (rate.parse(), burst.parse()) {
+            (Ok(rate), Ok(burst)) => {
+                icmp::set_rate_limit(rate, burst);
+                writeln!(out, "ICMP errors limited to {} a second, {} at once", rate, burst)
+            }
+            _ => writeln!(out, "{}", USAGE),
+        },
+        (Some("listen"), on, None) => match parse_on_off(on) {
+            Some(true) => match ipv4::register(IP_PROTOCOL_EXPERIMENTAL, log_test_packet) {
+                Ok(()) => writeln!(out, "logging test packets"),
+                Err(e) => writeln!(out, "could not listen: {:?}", e),
+            },
+            Some(false) => {
+                ipv4::unregister(IP_PROTOCOL_EXPERIMENTAL);
+                writeln!(out, "not logging test packets")
+            }
+            None => writeln!(out, "{}", USAGE),
+        },
+        _ => writeln!(out, "{}", USAGE),
+    }
+}
+
+fn ping(args: &str, mut out: &mut dyn Write) -> fmt::Result {
+    const USAGE: &str = "usage: ping <address> [count] [size]";
+    let mut words = args.split_whitespace();
+
+    let Some(Ok(destination)) = words.next().map(str::parse::<Ipv4Addr>) else {
+        return writeln!(out, "{}", USAGE);
+    };
+    let count = match words.next().map(str::parse::<u16>) {
+        None => PING_COUNT,
+        Some(Ok(count)) if count > 0 => count,
+        Some(_) => return writeln!(out, "{}", USAGE),
+    };
+    let size = match words.next().map(str::parse::<usize>) {
+        None => PING_SIZE,
+        Some(Ok(size)) if size <= MAX_PING_SIZE => size,
+        Some(_) => return writeln!(out, "{}", USAGE),
+    };
+
+    //it prints the replies and the statistics itself
+    icmp::ping(destination, count, size, &mut out);
+    Ok(())
+}
+
+fn panic(args: &str, out: &mut dyn Write) -> fmt::Result {
+    let mut words = args.split_whitespace();
+
+    match (words.next(), words.next()) {
+        (None, _) => match panic::action() {
+            PanicAction::Halt => writeln!(out, "a panic halts the system"),
+            PanicAction::Reboot(secs) => writeln!(out, "a panic reboots after {} s", secs),
+        },
+        (Some("halt"), None) => {
+            panic::set_action(PanicAction::Halt);
+            writeln!(out, "a panic now halts the system")
+        }
+        (Some("reboot"), Some(secs)) => match secs.parse() {
+            Ok(secs) => {
+                panic::set_action(PanicAction::Reboot(secs));
+                writeln!(out, "a panic now reboots after {} s", secs)
+            }
+            Err(_) => writeln!(out, "'{}' is not a number of seconds", secs),
+        },
+        _ => writeln!(out, "usage: panic [halt|reboot <secs>]"),
+    }
+}
+
+fn peek(args: &str, out: &mut dyn Write) -> fmt::Result {
+    let digits = args.trim_start_matches("0x");
+    let Ok(address) = usize::from_str_radix(digits, 16) else {
+        return writeln!(out, "usage: peek <hex address>");
+    };
+
+    //the read is covered by a fixup, so an address with nothing behind it just reports a fault
+    match exceptions::probe_read_u32(address) {
+        Some(value) => writeln!(out, "{:#x}: {:#010x}", address, value),
+        None => writeln!(out, "{:#x}: the read faulted", address),
+    }
+}
+
+//parses "bb:dd.f", all in hex
+fn parse_pci_address(text: &str) -> Option<PciAddress> {
+    let (bus, rest) = text.split_once(':')?;
+    let (device, function) = rest.split_once('.')?;
+    Some(PciAddress::new(
+        u8::from_str_radix(bus, 16).ok()?,
+        u8::from_str_radix(device, 16).ok()?,
+        u8::from_str_radix(function, 16).ok()?,
+    ))
+}
+
+//parses "vvvv:dddd" or "cc.ss[.pp]", all in hex, into what a driver would match on
+fn parse_pci_match(kind: &str, text: &str) -> Option<PciMatch> {
+    match kind {
+        "id" => {
+            let (vendor, device) = text.split_once(':')?;
+            Some(PciMatch::Id {
+                vendor: u16::from_str_radix(vendor, 16).ok()?,
+                device: u16::from_str_radix(device, 16).ok()?,
+            })
+        }
+        "class" => {
+            let mut fields = text.split('.').map(|field| u8::from_str_radix(field, 16).ok());
+            let (class, subclass) = (fields.next()??, fields.next()??);
+            let prog_if = match fields.next() {
+                Some(prog_if) => Some(prog_if?),
+                None => None,
+            };
+            fields.next().is_none().then_some(PciMatch::Class { class, subclass, prog_if })
+        }
+        _ => None,
+    }
+}
+
+fn pci(args: &str, out: &mut dyn Write) -> fmt::Result {
+    const USAGE: &str = "usage: pci [<bb:dd.f>|id <vendor>:<device>|class <class>.<subclass>[.<prog if>]|irq <bb:dd.f> <count>]";
+    let mut words = args.split_whitespace();
+
+    let filter = match (words.next(), words.next(), words.next()) {
+        (None, _, _) => None,
+        (Some(address), None, _) => match parse_pci_address(address) {
+            Some(address) => return pci_function(address, out),
+            None => return writeln!(out, "{}", USAGE),
+        },
+        (Some("irq"), Some(address), Some(count)) => {
+            return match (parse_pci_address(address), count.parse()) {
+                (Some(address), Ok(count)) => pci_interrupts(address, count, out),
+                _ => writeln!(out, "{}", USAGE),
+            };
+        }
+        (Some(kind), Some(text), None) => match parse_pci_match(kind, text) {
+            Some(filter) => Some(filter),
+            None => return writeln!(out, "{}", USAGE),
+        },
+        _ => return writeln!(out, "{}", USAGE),
+    };
+
+    writeln!(out, "config space through {:?}", pci::config::mechanism())?;
+    for device in pci::devices().iter().filter(|device| filter.is_none_or(|m| m.matches(device))) {
+        writeln!(out, "  {} {:04x}:{:04x} rev {:02x} {} ({:02x}.{:02x}.{:02x}), {}",
+            device.address, device.vendor, device.device, device.revision, device.class_name(),
+            device.class, device.subclass, device.prog_if, device.driver().unwrap_or("no driver")
+        )?;
+    }
+    Ok(())
+}
+
+fn pci_function(address: PciAddress, out: &mut dyn Write) -> fmt::Result {
+    let Some(device) = pci::devices().iter().find(|device| device.address == address) else {
+        return writeln!(out, "nothing at {}", address);
+    };
+
+    writeln!(out, "{} {:04x}:{:04x} {}, subsystem {:04x}:{:04x}, header type {:#04x}",
+        device.address, device.vendor, device.device, device.class_name(),
+        device.subsystem_vendor, device.subsystem, device.header_type
+    )?;
+    if device.is_bridge() {
+        writeln!(out, "bridge to bus {}", device.secondary_bus.unwrap_or(0))?;
+    }
+    if (1..=4).contains(&device.interrupt_pin) {
+        writeln!(out, "INT{}# on IRQ line {}", (b'A' + device.interrupt_pin - 1) as char, device.interrupt_line)?;
+    }
+
+    for (index, bar) in device.bars.iter().enumerate() {
+        if let Some(bar) = bar {
+            writeln!(out, "  BAR{}: {:?}", index, bar)?;
+        }
+    }
+    for (id, offset) in device.capabilities.iter() {
+        writeln!(out, "  capability {:#04x} at {:#04x}", id, offset)?;
+    }
+    Ok(())
+}
+
+//sets up interrupt vectors for a function no driver has claimed, moves, masks and unmasks each
+//one, and frees them again
+fn pci_interrupts(address: PciAddress, count: usize, out: &mut dyn Write) -> fmt::Result {
+    let Some(device) = pci::devices().iter().find(|device| device.address == address) else {
+        return writeln!(out, "nothing at {}", address);
+    };
+    if let Some(driver) = device.driver() {
+        return writeln!(out, "{} belongs to {}", address, driver);
+    }
+
+    let interrupts = match msi::allocate(device, count, |_: &InterruptContext| {}) {
+        Ok(interrupts) => interrupts,
+        Err(e) => return writeln!(out, "could not set up interrupts: {:?}", e),
+    };
+    write!(out, "{} vector(s) through {:?}:", interrupts.vectors().len(), interrupts.mode())?;
+    for vector in interrupts.vectors() {
+        write!(out, " {}", vector)?;
+    }
+    writeln!(out)?;
+
+    let cpu = apic::id().unwrap_or(0);
+    for index in 0..interrupts.vectors().len() {
+        let result = interrupts
+            .set_affinity(index, cpu)
+            .and_then(|()| interrupts.mask(index))
+            .and_then(|()| interrupts.unmask(index));
+        if let Err(e) = result {
+            writeln!(out, "  message {}: {:?}", index, e)?;
+        }
+    }
+
+    interrupts.free();
+    writeln!(out, "vectors freed")
+}
+
+fn heap(_args: &str, out: &mut dyn Write) -> fmt::Result {
+    let stats = heap::stats();
+
+    for (size, class) in heap::SIZE_CLASSES.iter().zip(stats.classes.iter()) {
+        writeln!(out, "  {:>5} bytes: {} allocs, {} frees, {} in use, {} pages",
+            size, class.allocs, class.frees, class.active, class.pages
+        )?;
+    }
+    writeln!(out, "  large:       {} allocs, {} frees, {} pages in use",
+        stats.large.allocs, stats.large.frees, stats.large.active_pages
+    )?;
+
+    writeln!(out, "{} pages mapped, {} failed allocations", stats.mapped_pages(), stats.failures)
+}
+
+fn irq(args: &str, out: &mut dyn Write) -> fmt::Result {
+    let mut words = args.split_whitespace();
+
+    match (words.next(), words.next().map(str::parse::<u32>)) {
+        (None, _) => {
+            writeln!(out, "routed through the {:?}", irq::backend())?;
+            if let Some(id) = apic::id() {
+                writeln!(out, "local APIC {}: version {:#x}, {} error(s)",
+                    id, apic::version().unwrap_or(0), apic::error_count()
+                )?;
+            }
+            if let Some(madt) = acpi::madt::get() {
+                for io_apic in madt.io_apics() {
+                    writeln!(out, "I/O APIC {} at {:#x}, GSIs from {}", io_apic.id, io_apic.address, io_apic.gsi_base)?;
+                }
+            }
+
+            for vector in 32..=255u8 {
+                let count = manager::count(vector);
+                if count != 0 {
+                    writeln!(out, "  vector {:>3}: {}", vector, count)?;
+                }
+            }
+
+            writeln!(out, "  spurious:   {}", manager::spurious_count())
+        }
+        (Some("mask"), Some(Ok(gsi))) => match irq::mask_irq(gsi) {
+            Ok(()) => writeln!(out, "GSI {} masked", gsi),
+            Err(e) => writeln!(out, "could not mask GSI {}: {:?}", gsi, e),
+        },
+        (Some("unmask"), Some(Ok(gsi))) => match irq::unmask_irq(gsi) {
+            Ok(()) => writeln!(out, "GSI {} unmasked", gsi),
+            Err(e) => writeln!(out, "could not unmask GSI {}: {:?}", gsi, e),
+        },
+        _ => writeln!(out, "usage: irq [mask|unmask <gsi>]"),
+    }
+}
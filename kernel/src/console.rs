@@ -0,0 +1,199 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+
+use crate::interrupt::interrupt::without_interrupts;
+use crate::log_ring::LOG_RING;
+use crate::serial::{self, SerialPort};
+
+pub mod font;
+pub mod framebuffer;
+pub mod logger;
+
+/*
+ * Kernel text output. Everything printed goes to every registered sink: the COM1 serial port
+ * and the in-memory log ring are always there, and the framebuffer console registers itself
+ * once paging and the heap are up.
+ *
+ * Normal output holds the console lock for a whole print, so lines from different contexts
+ * don't interleave. A panic can't rely on that lock, it may be the one holding it, so the
+ * emergency path takes no locks at all: each sink gets a chance to write without its own
+ * locks, and once a panic has started all output goes that way.
+ */
+
+/// The most sinks that can be registered, including the two built in ones
+pub const MAX_SINKS: usize = 8;
+
+/// Somewhere console output can go
+pub trait Sink: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Writes text. Called with the console lock held and interrupts off.
+    fn write_str(&self, s: &str);
+
+    /// Writes text from the panic path. This must not wait on any lock, so sinks that can't
+    /// write without one drop the text instead.
+    fn write_emergency(&self, s: &str);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConsoleError {
+    /// Every sink slot is taken
+    TooManySinks,
+}
+
+//slots are filled once and never emptied, so the emergency path can read them without a lock
+static SINKS: [Once<&'static dyn Sink>; MAX_SINKS] = [const { Once::new() }; MAX_SINKS];
+static SINK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+static CONSOLE_LOCK: Mutex<()> = Mutex::new(());
+static EMERGENCY: AtomicBool = AtomicBool::new(false);
+
+/// The COM1 serial port
+pub struct SerialSink {
+    port: Mutex<SerialPort>,
+}
+
+impl Sink for SerialSink {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn write_str(&self, s: &str) {
+        let _ = self.port.lock().write_str(s);
+    }
+
+    fn write_emergency(&self, s: &str) {
+        //the port has no state of its own, so a second handle to it is as good as the first
+        let _ = SerialPort::new(serial::COM1).write_str(s);
+    }
+}
+
+/// The in-memory log ring, which the crash store and the management CLI read back
+pub struct RingSink;
+
+impl Sink for RingSink {
+    fn name(&self) -> &'static str {
+        "log ring"
+    }
+
+    fn write_str(&self, s: &str) {
+        LOG_RING.lock().push(s.as_bytes());
+    }
+
+    fn write_emergency(&self, s: &str) {
+        if let Some(mut ring) = LOG_RING.try_lock() {
+            ring.push(s.as_bytes());
+        }
+    }
+}
+
+static SERIAL_SINK: SerialSink = SerialSink {
+    port: Mutex::new(SerialPort::new(serial::COM1)),
+};
+static RING_SINK: RingSink = RingSink;
+
+/// Sets up COM1, registers the serial and log ring sinks, and installs the `log` backend
+pub fn init() {
+    SERIAL_SINK.port.lock().init();
+    let _ = register_sink(&SERIAL_SINK);
+    let _ = register_sink(&RING_SINK);
+    logger::init();
+}
+
+/// Adds a sink. Sinks can't be removed, so the emergency path never sees one go away.
+pub fn register_sink(sink: &'static dyn Sink) -> Result<(), ConsoleError> {
+    let index = SINK_COUNT.fetch_add(1, Ordering::AcqRel);
+    if index >= MAX_SINKS {
+        SINK_COUNT.store(MAX_SINKS, Ordering::Release);
+        return Err(ConsoleError::TooManySinks);
+    }
+
+    SINKS[index].call_once(|| sink);
+    Ok(())
+}
+
+fn sinks() -> impl Iterator<Item = &'static dyn Sink> {
+    SINKS.iter().filter_map(|slot| slot.get().copied())
+}
+
+/// The names of the registered sinks, in the order they were added
+pub fn sink_names() -> impl Iterator<Item = &'static str> {
+    sinks().map(|sink| sink.name())
+}
+
+/// Sends all further output down the emergency path. Called when the kernel panics.
+pub fn enter_emergency() {
+    EMERGENCY.store(true, Ordering::Release);
+}
+
+pub fn is_emergency() -> bool {
+    EMERGENCY.load(Ordering::Acquire)
+}
+
+/// A `fmt::Write` that goes to every sink. Each write takes the console lock on its own, so use
+/// `print!` for output that has to stay together.
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if is_emergency() {
+            return EmergencyConsole.write_str(s);
+        }
+
+        without_interrupts(|| {
+            let _guard = CONSOLE_LOCK.lock();
+            Locked.write_str(s)
+        })
+    }
+}
+
+//writes to every sink, for callers that already hold the console lock
+struct Locked;
+
+impl Write for Locked {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for sink in sinks() {
+            sink.write_str(s);
+        }
+        Ok(())
+    }
+}
+
+/// A `fmt::Write` that goes to every sink without taking any lock, for the panic path
+pub struct EmergencyConsole;
+
+impl Write for EmergencyConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for sink in sinks() {
+            sink.write_emergency(s);
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    if is_emergency() {
+        let _ = EmergencyConsole.write_fmt(args);
+        return;
+    }
+
+    without_interrupts(|| {
+        let _guard = CONSOLE_LOCK.lock();
+        let _ = Locked.write_fmt(args);
+    });
+}
+
+/// Prints to every console sink
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+/// Prints to every console sink, with a newline
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::console::_print(format_args!("{}\n", format_args!($($arg)*))));
+}
@@ -0,0 +1,437 @@
+use alloc::vec::Vec;
+use core::ptr;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use super::font::{self, FONT_HEIGHT, FONT_WIDTH};
+use super::{ConsoleError, Sink};
+use crate::kernel_args::{pixel_format, FramebufferInfo};
+use crate::memory::paging::{self, CacheMode, PagingError};
+
+/*
+ * A text console on the GOP framebuffer, registered as a console sink so everything printed
+ * shows up on a monitor.
+ *
+ * The screen is a grid of character cells. Writes only change the grid; at the end of every
+ * write the cells that differ from what is on screen are drawn, so a burst of output, even one
+ * that scrolls, costs one pass over the framebuffer, and cells that didn't change (mostly the
+ * blank ones) are never drawn. The framebuffer is mapped write combining and never read back.
+ *
+ * A subset of the ANSI escape sequences is understood: SGR colors (30-37, 40-47, 90-97,
+ * 100-107, bold as bright), cursor movement (A B C D H f), and erasing (J K).
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/Drawing_In_a_Linear_Framebuffer
+ * https://wiki.osdev.org/VGA_Fonts
+ * https://en.wikipedia.org/wiki/ANSI_escape_code
+ * UEFI 2.10, 12.9 "Graphics Output Protocol"
+ */
+
+//the 16 VGA text mode colors
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00), (0xAA, 0x00, 0x00), (0x00, 0xAA, 0x00), (0xAA, 0x55, 0x00),
+    (0x00, 0x00, 0xAA), (0xAA, 0x00, 0xAA), (0x00, 0xAA, 0xAA), (0xAA, 0xAA, 0xAA),
+    (0x55, 0x55, 0x55), (0xFF, 0x55, 0x55), (0x55, 0xFF, 0x55), (0xFF, 0xFF, 0x55),
+    (0x55, 0x55, 0xFF), (0xFF, 0x55, 0xFF), (0x55, 0xFF, 0xFF), (0xFF, 0xFF, 0xFF),
+];
+
+const DEFAULT_FG: u8 = 7;
+const DEFAULT_BG: u8 = 0;
+const TAB_WIDTH: usize = 8;
+
+//the most numeric parameters kept from one escape sequence. later ones are ignored.
+const MAX_PARAMS: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FramebufferError {
+    /// The bootloader didn't find a framebuffer
+    NotPresent,
+
+    /// The pixel format is unknown, or the framebuffer is too small for its mode or for a single
+    /// character
+    BadMode,
+
+    /// The framebuffer couldn't be mapped
+    Map(PagingError),
+
+    /// There was no memory for the character grid
+    OutOfMemory,
+
+    /// The console had no room for another sink
+    Console(ConsoleError),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Cell {
+    ch: char,
+    fg: u8,
+    bg: u8,
+}
+
+const BLANK: Cell = Cell { ch: ' ', fg: DEFAULT_FG, bg: DEFAULT_BG };
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum State {
+    Ground,
+
+    /// After an ESC
+    Escape,
+
+    /// Inside an ESC [ sequence
+    Csi,
+}
+
+struct FramebufferConsole {
+    pixels: *mut u32,
+    stride: usize,
+    width: usize,
+    height: usize,
+
+    //the palette, already encoded in the framebuffer's pixel format
+    colors: [u32; 16],
+
+    cols: usize,
+    rows: usize,
+
+    //what the text should look like, and what is on screen now
+    cells: Vec<Cell>,
+    shown: Vec<Cell>,
+
+    //the cursor. `col == cols` means the next character wraps to the next line first.
+    col: usize,
+    row: usize,
+    cursor_visible: bool,
+
+    //the cell the cursor was last drawn on
+    drawn_cursor: usize,
+
+    fg: u8,
+    bg: u8,
+    bold: bool,
+
+    state: State,
+    params: [u16; MAX_PARAMS],
+    param_count: usize,
+}
+
+// The framebuffer pointer is only used while the sink's lock is held
+unsafe impl Send for FramebufferConsole {}
+
+//scales an 8 bit color channel into the bits of `mask`
+fn channel(value: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+
+    let shift = mask.trailing_zeros();
+    let max = mask >> shift;
+    ((value as u32 * max) / 0xFF) << shift
+}
+
+fn encode(info: &FramebufferInfo, (r, g, b): (u8, u8, u8)) -> u32 {
+    match info.format {
+        pixel_format::RGB => r as u32 | (g as u32) << 8 | (b as u32) << 16,
+        pixel_format::BGR => b as u32 | (g as u32) << 8 | (r as u32) << 16,
+        //pixel_format::BITMASK, the only other format `init` takes
+        _ => channel(r, info.red_mask) | channel(g, info.green_mask) | channel(b, info.blue_mask),
+    }
+}
+
+impl FramebufferConsole {
+    fn index(&self, col: usize, row: usize) -> usize {
+        row * self.cols + col
+    }
+
+    //the colors a cell is drawn in. the cursor is drawn inverted.
+    fn colors_of(&self, cell: Cell, index: usize) -> (u32, u32) {
+        let cursor = self.cursor_visible && index == self.cursor();
+        let (fg, bg) = if cursor { (cell.bg, cell.fg) } else { (cell.fg, cell.bg) };
+        (self.colors[fg as usize], self.colors[bg as usize])
+    }
+
+    fn draw_cell(&self, index: usize, cell: Cell) {
+        let (fg, bg) = self.colors_of(cell, index);
+        let x = (index % self.cols) * FONT_WIDTH;
+        let y = (index / self.cols) * FONT_HEIGHT;
+
+        for (dy, bits) in font::glyph(cell.ch).iter().enumerate() {
+            let line = unsafe { self.pixels.add((y + dy) * self.stride + x) };
+            for dx in 0..FONT_WIDTH {
+                let color = if bits & (0x80 >> dx) != 0 { fg } else { bg };
+                unsafe { ptr::write_volatile(line.add(dx), color) };
+            }
+        }
+    }
+
+    fn cursor(&self) -> usize {
+        self.index(self.col.min(self.cols - 1), self.row)
+    }
+
+    //draws every cell that changed since the last flush, and the cells the cursor moved between
+    fn flush(&mut self) {
+        for index in 0..self.cells.len() {
+            let cell = self.cells[index];
+            if cell != self.shown[index] {
+                self.draw_cell(index, cell);
+                self.shown[index] = cell;
+            }
+        }
+
+        //the cell the cursor was on last time has to lose its inverted colors
+        let cursor = self.cursor();
+        if self.drawn_cursor != cursor {
+            self.draw_cell(self.drawn_cursor, self.cells[self.drawn_cursor]);
+        }
+        self.draw_cell(cursor, self.cells[cursor]);
+        self.drawn_cursor = cursor;
+    }
+
+    //fills the whole framebuffer, including the margin right of and below the grid
+    fn clear_screen(&mut self) {
+        let bg = self.colors[DEFAULT_BG as usize];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                unsafe { ptr::write_volatile(self.pixels.add(y * self.stride + x), bg) };
+            }
+        }
+
+        self.cells.fill(BLANK);
+        self.shown.fill(BLANK);
+    }
+
+    fn blank(&self) -> Cell {
+        Cell { ch: ' ', fg: self.fg, bg: self.bg }
+    }
+
+    fn scroll(&mut self) {
+        let blank = self.blank();
+        self.cells.copy_within(self.cols.., 0);
+        let last = self.index(0, self.rows - 1);
+        self.cells[last..].fill(blank);
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 == self.rows {
+            self.scroll();
+        } else {
+            self.row += 1;
+        }
+    }
+
+    fn put(&mut self, ch: char) {
+        if self.col == self.cols {
+            self.newline();
+        }
+
+        let fg = if self.bold && self.fg < 8 { self.fg + 8 } else { self.fg };
+        let index = self.index(self.col, self.row);
+        self.cells[index] = Cell { ch, fg, bg: self.bg };
+        self.col += 1;
+    }
+
+    //a parameter of the current escape sequence. missing and zero parameters take the default.
+    fn param(&self, index: usize, default: u16) -> u16 {
+        if index < self.param_count && self.params[index] != 0 {
+            self.params[index]
+        } else {
+            default
+        }
+    }
+
+    //erases a range of cells, in the current background color
+    fn erase(&mut self, start: usize, end: usize) {
+        let blank = self.blank();
+        self.cells[start..end].fill(blank);
+    }
+
+    fn select_graphic_rendition(&mut self) {
+        //ESC [ m is the same as ESC [ 0 m
+        for i in 0..self.param_count.max(1) {
+            match self.params[i] {
+                0 => {
+                    self.fg = DEFAULT_FG;
+                    self.bg = DEFAULT_BG;
+                    self.bold = false;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                p @ 30..=37 => self.fg = (p - 30) as u8,
+                39 => self.fg = DEFAULT_FG,
+                p @ 40..=47 => self.bg = (p - 40) as u8,
+                49 => self.bg = DEFAULT_BG,
+                p @ 90..=97 => self.fg = (p - 90) as u8 + 8,
+                p @ 100..=107 => self.bg = (p - 100) as u8 + 8,
+                _ => {}
+            }
+        }
+    }
+
+    fn execute(&mut self, command: char) {
+        let col = self.col.min(self.cols - 1);
+        match command {
+            'm' => self.select_graphic_rendition(),
+            'A' => self.row = self.row.saturating_sub(self.param(0, 1) as usize),
+            'B' => self.row = (self.row + self.param(0, 1) as usize).min(self.rows - 1),
+            'C' => self.col = (col + self.param(0, 1) as usize).min(self.cols - 1),
+            'D' => self.col = col.saturating_sub(self.param(0, 1) as usize),
+            'H' | 'f' => {
+                self.row = (self.param(0, 1) as usize - 1).min(self.rows - 1);
+                self.col = (self.param(1, 1) as usize - 1).min(self.cols - 1);
+            }
+            'J' => {
+                let cursor = self.index(col, self.row);
+                match self.param(0, 0) {
+                    0 => self.erase(cursor, self.cells.len()),
+                    1 => self.erase(0, cursor + 1),
+                    _ => self.erase(0, self.cells.len()),
+                }
+            }
+            'K' => {
+                let (start, cursor) = (self.index(0, self.row), self.index(col, self.row));
+                match self.param(0, 0) {
+                    0 => self.erase(cursor, start + self.cols),
+                    1 => self.erase(start, cursor + 1),
+                    _ => self.erase(start, start + self.cols),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn write_char(&mut self, ch: char) {
+        match self.state {
+            State::Ground => match ch {
+                '\x1b' => self.state = State::Escape,
+                '\n' => self.newline(),
+                '\r' => self.col = 0,
+                '\t' => {
+                    let next = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                    while self.col < next.min(self.cols) {
+                        self.put(' ');
+                    }
+                }
+                '\x08' => self.col = self.col.min(self.cols - 1).saturating_sub(1),
+                c if c.is_control() => {}
+                c => self.put(c),
+            },
+            State::Escape => {
+                if ch == '[' {
+                    self.params = [0; MAX_PARAMS];
+                    self.param_count = 0;
+                    self.state = State::Csi;
+                } else {
+                    self.state = State::Ground;
+                }
+            }
+            State::Csi => match ch {
+                '0'..='9' => {
+                    if self.param_count == 0 {
+                        self.param_count = 1;
+                    }
+                    if let Some(param) = self.params.get_mut(self.param_count - 1) {
+                        *param = param.saturating_mul(10).saturating_add(ch as u16 - '0' as u16);
+                    }
+                }
+                ';' => self.param_count = (self.param_count.max(1) + 1).min(MAX_PARAMS + 1),
+                '@'..='~' => {
+                    self.param_count = self.param_count.min(MAX_PARAMS);
+                    self.execute(ch);
+                    self.state = State::Ground;
+                }
+                _ => self.state = State::Ground,
+            },
+        }
+    }
+
+    fn write_str(&mut self, s: &str) {
+        for ch in s.chars() {
+            self.write_char(ch);
+        }
+        self.flush();
+    }
+}
+
+/// The framebuffer console's console sink
+pub struct FramebufferSink {
+    console: Mutex<Option<FramebufferConsole>>,
+}
+
+impl Sink for FramebufferSink {
+    fn name(&self) -> &'static str {
+        "framebuffer"
+    }
+
+    fn write_str(&self, s: &str) {
+        if let Some(console) = self.console.lock().as_mut() {
+            console.write_str(s);
+        }
+    }
+
+    fn write_emergency(&self, s: &str) {
+        //if the panic hit while the screen was being drawn, the screen is left alone
+        if let Some(mut guard) = self.console.try_lock()
+            && let Some(console) = guard.as_mut()
+        {
+            console.write_str(s);
+        }
+    }
+}
+
+static SINK: FramebufferSink = FramebufferSink { console: Mutex::new(None) };
+
+/// Maps the bootloader's framebuffer, clears it, and adds it as a console sink. Returns the size
+/// of the text grid in columns and rows. Needs paging and the heap.
+pub fn init(info: FramebufferInfo) -> Result<(usize, usize), FramebufferError> {
+    if info.format == pixel_format::NONE || info.base == 0 {
+        return Err(FramebufferError::NotPresent);
+    }
+    if !matches!(info.format, pixel_format::RGB | pixel_format::BGR | pixel_format::BITMASK) {
+        return Err(FramebufferError::BadMode);
+    }
+
+    let (width, height, stride) = (info.width as usize, info.height as usize, info.stride as usize);
+    let cols = width / FONT_WIDTH;
+    let rows = height / FONT_HEIGHT;
+    if cols == 0 || rows == 0 || stride < width || stride * height * size_of::<u32>() > info.size {
+        return Err(FramebufferError::BadMode);
+    }
+
+    let mut cells = Vec::new();
+    let mut shown = Vec::new();
+    cells.try_reserve_exact(cols * rows).map_err(|_| FramebufferError::OutOfMemory)?;
+    shown.try_reserve_exact(cols * rows).map_err(|_| FramebufferError::OutOfMemory)?;
+    cells.resize(cols * rows, BLANK);
+    shown.resize(cols * rows, BLANK);
+
+    let pixels = paging::map_mmio(PhysAddr::new(info.base as u64), info.size as u64, CacheMode::WriteCombining)
+        .map_err(FramebufferError::Map)?;
+
+    let mut console = FramebufferConsole {
+        pixels: pixels.as_mut_ptr(),
+        stride,
+        width,
+        height,
+        colors: PALETTE.map(|color| encode(&info, color)),
+        cols,
+        rows,
+        cells,
+        shown,
+        col: 0,
+        row: 0,
+        cursor_visible: true,
+        drawn_cursor: 0,
+        fg: DEFAULT_FG,
+        bg: DEFAULT_BG,
+        bold: false,
+        state: State::Ground,
+        params: [0; MAX_PARAMS],
+        param_count: 0,
+    };
+    console.clear_screen();
+    console.flush();
+
+    *SINK.console.lock() = Some(console);
+    super::register_sink(&SINK).map_err(FramebufferError::Console)?;
+    Ok((cols, rows))
+}
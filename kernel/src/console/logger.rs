@@ -0,0 +1,218 @@
+use core::arch::x86_64::__cpuid;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::RwLock;
+
+use crate::boot_time;
+use crate::interrupt::apic;
+use crate::interrupt::interrupt::without_interrupts;
+use crate::time;
+
+/*
+ * The `log` backend. Records are filtered by level, with a default level and per-module
+ * overrides, and printed to the console as
+ *
+ *   [    1.234567] cpu0 INFO  kernel::time: TSC at 2400000000 Hz
+ *
+ * The timestamp is the time since the logger was installed. Until the kernel has calibrated the
+ * TSC it uses the bootloader's calibration, and prints zeroes if there is neither.
+ */
+
+/// The most per-module level overrides
+pub const MAX_MODULE_FILTERS: usize = 16;
+
+/// The longest module path an override can name
+pub const MAX_MODULE_LEN: usize = 48;
+
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LoggerError {
+    /// Every module filter slot is taken
+    TooManyFilters,
+
+    /// The module path is longer than `MAX_MODULE_LEN`
+    NameTooLong,
+}
+
+//a level override. the module path is copied in, so it can come from anywhere.
+#[derive(Copy, Clone)]
+struct ModuleFilter {
+    name: [u8; MAX_MODULE_LEN],
+    len: usize,
+    level: LevelFilter,
+}
+
+impl ModuleFilter {
+    fn module(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.len]).unwrap_or("")
+    }
+}
+
+struct Filters {
+    default: LevelFilter,
+    modules: [Option<ModuleFilter>; MAX_MODULE_FILTERS],
+}
+
+impl Filters {
+    //the level for a target, from the longest module path that contains it
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .flatten()
+            .filter(|filter| contains(filter.module(), target))
+            .max_by_key(|filter| filter.len)
+            .map_or(self.default, |filter| filter.level)
+    }
+
+    //the `log` crate skips anything above its max level before calling the logger, so that has
+    //to be the most verbose level of any filter
+    fn max(&self) -> LevelFilter {
+        self.modules.iter().flatten().map(|filter| filter.level).fold(self.default, Ord::max)
+    }
+}
+
+//whether `target` is `module` or one of its submodules
+fn contains(module: &str, target: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+static FILTERS: RwLock<Filters> = RwLock::new(Filters {
+    default: DEFAULT_LEVEL,
+    modules: [None; MAX_MODULE_FILTERS],
+});
+
+static START_TSC: AtomicU64 = AtomicU64::new(0);
+
+//the bootloader's TSC calibration, copied so logging never has to take the boot time lock
+static LOADER_TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+/// The ID of the current CPU: its APIC ID once the local APIC is up, and the initial APIC ID
+/// from CPUID before that
+pub fn cpu_id() -> u32 {
+    apic::id().unwrap_or_else(|| __cpuid(1).ebx >> 24)
+}
+
+//microseconds since the logger was installed
+fn uptime_us() -> u64 {
+    let hz = match time::tsc_hz() {
+        0 => LOADER_TSC_HZ.load(Ordering::Relaxed),
+        hz => hz,
+    };
+    boot_time::ticks_to_us(boot_time::rdtsc().saturating_sub(START_TSC.load(Ordering::Relaxed)), hz)
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTERS.read().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let us = uptime_us();
+        let level = match record.level() {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+
+        crate::println!("[{:>5}.{:06}] cpu{} {} {}: {}",
+            us / 1_000_000, us % 1_000_000, cpu_id(), level, record.target(), record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger. Called by `console::init`.
+pub fn init() {
+    START_TSC.store(boot_time::rdtsc(), Ordering::Relaxed);
+    LOADER_TSC_HZ.store(boot_time::tsc_hz(), Ordering::Relaxed);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(FILTERS.read().max());
+    }
+}
+
+/// Sets the level for every module without an override of its own
+pub fn set_level(level: LevelFilter) {
+    without_interrupts(|| {
+        let mut filters = FILTERS.write();
+        filters.default = level;
+        log::set_max_level(filters.max());
+    })
+}
+
+/// Sets the level for a module and its submodules, like `kernel::net`. `LevelFilter::Off`
+/// silences it.
+pub fn set_module_level(module: &str, level: LevelFilter) -> Result<(), LoggerError> {
+    if module.len() > MAX_MODULE_LEN {
+        return Err(LoggerError::NameTooLong);
+    }
+    let mut filter = ModuleFilter { name: [0; MAX_MODULE_LEN], len: module.len(), level };
+    filter.name[..module.len()].copy_from_slice(module.as_bytes());
+
+    without_interrupts(|| {
+        let mut filters = FILTERS.write();
+        let slot = match filters.modules.iter().position(|f| f.is_some_and(|f| f.module() == module)) {
+            Some(index) => index,
+            None => filters.modules.iter().position(Option::is_none).ok_or(LoggerError::TooManyFilters)?,
+        };
+
+        filters.modules[slot] = Some(filter);
+        log::set_max_level(filters.max());
+        Ok(())
+    })
+}
+
+/// Removes a module's override, so it goes back to the default level
+pub fn clear_module_level(module: &str) {
+    without_interrupts(|| {
+        let mut filters = FILTERS.write();
+        for slot in filters.modules.iter_mut() {
+            if slot.is_some_and(|f| f.module() == module) {
+                *slot = None;
+            }
+        }
+        log::set_max_level(filters.max());
+    })
+}
+
+/// Prints the default level and every module override
+pub fn report(out: &mut impl Write) -> fmt::Result {
+    let filters = without_interrupts(|| {
+        let filters = FILTERS.read();
+        (filters.default, filters.modules)
+    });
+
+    writeln!(out, "default level: {}", filters.0)?;
+    for filter in filters.1.iter().flatten() {
+        writeln!(out, "  {}: {}", filter.module(), filter.level)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn module_filters_match_whole_path_segments() {
+        assert!(contains("kernel::net", "kernel::net"));
+        assert!(contains("kernel::net", "kernel::net::arp"));
+        assert!(!contains("kernel::net", "kernel::netdev"));
+        assert!(!contains("kernel::net::arp", "kernel::net"));
+    }
+}
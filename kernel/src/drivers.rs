@@ -0,0 +1,3 @@
+pub mod net;
+pub mod virtio;
+pub mod watchdog;
@@ -0,0 +1,185 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::net::packet::{Packet, PacketError};
+use crate::pci::driver;
+use crate::pci::PciDevice;
+
+pub mod e1000;
+pub mod virtio_net;
+
+/*
+ * Network interface card drivers. The drivers register with the PCI driver registry and keep
+ * the NICs they claim; the network stack reaches them through the NetDevice trait and polls
+ * them for received frames.
+ */
+
+/// The MTU every driver starts out with
+pub const DEFAULT_MTU: usize = 1500;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NetError {
+    /// Every transmit descriptor is in use. Retry once the device has sent some.
+    QueueFull,
+
+    /// The frame is bigger than the device can send
+    TooLarge,
+
+    /// The packet buffer pool ran out of buffers
+    NoBuffers,
+
+    /// The frame has no headroom for the NIC's header, or its checksum field is outside it
+    Malformed,
+}
+
+impl From<PacketError> for NetError {
+    fn from(e: PacketError) -> Self {
+        match e {
+            PacketError::Exhausted | PacketError::Memory(_) => NetError::NoBuffers,
+            PacketError::NoHeadroom | PacketError::NoTailroom | PacketError::TooShort => NetError::Malformed,
+        }
+    }
+}
+
+/// An Ethernet MAC address
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xFF; 6]);
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+/// A checksum for the device to fill in when it sends a frame: the ones' complement sum of the
+/// frame from `start` to its end goes at `start + offset`. For TCP and UDP the checksum field
+/// has to hold the pseudo-header sum beforehand.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TxChecksum {
+    pub start: u16,
+    pub offset: u16,
+}
+
+/// What the device said about a received frame
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RxInfo {
+    /// Whether the device already checked the frame's TCP or UDP checksum
+    pub checksum_valid: bool,
+}
+
+/// Packet and byte counters of one NIC
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NetStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+
+    /// Frames dropped because there was no buffer to put them in
+    pub rx_dropped: u64,
+
+    /// Frames the device reported as damaged, like CRC or length errors
+    pub rx_errors: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+
+    /// Frames that couldn't be queued, because the ring was full
+    pub tx_dropped: u64,
+}
+
+/// What a NIC can do in hardware that would otherwise be done in software
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Offloads {
+    /// The NIC fills in the checksum a TxChecksum asks for
+    pub tx_checksum: bool,
+
+    /// The NIC checks TCP and UDP checksums of received frames, see RxInfo::checksum_valid
+    pub rx_checksum: bool,
+}
+
+/// The operations every NIC driver provides. Every method takes `&self`, so a NIC can be
+/// shared; the drivers lock what they need inside.
+pub trait NetDevice: Sync {
+    /// The driver or model name
+    fn name(&self) -> &'static str;
+
+    fn pci(&self) -> &'static PciDevice;
+
+    fn mac(&self) -> MacAddress;
+
+    fn mtu(&self) -> usize;
+
+    fn link_up(&self) -> bool;
+
+    fn offloads(&self) -> Offloads;
+
+    fn stats(&self) -> NetStats;
+
+    /// Queues a frame to be sent, filling in `checksum` in hardware if the NIC can and in
+    /// software otherwise. The NIC reads the frame straight out of the packet's buffers, and
+    /// holds on to them until it is done.
+    fn transmit(&self, frame: Packet, checksum: Option<TxChecksum>) -> Result<(), NetError>;
+
+    /// Takes the next received frame, if there is one. The NIC received it straight into the
+    /// packet's buffers.
+    fn receive(&self) -> Option<(Packet, RxInfo)>;
+
+    /// Receives every frame on the wire. NICs without receive filters already do.
+    fn set_promiscuous(&self, _on: bool) {}
+
+    /// Replaces the multicast groups the NIC lets through. NICs without receive filters let
+    /// every group through.
+    fn set_multicast(&self, _groups: &[MacAddress]) {}
+}
+
+/// Fills in a TxChecksum in software, for devices that can't. The checksum field has to be in
+/// the head buffer, which is copied first if it is shared.
+pub fn fill_checksum(frame: &mut Packet, checksum: TxChecksum) -> Result<(), PacketError> {
+    let (start, field) = (checksum.start as usize, checksum.start as usize + checksum.offset as usize);
+    if field + 2 > frame.data().len() {
+        return Err(PacketError::TooShort);
+    }
+
+    let sum = frame.checksum(start);
+    frame.data_mut()?[field..field + 2].copy_from_slice(&sum.to_be_bytes());
+    Ok(())
+}
+
+/// Registers every NIC driver with the PCI registry, which probes the NICs already found.
+/// Returns how many NICs were claimed.
+pub fn init() -> usize {
+    [&virtio_net::DRIVER, &e1000::DRIVER]
+        .into_iter()
+        .map(|driver| driver::register(driver).unwrap_or(0))
+        .sum()
+}
+
+/// Every NIC the drivers have claimed
+pub fn devices() -> Vec<&'static dyn NetDevice> {
+    let virtio = virtio_net::nics().into_iter().map(|nic| nic as &'static dyn NetDevice);
+    let e1000 = e1000::nics().into_iter().map(|nic| nic as &'static dyn NetDevice);
+    virtio.chain(e1000).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn software_checksum_matches_ipv4_header() {
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+            0x00, 0x00, 0xC0, 0xA8, 0x00, 0x01, 0xC0, 0xA8, 0x00, 0xC7,
+        ];
+        let mut frame = Packet::from_slice(&header).unwrap();
+        fill_checksum(&mut frame, TxChecksum { start: 0, offset: 10 }).unwrap();
+        assert_eq!(frame.data()[10..12], [0xB8, 0x61]);
+    }
+}
@@ -0,0 +1,818 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use core::time::Duration;
+use log::{info, warn};
+use spin::{Mutex, Once};
+use x86_64::VirtAddr;
+
+use super::{fill_checksum, MacAddress, NetDevice, NetError, NetStats, Offloads, RxInfo, TxChecksum, DEFAULT_MTU};
+use crate::interrupt::entry::InterruptContext;
+use crate::interrupt::interrupt::without_interrupts;
+use crate::memory::dma::DmaRegion;
+use crate::memory::frame::{FrameConstraints, FrameError};
+use crate::net::packet::Packet;
+use crate::memory::paging::CacheMode;
+use crate::pci::driver::{PciDriver, PciMatch, ProbeError};
+use crate::pci::msi::{self, InterruptMode, PciInterrupts};
+use crate::pci::{PciDevice, PciError};
+use crate::time::{self, Instant};
+
+/*
+ * Driver for the Intel 8254x (e1000) and 82574 (e1000e) gigabit NICs, QEMU's `-nic` default
+ * and `-device e1000e`. Both are driven the same way here, through legacy descriptors: a ring
+ * of receive descriptors each pointing at an empty packet buffer, and a ring of transmit
+ * descriptors each pointing at one buffer of a packet to send. The head of a ring is where the
+ * NIC is, the tail is where the driver is. A receive buffer takes 2 KiB, so bigger frames come
+ * in over several descriptors and are chained.
+ *
+ * Received frames are polled for. The interrupt, throttled by ITR, is there for link changes
+ * and to tell a poller there is work.
+ *
+ * The statistics registers clear when read, so they are added up into a copy in memory. The
+ * packet counters are 32 bits, so reading them less often than every few minutes at line rate
+ * loses counts.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/Intel_Ethernet_i217
+ * PCI/PCI-X Family of Gigabit Ethernet Controllers Software Developer's Manual (8254x), 13 "Register Descriptions"
+ * Intel 82574 GbE Controller Family Datasheet, 10 "Programming Interface"
+ */
+
+const VENDOR_INTEL: u16 = 0x8086;
+
+//registers
+const CTRL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const EERD: usize = 0x0014;
+const ICR: usize = 0x00C0;
+const ITR: usize = 0x00C4;
+const IMS: usize = 0x00D0;
+const IMC: usize = 0x00D8;
+const IVAR: usize = 0x00E4;
+const RCTL: usize = 0x0100;
+const TCTL: usize = 0x0400;
+const TIPG: usize = 0x0410;
+const RDBAL: usize = 0x2800;
+const RDBAH: usize = 0x2804;
+const RDLEN: usize = 0x2808;
+const RDH: usize = 0x2810;
+const RDT: usize = 0x2818;
+const RDTR: usize = 0x2820;
+const TDBAL: usize = 0x3800;
+const TDBAH: usize = 0x3804;
+const TDLEN: usize = 0x3808;
+const TDH: usize = 0x3810;
+const TDT: usize = 0x3818;
+const RXCSUM: usize = 0x5000;
+const MTA: usize = 0x5200;
+const RAL0: usize = 0x5400;
+const RAH0: usize = 0x5404;
+
+//statistics registers
+const CRCERRS: usize = 0x4000;
+const ALGNERRC: usize = 0x4004;
+const SYMERRS: usize = 0x4008;
+const RXERRC: usize = 0x400C;
+const MPC: usize = 0x4010;
+const COLC: usize = 0x4028;
+const RLEC: usize = 0x4040;
+const GPRC: usize = 0x4074;
+const BPRC: usize = 0x4078;
+const MPRC: usize = 0x407C;
+const GPTC: usize = 0x4080;
+const GORCL: usize = 0x4088;
+const GOTCL: usize = 0x4090;
+const RNBC: usize = 0x40A0;
+const TPR: usize = 0x40D0;
+const TPT: usize = 0x40D4;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+const STATUS_FD: u32 = 1 << 0;
+const STATUS_LU: u32 = 1 << 1;
+const STATUS_SPEED_SHIFT: u32 = 6;
+
+//interrupt causes
+const INT_LSC: u32 = 1 << 2;
+const INT_RXO: u32 = 1 << 6;
+const INT_RXT0: u32 = 1 << 7;
+const INT_OTHER: u32 = 1 << 24;
+
+//82574 IVAR: the receive queue, transmit queue and other causes all on MSI-X vector 0
+const IVAR_ALL_ON_VECTOR_0: u32 = 1 << 3 | 1 << 11 | 1 << 19;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_UPE: u32 = 1 << 3;
+const RCTL_MPE: u32 = 1 << 4;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x0F << 4;
+const TCTL_COLD_FULL_DUPLEX: u32 = 0x40 << 12;
+
+//the recommended inter packet gap for copper
+const TIPG_COPPER: u32 = 10 | 8 << 10 | 6 << 20;
+
+const RXCSUM_TUOFLD: u32 = 1 << 9;
+const RAH_AV: u32 = 1 << 31;
+
+//descriptor bits
+const RX_STATUS_DD: u8 = 1 << 0;
+const RX_STATUS_EOP: u8 = 1 << 1;
+const RX_STATUS_IXSM: u8 = 1 << 2;
+const RX_STATUS_UDPCS: u8 = 1 << 4;
+const RX_STATUS_TCPCS: u8 = 1 << 5;
+const RX_ERRORS_TCPE: u8 = 1 << 5;
+
+//every error but the checksum ones, which only mean the checksum wasn't verified
+const RX_ERRORS_FRAME: u8 = 0b1001_1111;
+
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_IC: u8 = 1 << 2;
+const TX_CMD_RS: u8 = 1 << 3;
+const TX_STATUS_DD: u8 = 1 << 0;
+
+const RING_SIZE: usize = 256;
+const MTA_ENTRIES: usize = 128;
+
+/// The interrupt rate the driver starts out with
+pub const DEFAULT_INTERRUPTS_PER_SEC: u32 = 8000;
+
+/// How a model's EEPROM read register is laid out
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Eerd {
+    /// 82540, 82545 and 82546: the address at bit 8 and the done bit at bit 4
+    Narrow,
+
+    /// 82541, 82547 and 82574: the address at bit 2 and the done bit at bit 1
+    Wide,
+}
+
+impl Eerd {
+    fn start(&self, word: u8) -> u32 {
+        match self {
+            Eerd::Narrow => (word as u32) << 8 | 1,
+            Eerd::Wide => (word as u32) << 2 | 1,
+        }
+    }
+
+    fn done(&self) -> u32 {
+        match self {
+            Eerd::Narrow => 1 << 4,
+            Eerd::Wide => 1 << 1,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Model {
+    device: u16,
+    name: &'static str,
+    eerd: Eerd,
+
+    /// 82574 routes its MSI-X causes through IVAR
+    ivar: bool,
+}
+
+const MODELS: [Model; 13] = [
+    Model { device: 0x100E, name: "82540EM", eerd: Eerd::Narrow, ivar: false },
+    Model { device: 0x100F, name: "82545EM", eerd: Eerd::Narrow, ivar: false },
+    Model { device: 0x1010, name: "82546EB", eerd: Eerd::Narrow, ivar: false },
+    Model { device: 0x1011, name: "82545EM", eerd: Eerd::Narrow, ivar: false },
+    Model { device: 0x1015, name: "82540EM", eerd: Eerd::Narrow, ivar: false },
+    Model { device: 0x1026, name: "82545GM", eerd: Eerd::Narrow, ivar: false },
+    Model { device: 0x1079, name: "82546GB", eerd: Eerd::Narrow, ivar: false },
+    Model { device: 0x1075, name: "82547GI", eerd: Eerd::Wide, ivar: false },
+    Model { device: 0x1076, name: "82541GI", eerd: Eerd::Wide, ivar: false },
+    Model { device: 0x1078, name: "82541ER", eerd: Eerd::Wide, ivar: false },
+    Model { device: 0x107C, name: "82541PI", eerd: Eerd::Wide, ivar: false },
+    Model { device: 0x10D3, name: "82574L", eerd: Eerd::Wide, ivar: true },
+    Model { device: 0x10F6, name: "82574LA", eerd: Eerd::Wide, ivar: true },
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum SetupError {
+    Pci(PciError),
+    Memory(FrameError),
+
+    /// The NIC didn't come out of reset
+    Reset,
+}
+
+/// The NIC's own counters, added up since the driver took it over
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HwStats {
+    pub crc_errors: u64,
+    pub alignment_errors: u64,
+    pub symbol_errors: u64,
+    pub rx_errors: u64,
+
+    /// Frames dropped because the receive FIFO was full
+    pub missed: u64,
+    pub collisions: u64,
+    pub length_errors: u64,
+
+    /// Times a frame came in with no free receive descriptor
+    pub no_buffers: u64,
+    pub good_rx_packets: u64,
+    pub good_rx_bytes: u64,
+    pub broadcast_rx: u64,
+    pub multicast_rx: u64,
+    pub good_tx_packets: u64,
+    pub good_tx_bytes: u64,
+    pub total_rx_packets: u64,
+    pub total_tx_packets: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct RxDescriptor {
+    addr: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct TxDescriptor {
+    addr: u64,
+    length: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+//a descriptor ring, and the packet given to the NIC with each descriptor
+struct Ring {
+    descriptors: DmaRegion,
+    packets: Vec<Option<Packet>>,
+
+    //receive: the next descriptor to give an empty packet. transmit: the next one to fill.
+    next_to_use: usize,
+
+    //receive: the next descriptor to check for a frame. transmit: the oldest one not yet
+    //reclaimed.
+    next_to_clean: usize,
+    stats: NetStats,
+}
+
+impl Ring {
+    fn new() -> Result<Self, FrameError> {
+        Ok(Ring {
+            descriptors: DmaRegion::alloc(RING_SIZE * size_of::<RxDescriptor>(), FrameConstraints::ANY)?,
+            packets: (0..RING_SIZE).map(|_| None).collect(),
+            next_to_use: 0,
+            next_to_clean: 0,
+            stats: NetStats::default(),
+        })
+    }
+
+    //the descriptors between the two indexes, which the NIC has or hasn't handed back yet
+    fn in_flight(&self) -> usize {
+        (self.next_to_use + RING_SIZE - self.next_to_clean) % RING_SIZE
+    }
+
+    fn rx_descriptor(&self, index: usize) -> *mut RxDescriptor {
+        unsafe { self.descriptors.as_ptr::<RxDescriptor>().add(index) }
+    }
+
+    fn tx_descriptor(&self, index: usize) -> *mut TxDescriptor {
+        unsafe { self.descriptors.as_ptr::<TxDescriptor>().add(index) }
+    }
+}
+
+/// One e1000 family NIC
+pub struct E1000 {
+    pci: &'static PciDevice,
+    model: Model,
+    regs: VirtAddr,
+    mac: MacAddress,
+    rx: Mutex<Ring>,
+    tx: Mutex<Ring>,
+    hw_stats: Mutex<HwStats>,
+    interrupts: Once<PciInterrupts>,
+    link_changes: AtomicU64,
+}
+
+impl E1000 {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.regs + register as u64).as_ptr()) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.regs + register as u64).as_mut_ptr(), value) }
+    }
+
+    fn new(pci: &'static PciDevice, model: Model) -> Result<Self, SetupError> {
+        pci.power_on();
+        pci.enable();
+        pci.enable_bus_master();
+        let regs = pci.map_bar(0, CacheMode::Uncached).map_err(SetupError::Pci)?;
+
+        let mut nic = E1000 {
+            pci,
+            model,
+            regs,
+            mac: MacAddress::default(),
+            rx: Mutex::new(Ring::new().map_err(SetupError::Memory)?),
+            tx: Mutex::new(Ring::new().map_err(SetupError::Memory)?),
+            hw_stats: Mutex::new(HwStats::default()),
+            interrupts: Once::new(),
+            link_changes: AtomicU64::new(0),
+        };
+
+        nic.reset()?;
+        nic.mac = nic.read_mac();
+        nic.setup_rx();
+        nic.setup_tx();
+        nic.set_interrupt_rate(DEFAULT_INTERRUPTS_PER_SEC);
+
+        //the counters hold whatever happened before the reset was done, so start from zero
+        nic.read_hw_stats(&mut HwStats::default());
+        Ok(nic)
+    }
+
+    fn reset(&self) -> Result<(), SetupError> {
+        self.write(IMC, u32::MAX);
+        self.write(CTRL, self.read(CTRL) | CTRL_RST);
+        time::sleep(Duration::from_millis(1));
+
+        let start = Instant::now();
+        while self.read(CTRL) & CTRL_RST != 0 {
+            if start.elapsed() > Duration::from_millis(100) {
+                return Err(SetupError::Reset);
+            }
+            core::hint::spin_loop();
+        }
+
+        //the reset turns interrupts back on, so mask them again and drop anything pending
+        self.write(IMC, u32::MAX);
+        self.read(ICR);
+        self.write(CTRL, self.read(CTRL) | CTRL_SLU | CTRL_ASDE);
+        Ok(())
+    }
+
+    fn read_eeprom(&self, word: u8) -> Option<u16> {
+        let eerd = self.model.eerd;
+        self.write(EERD, eerd.start(word));
+
+        let start = Instant::now();
+        loop {
+            let value = self.read(EERD);
+            if value & eerd.done() != 0 {
+                return Some((value >> 16) as u16);
+            }
+            if start.elapsed() > Duration::from_millis(10) {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    //the MAC address is in the first three EEPROM words. without an EEPROM, the one the
+    //firmware put in the first receive address register is used.
+    fn read_mac(&self) -> MacAddress {
+        let words: Option<Vec<u16>> = (0..3).map(|word| self.read_eeprom(word)).collect();
+        let mac = match words {
+            Some(words) => {
+                let mut mac = [0; 6];
+                for (i, word) in words.iter().enumerate() {
+                    mac[2 * i..2 * i + 2].copy_from_slice(&word.to_le_bytes());
+                }
+                mac
+            }
+            None => {
+                let (low, high) = (self.read(RAL0), self.read(RAH0));
+                let [a, b, c, d] = low.to_le_bytes();
+                let [e, f, _, _] = high.to_le_bytes();
+                [a, b, c, d, e, f]
+            }
+        };
+
+        //the receive filter has to match the address
+        let [a, b, c, d, e, f] = mac;
+        self.write(RAL0, u32::from_le_bytes([a, b, c, d]));
+        self.write(RAH0, u32::from_le_bytes([e, f, 0, 0]) | RAH_AV);
+        MacAddress(mac)
+    }
+
+    fn setup_rx(&self) {
+        let mut rx = self.rx.lock();
+        for entry in 0..MTA_ENTRIES {
+            self.write(MTA + 4 * entry, 0);
+        }
+
+        let base = rx.descriptors.phys().as_u64();
+        self.write(RDBAL, base as u32);
+        self.write(RDBAH, (base >> 32) as u32);
+        self.write(RDLEN, (RING_SIZE * size_of::<RxDescriptor>()) as u32);
+        self.write(RDH, 0);
+        self.write(RDT, 0);
+        self.write(RDTR, 0);
+        self.write(RXCSUM, self.read(RXCSUM) | RXCSUM_TUOFLD);
+
+        //2 KiB buffers, broadcasts accepted, the CRC stripped
+        self.write(RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+        self.refill(&mut rx);
+    }
+
+    //gives the NIC an empty packet for every descriptor it doesn't have, as far as the pool
+    //allows. One descriptor always stays with the driver, since a tail equal to the head means
+    //the NIC has none.
+    fn refill(&self, rx: &mut Ring) {
+        let start = rx.next_to_use;
+        while rx.in_flight() < RING_SIZE - 1 {
+            let Ok(packet) = Packet::new() else {
+                break;
+            };
+
+            let index = rx.next_to_use;
+            let descriptor = RxDescriptor { addr: packet.phys().as_u64(), ..Default::default() };
+            unsafe { rx.rx_descriptor(index).write_volatile(descriptor) };
+            rx.packets[index] = Some(packet);
+            rx.next_to_use = (index + 1) % RING_SIZE;
+        }
+
+        if rx.next_to_use != start {
+            //the descriptors have to be written before the NIC sees the new tail
+            fence(Ordering::SeqCst);
+            self.write(RDT, rx.next_to_use as u32);
+        }
+    }
+
+    fn setup_tx(&self) {
+        let tx = self.tx.lock();
+        let base = tx.descriptors.phys().as_u64();
+        self.write(TDBAL, base as u32);
+        self.write(TDBAH, (base >> 32) as u32);
+        self.write(TDLEN, (RING_SIZE * size_of::<TxDescriptor>()) as u32);
+        self.write(TDH, 0);
+        self.write(TDT, 0);
+        self.write(TIPG, TIPG_COPPER);
+        self.write(TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD_FULL_DUPLEX);
+    }
+
+    //sets up the interrupt and turns on the causes the driver wants
+    fn enable_interrupts(&'static self) -> Result<(), msi::MsiError> {
+        let interrupts = msi::allocate(self.pci, 1, handle_interrupt)?;
+        let mut causes = INT_LSC | INT_RXT0 | INT_RXO;
+        if interrupts.mode() == InterruptMode::MsiX && self.model.ivar {
+            self.write(IVAR, IVAR_ALL_ON_VECTOR_0);
+            causes |= INT_OTHER;
+        }
+
+        self.interrupts.call_once(|| interrupts);
+        self.write(IMS, causes);
+        Ok(())
+    }
+
+    //called for every interrupt on the NIC's vector, which an INTx line may share
+    fn interrupt(&self) {
+        let causes = self.read(ICR);
+        if causes & INT_LSC != 0 {
+            self.link_changes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The link speed in Mb/s and whether it is full duplex, or None if the link is down
+    pub fn link_speed(&self) -> Option<(u32, bool)> {
+        let status = self.read(STATUS);
+        if status & STATUS_LU == 0 {
+            return None;
+        }
+
+        let speed = match (status >> STATUS_SPEED_SHIFT) & 0b11 {
+            0 => 10,
+            1 => 100,
+            _ => 1000,
+        };
+        Some((speed, status & STATUS_FD != 0))
+    }
+
+    /// How many link state change interrupts there have been
+    pub fn link_changes(&self) -> u64 {
+        self.link_changes.load(Ordering::Relaxed)
+    }
+
+    /// Limits the NIC to `per_sec` interrupts a second, or lets it interrupt for every event
+    /// with 0
+    pub fn set_interrupt_rate(&self, per_sec: u32) {
+        //the interval is counted in 256 ns units
+        let interval = if per_sec == 0 { 0 } else { (1_000_000_000 / 256 / per_sec as u64).min(0xFFFF) as u32 };
+        self.write(ITR, interval);
+    }
+
+    fn set_rctl(&self, bits: u32, on: bool) {
+        without_interrupts(|| {
+            let _rx = self.rx.lock();
+            let rctl = self.read(RCTL);
+            self.write(RCTL, if on { rctl | bits } else { rctl & !bits });
+        })
+    }
+
+    /// Receives every multicast frame, not only the groups in the filter
+    pub fn set_all_multicast(&self, on: bool) {
+        self.set_rctl(RCTL_MPE, on);
+    }
+
+    fn read_hw_stats(&self, stats: &mut HwStats) {
+        let counter = |register| self.read(register) as u64;
+        //the low half of a 64 bit counter has to be read first
+        let wide = |register| counter(register) | counter(register + 4) << 32;
+
+        stats.crc_errors += counter(CRCERRS);
+        stats.alignment_errors += counter(ALGNERRC);
+        stats.symbol_errors += counter(SYMERRS);
+        stats.rx_errors += counter(RXERRC);
+        stats.missed += counter(MPC);
+        stats.collisions += counter(COLC);
+        stats.length_errors += counter(RLEC);
+        stats.no_buffers += counter(RNBC);
+        stats.good_rx_packets += counter(GPRC);
+        stats.broadcast_rx += counter(BPRC);
+        stats.multicast_rx += counter(MPRC);
+        stats.good_tx_packets += counter(GPTC);
+        stats.good_rx_bytes += wide(GORCL);
+        stats.good_tx_bytes += wide(GOTCL);
+        stats.total_rx_packets += counter(TPR);
+        stats.total_tx_packets += counter(TPT);
+    }
+
+    /// Reads the NIC's statistics registers and returns the totals so far
+    pub fn hardware_stats(&self) -> HwStats {
+        without_interrupts(|| {
+            let mut stats = self.hw_stats.lock();
+            self.read_hw_stats(&mut stats);
+            *stats
+        })
+    }
+}
+
+impl NetDevice for E1000 {
+    fn name(&self) -> &'static str {
+        self.model.name
+    }
+
+    fn pci(&self) -> &'static PciDevice {
+        self.pci
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn mtu(&self) -> usize {
+        DEFAULT_MTU
+    }
+
+    fn link_up(&self) -> bool {
+        self.read(STATUS) & STATUS_LU != 0
+    }
+
+    fn offloads(&self) -> Offloads {
+        Offloads { tx_checksum: true, rx_checksum: true }
+    }
+
+    /// Receives every frame on the wire, whoever it is for
+    fn set_promiscuous(&self, on: bool) {
+        self.set_rctl(RCTL_UPE | RCTL_MPE, on);
+    }
+
+    /// Replaces the multicast filter with the given groups. The filter is a hash table, so
+    /// frames for other groups that share a hash get through too.
+    fn set_multicast(&self, groups: &[MacAddress]) {
+        let mut table = [0u32; MTA_ENTRIES];
+        for group in groups {
+            let hash = multicast_hash(group);
+            table[hash >> 5] |= 1 << (hash & 31);
+        }
+
+        for (entry, bits) in table.iter().enumerate() {
+            self.write(MTA + 4 * entry, *bits);
+        }
+    }
+
+    /// Queues a frame to be sent. Descriptors the NIC is done with are reclaimed first.
+    fn transmit(&self, mut frame: Packet, checksum: Option<TxChecksum>) -> Result<(), NetError> {
+        let len = frame.len();
+        let mut template = TxDescriptor { cmd: TX_CMD_IFCS | TX_CMD_RS, ..Default::default() };
+
+        //the legacy descriptor has a byte for where the checksum starts and where it goes
+        if let Some(checksum) = checksum {
+            let field = checksum.start as usize + checksum.offset as usize;
+            match (u8::try_from(checksum.start), u8::try_from(field)) {
+                (Ok(start), Ok(field)) => {
+                    template.cmd |= TX_CMD_IC;
+                    template.css = start;
+                    template.cso = field;
+                }
+                _ => fill_checksum(&mut frame, checksum)?,
+            }
+        }
+
+        let segments = frame.dma_segments().count();
+        if segments >= RING_SIZE {
+            return Err(NetError::TooLarge);
+        }
+
+        without_interrupts(|| {
+            let mut tx = self.tx.lock();
+            while tx.in_flight() > 0 && unsafe { (*tx.tx_descriptor(tx.next_to_clean)).status } & TX_STATUS_DD != 0 {
+                let index = tx.next_to_clean;
+                tx.packets[index] = None;
+                tx.next_to_clean = (index + 1) % RING_SIZE;
+            }
+
+            if tx.in_flight() + segments > RING_SIZE - 1 {
+                tx.stats.tx_dropped += 1;
+                return Err(NetError::QueueFull);
+            }
+
+            let mut index = tx.next_to_use;
+            for (i, (address, length)) in frame.dma_segments().enumerate() {
+                let mut descriptor = TxDescriptor { addr: address.as_u64(), length: length as u16, ..template };
+                if i + 1 == segments {
+                    descriptor.cmd |= TX_CMD_EOP;
+                }
+                unsafe { tx.tx_descriptor(index).write_volatile(descriptor) };
+                index = (index + 1) % RING_SIZE;
+            }
+
+            //the NIC reads the packet until the last descriptor is done
+            let last = (index + RING_SIZE - 1) % RING_SIZE;
+            tx.packets[last] = Some(frame);
+
+            //the descriptors have to be written before the NIC sees the new tail
+            fence(Ordering::SeqCst);
+            tx.next_to_use = index;
+            self.write(TDT, index as u32);
+
+            tx.stats.tx_packets += 1;
+            tx.stats.tx_bytes += len as u64;
+            Ok(())
+        })
+    }
+
+    /// Takes the next received frame. A frame the NIC marked as damaged is dropped and counted
+    /// as an error.
+    fn receive(&self) -> Option<(Packet, RxInfo)> {
+        without_interrupts(|| {
+            let mut rx = self.rx.lock();
+            //the pool may have run dry when the ring was last refilled
+            self.refill(&mut rx);
+
+            loop {
+                //a frame spread over several descriptors is only taken once all of them are done
+                let mut count = 0;
+                loop {
+                    if count == rx.in_flight() {
+                        return None;
+                    }
+
+                    let descriptor = unsafe { rx.rx_descriptor((rx.next_to_clean + count) % RING_SIZE).read_volatile() };
+                    if descriptor.status & RX_STATUS_DD == 0 {
+                        return None;
+                    }
+                    count += 1;
+                    if descriptor.status & RX_STATUS_EOP != 0 {
+                        break;
+                    }
+                }
+
+                //the descriptors' contents can't be read before their done bits
+                fence(Ordering::SeqCst);
+                let mut frame: Option<Packet> = None;
+                let mut damaged = false;
+                let mut last = RxDescriptor::default();
+                for _ in 0..count {
+                    let index = rx.next_to_clean;
+                    last = unsafe { rx.rx_descriptor(index).read_volatile() };
+                    unsafe { rx.rx_descriptor(index).write_volatile(RxDescriptor::default()) };
+                    rx.next_to_clean = (index + 1) % RING_SIZE;
+                    damaged |= last.errors & RX_ERRORS_FRAME != 0;
+
+                    let Some(mut packet) = rx.packets[index].take() else {
+                        damaged = true;
+                        continue;
+                    };
+                    damaged |= packet.put(last.length as usize).is_err();
+                    match &mut frame {
+                        Some(frame) => damaged |= frame.append(packet).is_err(),
+                        None => frame = Some(packet),
+                    }
+                }
+                self.refill(&mut rx);
+
+                let Some(frame) = frame.filter(|_| !damaged) else {
+                    rx.stats.rx_errors += 1;
+                    continue;
+                };
+
+                let checked = last.status & RX_STATUS_IXSM == 0
+                    && last.status & (RX_STATUS_TCPCS | RX_STATUS_UDPCS) != 0
+                    && last.errors & RX_ERRORS_TCPE == 0;
+                rx.stats.rx_packets += 1;
+                rx.stats.rx_bytes += frame.len() as u64;
+                return Some((frame, RxInfo { checksum_valid: checked }));
+            }
+        })
+    }
+
+    fn stats(&self) -> NetStats {
+        without_interrupts(|| {
+            let (rx, tx) = (self.rx.lock().stats, self.tx.lock().stats);
+            NetStats { tx_packets: tx.tx_packets, tx_bytes: tx.tx_bytes, tx_dropped: tx.tx_dropped, ..rx }
+        })
+    }
+}
+
+//the multicast table index of an address, with the default filter offset: bits 36 to 47
+fn multicast_hash(mac: &MacAddress) -> usize {
+    ((mac.0[4] as usize >> 4) | (mac.0[5] as usize) << 4) & 0xFFF
+}
+
+static NICS: Mutex<Vec<&'static E1000>> = Mutex::new(Vec::new());
+
+/// Every e1000 family NIC the driver has claimed
+pub fn nics() -> Vec<&'static E1000> {
+    without_interrupts(|| NICS.lock().clone())
+}
+
+fn handle_interrupt(ctx: &InterruptContext) {
+    let nics = NICS.lock();
+    for nic in nics.iter() {
+        if nic.interrupts.get().is_some_and(|interrupts| interrupts.index_of(ctx.vector as u8).is_some()) {
+            nic.interrupt();
+        }
+    }
+}
+
+fn probe(pci: &'static PciDevice) -> Result<(), ProbeError> {
+    let model = *MODELS.iter().find(|model| model.device == pci.device).ok_or(ProbeError::Unsupported)?;
+    let nic = E1000::new(pci, model).map_err(|e| {
+        warn!("e1000: {} could not be set up: {:?}", pci.address, e);
+        match e {
+            SetupError::Pci(_) | SetupError::Memory(_) => ProbeError::NoResources,
+            SetupError::Reset => ProbeError::Device,
+        }
+    })?;
+
+    //NICs stay for the life of the kernel
+    let nic: &'static E1000 = Box::leak(Box::new(nic));
+    without_interrupts(|| NICS.lock().push(nic));
+
+    //the NIC still works without interrupts, it just doesn't count link changes
+    let mode = match nic.enable_interrupts() {
+        Ok(()) => nic.interrupts.get().map(|interrupts| interrupts.mode()),
+        Err(e) => {
+            warn!("e1000: {} has no interrupt: {:?}", pci.address, e);
+            None
+        }
+    };
+
+    let link = match nic.link_speed() {
+        Some((speed, full_duplex)) => alloc::format!("up {} Mb/s {}", speed, if full_duplex { "full duplex" } else { "half duplex" }),
+        None => "down".into(),
+    };
+    info!("e1000: {} {} MAC {}, link {}, interrupts {:?}", pci.address, model.name, nic.mac(), link, mode);
+    Ok(())
+}
+
+const MATCHES: [PciMatch; MODELS.len()] = {
+    let mut matches = [PciMatch::Id { vendor: VENDOR_INTEL, device: 0 }; MODELS.len()];
+    let mut i = 0;
+    while i < MODELS.len() {
+        matches[i] = PciMatch::Id { vendor: VENDOR_INTEL, device: MODELS[i].device };
+        i += 1;
+    }
+    matches
+};
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "e1000",
+    matches: &MATCHES,
+    probe,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn multicast_hash_uses_the_last_twelve_bits() {
+        //01:00:5e:00:00:01, the all-hosts group
+        assert_eq!(multicast_hash(&MacAddress([0x01, 0x00, 0x5E, 0x00, 0x00, 0x01])), 0x010);
+        assert_eq!(multicast_hash(&MacAddress([0x33, 0x33, 0x00, 0x00, 0xAB, 0xCD])), 0xCDA);
+    }
+}
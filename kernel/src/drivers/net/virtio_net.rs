@@ -0,0 +1,337 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, Ordering};
+use log::{info, warn};
+use spin::Mutex;
+
+use super::{fill_checksum, MacAddress, NetDevice, NetError, NetStats, Offloads, RxInfo, TxChecksum, DEFAULT_MTU};
+use crate::drivers::virtio::queue::Virtqueue;
+use crate::drivers::virtio::{VirtioError, VirtioPci, STATUS_DRIVER_OK, STATUS_FAILED, VENDOR_VIRTIO};
+use crate::interrupt::interrupt::without_interrupts;
+use crate::net::packet::Packet;
+use crate::pci::driver::{PciDriver, PciMatch, ProbeError};
+use crate::pci::PciDevice;
+
+/*
+ * Driver for virtio-net, QEMU's paravirtual NIC (`-device virtio-net-pci`). Queue 0 receives
+ * and queue 1 transmits. The device reads and writes packet buffers directly, with the
+ * virtio-net header in front of the frame. With mergeable receive buffers a frame bigger than a
+ * buffer comes in over several, which are chained, and the header of the first says how many.
+ *
+ * Interrupts are left off: the network stack polls, and a poll also reaps sent frames.
+ *
+ * Built with help from:
+ * Virtio 1.2, 5.1 "Network Device"
+ * https://github.com/torvalds/linux/blob/master/drivers/net/virtio_net.c
+ */
+
+const DEVICE_NET_TRANSITIONAL: u16 = 0x1000;
+const DEVICE_NET: u16 = 0x1041;
+
+//feature bits
+const F_CSUM: u64 = 1 << 0;
+const F_GUEST_CSUM: u64 = 1 << 1;
+const F_MAC: u64 = 1 << 5;
+const F_MRG_RXBUF: u64 = 1 << 15;
+const F_STATUS: u64 = 1 << 16;
+
+//device configuration
+const CONFIG_MAC: usize = 0;
+const CONFIG_STATUS: usize = 6;
+const STATUS_LINK_UP: u16 = 1;
+
+//virtio-net header flags
+const HDR_F_NEEDS_CSUM: u8 = 1;
+const HDR_F_DATA_VALID: u8 = 2;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+//the most buffers a frame to send can be chained over
+const MAX_SEGMENTS: usize = 8;
+
+/// The header in front of every frame. With VERSION_1 it always has the num_buffers field.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct NetHeader {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+    num_buffers: u16,
+}
+
+const HEADER_SIZE: usize = size_of::<NetHeader>();
+
+//a queue and the packets the device has
+struct Ring {
+    queue: Virtqueue,
+
+    //the packet given to the device with each descriptor ID
+    packets: Vec<Option<Packet>>,
+    stats: NetStats,
+}
+
+impl Ring {
+    fn new(queue: Virtqueue) -> Self {
+        let size = queue.size() as usize;
+        Ring {
+            queue,
+            packets: (0..size).map(|_| None).collect(),
+            stats: NetStats::default(),
+        }
+    }
+
+    //gives the device an empty packet to receive into for every free descriptor, as far as the
+    //pool allows
+    fn refill(&mut self) {
+        let mut pushed = false;
+        while self.queue.free_count() > 0 {
+            let Ok(packet) = Packet::new() else {
+                break;
+            };
+            let Some(id) = self.queue.push(&[(packet.phys().as_u64(), packet.tailroom() as u32, true)]) else {
+                break;
+            };
+            self.packets[id as usize] = Some(packet);
+            pushed = true;
+        }
+
+        if pushed {
+            self.queue.kick();
+        }
+    }
+
+    //takes the next packet the device is done with, and how much it wrote
+    fn pop(&mut self) -> Option<(Packet, usize)> {
+        let (id, len) = self.queue.pop_used()?;
+        self.packets[id as usize].take().map(|packet| (packet, len as usize))
+    }
+
+    //drops the packets the device has sent
+    fn reap(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+/// One virtio-net NIC
+pub struct VirtioNet {
+    pci: &'static PciDevice,
+    transport: VirtioPci,
+    features: u64,
+    mac: MacAddress,
+    rx: Mutex<Ring>,
+    tx: Mutex<Ring>,
+}
+
+//the last byte of the made up MAC address for NICs that don't report one
+static NEXT_LOCAL_MAC: AtomicU8 = AtomicU8::new(1);
+
+impl VirtioNet {
+    fn new(pci: &'static PciDevice) -> Result<Self, VirtioError> {
+        let transport = VirtioPci::new(pci)?;
+        let setup = Self::setup(pci, transport);
+        if let Err((transport, _)) = &setup {
+            transport.add_status(STATUS_FAILED);
+        }
+        setup.map_err(|(_, e)| e)
+    }
+
+    fn setup(pci: &'static PciDevice, transport: VirtioPci) -> Result<Self, (VirtioPci, VirtioError)> {
+        let features = match transport.negotiate(F_CSUM | F_GUEST_CSUM | F_MAC | F_MRG_RXBUF | F_STATUS) {
+            Ok(features) => features,
+            Err(e) => return Err((transport, e)),
+        };
+
+        let rings = transport
+            .setup_queue(RX_QUEUE)
+            .and_then(|rx| Ok((Ring::new(rx), Ring::new(transport.setup_queue(TX_QUEUE)?))));
+        let (mut rx, tx) = match rings {
+            Ok(rings) => rings,
+            Err(e) => return Err((transport, e)),
+        };
+
+        //a locally administered address, when the device has none of its own
+        let mut mac = [0x02, 0, 0, 0, 0, NEXT_LOCAL_MAC.fetch_add(1, Ordering::Relaxed)];
+        if features & F_MAC != 0 {
+            transport.read_config(CONFIG_MAC, &mut mac);
+        }
+
+        transport.add_status(STATUS_DRIVER_OK);
+        rx.refill();
+
+        Ok(VirtioNet {
+            pci,
+            transport,
+            features,
+            mac: MacAddress(mac),
+            rx: Mutex::new(rx),
+            tx: Mutex::new(tx),
+        })
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn pci(&self) -> &'static PciDevice {
+        self.pci
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn mtu(&self) -> usize {
+        DEFAULT_MTU
+    }
+
+    /// Whether the link is up. Devices without the status feature are always up.
+    fn link_up(&self) -> bool {
+        if self.features & F_STATUS == 0 {
+            return true;
+        }
+
+        let mut status = [0; 2];
+        self.transport.read_config(CONFIG_STATUS, &mut status);
+        u16::from_le_bytes(status) & STATUS_LINK_UP != 0
+    }
+
+    fn offloads(&self) -> Offloads {
+        Offloads {
+            tx_checksum: self.features & F_CSUM != 0,
+            rx_checksum: self.features & F_GUEST_CSUM != 0,
+        }
+    }
+
+    /// Queues a frame to be sent. Frames the device already sent are reaped first, to make
+    /// room.
+    fn transmit(&self, mut frame: Packet, checksum: Option<TxChecksum>) -> Result<(), NetError> {
+        let len = frame.len();
+        let mut header = NetHeader::default();
+        match checksum {
+            Some(checksum) if self.features & F_CSUM != 0 => {
+                header.flags = HDR_F_NEEDS_CSUM;
+                header.csum_start = checksum.start;
+                header.csum_offset = checksum.offset;
+            }
+            Some(checksum) => fill_checksum(&mut frame, checksum)?,
+            None => {}
+        }
+        unsafe { (frame.push(HEADER_SIZE)?.as_mut_ptr() as *mut NetHeader).write_unaligned(header) };
+
+        let mut pieces = [(0, 0, false); MAX_SEGMENTS];
+        for (i, (address, length)) in frame.dma_segments().enumerate() {
+            *pieces.get_mut(i).ok_or(NetError::TooLarge)? = (address.as_u64(), length as u32, false);
+        }
+        let pieces = &pieces[..frame.dma_segments().count()];
+
+        without_interrupts(|| {
+            let mut tx = self.tx.lock();
+            tx.reap();
+
+            let Some(id) = tx.queue.push(pieces) else {
+                tx.stats.tx_dropped += 1;
+                return Err(NetError::QueueFull);
+            };
+            //the device reads the packet until it hands the descriptor back
+            tx.packets[id as usize] = Some(frame);
+            tx.queue.kick();
+
+            tx.stats.tx_packets += 1;
+            tx.stats.tx_bytes += len as u64;
+            Ok(())
+        })
+    }
+
+    /// Takes the next received frame. A frame whose buffers didn't all arrive is dropped and
+    /// counted as an error.
+    fn receive(&self) -> Option<(Packet, RxInfo)> {
+        without_interrupts(|| {
+            let mut rx = self.rx.lock();
+            //the pool may have run dry when the queue was last refilled
+            rx.refill();
+
+            loop {
+                let (mut frame, written) = rx.pop()?;
+                let mut complete = frame.put(written).is_ok() && frame.data().len() >= HEADER_SIZE;
+                let header = if complete {
+                    unsafe { (frame.data().as_ptr() as *const NetHeader).read_unaligned() }
+                } else {
+                    NetHeader::default()
+                };
+                complete &= frame.pull(HEADER_SIZE).is_ok();
+
+                //the rest of a frame that came in over several buffers
+                let buffers = if self.features & F_MRG_RXBUF != 0 { header.num_buffers.max(1) } else { 1 };
+                for _ in 1..buffers {
+                    match rx.pop() {
+                        Some((mut more, written)) => {
+                            complete &= more.put(written).is_ok() && frame.append(more).is_ok();
+                        }
+                        None => complete = false,
+                    }
+                }
+                rx.refill();
+
+                if !complete {
+                    rx.stats.rx_errors += 1;
+                    continue;
+                }
+
+                rx.stats.rx_packets += 1;
+                rx.stats.rx_bytes += frame.len() as u64;
+                return Some((frame, RxInfo { checksum_valid: header.flags & HDR_F_DATA_VALID != 0 }));
+            }
+        })
+    }
+
+    fn stats(&self) -> NetStats {
+        without_interrupts(|| {
+            let (rx, tx) = (self.rx.lock().stats, self.tx.lock().stats);
+            NetStats { tx_packets: tx.tx_packets, tx_bytes: tx.tx_bytes, tx_dropped: tx.tx_dropped, ..rx }
+        })
+    }
+}
+
+static NICS: Mutex<Vec<&'static VirtioNet>> = Mutex::new(Vec::new());
+
+/// Every virtio-net NIC the driver has claimed
+pub fn nics() -> Vec<&'static VirtioNet> {
+    without_interrupts(|| NICS.lock().clone())
+}
+
+fn probe(pci: &'static PciDevice) -> Result<(), ProbeError> {
+    let nic = VirtioNet::new(pci).map_err(|e| {
+        warn!("virtio-net: {} could not be set up: {:?}", pci.address, e);
+        match e {
+            VirtioError::Legacy | VirtioError::Features => ProbeError::Unsupported,
+            VirtioError::Map(_) | VirtioError::Memory(_) => ProbeError::NoResources,
+            VirtioError::BadBar | VirtioError::NoQueue => ProbeError::Device,
+        }
+    })?;
+
+    let offloads = nic.offloads();
+    info!("virtio-net: {} MAC {}, link {}, checksum offload tx {} rx {}, mergeable rx buffers {}",
+        pci.address, nic.mac(), if nic.link_up() { "up" } else { "down" },
+        offloads.tx_checksum, offloads.rx_checksum, nic.features & F_MRG_RXBUF != 0
+    );
+
+    //NICs stay for the life of the kernel
+    let nic: &'static VirtioNet = Box::leak(Box::new(nic));
+    without_interrupts(|| NICS.lock().push(nic));
+    Ok(())
+}
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-net",
+    matches: &[
+        PciMatch::Id { vendor: VENDOR_VIRTIO, device: DEVICE_NET },
+        PciMatch::Id { vendor: VENDOR_VIRTIO, device: DEVICE_NET_TRANSITIONAL },
+    ],
+    probe,
+};
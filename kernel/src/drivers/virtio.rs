@@ -0,0 +1,270 @@
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::frame::FrameError;
+use crate::memory::paging::{self, CacheMode, PagingError};
+use crate::pci::bar::Bar;
+use crate::pci::PciDevice;
+
+pub mod queue;
+
+use queue::{Virtqueue, MAX_QUEUE_SIZE};
+
+/*
+ * The virtio 1.x PCI transport ("modern" virtio). The device describes where its register
+ * blocks are with vendor specific capabilities, each naming a BAR and a range in it: the common
+ * configuration, the queue notification area, the ISR status, and the device specific
+ * configuration. The ISR status only matters for INTx interrupts, which aren't used, so it is
+ * not mapped. Legacy-only devices, which put everything in an I/O BAR, aren't supported.
+ *
+ * Built with help from:
+ * Virtio 1.2, 4.1 "Virtio Over PCI Bus"
+ * https://wiki.osdev.org/Virtio
+ */
+
+pub const VENDOR_VIRTIO: u16 = 0x1AF4;
+
+//the capability ID all virtio structures use, and the structure types
+const CAP_VENDOR_SPECIFIC: u8 = 0x09;
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_DEVICE_CFG: u8 = 4;
+
+//common configuration registers
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_NUM_QUEUES: usize = 0x12;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_CONFIG_GENERATION: usize = 0x15;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1A;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+//device status bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+/// Set by every device that follows virtio 1.x rather than the legacy interface
+pub const F_VERSION_1: u64 = 1 << 32;
+
+//the MSI-X vector value for "no interrupt"
+const NO_VECTOR: u16 = 0xFFFF;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The device doesn't have the modern PCI capabilities
+    Legacy,
+
+    /// A capability names a BAR that isn't a memory BAR with an address
+    BadBar,
+    Map(PagingError),
+
+    /// The device didn't accept the feature set, or lacks one the driver needs
+    Features,
+
+    /// The device doesn't have a queue with that index
+    NoQueue,
+    Memory(FrameError),
+}
+
+//a mapped register block
+#[derive(Copy, Clone)]
+struct Region {
+    base: VirtAddr,
+    length: u64,
+}
+
+impl Region {
+    fn read<T>(&self, offset: usize) -> T {
+        unsafe { core::ptr::read_volatile((self.base.as_u64() as usize + offset) as *const T) }
+    }
+
+    fn write<T>(&self, offset: usize, value: T) {
+        unsafe { core::ptr::write_volatile((self.base.as_u64() as usize + offset) as *mut T, value) }
+    }
+}
+
+/// The register blocks of one virtio PCI device
+pub struct VirtioPci {
+    common: Region,
+    notify: Region,
+    notify_multiplier: u32,
+    device: Region,
+}
+
+// The regions are MMIO that only the owning driver touches
+unsafe impl Send for VirtioPci {}
+unsafe impl Sync for VirtioPci {}
+
+//maps the range a capability points at
+fn map_region(pci: &PciDevice, cap: u8) -> Result<Region, VirtioError> {
+    let bar = pci.address.read8(cap + 4);
+    let offset = pci.address.read32(cap + 8) as u64;
+    let length = pci.address.read32(cap + 12) as u64;
+
+    let Some(Bar::Memory { address, .. }) = pci.bar(bar as usize).filter(Bar::is_assigned) else {
+        return Err(VirtioError::BadBar);
+    };
+
+    let base = paging::map_mmio(PhysAddr::new(address + offset), length.max(1), CacheMode::Uncached)
+        .map_err(VirtioError::Map)?;
+    Ok(Region { base, length })
+}
+
+impl VirtioPci {
+    /// Finds and maps the device's register blocks, and resets it
+    pub fn new(pci: &PciDevice) -> Result<Self, VirtioError> {
+        let (mut common, mut notify, mut device) = (None, None, None);
+        let mut notify_multiplier = 0;
+
+        //the first capability of each type is the one to use
+        for (id, offset) in pci.capabilities.iter() {
+            if id != CAP_VENDOR_SPECIFIC {
+                continue;
+            }
+
+            let slot = match pci.address.read8(offset + 3) {
+                CAP_COMMON_CFG => &mut common,
+                CAP_NOTIFY_CFG => {
+                    if notify.is_none() {
+                        notify_multiplier = pci.address.read32(offset + 16);
+                    }
+                    &mut notify
+                }
+                CAP_DEVICE_CFG => &mut device,
+                _ => continue,
+            };
+            if slot.is_none() {
+                *slot = Some(offset);
+            }
+        }
+
+        let (Some(common), Some(notify)) = (common, notify) else {
+            return Err(VirtioError::Legacy);
+        };
+
+        pci.power_on();
+        pci.enable();
+        pci.enable_bus_master();
+
+        let transport = VirtioPci {
+            common: map_region(pci, common)?,
+            notify: map_region(pci, notify)?,
+            notify_multiplier,
+            device: match device {
+                Some(device) => map_region(pci, device)?,
+                None => Region { base: VirtAddr::zero(), length: 0 },
+            },
+        };
+
+        transport.reset();
+        Ok(transport)
+    }
+
+    /// Resets the device, which stops it using any queue
+    pub fn reset(&self) {
+        self.common.write::<u8>(COMMON_DEVICE_STATUS, 0);
+        while self.common.read::<u8>(COMMON_DEVICE_STATUS) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn status(&self) -> u8 {
+        self.common.read(COMMON_DEVICE_STATUS)
+    }
+
+    pub fn add_status(&self, bits: u8) {
+        self.common.write(COMMON_DEVICE_STATUS, self.status() | bits);
+    }
+
+    /// Acknowledges the device, and agrees on the features both sides support out of `wanted`.
+    /// F_VERSION_1 is always asked for and has to be offered. Returns the agreed features.
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, VirtioError> {
+        self.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let mut offered = 0u64;
+        for half in 0..2u32 {
+            self.common.write(COMMON_DEVICE_FEATURE_SELECT, half);
+            offered |= (self.common.read::<u32>(COMMON_DEVICE_FEATURE) as u64) << (32 * half);
+        }
+
+        let features = offered & (wanted | F_VERSION_1);
+        if features & F_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(VirtioError::Legacy);
+        }
+
+        for half in 0..2u32 {
+            self.common.write(COMMON_DRIVER_FEATURE_SELECT, half);
+            self.common.write(COMMON_DRIVER_FEATURE, (features >> (32 * half)) as u32);
+        }
+
+        self.add_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(VirtioError::Features);
+        }
+        Ok(features)
+    }
+
+    pub fn queue_count(&self) -> u16 {
+        self.common.read(COMMON_NUM_QUEUES)
+    }
+
+    /// Creates queue `index`, as big as the device allows up to MAX_QUEUE_SIZE, and hands it
+    /// to the device
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        if index >= self.queue_count() {
+            return Err(VirtioError::NoQueue);
+        }
+
+        self.common.write(COMMON_QUEUE_SELECT, index);
+        let offered: u16 = self.common.read(COMMON_QUEUE_SIZE);
+        if offered == 0 {
+            return Err(VirtioError::NoQueue);
+        }
+
+        let notify_offset = self.common.read::<u16>(COMMON_QUEUE_NOTIFY_OFF) as u64 * self.notify_multiplier as u64;
+        if notify_offset + 2 > self.notify.length {
+            return Err(VirtioError::NoQueue);
+        }
+
+        //split queue sizes are powers of two, so the smaller of two is one too
+        let size = offered.min(MAX_QUEUE_SIZE);
+        let notify = (self.notify.base + notify_offset).as_mut_ptr();
+        let queue = Virtqueue::new(size, index, notify).map_err(VirtioError::Memory)?;
+        let (desc, driver, device) = queue.addresses();
+
+        self.common.write(COMMON_QUEUE_SIZE, size);
+        self.common.write(COMMON_QUEUE_MSIX_VECTOR, NO_VECTOR);
+        self.common.write(COMMON_QUEUE_DESC, desc);
+        self.common.write(COMMON_QUEUE_DRIVER, driver);
+        self.common.write(COMMON_QUEUE_DEVICE, device);
+        self.common.write::<u16>(COMMON_QUEUE_ENABLE, 1);
+        Ok(queue)
+    }
+
+    /// Reads `buf.len()` bytes of the device specific configuration from `offset`, retrying if
+    /// the device changes it midway. Bytes past the end of it read as zero.
+    pub fn read_config(&self, offset: usize, buf: &mut [u8]) {
+        loop {
+            let before: u8 = self.common.read(COMMON_CONFIG_GENERATION);
+            for (i, byte) in buf.iter_mut().enumerate() {
+                let at = offset + i;
+                *byte = if (at as u64) < self.device.length { self.device.read(at) } else { 0 };
+            }
+            if self.common.read::<u8>(COMMON_CONFIG_GENERATION) == before {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use core::sync::atomic::{fence, Ordering};
+use log::warn;
+
+use crate::memory::dma::DmaRegion;
+use crate::memory::frame::{FrameConstraints, FrameError};
+
+/*
+ * A split virtqueue: a descriptor table, the available ring the driver hands descriptors to the
+ * device through, and the used ring the device hands them back through. All three live in one
+ * DMA region. A buffer made of several pieces, like a chained packet, is a chain of descriptors
+ * linked through their next fields.
+ *
+ * Built with help from:
+ * Virtio 1.2, 2.7 "Split Virtqueues"
+ */
+
+/// The biggest queue the driver sets up, whatever the device offers
+pub const MAX_QUEUE_SIZE: u16 = 256;
+
+const DESC_SIZE: usize = 16;
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+//the used ring flag a device sets when it doesn't need to be notified
+const USED_F_NO_NOTIFY: u16 = 1;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+pub struct Virtqueue {
+    region: DmaRegion,
+    size: u16,
+
+    //where the available and used rings start in the region
+    avail_offset: usize,
+    used_offset: usize,
+
+    //the free descriptors, linked through their next fields
+    free_head: u16,
+    free_count: u16,
+
+    //the next available ring slot to fill, and the next used ring slot to read
+    avail_idx: u16,
+    last_used: u16,
+
+    //a bit per descriptor, set while it heads a chain the device has
+    in_flight: [u64; MAX_QUEUE_SIZE as usize / 64],
+
+    //the queue's index, and where writing it notifies the device
+    index: u16,
+    notify: *mut u16,
+}
+
+// The rings are only touched through the queue, by whoever holds it
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    /// Allocates queue `index` with `size` descriptors, a power of two. Writing the index to
+    /// `notify` tells the device there are new buffers.
+    pub fn new(size: u16, index: u16, notify: *mut u16) -> Result<Self, FrameError> {
+        let n = size as usize;
+        let avail_offset = n * DESC_SIZE;
+        let used_offset = (avail_offset + 6 + 2 * n).next_multiple_of(4);
+        let length = used_offset + 6 + 8 * n;
+
+        let region = DmaRegion::alloc(length, FrameConstraints::ANY)?;
+        let queue = Virtqueue {
+            region,
+            size,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            avail_idx: 0,
+            last_used: 0,
+            in_flight: [0; MAX_QUEUE_SIZE as usize / 64],
+            index,
+            notify,
+        };
+
+        for i in 0..size {
+            unsafe { (*queue.descriptor(i)).next = i + 1 };
+        }
+        Ok(queue)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// The physical addresses of the descriptor table, the available ring and the used ring
+    pub fn addresses(&self) -> (u64, u64, u64) {
+        let base = self.region.phys().as_u64();
+        (base, base + self.avail_offset as u64, base + self.used_offset as u64)
+    }
+
+    pub fn free_count(&self) -> u16 {
+        self.free_count
+    }
+
+    fn descriptor(&self, id: u16) -> *mut Descriptor {
+        unsafe { self.region.as_ptr::<Descriptor>().add(id as usize) }
+    }
+
+    fn is_in_flight(&self, id: u16) -> bool {
+        self.in_flight[id as usize / 64] & (1 << (id % 64)) != 0
+    }
+
+    fn set_in_flight(&mut self, id: u16, in_flight: bool) {
+        if in_flight {
+            self.in_flight[id as usize / 64] |= 1 << (id % 64);
+        } else {
+            self.in_flight[id as usize / 64] &= !(1 << (id % 64));
+        }
+    }
+
+    fn ring16(&self, offset: usize) -> *mut u16 {
+        unsafe { self.region.as_ptr::<u8>().add(offset) as *mut u16 }
+    }
+
+    /// Gives the device a buffer made of `(address, length, writable)` pieces, which it reads
+    /// from or, for writable ones, writes into. Returns the ID of the first descriptor, which
+    /// comes back from `pop_used`, or None if there aren't enough free descriptors.
+    pub fn push(&mut self, pieces: &[(u64, u32, bool)]) -> Option<u16> {
+        if pieces.is_empty() || pieces.len() > self.free_count as usize {
+            return None;
+        }
+
+        //the chain takes descriptors in free list order, so their next fields already link them
+        let head = self.free_head;
+        let mut id = head;
+        for (i, &(addr, len, writable)) in pieces.iter().enumerate() {
+            let desc = self.descriptor(id);
+            let next = unsafe { (*desc).next };
+            let mut flags = if writable { DESC_F_WRITE } else { 0 };
+            if i + 1 < pieces.len() {
+                flags |= DESC_F_NEXT;
+            }
+
+            unsafe { desc.write_volatile(Descriptor { addr, len, flags, next }) };
+            id = next;
+        }
+        self.free_head = id;
+        self.free_count -= pieces.len() as u16;
+        self.set_in_flight(head, true);
+
+        unsafe {
+            //ring entries follow the flags and index fields
+            let slot = self.avail_idx % self.size;
+            self.ring16(self.avail_offset + 4 + 2 * slot as usize).write_volatile(head);
+
+            //the descriptors and the ring entry have to be visible before the new index
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            self.ring16(self.avail_offset + 2).write_volatile(self.avail_idx);
+        }
+        Some(head)
+    }
+
+    /// Tells the device about the buffers pushed since the last kick, unless it asked not to be
+    pub fn kick(&self) {
+        fence(Ordering::SeqCst);
+        if unsafe { self.ring16(self.used_offset).read_volatile() } & USED_F_NO_NOTIFY == 0 {
+            unsafe { self.notify.write_volatile(self.index) };
+        }
+    }
+
+    /// Takes the next buffer the device is done with, as the ID of its first descriptor and the
+    /// number of bytes the device wrote into it. Entries for descriptors the device doesn't
+    /// have are logged and skipped.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let element = loop {
+            let used_idx = unsafe { self.ring16(self.used_offset + 2).read_volatile() };
+            if used_idx == self.last_used {
+                return None;
+            }
+
+            //the element can't be read before the index that says it is there
+            fence(Ordering::SeqCst);
+            let slot = self.last_used % self.size;
+            let element = unsafe {
+                let ring = self.region.as_ptr::<u8>().add(self.used_offset + 4) as *const UsedElement;
+                ring.add(slot as usize).read_volatile()
+            };
+            self.last_used = self.last_used.wrapping_add(1);
+
+            if element.id < self.size as u32 && self.is_in_flight(element.id as u16) {
+                break element;
+            }
+            warn!("virtio: queue {} returned descriptor {}, which it was never given", self.index, element.id);
+        };
+
+        //the whole chain goes back on the free list, still linked
+        let id = element.id as u16;
+        self.set_in_flight(id, false);
+        let mut tail = id;
+        let mut count = 1;
+        while unsafe { (*self.descriptor(tail)).flags } & DESC_F_NEXT != 0 {
+            tail = unsafe { (*self.descriptor(tail)).next };
+            count += 1;
+        }
+        unsafe { (*self.descriptor(tail)).next = self.free_head };
+        self.free_head = id;
+        self.free_count += count;
+        Some((id, element.len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //plays the device, putting `(id, len)` in the used ring and publishing it
+    fn complete(queue: &mut Virtqueue, id: u32, len: u32) {
+        let used_idx = unsafe { queue.ring16(queue.used_offset + 2).read_volatile() };
+        let slot = (used_idx % queue.size) as usize;
+        unsafe {
+            let ring = queue.region.as_ptr::<u8>().add(queue.used_offset + 4) as *mut UsedElement;
+            ring.add(slot).write_volatile(UsedElement { id, len });
+            queue.ring16(queue.used_offset + 2).write_volatile(used_idx.wrapping_add(1));
+        }
+    }
+
+    #[test_case]
+    fn used_entries_for_unknown_descriptors_are_skipped() {
+        let mut queue = Virtqueue::new(8, 0, core::ptr::null_mut()).unwrap();
+        let id = queue.push(&[(0x1000, 64, true), (0x2000, 64, true)]).unwrap();
+
+        complete(&mut queue, 1000, 64);
+        complete(&mut queue, id as u32 + 1, 64);
+        complete(&mut queue, id as u32, 100);
+        complete(&mut queue, id as u32, 100);
+
+        assert_eq!(queue.pop_used(), Some((id, 100)));
+        assert_eq!(queue.pop_used(), None);
+        assert_eq!(queue.free_count(), 8);
+    }
+}
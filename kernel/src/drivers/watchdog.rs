@@ -0,0 +1,166 @@
+use spin::Mutex;
+
+use crate::boot_time;
+use crate::time;
+
+pub mod i6300esb;
+pub mod itco;
+
+use i6300esb::I6300Esb;
+use itco::Itco;
+
+/// The timeout used when nothing else has been configured
+pub const DEFAULT_TIMEOUT_SECS: u32 = 30;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchdogError {
+    /// The requested timeout is outside what the hardware can count
+    InvalidTimeout,
+
+    /// The firmware locked the watchdog configuration
+    Locked,
+
+    /// The device is missing a resource it needs, like an I/O or MMIO base address
+    NotConfigured,
+}
+
+/// Why the machine last came out of reset, as far as the watchdog can tell
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResetReason {
+    /// The watchdog expired and reset the machine
+    Watchdog,
+
+    /// Power on, or a reset the watchdog did not cause
+    Other,
+}
+
+/// The operations every hardware watchdog driver provides
+pub trait Watchdog {
+    fn name(&self) -> &'static str;
+
+    /// Sets the timeout, returning the timeout the hardware actually uses after rounding
+    fn set_timeout(&mut self, secs: u32) -> Result<u32, WatchdogError>;
+
+    fn start(&mut self);
+
+    fn stop(&mut self) -> Result<(), WatchdogError>;
+
+    /// Reloads the timer so the watchdog does not expire
+    fn pet(&mut self);
+
+    /// Whether the watchdog caused the last reset. This is read once when the device is probed,
+    /// before the status is cleared.
+    fn reset_reason(&self) -> ResetReason;
+}
+
+enum WatchdogDevice {
+    I6300Esb(I6300Esb),
+    Itco(Itco),
+}
+
+impl WatchdogDevice {
+    fn get(&mut self) -> &mut dyn Watchdog {
+        match self {
+            WatchdogDevice::I6300Esb(wdt) => wdt,
+            WatchdogDevice::Itco(wdt) => wdt,
+        }
+    }
+}
+
+struct WatchdogState {
+    device: WatchdogDevice,
+    timeout_secs: u32,
+
+    /// The TSC value of the last time the watchdog was pet
+    last_pet: u64,
+}
+
+static WATCHDOG: Mutex<Option<WatchdogState>> = Mutex::new(None);
+
+/// Probes for a supported watchdog, configures it with `timeout_secs` and starts it. Returns the
+/// name of the device that was found.
+pub fn init(timeout_secs: u32) -> Result<Option<&'static str>, WatchdogError> {
+    let device = if let Some(wdt) = I6300Esb::probe() {
+        WatchdogDevice::I6300Esb(wdt)
+    } else if let Some(wdt) = Itco::probe() {
+        WatchdogDevice::Itco(wdt)
+    } else {
+        return Ok(None);
+    };
+
+    let mut state = WatchdogState {
+        device,
+        timeout_secs: 0,
+        last_pet: 0,
+    };
+
+    let wdt = state.device.get();
+    let name = wdt.name();
+    state.timeout_secs = wdt.set_timeout(timeout_secs)?;
+    wdt.pet();
+    wdt.start();
+    state.last_pet = boot_time::rdtsc();
+
+    *WATCHDOG.lock() = Some(state);
+    Ok(Some(name))
+}
+
+/// Changes the timeout of the running watchdog
+pub fn set_timeout(timeout_secs: u32) -> Result<u32, WatchdogError> {
+    match WATCHDOG.lock().as_mut() {
+        Some(state) => {
+            let wdt = state.device.get();
+            state.timeout_secs = wdt.set_timeout(timeout_secs)?;
+            wdt.pet();
+            Ok(state.timeout_secs)
+        }
+        None => Err(WatchdogError::NotConfigured),
+    }
+}
+
+/// Stops the watchdog, for an orderly shutdown
+pub fn stop() -> Result<(), WatchdogError> {
+    match WATCHDOG.lock().as_mut() {
+        Some(state) => state.device.get().stop(),
+        None => Ok(()),
+    }
+}
+
+/// Pets the watchdog right away
+pub fn pet() {
+    if let Some(state) = WATCHDOG.lock().as_mut() {
+        state.device.get().pet();
+        state.last_pet = boot_time::rdtsc();
+    }
+}
+
+/// The petting task. This is called from the kernel's main loop, and from anything that waits
+/// in place of it, and pets the watchdog once half of the timeout has passed, so a kernel that
+/// stops reaching its main loop gets reset.
+pub fn poll() {
+    let tsc_hz = time::tsc_hz();
+    let Some(mut guard) = WATCHDOG.try_lock() else {
+        return;
+    };
+
+    if let Some(state) = guard.as_mut() {
+        let interval = tsc_hz * state.timeout_secs as u64 / 2;
+        let now = boot_time::rdtsc();
+
+        //without a calibrated TSC there is no way to tell how long it has been, so pet every time
+        if tsc_hz == 0 || now.wrapping_sub(state.last_pet) >= interval {
+            state.device.get().pet();
+            state.last_pet = now;
+        }
+    }
+}
+
+/// Whether the watchdog caused the last reset, or None if there is no watchdog
+pub fn last_reset_reason() -> Option<ResetReason> {
+    WATCHDOG.lock().as_mut().map(|state| state.device.get().reset_reason())
+}
+
+/// The name of the active watchdog and its timeout in seconds
+pub fn status() -> Option<(&'static str, u32)> {
+    WATCHDOG.lock().as_mut().map(|state| (state.device.get().name(), state.timeout_secs))
+}
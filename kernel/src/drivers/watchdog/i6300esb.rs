@@ -0,0 +1,171 @@
+use spin::Once;
+
+use crate::memory::paging::CacheMode;
+use crate::pci::driver::{self, PciDriver, PciMatch, ProbeError};
+use crate::pci::{PciAddress, PciDevice, PciError};
+
+use super::{ResetReason, Watchdog, WatchdogError};
+
+// Driver for the watchdog in the Intel 6300ESB I/O controller hub, which QEMU emulates with
+// `-device i6300esb`.
+//
+// Built with help from:
+// https://github.com/torvalds/linux/blob/master/drivers/watchdog/i6300esb.c
+
+pub const VENDOR_INTEL: u16 = 0x8086;
+pub const DEVICE_ESB_WDT: u16 = 0x25AB;
+
+//PCI config registers
+const ESB_CONFIG_REG: u8 = 0x60;
+const ESB_LOCK_REG: u8 = 0x68;
+
+//MMIO registers, offset from BAR0
+const ESB_TIMER1_REG: usize = 0x00;
+const ESB_TIMER2_REG: usize = 0x04;
+const ESB_RELOAD_REG: usize = 0x0C;
+
+//config register: WDT_OUTPUT enabled, ~1KHz clock, and no interrupt on the first stage
+const ESB_CONFIG_NO_INTERRUPT: u16 = 0x0003;
+
+//lock register bits
+const ESB_WDT_ENABLE: u8 = 1 << 1;
+const ESB_WDT_LOCK: u8 = 1 << 0;
+
+//reload register bits
+const ESB_WDT_TIMEOUT: u16 = 1 << 9;
+const ESB_WDT_RELOAD: u16 = 1 << 8;
+
+//the two writes to the reload register that unlock the timer registers for one write
+const ESB_UNLOCK1: u16 = 0x80;
+const ESB_UNLOCK2: u16 = 0x86;
+
+//the timer has 20 bits of 1KHz ticks, and the heartbeat is split across its two stages
+const MAX_TIMEOUT_SECS: u32 = 2046;
+
+static DRIVER: PciDriver = PciDriver {
+    name: "i6300esb",
+    matches: &[PciMatch::Id { vendor: VENDOR_INTEL, device: DEVICE_ESB_WDT }],
+    probe: probe_pci,
+};
+
+//the function the PCI registry handed over, and where its registers are mapped
+static FOUND: Once<(PciAddress, usize)> = Once::new();
+
+fn probe_pci(device: &'static PciDevice) -> Result<(), ProbeError> {
+    device.power_on();
+    device.enable();
+
+    let base = device.map_bar(0, CacheMode::Uncached).map_err(|e| match e {
+        PciError::Map(_) => ProbeError::NoResources,
+        _ => ProbeError::Device,
+    })?;
+    FOUND.call_once(|| (device.address, base.as_u64() as usize));
+    Ok(())
+}
+
+pub struct I6300Esb {
+    pci: PciAddress,
+    base: usize,
+    reset_reason: ResetReason,
+}
+
+impl I6300Esb {
+    /// Registers with the PCI registry to claim the 6300ESB, and puts it in watchdog mode,
+    /// stopped. The bus has to be enumerated first.
+    pub fn probe() -> Option<Self> {
+        if driver::register(&DRIVER).is_err() {
+            return None;
+        }
+        let &(pci, base) = FOUND.get()?;
+
+        let mut wdt = I6300Esb {
+            pci,
+            base,
+            reset_reason: ResetReason::Other,
+        };
+
+        pci.write16(ESB_CONFIG_REG, ESB_CONFIG_NO_INTERRUPT);
+
+        //the timeout flag survives the reset, so it says whether the watchdog caused it
+        if wdt.read16(ESB_RELOAD_REG) & ESB_WDT_TIMEOUT != 0 {
+            wdt.reset_reason = ResetReason::Watchdog;
+        }
+
+        //stop the timer and clear the timeout flag. a locked watchdog can't be stopped.
+        if !wdt.is_locked() {
+            pci.write8(ESB_LOCK_REG, 0);
+        }
+        wdt.unlock();
+        wdt.write16(ESB_RELOAD_REG, ESB_WDT_TIMEOUT | ESB_WDT_RELOAD);
+
+        Some(wdt)
+    }
+
+    fn read16(&self, reg: usize) -> u16 {
+        unsafe { core::ptr::read_volatile((self.base + reg) as *const u16) }
+    }
+
+    fn write16(&mut self, reg: usize, value: u16) {
+        unsafe { core::ptr::write_volatile((self.base + reg) as *mut u16, value) }
+    }
+
+    fn write32(&mut self, reg: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    fn unlock(&mut self) {
+        self.write16(ESB_RELOAD_REG, ESB_UNLOCK1);
+        self.write16(ESB_RELOAD_REG, ESB_UNLOCK2);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.pci.read8(ESB_LOCK_REG) & ESB_WDT_LOCK != 0
+    }
+}
+
+impl Watchdog for I6300Esb {
+    fn name(&self) -> &'static str {
+        "i6300esb"
+    }
+
+    fn set_timeout(&mut self, secs: u32) -> Result<u32, WatchdogError> {
+        if secs == 0 || secs > MAX_TIMEOUT_SECS {
+            return Err(WatchdogError::InvalidTimeout);
+        }
+
+        //each stage counts (secs << 9) ticks of ~1KHz, so the two stages take about secs
+        let ticks = secs << 9;
+
+        self.unlock();
+        self.write32(ESB_TIMER1_REG, ticks);
+        self.unlock();
+        self.write32(ESB_TIMER2_REG, ticks);
+        self.pet();
+
+        Ok(secs)
+    }
+
+    fn start(&mut self) {
+        self.pet();
+        self.pci.write8(ESB_LOCK_REG, ESB_WDT_ENABLE);
+    }
+
+    fn stop(&mut self) -> Result<(), WatchdogError> {
+        if self.is_locked() {
+            return Err(WatchdogError::Locked);
+        }
+
+        self.pet();
+        self.pci.write8(ESB_LOCK_REG, 0);
+        Ok(())
+    }
+
+    fn pet(&mut self) {
+        self.unlock();
+        self.write16(ESB_RELOAD_REG, ESB_WDT_RELOAD);
+    }
+
+    fn reset_reason(&self) -> ResetReason {
+        self.reset_reason
+    }
+}
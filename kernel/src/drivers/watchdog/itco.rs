@@ -0,0 +1,183 @@
+use x86_64::instructions::port::Port;
+
+use crate::pci::{self, PciAddress};
+
+use super::{ResetReason, Watchdog, WatchdogError};
+
+// Driver for the TCO watchdog in the Intel ICH9 LPC bridge (iTCO version 2), which is part of
+// QEMU's q35 machine.
+//
+// Built with help from:
+// https://github.com/torvalds/linux/blob/master/drivers/watchdog/iTCO_wdt.c
+// Intel I/O Controller Hub 9 (ICH9) Family Datasheet, sections 13.8 and 13.9
+
+pub const VENDOR_INTEL: u16 = 0x8086;
+pub const DEVICE_ICH9_LPC: u16 = 0x2918;
+
+//LPC bridge config registers
+const LPC_PMBASE: u8 = 0x40;
+const LPC_ACPI_CNTL: u8 = 0x44;
+const LPC_RCBA: u8 = 0xF0;
+
+const ACPI_CNTL_ACPI_EN: u8 = 1 << 7;
+const RCBA_ENABLE: u32 = 1 << 0;
+
+//the general control and status register in the root complex block, and its NO_REBOOT strap
+const RCBA_GCS: usize = 0x3410;
+const GCS_NO_REBOOT: u32 = 1 << 5;
+
+//offsets from PMBASE
+const PM_SMI_EN: u16 = 0x30;
+const PM_TCO_BASE: u16 = 0x60;
+const SMI_EN_TCO_EN: u32 = 1 << 13;
+
+//offsets from the TCO base
+const TCO_RLD: u16 = 0x00;
+const TCO1_STS: u16 = 0x04;
+const TCO2_STS: u16 = 0x06;
+const TCO1_CNT: u16 = 0x08;
+const TCO_TMR: u16 = 0x12;
+
+const TCO1_STS_TIMEOUT: u16 = 1 << 3;
+const TCO2_STS_SECOND_TO: u16 = 1 << 1;
+const TCO1_CNT_TMR_HLT: u16 = 1 << 11;
+
+//the timer counts down in 0.6s ticks, and has to expire twice before the machine is reset
+const TICK_TENTHS: u32 = 6;
+const MIN_TICKS: u32 = 0x04;
+const MAX_TICKS: u32 = 0x3FF;
+
+pub struct Itco {
+    pm_base: u16,
+    tco_base: u16,
+    rcba: usize,
+    reset_reason: ResetReason,
+}
+
+impl Itco {
+    /// Looks for the ICH9 LPC bridge and sets up its TCO timer, stopped
+    pub fn probe() -> Option<Self> {
+        let lpc: PciAddress = pci::find_device(VENDOR_INTEL, DEVICE_ICH9_LPC)?;
+
+        if lpc.read8(LPC_ACPI_CNTL) & ACPI_CNTL_ACPI_EN == 0 {
+            return None;
+        }
+
+        let pm_base = (lpc.read32(LPC_PMBASE) & 0xFF80) as u16;
+        let rcba_reg = lpc.read32(LPC_RCBA);
+        if pm_base == 0 || rcba_reg & RCBA_ENABLE == 0 {
+            return None;
+        }
+
+        let mut wdt = Itco {
+            pm_base,
+            tco_base: pm_base + PM_TCO_BASE,
+            rcba: (rcba_reg & !0x3FFF) as usize,
+            reset_reason: ResetReason::Other,
+        };
+
+        //the second timeout status survives the reset, so it says whether the watchdog caused it
+        if wdt.read16(TCO2_STS) & TCO2_STS_SECOND_TO != 0 {
+            wdt.reset_reason = ResetReason::Watchdog;
+        }
+
+        //the first timeout should not raise an SMI for the firmware to handle
+        unsafe {
+            let mut smi_en = Port::<u32>::new(wdt.pm_base + PM_SMI_EN);
+            let value = smi_en.read();
+            smi_en.write(value & !SMI_EN_TCO_EN);
+        }
+
+        //stop the timer and clear the status bits, which are write-1-to-clear
+        wdt.halt();
+        wdt.write16(TCO1_STS, TCO1_STS_TIMEOUT);
+        wdt.write16(TCO2_STS, TCO2_STS_SECOND_TO);
+
+        Some(wdt)
+    }
+
+    fn read16(&self, reg: u16) -> u16 {
+        unsafe { Port::<u16>::new(self.tco_base + reg).read() }
+    }
+
+    fn write16(&mut self, reg: u16, value: u16) {
+        unsafe { Port::<u16>::new(self.tco_base + reg).write(value) }
+    }
+
+    fn gcs(&self) -> *mut u32 {
+        (self.rcba + RCBA_GCS) as *mut u32
+    }
+
+    /// Sets or clears the NO_REBOOT bit. while it is set, the second timeout does nothing.
+    fn set_no_reboot(&mut self, no_reboot: bool) -> Result<(), WatchdogError> {
+        unsafe {
+            let gcs = core::ptr::read_volatile(self.gcs());
+            let value = if no_reboot { gcs | GCS_NO_REBOOT } else { gcs & !GCS_NO_REBOOT };
+            core::ptr::write_volatile(self.gcs(), value);
+
+            //the bit can be locked by the firmware, so check that it actually changed
+            if core::ptr::read_volatile(self.gcs()) != value {
+                return Err(WatchdogError::Locked);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn halt(&mut self) {
+        let cnt = self.read16(TCO1_CNT);
+        self.write16(TCO1_CNT, cnt | TCO1_CNT_TMR_HLT);
+    }
+}
+
+impl Watchdog for Itco {
+    fn name(&self) -> &'static str {
+        "iTCO"
+    }
+
+    fn set_timeout(&mut self, secs: u32) -> Result<u32, WatchdogError> {
+        //convert to ticks, and halve it because the timer has to expire twice. anything big
+        //enough to overflow here is far beyond MAX_TICKS anyway.
+        let tenths = secs.checked_mul(10).ok_or(WatchdogError::InvalidTimeout)?;
+        let ticks = (tenths / TICK_TENTHS) / 2;
+        if !(MIN_TICKS..=MAX_TICKS).contains(&ticks) {
+            return Err(WatchdogError::InvalidTimeout);
+        }
+
+        let tmr = self.read16(TCO_TMR);
+        self.write16(TCO_TMR, (tmr & !(MAX_TICKS as u16)) | ticks as u16);
+        self.pet();
+
+        Ok(ticks * 2 * TICK_TENTHS / 10)
+    }
+
+    fn start(&mut self) {
+        //if the firmware locked NO_REBOOT on, the timer still runs but can't reset the machine
+        let _ = self.set_no_reboot(false);
+
+        self.pet();
+        let cnt = self.read16(TCO1_CNT);
+        self.write16(TCO1_CNT, cnt & !TCO1_CNT_TMR_HLT);
+    }
+
+    fn stop(&mut self) -> Result<(), WatchdogError> {
+        self.halt();
+        if self.read16(TCO1_CNT) & TCO1_CNT_TMR_HLT == 0 {
+            return Err(WatchdogError::Locked);
+        }
+
+        self.set_no_reboot(true)
+    }
+
+    fn pet(&mut self) {
+        //any write reloads the timer
+        self.write16(TCO_RLD, 0x01);
+
+        //a first timeout that was never cleared makes the next one reset the machine right away
+        self.write16(TCO1_STS, TCO1_STS_TIMEOUT);
+    }
+
+    fn reset_reason(&self) -> ResetReason {
+        self.reset_reason
+    }
+}
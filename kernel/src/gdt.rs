@@ -0,0 +1,185 @@
+use core::arch::asm;
+use spin::Once;
+
+use crate::interrupt::structures::DescriptorTablePointer;
+
+/*
+ * The kernel's own GDT and TSS. The firmware's GDT is still loaded when the kernel is entered,
+ * but its layout is not ours to rely on, so the kernel installs a table with known selectors.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/Global_Descriptor_Table
+ * https://wiki.osdev.org/Task_State_Segment
+ */
+
+/// A selector for a GDT entry: the entry index, plus the requested privilege level
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SegmentSelector(u16);
+
+impl SegmentSelector {
+    pub const fn new(index: u16, rpl: u16) -> Self {
+        SegmentSelector(index << 3 | (rpl & 0x3))
+    }
+
+    pub const fn bits(&self) -> u16 {
+        self.0
+    }
+}
+
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, 0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, 0);
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, 0);
+
+//flat segment descriptors. base and limit are ignored in long mode, apart from the flags. the
+//user segments are in data, code order so `sysret` can find them.
+const KERNEL_CODE_DESCRIPTOR: u64 = 0x00AF_9A00_0000_FFFF; //present, DPL 0, code, long mode
+const KERNEL_DATA_DESCRIPTOR: u64 = 0x00CF_9200_0000_FFFF; //present, DPL 0, writable data
+const USER_DATA_DESCRIPTOR: u64 = 0x00CF_F200_0000_FFFF; //present, DPL 3, writable data
+const USER_CODE_DESCRIPTOR: u64 = 0x00AF_FA00_0000_FFFF; //present, DPL 3, code, long mode
+
+/// The stacks in the interrupt stack table. The value is what goes in an `IDTEntry`'s ist field;
+/// the TSS slot is one lower, because 0 means "don't switch stacks".
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IstIndex {
+    DoubleFault = 1,
+    Nmi = 2,
+    MachineCheck = 3,
+}
+
+pub const IST_COUNT: usize = 3;
+pub const IST_STACK_SIZE: usize = 16 * 1024;
+pub const GUARD_PAGE_SIZE: usize = 4096;
+
+/// The 64-bit task state segment. The kernel only uses it for the interrupt stack table.
+#[repr(C, packed(4))]
+pub struct TaskStateSegment {
+    reserved_1: u32,
+    pub privilege_stack_table: [u64; 3],
+    reserved_2: u64,
+    pub interrupt_stack_table: [u64; 7],
+    reserved_3: u64,
+    reserved_4: u16,
+    pub iomap_base: u16,
+}
+
+/// One IST stack, with a guard page below it so an overflow faults instead of running into
+/// whatever is next in memory. The guard page has to be unmapped for that to happen.
+#[repr(C, align(4096))]
+struct GuardedStack {
+    guard: [u8; GUARD_PAGE_SIZE],
+    stack: [u8; IST_STACK_SIZE],
+}
+
+static mut IST_STACKS: [GuardedStack; IST_COUNT] = [const {
+    GuardedStack {
+        guard: [0; GUARD_PAGE_SIZE],
+        stack: [0; IST_STACK_SIZE],
+    }
+}; IST_COUNT];
+
+#[repr(C)]
+struct Gdt {
+    entries: [u64; 7],
+}
+
+static TSS: Once<TaskStateSegment> = Once::new();
+static GDT: Once<Gdt> = Once::new();
+
+/// Returns the address of the guard page below the stack for `index`
+pub fn guard_page(index: IstIndex) -> usize {
+    let stacks = &raw const IST_STACKS;
+    unsafe { (&raw const (*stacks)[index as usize - 1].guard) as usize }
+}
+
+//returns the top of the stack for `index`. stacks grow down, so this is the end of the array.
+fn stack_top(index: IstIndex) -> u64 {
+    let stacks = &raw const IST_STACKS;
+    let stack = unsafe { &raw const (*stacks)[index as usize - 1].stack };
+    stack as u64 + IST_STACK_SIZE as u64
+}
+
+//builds the two descriptor words of the TSS's system segment descriptor
+fn tss_descriptor(tss: &'static TaskStateSegment) -> (u64, u64) {
+    let base = tss as *const _ as u64;
+    let limit = (core::mem::size_of::<TaskStateSegment>() - 1) as u64;
+
+    let low = (limit & 0xFFFF)
+        | (base & 0xFF_FFFF) << 16
+        | 0x89 << 40 //present, DPL 0, available 64-bit TSS
+        | (limit >> 16 & 0xF) << 48
+        | (base >> 24 & 0xFF) << 56;
+    let high = base >> 32;
+
+    (low, high)
+}
+
+/// Builds the GDT and TSS, loads them, and reloads every segment register with the new selectors
+pub fn init() {
+    let tss = TSS.call_once(|| {
+        let mut tss = TaskStateSegment {
+            reserved_1: 0,
+            privilege_stack_table: [0; 3],
+            reserved_2: 0,
+            interrupt_stack_table: [0; 7],
+            reserved_3: 0,
+            reserved_4: 0,
+            //an offset past the end of the TSS means there is no I/O permission bitmap
+            iomap_base: core::mem::size_of::<TaskStateSegment>() as u16,
+        };
+
+        for index in [IstIndex::DoubleFault, IstIndex::Nmi, IstIndex::MachineCheck] {
+            tss.interrupt_stack_table[index as usize - 1] = stack_top(index);
+        }
+
+        tss
+    });
+
+    let gdt = GDT.call_once(|| {
+        let (tss_low, tss_high) = tss_descriptor(tss);
+
+        Gdt {
+            entries: [
+                0, //the null descriptor
+                KERNEL_CODE_DESCRIPTOR,
+                KERNEL_DATA_DESCRIPTOR,
+                USER_DATA_DESCRIPTOR,
+                USER_CODE_DESCRIPTOR,
+                tss_low,
+                tss_high,
+            ],
+        }
+    });
+
+    let pointer = DescriptorTablePointer {
+        limit: (core::mem::size_of::<Gdt>() - 1) as u16,
+        base: gdt as *const _ as u64,
+    };
+
+    unsafe {
+        asm!(
+            "lgdt [{ptr}]",
+
+            //cs can't be moved into, so it's loaded with a far return to the next instruction
+            "push {code}",
+            "lea {tmp}, [rip + 2f]",
+            "push {tmp}",
+            "retfq",
+            "2:",
+
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "mov ss, {data:x}",
+            "xor {tmp:e}, {tmp:e}",
+            "mov fs, {tmp:x}",
+            "mov gs, {tmp:x}",
+
+            "ltr {tss:x}",
+            ptr = in(reg) &pointer,
+            code = in(reg) KERNEL_CODE_SELECTOR.bits() as u64,
+            data = in(reg) KERNEL_DATA_SELECTOR.bits() as u64,
+            tss = in(reg) TSS_SELECTOR.bits() as u64,
+            tmp = out(reg) _,
+        );
+    }
+}
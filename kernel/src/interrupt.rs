@@ -0,0 +1,12 @@
+pub mod apic;
+pub mod entry;
+pub mod exceptions;
+#[allow(clippy::module_inception)]
+pub mod interrupt;
+pub mod ioapic;
+pub mod irq;
+pub mod manager;
+pub mod pic;
+pub mod structures;
+
+pub use interrupt::{disable_interrupts, enable_interrupts};
@@ -0,0 +1,288 @@
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+use super::entry::InterruptContext;
+use super::irq::{Polarity, Trigger};
+use super::manager::{self, InterruptController, InterruptError, SPURIOUS_VECTOR};
+use crate::acpi::madt::{Madt, ALL_PROCESSORS};
+use crate::memory::paging::{self, CacheMode, PagingError};
+
+/*
+ * Driver for the local APIC, in x2APIC mode when the CPU supports it and xAPIC mode otherwise.
+ * In xAPIC mode the registers are memory mapped at the base in IA32_APIC_BASE; in x2APIC mode
+ * the same registers are MSRs starting at 0x800, one for every 16 bytes of the xAPIC page.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/APIC
+ * Intel SDM Vol. 3A, 11 "Advanced Programmable Interrupt Controller (APIC)"
+ */
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+//the xAPIC registers fill one page
+const XAPIC_REGISTERS_SIZE: u64 = 0x1000;
+
+//register offsets into the xAPIC page
+const REG_ID: u32 = 0x20;
+const REG_VERSION: u32 = 0x30;
+const REG_TPR: u32 = 0x80;
+const REG_EOI: u32 = 0xB0;
+const REG_SVR: u32 = 0xF0;
+const REG_ESR: u32 = 0x280;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_LVT_LINT0: u32 = 0x350;
+const REG_LVT_LINT1: u32 = 0x360;
+const REG_LVT_ERROR: u32 = 0x370;
+const REG_TIMER_INITIAL: u32 = 0x380;
+const REG_TIMER_CURRENT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_LEVEL: u32 = 1 << 15;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+//the timer counts the bus clock divided by 16
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+//memory writes to this window are delivered to a local APIC as interrupts
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+/// The vector the local APIC reports its own errors on
+pub const ERROR_VECTOR: u8 = 0xFE;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ApicError {
+    /// CPUID says there is no local APIC
+    NotPresent,
+
+    /// The xAPIC registers couldn't be mapped
+    Map(PagingError),
+}
+
+/// How the LVT timer counts
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot = 0b00 << 17,
+    Periodic = 0b01 << 17,
+}
+
+enum Mode {
+    XApic(usize),
+    X2Apic,
+}
+
+struct LocalApic {
+    mode: Mode,
+}
+
+impl LocalApic {
+    fn read(&self, reg: u32) -> u32 {
+        match self.mode {
+            Mode::XApic(base) => unsafe { ((base + reg as usize) as *const u32).read_volatile() },
+            Mode::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + (reg >> 4)).read() as u32 },
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        match self.mode {
+            Mode::XApic(base) => unsafe { ((base + reg as usize) as *mut u32).write_volatile(value) },
+            Mode::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + (reg >> 4)).write(value as u64) },
+        }
+    }
+
+    fn id(&self) -> u32 {
+        match self.mode {
+            Mode::XApic(_) => self.read(REG_ID) >> 24,
+            Mode::X2Apic => self.read(REG_ID),
+        }
+    }
+}
+
+static LAPIC: Once<LocalApic> = Once::new();
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+struct ApicController;
+
+static APIC_CONTROLLER: ApicController = ApicController;
+
+impl InterruptController for ApicController {
+    fn eoi(&self, _vector: u8) {
+        eoi();
+    }
+}
+
+fn lvt_flags(polarity: Polarity, trigger: Trigger) -> u32 {
+    let mut flags = 0;
+    if polarity == Polarity::ActiveLow {
+        flags |= LVT_ACTIVE_LOW;
+    }
+    if trigger == Trigger::Level {
+        flags |= LVT_LEVEL;
+    }
+    flags
+}
+
+fn error_handler(_ctx: &InterruptContext) {
+    if let Some(lapic) = LAPIC.get() {
+        //the ESR only latches new errors after a write
+        lapic.write(REG_ESR, 0);
+        let _ = lapic.read(REG_ESR);
+    }
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Whether the CPU has a local APIC, and whether it can run in x2APIC mode
+pub fn detect() -> (bool, bool) {
+    let features = __cpuid(1);
+    (features.edx & (1 << 9) != 0, features.ecx & (1 << 21) != 0)
+}
+
+/// Enables the local APIC of the current CPU, wires its LINT pins from the MADT, and makes it
+/// the interrupt controller. The PIC has to be disabled first, LINT0 is left masked.
+pub fn init(madt: &Madt) -> Result<(), ApicError> {
+    let (present, x2apic) = detect();
+    if !present {
+        return Err(ApicError::NotPresent);
+    }
+
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { base_msr.read() };
+
+    //x2APIC mode reaches the registers through MSRs, so only xAPIC mode needs them mapped
+    let registers = if x2apic {
+        None
+    } else {
+        let phys = PhysAddr::new(base & APIC_BASE_ADDRESS_MASK);
+        Some(paging::map_mmio(phys, XAPIC_REGISTERS_SIZE, CacheMode::Uncached).map_err(ApicError::Map)?)
+    };
+
+    let lapic = LAPIC.call_once(|| {
+        if let Some(registers) = registers {
+            unsafe { base_msr.write(base | APIC_BASE_ENABLE) };
+            LocalApic { mode: Mode::XApic(registers.as_u64() as usize) }
+        } else {
+            //x2APIC mode can only be entered from xAPIC mode, not straight from disabled
+            unsafe {
+                base_msr.write(base | APIC_BASE_ENABLE);
+                base_msr.write(base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+            }
+            LocalApic { mode: Mode::X2Apic }
+        }
+    });
+
+    let _ = manager::reserve_vector(ERROR_VECTOR);
+    let _ = manager::register_handler(ERROR_VECTOR, error_handler);
+
+    //accept every priority, and send spurious interrupts to a vector that is never acknowledged
+    lapic.write(REG_TPR, 0);
+    lapic.write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+    lapic.write(REG_LVT_TIMER, LVT_MASKED);
+    lapic.write(REG_LVT_LINT0, LVT_MASKED);
+    lapic.write(REG_LVT_LINT1, LVT_MASKED);
+    lapic.write(REG_LVT_ERROR, ERROR_VECTOR as u32);
+    lapic.write(REG_ESR, 0);
+    lapic.write(REG_ESR, 0);
+
+    //wire the NMI pins for this CPU, or for every CPU
+    let id = lapic.id();
+    let uid = madt.cpus().iter().find(|cpu| cpu.apic_id == id).map(|cpu| cpu.processor_uid);
+    for nmi in madt.nmis() {
+        if nmi.processor_uid != ALL_PROCESSORS && Some(nmi.processor_uid) != uid {
+            continue;
+        }
+
+        let reg = match nmi.lint {
+            0 => REG_LVT_LINT0,
+            1 => REG_LVT_LINT1,
+            _ => continue,
+        };
+        lapic.write(reg, LVT_DELIVERY_NMI | lvt_flags(nmi.polarity, nmi.trigger));
+    }
+
+    //nothing can be pending at the APIC yet, but clear anything the firmware left in service
+    eoi();
+    manager::set_controller(Some(&APIC_CONTROLLER));
+    Ok(())
+}
+
+/// Whether `init` has enabled the local APIC
+pub fn is_enabled() -> bool {
+    LAPIC.get().is_some()
+}
+
+/// Whether the local APIC is in x2APIC mode
+pub fn is_x2apic() -> bool {
+    matches!(LAPIC.get(), Some(LocalApic { mode: Mode::X2Apic }))
+}
+
+/// The APIC ID of the current CPU
+pub fn id() -> Option<u32> {
+    LAPIC.get().map(|lapic| lapic.id())
+}
+
+/// The version register, with the number of LVT entries in bits 16-23
+pub fn version() -> Option<u32> {
+    LAPIC.get().map(|lapic| lapic.read(REG_VERSION))
+}
+
+/// Signals the end of the interrupt currently being serviced
+pub fn eoi() {
+    if let Some(lapic) = LAPIC.get() {
+        lapic.write(REG_EOI, 0);
+    }
+}
+
+/// The number of errors the local APIC has reported
+pub fn error_count() -> u64 {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+/// The address and data of a message signalled interrupt that delivers `vector` to the CPU with
+/// APIC ID `destination`, as a fixed, edge triggered interrupt. See Intel SDM Vol. 3A, 11.11
+/// "Message Signalled Interrupts".
+pub fn msi_message(destination: u32, vector: u8) -> Result<(u64, u32), InterruptError> {
+    if !is_enabled() {
+        return Err(InterruptError::NoController);
+    }
+
+    //the destination field is 8 bits, higher x2APIC IDs would need interrupt remapping
+    if destination > 0xFF {
+        return Err(InterruptError::InvalidDestination);
+    }
+
+    Ok((MSI_ADDRESS_BASE | (destination as u64) << 12, vector as u32))
+}
+
+/// Programs the LVT timer to fire `vector`. `initial` is the count in bus clocks divided by 16.
+pub fn set_timer(vector: u8, mode: TimerMode, initial: u32) -> Result<(), InterruptError> {
+    let lapic = LAPIC.get().ok_or(InterruptError::NoController)?;
+    lapic.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic.write(REG_LVT_TIMER, mode as u32 | vector as u32);
+    lapic.write(REG_TIMER_INITIAL, initial);
+    Ok(())
+}
+
+/// Masks the LVT timer and stops the count
+pub fn stop_timer() {
+    if let Some(lapic) = LAPIC.get() {
+        lapic.write(REG_LVT_TIMER, LVT_MASKED);
+        lapic.write(REG_TIMER_INITIAL, 0);
+    }
+}
+
+/// The timer's current count, which counts down from the initial count
+pub fn timer_count() -> u32 {
+    LAPIC.get().map_or(0, |lapic| lapic.read(REG_TIMER_CURRENT))
+}
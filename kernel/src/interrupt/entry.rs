@@ -0,0 +1,127 @@
+use core::arch::global_asm;
+
+use super::{exceptions, manager};
+use super::structures::InterruptStackFrame;
+
+/*
+ * The entry stubs for all 256 vectors. Each stub pushes its vector number (and a dummy error
+ * code for vectors where the CPU doesn't push one), then jumps to a common routine that saves
+ * every general purpose register and calls `interrupt_dispatch` with an `InterruptContext`.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/Interrupt_Service_Routines
+ * Intel SDM Vol. 3A, 6.13 "Error Code"
+ */
+
+//every stub is padded to this many bytes, so the stub for a vector is at a fixed offset
+pub const STUB_SIZE: usize = 16;
+
+global_asm!(r#"
+.section .text.interrupt_stubs, "ax"
+.global interrupt_stubs
+.p2align 4
+interrupt_stubs:
+.set vector, 0
+.rept 256
+    .p2align 4
+    .if (vector == 8) || (vector == 10) || (vector == 11) || (vector == 12) || (vector == 13) || (vector == 14) || (vector == 17) || (vector == 21) || (vector == 29) || (vector == 30)
+        pushq $vector
+    .else
+        pushq $0
+        pushq $vector
+    .endif
+    jmp interrupt_common
+    .set vector, vector + 1
+.endr
+
+interrupt_common:
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    cld
+
+    movq %rsp, %rdi
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    call interrupt_dispatch
+    movq %rbx, %rsp
+
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+
+    //drop the vector number and error code
+    addq $16, %rsp
+    iretq
+"#, options(att_syntax));
+
+unsafe extern "C" {
+    static interrupt_stubs: u8;
+}
+
+/// Everything saved on the stack when an interrupt is taken, in the order the entry stub pushes
+/// it. Handlers can change the saved registers, and the changes take effect on return.
+#[repr(C)]
+#[derive(Debug)]
+pub struct InterruptContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+
+    /// The error code pushed by the CPU, or 0 for vectors that don't have one
+    pub error_code: u64,
+
+    pub frame: InterruptStackFrame,
+}
+
+/// Returns the address of the entry stub for a vector, for use in an `IDTEntry`
+pub fn stub_address(vector: u8) -> *const () {
+    let base = &raw const interrupt_stubs as usize;
+    (base + vector as usize * STUB_SIZE) as *const ()
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn interrupt_dispatch(ctx: &mut InterruptContext) {
+    if ctx.vector < 32 {
+        exceptions::handle(ctx);
+    } else {
+        manager::dispatch(ctx);
+    }
+}
@@ -0,0 +1,342 @@
+use core::arch::{asm, global_asm};
+use core::fmt::{self, Write};
+use spin::Mutex;
+
+use super::entry::InterruptContext;
+use crate::log_ring::LOG_RING;
+use crate::serial::{self, SerialPort};
+
+/*
+ * Handlers for the CPU exceptions, vectors 0-31.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/Exceptions
+ * Intel SDM Vol. 3A, 6.15 "Exception and Interrupt Reference"
+ */
+
+pub const DEBUG: u8 = 1;
+pub const NMI: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_SEGMENT_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const MACHINE_CHECK: u8 = 18;
+
+/// The name and mnemonic of each exception vector
+pub fn exception_name(vector: u8) -> &'static str {
+    match vector {
+        0 => "#DE divide error",
+        1 => "#DB debug",
+        2 => "NMI",
+        3 => "#BP breakpoint",
+        4 => "#OF overflow",
+        5 => "#BR bound range exceeded",
+        6 => "#UD invalid opcode",
+        7 => "#NM device not available",
+        8 => "#DF double fault",
+        9 => "coprocessor segment overrun",
+        10 => "#TS invalid TSS",
+        11 => "#NP segment not present",
+        12 => "#SS stack segment fault",
+        13 => "#GP general protection",
+        14 => "#PF page fault",
+        16 => "#MF x87 floating point",
+        17 => "#AC alignment check",
+        18 => "#MC machine check",
+        19 => "#XM SIMD floating point",
+        20 => "#VE virtualization",
+        21 => "#CP control protection",
+        28 => "#HV hypervisor injection",
+        29 => "#VC VMM communication",
+        30 => "#SX security",
+        _ => "reserved",
+    }
+}
+
+/// The error code pushed by #PF
+#[derive(Copy, Clone)]
+pub struct PageFaultErrorCode(pub u64);
+
+impl PageFaultErrorCode {
+    /// The page was present, and the fault was a protection violation
+    pub fn protection_violation(&self) -> bool { self.0 & (1 << 0) != 0 }
+    pub fn write(&self) -> bool { self.0 & (1 << 1) != 0 }
+    pub fn user(&self) -> bool { self.0 & (1 << 2) != 0 }
+    pub fn reserved_bit(&self) -> bool { self.0 & (1 << 3) != 0 }
+    pub fn instruction_fetch(&self) -> bool { self.0 & (1 << 4) != 0 }
+    pub fn protection_key(&self) -> bool { self.0 & (1 << 5) != 0 }
+    pub fn shadow_stack(&self) -> bool { self.0 & (1 << 6) != 0 }
+}
+
+impl fmt::Display for PageFaultErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} in {} mode",
+            if self.protection_violation() { "protection violation on" } else { "non-present page on" },
+            if self.instruction_fetch() { "instruction fetch" } else if self.write() { "write" } else { "read" },
+            if self.user() { "user" } else { "kernel" },
+        )?;
+
+        if self.reserved_bit() {
+            f.write_str(", reserved bit set in a page table entry")?;
+        }
+        if self.protection_key() {
+            f.write_str(", protection key violation")?;
+        }
+        if self.shadow_stack() {
+            f.write_str(", shadow stack access")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The selector error code pushed by #TS, #NP, #SS and #GP
+#[derive(Copy, Clone)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+    /// The exception was caused by something external to the program, like a hardware interrupt
+    pub fn external(&self) -> bool { self.0 & 1 != 0 }
+
+    /// The index of the descriptor in its table
+    pub fn index(&self) -> u64 { (self.0 >> 3) & 0x1FFF }
+
+    pub fn table(&self) -> &'static str {
+        match (self.0 >> 1) & 0x3 {
+            0 => "GDT",
+            2 => "LDT",
+            _ => "IDT",
+        }
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("not selector related");
+        }
+
+        write!(f, "{} index {} (selector {:#x}){}",
+            self.table(), self.index(), self.0 & !0x7,
+            if self.external() { ", external event" } else { "" }
+        )
+    }
+}
+
+/// The handler for each exception, by the kind of error code the CPU pushes for it
+#[derive(Copy, Clone)]
+enum ExceptionHandler {
+    /// No error code. The handler returns if the interrupted code can continue.
+    Plain(fn(&mut InterruptContext)),
+
+    /// A selector error code
+    Selector(fn(&mut InterruptContext, SelectorErrorCode)),
+
+    /// The page fault error code, plus the faulting address from CR2
+    PageFault(fn(&mut InterruptContext, PageFaultErrorCode, u64)),
+
+    /// An exception the machine can't recover from
+    Diverging(fn(&mut InterruptContext) -> !),
+}
+
+const HANDLERS: [ExceptionHandler; 32] = {
+    let mut handlers = [ExceptionHandler::Diverging(fatal); 32];
+    handlers[DEBUG as usize] = ExceptionHandler::Plain(debug);
+    handlers[NMI as usize] = ExceptionHandler::Plain(nmi);
+    handlers[BREAKPOINT as usize] = ExceptionHandler::Plain(breakpoint);
+    handlers[INVALID_TSS as usize] = ExceptionHandler::Selector(selector_fault);
+    handlers[SEGMENT_NOT_PRESENT as usize] = ExceptionHandler::Selector(selector_fault);
+    handlers[STACK_SEGMENT_FAULT as usize] = ExceptionHandler::Selector(selector_fault);
+    handlers[GENERAL_PROTECTION as usize] = ExceptionHandler::Selector(selector_fault);
+    handlers[PAGE_FAULT as usize] = ExceptionHandler::PageFault(page_fault);
+    handlers[DOUBLE_FAULT as usize] = ExceptionHandler::Diverging(double_fault);
+    handlers[MACHINE_CHECK as usize] = ExceptionHandler::Diverging(machine_check);
+    handlers
+};
+
+/// An instruction that is expected to fault, and where to resume when it does
+#[derive(Copy, Clone)]
+pub struct Fixup {
+    pub fault_rip: u64,
+    pub resume_rip: u64,
+}
+
+const MAX_FIXUPS: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegisterError {
+    /// Every slot for fixups is taken
+    Full,
+}
+
+static FIXUPS: Mutex<[Option<Fixup>; MAX_FIXUPS]> = Mutex::new([None; MAX_FIXUPS]);
+
+/// Registers an instruction that is allowed to fault. When it does, execution continues at
+/// `fixup.resume_rip` instead.
+pub fn register_fixup(fixup: Fixup) -> Result<(), RegisterError> {
+    let mut fixups = FIXUPS.lock();
+    let slot = fixups.iter_mut().find(|slot| slot.is_none()).ok_or(RegisterError::Full)?;
+    *slot = Some(fixup);
+    Ok(())
+}
+
+//the lookup uses try_lock, as the exception could have interrupted a registration
+fn try_recover(ctx: &mut InterruptContext) -> bool {
+    let Some(fixups) = FIXUPS.try_lock() else {
+        return false;
+    };
+
+    let rip = ctx.frame.rip;
+    match fixups.iter().flatten().find(|fixup| fixup.fault_rip == rip) {
+        Some(fixup) => {
+            ctx.frame.rip = fixup.resume_rip;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Called from the interrupt entry stub for vectors 0-31
+pub fn handle(ctx: &mut InterruptContext) {
+    //machine checks and double faults can't be recovered from, whatever a fixup says
+    let vector = ctx.vector as u8;
+    if vector != DOUBLE_FAULT && vector != MACHINE_CHECK && try_recover(ctx) {
+        return;
+    }
+
+    match HANDLERS[vector as usize] {
+        ExceptionHandler::Plain(handler) => handler(ctx),
+        ExceptionHandler::Selector(handler) => handler(ctx, SelectorErrorCode(ctx.error_code)),
+        ExceptionHandler::PageFault(handler) => handler(ctx, PageFaultErrorCode(ctx.error_code), read_cr2()),
+        ExceptionHandler::Diverging(handler) => handler(ctx),
+    }
+}
+
+fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe { asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags)) };
+    cr2
+}
+
+/// Writes a message to the serial port, and to the log ring if it isn't busy. This doesn't wait
+/// on any lock, so it is safe in any exception.
+fn emit(f: impl Fn(&mut dyn Write) -> fmt::Result) {
+    let mut port = SerialPort::new(serial::COM1);
+    let _ = f(&mut port);
+
+    if let Some(mut ring) = LOG_RING.try_lock() {
+        let _ = f(&mut *ring);
+    }
+}
+
+/// Writes every saved register, plus the control registers
+pub fn dump_context(out: &mut dyn Write, ctx: &InterruptContext) -> fmt::Result {
+    let (cr0, cr3, cr4): (u64, u64, u64);
+    unsafe {
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+    }
+    let frame = &ctx.frame;
+
+    writeln!(out, "RIP {:#018x} CS  {:#06x} RFLAGS {:#018x}", frame.rip, frame.cs, frame.rflags)?;
+    writeln!(out, "RSP {:#018x} SS  {:#06x} ERROR  {:#018x}", frame.rsp, frame.ss, ctx.error_code)?;
+    writeln!(out, "RAX {:#018x} RBX {:#018x} RCX {:#018x} RDX {:#018x}", ctx.rax, ctx.rbx, ctx.rcx, ctx.rdx)?;
+    writeln!(out, "RSI {:#018x} RDI {:#018x} RBP {:#018x}", ctx.rsi, ctx.rdi, ctx.rbp)?;
+    writeln!(out, "R8  {:#018x} R9  {:#018x} R10 {:#018x} R11 {:#018x}", ctx.r8, ctx.r9, ctx.r10, ctx.r11)?;
+    writeln!(out, "R12 {:#018x} R13 {:#018x} R14 {:#018x} R15 {:#018x}", ctx.r12, ctx.r13, ctx.r14, ctx.r15)?;
+    writeln!(out, "CR0 {:#018x} CR2 {:#018x} CR3 {:#018x} CR4 {:#018x}", cr0, read_cr2(), cr3, cr4)
+}
+
+fn report(ctx: &InterruptContext, detail: fmt::Arguments) {
+    let vector = ctx.vector as u8;
+    emit(|out| {
+        writeln!(out, "EXCEPTION {} ({}): {}", vector, exception_name(vector), detail)?;
+        dump_context(out, ctx)
+    });
+}
+
+fn fatal(ctx: &mut InterruptContext) -> ! {
+    report(ctx, format_args!("error code {:#x}", ctx.error_code));
+    panic!("unhandled {} at {:#x}", exception_name(ctx.vector as u8), ctx.frame.rip);
+}
+
+fn debug(ctx: &mut InterruptContext) {
+    report(ctx, format_args!("continuing"));
+}
+
+fn nmi(ctx: &mut InterruptContext) {
+    report(ctx, format_args!("continuing"));
+}
+
+fn breakpoint(ctx: &mut InterruptContext) {
+    //int3 has already moved rip past itself, so returning continues after the breakpoint
+    report(ctx, format_args!("continuing"));
+}
+
+fn selector_fault(ctx: &mut InterruptContext, error: SelectorErrorCode) {
+    report(ctx, format_args!("{}", error));
+    panic!("unhandled {} at {:#x}: {}", exception_name(ctx.vector as u8), ctx.frame.rip, error);
+}
+
+fn page_fault(ctx: &mut InterruptContext, error: PageFaultErrorCode, address: u64) {
+    report(ctx, format_args!("{} at address {:#x}", error, address));
+    panic!("page fault at {:#x} accessing {:#x}: {}", ctx.frame.rip, address, error);
+}
+
+fn double_fault(ctx: &mut InterruptContext) -> ! {
+    report(ctx, format_args!("running on the double fault stack"));
+    panic!("double fault at {:#x}", ctx.frame.rip);
+}
+
+fn machine_check(ctx: &mut InterruptContext) -> ! {
+    report(ctx, format_args!("hardware error"));
+    panic!("machine check at {:#x}", ctx.frame.rip);
+}
+
+/*
+ * A 32-bit read that survives a fault, for probing MMIO that might not be there. The load
+ * instruction is registered as a fixup, so a #PF or #GP on it resumes at the fault label
+ * with the high half of rax set.
+ */
+global_asm!(r#"
+.section .text.probe_read_u32, "ax"
+.global probe_read_u32_asm
+.global probe_read_u32_load
+.global probe_read_u32_fault
+probe_read_u32_asm:
+probe_read_u32_load:
+    movl (%rdi), %eax
+    ret
+probe_read_u32_fault:
+    movabsq $0x100000000, %rax
+    ret
+"#, options(att_syntax));
+
+unsafe extern "sysv64" {
+    fn probe_read_u32_asm(addr: usize) -> u64;
+    static probe_read_u32_load: u8;
+    static probe_read_u32_fault: u8;
+}
+
+/// Reads a u32 from `addr`, or returns None if the read faulted
+pub fn probe_read_u32(addr: usize) -> Option<u32> {
+    let result = unsafe { probe_read_u32_asm(addr) };
+
+    if result >> 32 != 0 {
+        None
+    } else {
+        Some(result as u32)
+    }
+}
+
+/// Registers the kernel's own fixups. This has to run before `probe_read_u32` is used.
+pub fn init() {
+    let _ = register_fixup(Fixup {
+        fault_rip: &raw const probe_read_u32_load as u64,
+        resume_rip: &raw const probe_read_u32_fault as u64,
+    });
+}
@@ -1,3 +1,5 @@
+use core::arch::asm;
+
 pub fn enable_interrupts() {
     unsafe {
         //sets the interrupt flag
@@ -11,3 +13,28 @@ pub fn disable_interrupts() {
         asm!("cli")
     }
 }
+
+pub fn interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        //the interrupt flag is bit 9 of rflags
+        asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+    rflags & (1 << 9) != 0
+}
+
+/// Runs `f` with interrupts disabled, then restores the interrupt flag to what it was before.
+/// Anything that takes a lock an interrupt handler also takes has to be wrapped in this.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = interrupts_enabled();
+    if enabled {
+        disable_interrupts();
+    }
+
+    let result = f();
+
+    if enabled {
+        enable_interrupts();
+    }
+    result
+}
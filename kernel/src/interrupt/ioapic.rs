@@ -0,0 +1,149 @@
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use super::interrupt::without_interrupts;
+use super::irq::{Polarity, Trigger};
+use super::manager::InterruptError;
+use crate::acpi::madt::{IoApicEntry, Madt, MAX_IO_APICS};
+use crate::memory::paging::{self, CacheMode, PagingError};
+
+/*
+ * Driver for the I/O APICs listed in the MADT. Each one handles a range of global system
+ * interrupts (GSIs) starting at its GSI base, with a redirection entry per input that says
+ * which vector and CPU the input is delivered to.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/IOAPIC
+ * Intel 82093AA I/O APIC datasheet, 3.2 "IOAPIC Registers"
+ */
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+//the bytes of registers mapped, up to the end of IOWIN
+const REGISTERS_SIZE: u64 = 0x14;
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+#[derive(Copy, Clone)]
+struct IoApic {
+    base: usize,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApic {
+    //the register window is two registers wide, so every access has to hold the lock
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ((self.base + IOREGSEL) as *mut u32).write_volatile(reg);
+            ((self.base + IOWIN) as *const u32).read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ((self.base + IOREGSEL) as *mut u32).write_volatile(reg);
+            ((self.base + IOWIN) as *mut u32).write_volatile(value);
+        }
+    }
+
+    fn read_entry(&self, input: u32) -> u64 {
+        let reg = REG_REDIRECTION + input * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    fn write_entry(&self, input: u32, entry: u64) {
+        let reg = REG_REDIRECTION + input * 2;
+
+        //write the low half last, so the mask bit only clears once the destination is set
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.inputs
+    }
+}
+
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> = Mutex::new([None; MAX_IO_APICS]);
+
+//run `f` on the I/O APIC that handles `gsi`, and the input the GSI is on
+fn with_input<T>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> T) -> Result<T, InterruptError> {
+    without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        let io_apic = io_apics
+            .iter()
+            .flatten()
+            .find(|io_apic| io_apic.handles(gsi))
+            .ok_or(InterruptError::UnknownIrq)?;
+
+        Ok(f(io_apic, gsi - io_apic.gsi_base))
+    })
+}
+
+/// Maps every I/O APIC in the MADT, and masks all of their inputs. Returns how many there are.
+pub fn init(madt: &Madt) -> Result<usize, PagingError> {
+    without_interrupts(|| {
+        let mut io_apics = IO_APICS.lock();
+        let mut count = 0;
+
+        for (slot, &IoApicEntry { address, gsi_base, .. }) in io_apics.iter_mut().zip(madt.io_apics()) {
+            let base = paging::map_mmio(PhysAddr::new(address as u64), REGISTERS_SIZE, CacheMode::Uncached)?;
+            let mut io_apic = IoApic { base: base.as_u64() as usize, gsi_base, inputs: 0 };
+            io_apic.inputs = ((io_apic.read(REG_VERSION) >> 16) & 0xFF) + 1;
+
+            for input in 0..io_apic.inputs {
+                io_apic.write_entry(input, ENTRY_MASKED);
+            }
+
+            *slot = Some(io_apic);
+            count += 1;
+        }
+
+        Ok(count)
+    })
+}
+
+/// Whether some I/O APIC handles `gsi`
+pub fn handles(gsi: u32) -> bool {
+    with_input(gsi, |_, _| ()).is_ok()
+}
+
+/// Delivers `gsi` as `vector` to the CPU with APIC ID `destination`. The input stays masked
+/// until `unmask` is called.
+pub fn route(gsi: u32, vector: u8, polarity: Polarity, trigger: Trigger, destination: u32) -> Result<(), InterruptError> {
+    let mut entry = ENTRY_MASKED | vector as u64 | (destination as u64 & 0xFF) << 56;
+    if polarity == Polarity::ActiveLow {
+        entry |= ENTRY_ACTIVE_LOW;
+    }
+    if trigger == Trigger::Level {
+        entry |= ENTRY_LEVEL;
+    }
+
+    with_input(gsi, |io_apic, input| io_apic.write_entry(input, entry))
+}
+
+/// Stops `gsi` from being delivered
+pub fn mask(gsi: u32) -> Result<(), InterruptError> {
+    with_input(gsi, |io_apic, input| {
+        io_apic.write_entry(input, io_apic.read_entry(input) | ENTRY_MASKED)
+    })
+}
+
+/// Lets `gsi` be delivered
+pub fn unmask(gsi: u32) -> Result<(), InterruptError> {
+    with_input(gsi, |io_apic, input| {
+        io_apic.write_entry(input, io_apic.read_entry(input) & !ENTRY_MASKED)
+    })
+}
+
+/// Masks `gsi` and clears its redirection entry
+pub fn unroute(gsi: u32) -> Result<(), InterruptError> {
+    with_input(gsi, |io_apic, input| io_apic.write_entry(input, ENTRY_MASKED))
+}
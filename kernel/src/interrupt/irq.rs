@@ -0,0 +1,224 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use super::interrupt::without_interrupts;
+use super::manager::{self, InterruptError, InterruptHandler};
+use super::{apic, ioapic, pic};
+use crate::acpi::{self, AcpiError};
+use crate::memory::paging::PagingError;
+
+/*
+ * IRQ routing. Drivers ask for an interrupt line here, and get a vector with their handler
+ * registered on it, whichever controller ends up delivering it. The I/O APICs are used when the
+ * MADT lists any, and the PIC is kept otherwise.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/IOAPIC
+ * https://wiki.osdev.org/MADT#Entry_Type_2_:_IO.2FAPIC_Interrupt_Source_Override
+ */
+
+//at most one route per device vector
+const MAX_ROUTES: usize = 224;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// An interrupt line, by its global system interrupt (GSI) number. With the PIC, GSIs 0-15 are
+/// its IRQ lines.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Irq {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+impl Irq {
+    /// An ISA IRQ, like the PIT on 0 or COM1 on 4. The MADT's source overrides are applied when
+    /// the I/O APICs are in use.
+    pub fn isa(irq: u8) -> Self {
+        let source_override = match backend() {
+            Backend::Apic => acpi::madt::get().and_then(|madt| madt.source_override(irq)),
+            Backend::Pic => None,
+        };
+
+        match source_override {
+            Some(o) => Irq { gsi: o.gsi, polarity: o.polarity, trigger: o.trigger },
+            None => Irq { gsi: irq as u32, polarity: Polarity::ActiveHigh, trigger: Trigger::Edge },
+        }
+    }
+
+    /// A PCI INTx line, which is shared, level triggered and active low
+    pub fn pci(gsi: u32) -> Self {
+        Irq { gsi, polarity: Polarity::ActiveLow, trigger: Trigger::Level }
+    }
+}
+
+/// The controller that IRQs are routed through
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Pic,
+    Apic,
+}
+
+/// Why `init` stayed on the PIC
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IrqInitError {
+    Acpi(AcpiError),
+    Apic(apic::ApicError),
+
+    /// An I/O APIC's registers couldn't be mapped
+    IoApic(PagingError),
+
+    /// The MADT doesn't list any I/O APICs
+    NoIoApic,
+}
+
+#[derive(Copy, Clone)]
+struct Route {
+    gsi: u32,
+    vector: u8,
+}
+
+static APIC_ACTIVE: AtomicBool = AtomicBool::new(false);
+static ROUTES: Mutex<[Option<Route>; MAX_ROUTES]> = Mutex::new([None; MAX_ROUTES]);
+
+/// Moves interrupt delivery from the PIC to the local APIC and the I/O APICs in the MADT. On an
+/// error the PIC stays in charge. This has to run before any driver routes an IRQ.
+pub fn init() -> Result<Backend, IrqInitError> {
+    let madt = acpi::madt::init().map_err(IrqInitError::Acpi)?;
+    if madt.io_apics().is_empty() {
+        return Err(IrqInitError::NoIoApic);
+    }
+
+    without_interrupts(|| {
+        //the I/O APICs come first, so a failure leaves the PIC in charge with nothing changed.
+        //their inputs start out masked.
+        ioapic::init(madt).map_err(IrqInitError::IoApic)?;
+        apic::init(madt).map_err(IrqInitError::Apic)?;
+        //the 8259s only need masking if the MADT says they are there
+        if madt.pcat_compat {
+            pic::disable();
+        }
+        APIC_ACTIVE.store(true, Ordering::Release);
+        Ok(Backend::Apic)
+    })
+}
+
+/// The controller IRQs are currently routed through
+pub fn backend() -> Backend {
+    if APIC_ACTIVE.load(Ordering::Acquire) {
+        Backend::Apic
+    } else {
+        Backend::Pic
+    }
+}
+
+/// Routes `irq` to a vector and registers `handler` on it, then unmasks the line. Lines that
+/// are already routed are shared, and the existing vector is returned.
+pub fn route_irq(irq: Irq, handler: InterruptHandler) -> Result<u8, InterruptError> {
+    without_interrupts(|| {
+        let mut routes = ROUTES.lock();
+
+        if let Some(route) = routes.iter().flatten().find(|route| route.gsi == irq.gsi) {
+            manager::register_handler(route.vector, handler)?;
+            return Ok(route.vector);
+        }
+
+        let slot = routes
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(InterruptError::NoFreeVector)?;
+
+        let vector = match backend() {
+            Backend::Apic => {
+                if !ioapic::handles(irq.gsi) {
+                    return Err(InterruptError::UnknownIrq);
+                }
+                manager::allocate_vector()?
+            }
+            Backend::Pic => {
+                if irq.gsi >= pic::IRQ_COUNT as u32 {
+                    return Err(InterruptError::UnknownIrq);
+                }
+                pic::irq_vector(irq.gsi as u8)
+            }
+        };
+
+        let routed = manager::register_handler(vector, handler).and_then(|_| match backend() {
+            Backend::Apic => {
+                let destination = apic::id().ok_or(InterruptError::NoController)?;
+                ioapic::route(irq.gsi, vector, irq.polarity, irq.trigger, destination)?;
+                ioapic::unmask(irq.gsi)
+            }
+            Backend::Pic => pic::unmask(irq.gsi as u8),
+        });
+
+        if let Err(e) = routed {
+            if backend() == Backend::Apic {
+                manager::free_vector(vector);
+            } else {
+                let _ = manager::unregister_handler(vector, handler);
+            }
+            return Err(e);
+        }
+
+        *slot = Some(Route { gsi: irq.gsi, vector });
+        Ok(vector)
+    })
+}
+
+/// Removes a handler added by `route_irq`. Once a line has no handlers left it is masked, and
+/// its vector is freed.
+pub fn unroute_irq(irq: Irq, handler: InterruptHandler) -> Result<(), InterruptError> {
+    without_interrupts(|| {
+        let mut routes = ROUTES.lock();
+        let slot = routes
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|route| route.gsi == irq.gsi))
+            .ok_or(InterruptError::NotRegistered)?;
+        let vector = slot.unwrap().vector;
+
+        manager::unregister_handler(vector, handler)?;
+        if manager::handler_count(vector) > 0 {
+            return Ok(());
+        }
+
+        match backend() {
+            Backend::Apic => {
+                ioapic::unroute(irq.gsi)?;
+                manager::free_vector(vector);
+            }
+            Backend::Pic => pic::mask(irq.gsi as u8)?,
+        }
+
+        *slot = None;
+        Ok(())
+    })
+}
+
+/// Stops a routed line from being delivered, without removing its handlers
+pub fn mask_irq(gsi: u32) -> Result<(), InterruptError> {
+    match backend() {
+        Backend::Apic => ioapic::mask(gsi),
+        Backend::Pic if gsi < pic::IRQ_COUNT as u32 => pic::mask(gsi as u8),
+        Backend::Pic => Err(InterruptError::UnknownIrq),
+    }
+}
+
+/// Lets a line masked with `mask_irq` be delivered again
+pub fn unmask_irq(gsi: u32) -> Result<(), InterruptError> {
+    match backend() {
+        Backend::Apic => ioapic::unmask(gsi),
+        Backend::Pic if gsi < pic::IRQ_COUNT as u32 => pic::unmask(gsi as u8),
+        Backend::Pic => Err(InterruptError::UnknownIrq),
+    }
+}
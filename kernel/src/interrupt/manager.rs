@@ -0,0 +1,257 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::RwLock;
+
+use super::entry::InterruptContext;
+use super::interrupt::without_interrupts;
+
+/*
+ * The interrupt manager. Every vector from 32 up goes through the same entry stub into
+ * `dispatch`, which calls whatever handlers drivers have registered for it. The IDT itself
+ * never changes after it is loaded, so handlers can be registered and removed at any time.
+ */
+
+pub const FIRST_DEVICE_VECTOR: u8 = 32;
+
+/// The vector the local APIC uses for spurious interrupts. It is never handed out.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+//how many handlers can share one vector
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+/// A device interrupt handler. Handlers on a shared vector are all called, in the order they
+/// were registered, so each one has to check whether its device actually raised the interrupt.
+pub type InterruptHandler = fn(&InterruptContext);
+
+/// The interrupt controller that delivers device interrupts, like the PIC or the APIC
+pub trait InterruptController: Sync {
+    /// Checks whether an interrupt on `vector` was spurious, before any handler runs. The
+    /// controller does whatever acknowledgement a spurious interrupt needs itself.
+    fn is_spurious(&self, _vector: u8) -> bool {
+        false
+    }
+
+    /// Signals the end of an interrupt, once every handler has run
+    fn eoi(&self, vector: u8);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InterruptError {
+    /// The vector is a CPU exception, or otherwise can't be used for devices
+    InvalidVector,
+
+    /// Every device vector has already been allocated
+    NoFreeVector,
+
+    /// The vector already has `MAX_SHARED_HANDLERS` handlers
+    VectorFull,
+
+    /// The handler is not registered on the vector
+    NotRegistered,
+
+    /// No interrupt controller handles the IRQ
+    UnknownIrq,
+
+    /// The interrupt controller the call needs hasn't been initialized
+    NoController,
+
+    /// The destination CPU can't be reached this way, like an x2APIC ID above 255 from MSI
+    InvalidDestination,
+}
+
+static HANDLERS: RwLock<[[Option<InterruptHandler>; MAX_SHARED_HANDLERS]; 256]> =
+    RwLock::new([[None; MAX_SHARED_HANDLERS]; 256]);
+
+//a bit per vector, set when the vector has been allocated or reserved
+static ALLOCATED: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+static COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+//the active interrupt controller. None means no controller driver has taken over yet.
+static CONTROLLER: RwLock<Option<&'static dyn InterruptController>> = RwLock::new(None);
+
+fn is_device_vector(vector: u8) -> bool {
+    vector >= FIRST_DEVICE_VECTOR
+}
+
+/// Marks a specific vector as in use, for controllers with a fixed vector layout like the PIC
+pub fn reserve_vector(vector: u8) -> Result<(), InterruptError> {
+    if !is_device_vector(vector) {
+        return Err(InterruptError::InvalidVector);
+    }
+
+    ALLOCATED[vector as usize / 64].fetch_or(1 << (vector % 64), Ordering::AcqRel);
+    Ok(())
+}
+
+/// Allocates a free device vector
+pub fn allocate_vector() -> Result<u8, InterruptError> {
+    for vector in FIRST_DEVICE_VECTOR..SPURIOUS_VECTOR {
+        let word = &ALLOCATED[vector as usize / 64];
+        let bit = 1 << (vector % 64);
+
+        if word.fetch_or(bit, Ordering::AcqRel) & bit == 0 {
+            return Ok(vector);
+        }
+    }
+
+    Err(InterruptError::NoFreeVector)
+}
+
+/// Allocates `count` consecutive free vectors, starting at a multiple of `count`, which has to
+/// be a power of two up to 32. Multi-message MSI needs this, since the device puts the message
+/// number in the low bits of the vector.
+pub fn allocate_vectors(count: usize) -> Result<u8, InterruptError> {
+    if !count.is_power_of_two() || count > 32 {
+        return Err(InterruptError::InvalidVector);
+    }
+
+    //an aligned block never crosses a word, so it can be claimed with one atomic
+    let bits = (1u64 << count) - 1;
+    for base in (FIRST_DEVICE_VECTOR as usize..SPURIOUS_VECTOR as usize).step_by(count) {
+        if base + count > SPURIOUS_VECTOR as usize {
+            break;
+        }
+
+        let word = &ALLOCATED[base / 64];
+        let mask = bits << (base % 64);
+        let previous = word.fetch_or(mask, Ordering::AcqRel);
+        if previous & mask == 0 {
+            return Ok(base as u8);
+        }
+
+        //give back the bits this claimed, and keep the ones someone else holds
+        word.fetch_and(!(mask & !previous), Ordering::AcqRel);
+    }
+
+    Err(InterruptError::NoFreeVector)
+}
+
+/// Returns a vector to the free pool. Its handlers are removed as well.
+pub fn free_vector(vector: u8) {
+    if !is_device_vector(vector) {
+        return;
+    }
+
+    without_interrupts(|| {
+        HANDLERS.write()[vector as usize] = [None; MAX_SHARED_HANDLERS];
+    });
+    ALLOCATED[vector as usize / 64].fetch_and(!(1 << (vector % 64)), Ordering::AcqRel);
+}
+
+/// Adds a handler to a vector. The vector does not have to be allocated first, but it should
+/// be, so nothing else claims it.
+pub fn register_handler(vector: u8, handler: InterruptHandler) -> Result<(), InterruptError> {
+    if !is_device_vector(vector) {
+        return Err(InterruptError::InvalidVector);
+    }
+
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let slot = handlers[vector as usize]
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(InterruptError::VectorFull)?;
+
+        *slot = Some(handler);
+        Ok(())
+    })
+}
+
+/// Removes a handler from a vector
+pub fn unregister_handler(vector: u8, handler: InterruptHandler) -> Result<(), InterruptError> {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let chain = &mut handlers[vector as usize];
+        let index = chain
+            .iter()
+            .position(|slot| slot.is_some_and(|h| core::ptr::fn_addr_eq(h, handler)))
+            .ok_or(InterruptError::NotRegistered)?;
+
+        //close the gap, so the chain keeps its registration order
+        chain[index..].rotate_left(1);
+        chain[MAX_SHARED_HANDLERS - 1] = None;
+        Ok(())
+    })
+}
+
+/// The number of handlers registered on a vector
+pub fn handler_count(vector: u8) -> usize {
+    HANDLERS.read()[vector as usize].iter().flatten().count()
+}
+
+/// Makes `controller` the one that acknowledges device interrupts
+pub fn set_controller(controller: Option<&'static dyn InterruptController>) {
+    without_interrupts(|| *CONTROLLER.write() = controller);
+}
+
+/// Counts an interrupt that no device raised, like a spurious IRQ from the PIC
+pub fn record_spurious() {
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The number of times a vector has fired
+pub fn count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// The number of interrupts nobody claimed
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Called from the interrupt entry stub for vectors 32-255
+pub fn dispatch(ctx: &mut InterruptContext) {
+    let vector = ctx.vector as u8;
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+
+    //the spurious vector is never acknowledged
+    if vector == SPURIOUS_VECTOR {
+        record_spurious();
+        return;
+    }
+
+    let controller = *CONTROLLER.read();
+    if controller.is_some_and(|c| c.is_spurious(vector)) {
+        record_spurious();
+        return;
+    }
+
+    //copy the chain out, so a handler can register or unregister without deadlocking
+    let chain = HANDLERS.read()[vector as usize];
+    let mut handled = false;
+    for handler in chain.iter().flatten() {
+        handler(ctx);
+        handled = true;
+    }
+
+    if !handled {
+        super::structures::default_handler(ctx);
+    }
+
+    if let Some(controller) = controller {
+        controller.eoi(vector);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn vector_blocks_are_aligned() {
+        let block = allocate_vectors(8).unwrap();
+        assert_eq!(block % 8, 0);
+        assert!(block >= FIRST_DEVICE_VECTOR);
+
+        //none of the block is handed out again until it is freed
+        let single = allocate_vector().unwrap();
+        assert!(!(block..block + 8).contains(&single));
+
+        free_vector(single);
+        for vector in block..block + 8 {
+            free_vector(vector);
+        }
+        assert_eq!(allocate_vectors(3), Err(InterruptError::InvalidVector));
+    }
+}
@@ -0,0 +1,234 @@
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use super::interrupt::without_interrupts;
+use super::manager::{self, InterruptController, InterruptError};
+
+/*
+ * Driver for the two cascaded 8259 PICs. The firmware leaves them mapped over the CPU
+ * exception vectors, so they are remapped to PIC1_OFFSET..PIC2_OFFSET + 8 before anything is
+ * unmasked.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/8259_PIC
+ */
+
+pub const PIC1_OFFSET: u8 = 32;
+pub const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
+pub const IRQ_COUNT: u8 = 16;
+
+//the slave PIC is connected to this line of the master
+const CASCADE_IRQ: u8 = 2;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x01;
+const ICW4_8086: u8 = 0x01;
+const OCW3_READ_ISR: u8 = 0x0B;
+const PIC_EOI: u8 = 0x20;
+
+struct Pic {
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Pic {
+    const fn new(command: u16, data: u16) -> Self {
+        Pic {
+            command: Port::new(command),
+            data: Port::new(data),
+        }
+    }
+
+    fn read_isr(&mut self) -> u8 {
+        unsafe {
+            self.command.write(OCW3_READ_ISR);
+            self.command.read()
+        }
+    }
+}
+
+struct ChainedPics {
+    master: Pic,
+    slave: Pic,
+
+    /// The mask of all 16 lines, master in the low byte. A set bit means the line is masked.
+    mask: u16,
+}
+
+static PICS: Mutex<ChainedPics> = Mutex::new(ChainedPics {
+    master: Pic::new(PIC1_COMMAND, PIC1_DATA),
+    slave: Pic::new(PIC2_COMMAND, PIC2_DATA),
+    mask: 0xFFFF,
+});
+
+//a write to an unused port, to give the PICs time to take in each command on old hardware
+fn io_wait() {
+    unsafe { Port::<u8>::new(0x80).write(0) }
+}
+
+/// Returns the vector an IRQ line is delivered on
+pub fn irq_vector(irq: u8) -> u8 {
+    PIC1_OFFSET + irq
+}
+
+fn vector_irq(vector: u8) -> Option<u8> {
+    if (PIC1_OFFSET..PIC1_OFFSET + IRQ_COUNT).contains(&vector) {
+        Some(vector - PIC1_OFFSET)
+    } else {
+        None
+    }
+}
+
+impl ChainedPics {
+    fn write_mask(&mut self) {
+        unsafe {
+            self.master.data.write(self.mask as u8);
+            self.slave.data.write((self.mask >> 8) as u8);
+        }
+    }
+
+    fn remap(&mut self) {
+        unsafe {
+            //start the initialization sequence in cascade mode
+            self.master.command.write(ICW1_INIT | ICW1_ICW4);
+            io_wait();
+            self.slave.command.write(ICW1_INIT | ICW1_ICW4);
+            io_wait();
+
+            //ICW2: the vector offsets
+            self.master.data.write(PIC1_OFFSET);
+            io_wait();
+            self.slave.data.write(PIC2_OFFSET);
+            io_wait();
+
+            //ICW3: tell the master where the slave is, and the slave its cascade identity
+            self.master.data.write(1 << CASCADE_IRQ);
+            io_wait();
+            self.slave.data.write(CASCADE_IRQ);
+            io_wait();
+
+            //ICW4: 8086 mode
+            self.master.data.write(ICW4_8086);
+            io_wait();
+            self.slave.data.write(ICW4_8086);
+            io_wait();
+        }
+
+        self.write_mask();
+    }
+}
+
+struct PicController;
+
+static PIC_CONTROLLER: PicController = PicController;
+
+impl InterruptController for PicController {
+    fn is_spurious(&self, vector: u8) -> bool {
+        //a spurious IRQ shows up on the lowest priority line of a PIC, without its ISR bit set
+        match vector_irq(vector) {
+            Some(7) => {
+                let mut pics = PICS.lock();
+                pics.master.read_isr() & (1 << 7) == 0
+            }
+            Some(15) => {
+                let mut pics = PICS.lock();
+                if pics.slave.read_isr() & (1 << 7) == 0 {
+                    //the master doesn't know the slave's IRQ was spurious, so it still needs one
+                    unsafe { pics.master.command.write(PIC_EOI) };
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn eoi(&self, vector: u8) {
+        let Some(irq) = vector_irq(vector) else {
+            return;
+        };
+
+        unsafe {
+            if irq >= 8 {
+                Port::<u8>::new(PIC2_COMMAND).write(PIC_EOI);
+            }
+            Port::<u8>::new(PIC1_COMMAND).write(PIC_EOI);
+        }
+    }
+}
+
+/// Remaps the PICs to vectors PIC1_OFFSET and up, masks every line, and makes the PIC the
+/// interrupt controller. Lines are unmasked one at a time by their drivers.
+pub fn init() {
+    for irq in 0..IRQ_COUNT {
+        let _ = manager::reserve_vector(irq_vector(irq));
+    }
+
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        pics.mask = !(1 << CASCADE_IRQ);
+        pics.remap();
+    });
+
+    manager::set_controller(Some(&PIC_CONTROLLER));
+}
+
+/// Stops an IRQ line from being delivered
+pub fn mask(irq: u8) -> Result<(), InterruptError> {
+    if irq >= IRQ_COUNT {
+        return Err(InterruptError::UnknownIrq);
+    }
+
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        pics.mask |= 1 << irq;
+        pics.write_mask();
+    });
+    Ok(())
+}
+
+/// Lets an IRQ line be delivered
+pub fn unmask(irq: u8) -> Result<(), InterruptError> {
+    if irq >= IRQ_COUNT {
+        return Err(InterruptError::UnknownIrq);
+    }
+
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        pics.mask &= !(1 << irq);
+
+        //lines on the slave also need the cascade line open
+        if irq >= 8 {
+            pics.mask &= !(1 << CASCADE_IRQ);
+        }
+        pics.write_mask();
+    });
+    Ok(())
+}
+
+/// Masks every line, for when the APIC takes over. The vectors stay remapped, so a spurious IRQ
+/// that is already in flight still lands on a vector the kernel knows about.
+pub fn disable() {
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        pics.mask = 0xFFFF;
+        pics.write_mask();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn rejects_lines_past_the_slave() {
+        assert_eq!(mask(IRQ_COUNT), Err(InterruptError::UnknownIrq));
+        assert_eq!(unmask(u8::MAX), Err(InterruptError::UnknownIrq));
+    }
+}
@@ -2,7 +2,12 @@
 //https://chatgpt.com/share/679bedc9-36c0-800c-869e-e537c23eb7c9
 //
 
-static mut INTERRUPT_STACK: [u8; 4096] = [0; 4096]
+use core::arch::asm;
+use spin::Once;
+
+use super::entry::{self, InterruptContext};
+use super::manager;
+use crate::gdt::{self, IstIndex};
 
 
 /*
@@ -13,10 +18,11 @@ static mut INTERRUPT_STACK: [u8; 4096] = [0; 4096]
  * https://wiki.osdev.org/Interrupt_Descriptor_Table
  */
 #[repr(C)]
+#[derive(Copy, Clone)]
 pub struct IDTEntry {
     pub offset_1: u16,  // The first offset (bits 0-15)
     pub selector: u16,  // Segment selector. Points to a valid spot in the GDT
-    pub ist: u8,        // An offset to the interrupt stack table (not used if all bits 0)
+    pub ist: u8,        // An index into the interrupt stack table in the TSS (0 = don't switch)
     pub type_attrs: u8, // The gate type, and various other attributes (dpl & p fields)
     pub offset_2: u16,  // The second offset (bits 16-31)
     pub offset_3: u32,  // The third offset (bits 32-63)
@@ -24,32 +30,55 @@ pub struct IDTEntry {
 }
 
 impl IDTEntry {
-    
-    fn set_handler(&mut self, handler: *const ()) {
-        
+
+    pub const fn missing() -> Self {
+        IDTEntry {
+            offset_1: 0,
+            selector: 0,
+            ist: 0,
+            type_attrs: 0,
+            offset_2: 0,
+            offset_3: 0,
+            zero: 0,
+        }
+    }
+
+    pub fn set_handler(&mut self, handler: *const ()) {
+
         //get the address of the handler as a 64-bit unsigned int
         let handler = handler as u64;
 
         //split the address amongst the three offsets
-        self.offset_1 = (handler & 0xFFFF) as u16; //pulling out the first 16 bits
+        self.offset_1 = (handler & 0xFFFF) as u16; //pull out the first 16 bits
         self.offset_2 = ((handler >> 16) & 0xFFFF) as u16; //pull out the first 16, return next 16
         self.offset_3 = (handler >> 32) as u32; //pull out the first 32, return the rest.
 
         //setting the other entries
-        self.selector = 0x08;                                   //kernel code segment
-        self.ist = INTERRUPT_STACK.as_ptr().add(4096) as u8;    //IST location in memory
+        self.selector = gdt::KERNEL_CODE_SELECTOR.bits();       //kernel code segment
+        self.ist = 0;                                           //stay on the current stack
         self.type_attrs = 0x8E;                                 //Interrupt gate, present, DPL = 0
         self.zero = 0;                                          //reserved (must be zero)
     }
+
+    /// Makes the CPU switch to the given interrupt stack before calling the handler. This is for
+    /// vectors that can fire when the current stack is unusable, like a double fault.
+    pub fn set_ist(&mut self, index: IstIndex) {
+        self.ist = index as u8;
+    }
+}
+
+/// The operand of the `lidt` and `lgdt` instructions
+#[repr(C, packed)]
+pub struct DescriptorTablePointer {
+    pub limit: u16,
+    pub base: u64,
 }
 
 /*
- * The IDT. Stores the address
+ * The IDT. Stores an entry for each of the 256 interrupt vectors
  */
-#[repr(C)]
+#[repr(C, align(16))]
 pub struct Idt {
-    pub limit: u16,
-    pub base: u64,
     pub entries: [IDTEntry; 256],
 }
 
@@ -57,42 +86,51 @@ impl Idt {
 
     pub fn new() -> Self {
         let mut idt = Idt {
-            limit: (128 * 256 - 1) as u16, //The max size of the IDT
-            base: 0, //The start address of the IDT
-            entries: [IDTEntry { //initialize a list of 256 IDT entries
-                offset_1: 0,
-                selector: 0,
-                ist: 0,
-                type_attrs: 0,
-                offset_2: 0,
-                offset_3: 0,
-                zero: 0,
-            }; 256]
+            entries: [IDTEntry::missing(); 256] //initialize a list of 256 IDT entries
         };
 
-        //set up a default handler for all IDT entries. they will be filled in later.
-        for i in 0..256 {
-            idt.entries[i].set_handler(default_handler as *const ());
+        //point every vector at its entry stub, which saves the registers and dispatches it
+        for (vector, entry) in idt.entries.iter_mut().enumerate() {
+            entry.set_handler(entry::stub_address(vector as u8));
         }
 
-        idt.base = &idt.entries[0] as *const _ as u64 //store the base address of the IDT
+        //these can fire on a broken stack, so they get their own
+        idt.entries[2].set_ist(IstIndex::Nmi);
+        idt.entries[8].set_ist(IstIndex::DoubleFault);
+        idt.entries[18].set_ist(IstIndex::MachineCheck);
 
         idt //return the idt
     }
 
-    //loads the IDT into the itd section in memory.
-    pub unsafe fn load(&self) {
-        asm!(
-            "lidt [{}]",
-            in(reg) self,
-            options(nostack, preserves_flags)
-        )
+    //loads the IDT into the idtr register. the IDT has to live forever once it's loaded.
+    pub fn load(&'static self) {
+        let pointer = DescriptorTablePointer {
+            limit: (core::mem::size_of::<Self>() - 1) as u16, //the size of the IDT
+            base: self as *const _ as u64, //the start address of the IDT
+        };
+
+        unsafe {
+            asm!(
+                "lidt [{}]",
+                in(reg) &pointer,
+                options(readonly, nostack, preserves_flags)
+            )
+        }
     }
 }
 
+static IDT: Once<Idt> = Once::new();
+
+/// Builds the kernel's IDT and loads it. The GDT has to be loaded first, as every entry uses the
+/// kernel code selector.
+pub fn init() {
+    IDT.call_once(Idt::new).load();
+}
+
 
 //the stack frame that is pushed whenever an interrupt occurs
 #[repr(C)]
+#[derive(Debug)]
 pub struct InterruptStackFrame {
     pub rip: u64,      // Instruction pointer (return address)
     pub cs: u64,       // Code segment selector
@@ -104,15 +142,7 @@ pub struct InterruptStackFrame {
 
 //handlers go here
 
-extern "x86-interrupt" fn default_handler(stack_frame: &mut InterruptStackFrame) {
-    //stuff to handle the interrupt here
-    println!("handled interrupt: {:?}", stack_frame);
-
-    unsafe {
-        //send EOI to master PIC
-        outb(0x20, 0x20);
-
-        //send EOI to slave PIC
-        outb(0xA0, 0x20);
-    }
+//handles every vector from 32 up that no driver has registered a handler for
+pub fn default_handler(_ctx: &InterruptContext) {
+    manager::record_spurious();
 }
@@ -0,0 +1,205 @@
+use core::ffi::c_void;
+
+use crate::boot_time::BootTimestamps;
+
+// The kernel's copy of the structs the bootloader hands over in bootloader/src/kernel_args.rs.
+// Both sides are #[repr(C)], so any change to one has to be made to the other.
+
+/// The values of `OSMemEntry::ty`, which are UEFI's EFI_MEMORY_TYPE
+pub mod memory_type {
+    pub const RESERVED: u32 = 0;
+    pub const LOADER_CODE: u32 = 1;
+    pub const LOADER_DATA: u32 = 2;
+    pub const BOOT_SERVICES_CODE: u32 = 3;
+    pub const BOOT_SERVICES_DATA: u32 = 4;
+    pub const RUNTIME_SERVICES_CODE: u32 = 5;
+    pub const RUNTIME_SERVICES_DATA: u32 = 6;
+    pub const CONVENTIONAL: u32 = 7;
+    pub const UNUSABLE: u32 = 8;
+    pub const ACPI_RECLAIM: u32 = 9;
+    pub const ACPI_NON_VOLATILE: u32 = 10;
+    pub const MMIO: u32 = 11;
+    pub const MMIO_PORT_SPACE: u32 = 12;
+    pub const PAL_CODE: u32 = 13;
+    pub const PERSISTENT_MEMORY: u32 = 14;
+
+    /// A short name for a memory type, for printing the map
+    pub fn name(ty: u32) -> &'static str {
+        match ty {
+            RESERVED => "reserved",
+            LOADER_CODE => "loader code",
+            LOADER_DATA => "loader data",
+            BOOT_SERVICES_CODE => "boot services code",
+            BOOT_SERVICES_DATA => "boot services data",
+            RUNTIME_SERVICES_CODE => "runtime services code",
+            RUNTIME_SERVICES_DATA => "runtime services data",
+            CONVENTIONAL => "conventional",
+            UNUSABLE => "unusable",
+            ACPI_RECLAIM => "ACPI reclaim",
+            ACPI_NON_VOLATILE => "ACPI NVS",
+            MMIO => "MMIO",
+            MMIO_PORT_SPACE => "MMIO port space",
+            PAL_CODE => "PAL code",
+            PERSISTENT_MEMORY => "persistent",
+            _ => "unknown",
+        }
+    }
+}
+
+/// The values of `FramebufferInfo::format`
+pub mod pixel_format {
+    /// There is no framebuffer
+    pub const NONE: u32 = 0;
+
+    /// 32 bit pixels with red in the lowest byte
+    pub const RGB: u32 = 1;
+
+    /// 32 bit pixels with blue in the lowest byte
+    pub const BGR: u32 = 2;
+
+    /// 32 bit pixels laid out by the color masks
+    pub const BITMASK: u32 = 3;
+}
+
+#[repr(C)]
+pub struct OSMemEntry {
+    pub ty: u32,
+    pub base: usize,
+    pub pages: usize,
+    pub att: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct FramebufferInfo {
+    /// The physical address of the framebuffer
+    pub base: usize,
+
+    /// The size in bytes of the framebuffer
+    pub size: usize,
+
+    /// The visible size in pixels
+    pub width: u32,
+    pub height: u32,
+
+    /// The number of pixels from the start of one line to the start of the next
+    pub stride: u32,
+
+    /// One of the `pixel_format` values
+    pub format: u32,
+
+    /// Which bits of a pixel hold each color, for `pixel_format::BITMASK`
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct KernelArgs {
+    /// The physical address of the ACPI RSDP
+    acpi_ptr: *const c_void,
+
+    /// The physical address of the SMBIOS table
+    smbios_ptr: *const c_void,
+
+    /// The version of the ACPI RSDP pointed at by `self.acpi_ptr`
+    acpi_ver: u8,
+
+    /// The version of the SMBIOS table pointed at by `self.smbios_ptr`
+    smbios_ver: u8,
+
+    /// The pointer to the PCI Express ECAM Space
+    pcie_ptr: *mut c_void,
+
+    /// The pointer to the OSMemEntry list
+    memmap_ptr: *mut OSMemEntry,
+
+    /// The number of entries in the slice pointed at by memmap_ptr
+    memmap_entries: usize,
+
+    /// TSC timestamps of each bootloader phase
+    boot_timing: BootTimestamps,
+
+    /// The pointer to the persistent crash store region
+    pstore_ptr: *mut c_void,
+
+    /// The size in bytes of the region pointed at by pstore_ptr
+    pstore_size: usize,
+
+    /// The physical address of the first page the kernel image was loaded into
+    kernel_base: usize,
+
+    /// The size in bytes of the loaded kernel image, rounded up to whole pages
+    kernel_size: usize,
+
+    /// The physical address of the bottom of the kernel stack
+    stack_base: usize,
+
+    /// The size in bytes of the kernel stack
+    stack_size: usize,
+
+    /// The GOP framebuffer, with a format of `pixel_format::NONE` if there is none
+    framebuffer: FramebufferInfo,
+}
+
+impl KernelArgs {
+    /// Returns the ACPI pointer and version as a pair
+    pub fn get_acpi(&self) -> (*const c_void, u8) {
+        (self.acpi_ptr, self.acpi_ver)
+    }
+
+    /// Returns the SMBIOS pointer and version as a pair
+    pub fn get_smbios(&self) -> (*const c_void, u8) {
+        (self.smbios_ptr, self.smbios_ver)
+    }
+
+    /// Returns the PCI Express ECAM pointer
+    pub fn get_pcie(&self) -> *mut c_void {
+        self.pcie_ptr
+    }
+
+    /// Returns the MemMap pointer
+    pub fn get_memmap(&self) -> *mut OSMemEntry {
+        self.memmap_ptr
+    }
+
+    /// Returns the number of entries pointed at by the MemMap pointer
+    pub fn get_memmap_entries(&self) -> usize {
+        self.memmap_entries
+    }
+
+    /// Returns the bootloader phase timestamps
+    pub fn get_boot_timing(&self) -> &BootTimestamps {
+        &self.boot_timing
+    }
+
+    /// Returns the crash store pointer and size as a pair
+    pub fn get_pstore(&self) -> (*mut c_void, usize) {
+        (self.pstore_ptr, self.pstore_size)
+    }
+
+    /// Returns the physical base and size of the kernel image as a pair
+    pub fn get_kernel_image(&self) -> (usize, usize) {
+        (self.kernel_base, self.kernel_size)
+    }
+
+    /// Returns the physical base and size of the kernel stack as a pair
+    pub fn get_stack(&self) -> (usize, usize) {
+        (self.stack_base, self.stack_size)
+    }
+
+    /// Returns the GOP framebuffer
+    pub fn get_framebuffer(&self) -> FramebufferInfo {
+        self.framebuffer
+    }
+
+    /// Returns the memory map as a slice, or an empty one if the bootloader didn't pass one
+    pub fn memmap(&self) -> &'static [OSMemEntry] {
+        if self.memmap_ptr.is_null() {
+            return &[];
+        }
+
+        unsafe { core::slice::from_raw_parts(self.memmap_ptr, self.memmap_entries) }
+    }
+}
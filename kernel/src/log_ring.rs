@@ -0,0 +1,56 @@
+use core::fmt::{self, Write};
+use spin::Mutex;
+
+//the size of the in-memory log. once full, the oldest bytes are overwritten.
+pub const LOG_RING_SIZE: usize = 16 * 1024;
+
+/// A byte ring that keeps the most recent kernel output, so it can be saved with a crash record
+pub struct LogRing {
+    buf: [u8; LOG_RING_SIZE],
+
+    /// The total number of bytes ever written. `written % LOG_RING_SIZE` is the next write index.
+    written: usize,
+}
+
+pub static LOG_RING: Mutex<LogRing> = Mutex::new(LogRing::new());
+
+impl LogRing {
+    pub const fn new() -> Self {
+        LogRing {
+            buf: [0; LOG_RING_SIZE],
+            written: 0,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.buf[self.written % LOG_RING_SIZE] = byte;
+            self.written += 1;
+        }
+    }
+
+    /// The number of bytes currently held in the ring
+    fn len(&self) -> usize {
+        self.written.min(LOG_RING_SIZE)
+    }
+
+    /// Copies the newest bytes of the ring into `out`, oldest first. Returns the number of bytes
+    /// copied.
+    pub fn tail(&self, out: &mut [u8]) -> usize {
+        let count = out.len().min(self.len());
+        let start = self.written - count;
+
+        for (i, byte) in out[..count].iter_mut().enumerate() {
+            *byte = self.buf[(start + i) % LOG_RING_SIZE];
+        }
+
+        count
+    }
+}
+
+impl Write for LogRing {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
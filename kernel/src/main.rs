@@ -1,30 +1,203 @@
 #![no_std] // don't link the Rust standard library
 #![no_main] // disable all Rust-level entry points
+#![feature(alloc_error_handler)] // log heap exhaustion before panicking
+#![feature(custom_test_frameworks)] // kernel tests run inside QEMU, see testing.rs
+#![test_runner(crate::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
-use core::panic::PanicInfo;
+extern crate alloc;
+
+mod acpi;
+mod boot_time;
+mod cli;
+mod console;
+mod drivers;
+mod gdt;
+mod interrupt;
+mod kernel_args;
+mod log_ring;
+mod memory;
+mod net;
+mod panic;
+mod pci;
+mod pstore;
+#[cfg(not(test))] // the test kernel exits QEMU instead
+mod reboot;
+mod serial;
+#[cfg(test)]
+mod testing;
+mod time;
 
-const HELLO: &[u8] = b"Hello World!";
+use core::panic::PanicInfo;
+use drivers::watchdog;
+use kernel_args::KernelArgs;
+use log::{error, info, warn};
 
 #[unsafe(no_mangle)] // don't mangle the name of this function
-pub extern "C" fn _start() -> ! {
+pub extern "sysv64" fn _start(args: &'static KernelArgs) -> ! {
+    boot_time::init(args.get_boot_timing());
+    boot_time::stage("kernel entry");
+
+    //take over the crash store before anything else can panic, so every panic is recorded
+    let (pstore_ptr, pstore_size) = args.get_pstore();
+    pstore::init(pstore_ptr as *mut u8, pstore_size);
+
+    //replace the firmware's descriptor tables with the kernel's own. interrupts stay off until
+    //there is something to handle them.
+    interrupt::disable_interrupts();
+    gdt::init();
+    interrupt::structures::init();
+    interrupt::exceptions::init();
+    boot_time::stage("gdt and idt init");
+
+    //move the legacy IRQs off the exception vectors. every line starts masked, so it is safe to
+    //take interrupts from here on.
+    interrupt::pic::init();
+    interrupt::enable_interrupts();
+
+    //bring up the console on COM1 and the log ring, so everything from here on is logged
+    console::init();
+    boot_time::stage("console init");
+
+    //print whatever the last panic left behind. it is copied into the log ring as well, and
+    //stays in the crash store until a new crash overwrites it or it is cleared.
+    pstore::with_last_crash(|crash| {
+        if let Some(crash) = crash {
+            println!("==== kernel crash record ====");
+            let _ = crash.dump(&mut console::Console);
+        }
+    });
+
+    //take over physical memory from the boot memory map
+    match memory::frame::init(args) {
+        Ok(stats) => {
+            info!("memory: {} KiB free of {} KiB, {} KiB of boot services memory held back",
+                stats.free * 4, stats.total * 4, stats.reclaimable * 4
+            );
+        }
+        Err(e) => {
+            error!("memory: frame allocator could not be set up: {:?}", e);
+        }
+    }
+    boot_time::stage("frame allocator init");
+
+    //move off the firmware's page tables, which also frees the boot services memory they sit in
+    match memory::paging::init(args) {
+        Ok(info) => {
+            info!("paging: identity mapped {} MiB, MMIO hole at {:#x}, 1G pages: {}, NX: {}, PAT: {}, {} KiB reclaimed",
+                info.identity_end >> 20, info.mmio_hole, info.gigabyte_pages, info.no_execute, info.pat, info.reclaimed_frames * 4
+            );
+        }
+        Err(e) => {
+            error!("paging: could not switch page tables: {:?}", e);
+        }
+    }
+    boot_time::stage("paging init");
 
-    //get the address of the vga buffer
-    let vga_buffer = 0xb8000 as *mut u8;
+    //put the console on the monitor too, now that the framebuffer can be mapped and the heap
+    //can hold the character grid
+    match console::framebuffer::init(args.get_framebuffer()) {
+        Ok((cols, rows)) => info!("console: framebuffer text console is {}x{}", cols, rows),
+        Err(e) => warn!("console: no framebuffer console: {:?}", e),
+    }
+    boot_time::stage("framebuffer console init");
 
-    //loop through the HELLO ascii byte array, and print write it to the vga buffer
-    for (i, &byte) in HELLO.iter().enumerate() {
-        unsafe {
-            *vga_buffer.offset(i as isize * 2) = byte; //ascii byte
-            *vga_buffer.offset(i as isize * 2 + 1) = 0xb; //color
+    //find the ACPI tables, and move interrupt delivery from the PIC to the APICs if the MADT
+    //lists any
+    let (acpi_ptr, acpi_ver) = args.get_acpi();
+    if let Err(e) = acpi::init(acpi_ptr, acpi_ver) {
+        warn!("acpi: tables unavailable: {:?}", e);
+    }
+    match interrupt::irq::init() {
+        Ok(_) => {
+            info!("interrupts: local APIC {} in {} mode, {} I/O APIC(s)",
+                interrupt::apic::id().unwrap_or(0),
+                if interrupt::apic::is_x2apic() { "x2APIC" } else { "xAPIC" },
+                acpi::madt::get().map_or(0, |madt| madt.io_apics().len())
+            );
+        }
+        Err(e) => {
+            warn!("interrupts: staying on the 8259 PIC: {:?}", e);
         }
     }
+    boot_time::stage("interrupt controller init");
 
-    //loop continuously.
-    loop {}
+    //calibrate the TSC and start the tick, which the timer wheel runs from
+    match time::init() {
+        Ok(clock) => {
+            info!("time: TSC at {} Hz against the {:?}{}, {:?} tick at {} Hz",
+                clock.tsc_hz, clock.reference,
+                if clock.invariant_tsc { "" } else { " (TSC is not invariant)" },
+                clock.tick, time::TICK_HZ
+            );
+        }
+        Err(e) => {
+            error!("time: could not start the tick: {:?}", e);
+        }
+    }
+    boot_time::stage("time init");
+
+    //walk the PCI bus and hand what is on it to the drivers that registered
+    match pci::init(x86_64::PhysAddr::new(args.get_pcie() as u64)) {
+        Ok(info) => {
+            info!("pci: {} function(s) on {} bus(es) via {:?}, {} BAR(s) assigned, {} left unassigned",
+                info.functions, info.buses, info.mechanism, info.assigned_bars, info.unassigned_bars
+            );
+        }
+        Err(e) => {
+            error!("pci: could not enumerate the bus: {:?}", e);
+        }
+    }
+    boot_time::stage("pci init");
+
+    let nics = drivers::net::init();
+    info!("net: {} NIC(s) claimed", nics);
+    boot_time::stage("network drivers init");
+
+    //name the NICs by where they sit on the bus, so the names survive a reboot
+    net::init();
+    for interface in net::interface::interfaces() {
+        let device = interface.device();
+        info!("net: {} is {} at {}, MAC {}", interface.name(), device.name(), device.pci().address, device.mac());
+    }
+    boot_time::stage("interfaces init");
+
+    //start the hardware watchdog, so a hang from here on resets the router
+    match watchdog::init(watchdog::DEFAULT_TIMEOUT_SECS) {
+        Ok(Some(name)) => {
+            info!("watchdog: {} armed, last reset reason: {:?}",
+                name, watchdog::last_reset_reason()
+            );
+        }
+        Ok(None) => {
+            warn!("watchdog: no supported hardware watchdog found");
+        }
+        Err(e) => {
+            error!("watchdog: could not be started: {:?}", e);
+        }
+    }
+    boot_time::stage("watchdog init");
+
+    //print how long it took to get here, and keep a copy in the log ring
+    let _ = boot_time::report(&mut console::Console);
+
+    //the test kernel runs its tests on the fully booted kernel, and exits QEMU when done
+    #[cfg(test)]
+    test_main();
+
+    //take commands from the serial port, then idle. a received byte wakes the CPU, and the tick
+    //wakes it often enough to keep the watchdog pet.
+    cli::init();
+    loop {
+        cli::poll();
+        watchdog::poll();
+        net::poll();
+        x86_64::instructions::hlt();
+    }
 }
 
-/// This function is called on panic.
+/// This function is called on panic. In the test kernel it fails the running test.
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    panic::handle(info)
 }
@@ -0,0 +1,5 @@
+pub mod dma;
+pub mod frame;
+pub mod heap;
+pub mod paging;
+pub mod vspace;
@@ -0,0 +1,53 @@
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use super::frame::{self, FrameConstraints, FrameError, FRAME_SIZE};
+
+/*
+ * Memory for devices to read and write, like descriptor rings and packet buffers. It is
+ * physically contiguous and reached through the identity map, so its virtual address is its
+ * physical one and the CPU and the device see the same bytes at the same address. x86 keeps
+ * DMA coherent with the caches, so no flushing is needed, only the usual ordering between
+ * writing a buffer and telling the device about it.
+ */
+
+/// A physically contiguous, zeroed block of whole frames, freed when dropped
+pub struct DmaRegion {
+    start: PhysFrame,
+    frames: usize,
+}
+
+// Nothing else refers to the frames, so the region can move between contexts like any buffer
+unsafe impl Send for DmaRegion {}
+unsafe impl Sync for DmaRegion {}
+
+impl DmaRegion {
+    /// Allocates at least `size` bytes that meet `constraints`
+    pub fn alloc(size: usize, constraints: FrameConstraints) -> Result<Self, FrameError> {
+        let frames = (size as u64).div_ceil(FRAME_SIZE).max(1) as usize;
+        let start = frame::alloc_frames(frames, constraints)?;
+        let region = DmaRegion { start, frames };
+        unsafe { core::ptr::write_bytes(region.as_ptr::<u8>(), 0, region.len()) };
+        Ok(region)
+    }
+
+    /// The address the device uses
+    pub fn phys(&self) -> PhysAddr {
+        self.start.start_address()
+    }
+
+    /// The address the CPU uses
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.phys().as_u64() as *mut T
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames * FRAME_SIZE as usize
+    }
+}
+
+impl Drop for DmaRegion {
+    fn drop(&mut self) {
+        let _ = frame::free_frames(self.start, self.frames);
+    }
+}
@@ -0,0 +1,401 @@
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use crate::interrupt::interrupt::without_interrupts;
+use crate::kernel_args::{memory_type, KernelArgs, OSMemEntry};
+
+/*
+ * The physical frame allocator. It keeps two bitmaps over every frame up to the end of usable
+ * memory: `used` has a bit set for every frame that can't be handed out, and `reserved` has a
+ * bit set for every frame the allocator doesn't own at all, like MMIO holes, the kernel image,
+ * or memory the firmware is still using. Freeing a reserved frame, or a frame that is already
+ * free, is reported as an error instead of corrupting the bitmaps.
+ *
+ * The bitmaps are carved out of the first conventional memory region that fits them. Memory is
+ * identity mapped by the firmware, so a frame's physical address can be used to reach it.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/Page_Frame_Allocation
+ * UEFI 2.10, 7.2 "Memory Allocation Services" (EFI_MEMORY_TYPE usage after ExitBootServices)
+ */
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/// The highest address (exclusive) a device with 32 bit DMA can reach
+pub const DMA32_LIMIT: u64 = 1 << 32;
+
+//the first MiB is left alone: it holds the real mode IVT, the EBDA, and is where an AP trampoline
+//would go
+const LOW_MEMORY_LIMIT: u64 = 0x10_0000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// No free run of frames satisfies the request
+    OutOfMemory,
+
+    /// The frame is already free
+    DoubleFree(PhysAddr),
+
+    /// The frame isn't owned by the allocator, so it was never handed out
+    NotManaged(PhysAddr),
+
+    /// The alignment is not a power of two, or the count is zero
+    InvalidRequest,
+
+    /// The bootloader didn't pass a memory map
+    NoMemoryMap,
+
+    /// No conventional memory region is big enough to hold the bitmaps
+    NoBitmapSpace,
+
+    /// `init` hasn't run yet
+    NotInitialized,
+}
+
+/// Where an allocation has to be placed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameConstraints {
+    /// The alignment of the first frame in bytes, a power of two. Anything under FRAME_SIZE
+    /// means frame alignment.
+    pub align: u64,
+
+    /// Every frame has to end at or below this address
+    pub limit: u64,
+}
+
+impl FrameConstraints {
+    /// Anywhere in memory, frame aligned
+    pub const ANY: FrameConstraints = FrameConstraints { align: FRAME_SIZE, limit: u64::MAX };
+
+    /// Below 4 GiB, for devices that can only do 32 bit DMA
+    pub const DMA32: FrameConstraints = FrameConstraints { align: FRAME_SIZE, limit: DMA32_LIMIT };
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Frames the allocator owns, free or not
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+
+    /// Frames of boot services memory that `reclaim_boot_services` can still hand over
+    pub reclaimable: usize,
+}
+
+struct FrameAllocator {
+    used: &'static mut [u64],
+    reserved: &'static mut [u64],
+
+    //the number of frames the bitmaps cover
+    frames: usize,
+    total: usize,
+    free: usize,
+
+    //where the next single frame search starts
+    next: usize,
+
+    memmap: &'static [OSMemEntry],
+    boot_services_reclaimed: bool,
+}
+
+static FRAMES: Mutex<Option<FrameAllocator>> = Mutex::new(None);
+
+fn is_usable(ty: u32) -> bool {
+    matches!(ty, memory_type::CONVENTIONAL | memory_type::LOADER_CODE | memory_type::LOADER_DATA)
+}
+
+fn is_boot_services(ty: u32) -> bool {
+    matches!(ty, memory_type::BOOT_SERVICES_CODE | memory_type::BOOT_SERVICES_DATA)
+}
+
+fn entry_frames(entry: &OSMemEntry) -> (usize, usize) {
+    let start = entry.base / FRAME_SIZE as usize;
+    (start, start + entry.pages)
+}
+
+//the frames that cover a byte range, rounded outwards
+fn range_frames(base: usize, size: usize) -> (usize, usize) {
+    let start = base / FRAME_SIZE as usize;
+    let end = (base + size).div_ceil(FRAME_SIZE as usize);
+    (start, end)
+}
+
+fn get(bitmap: &[u64], frame: usize) -> bool {
+    bitmap[frame / 64] & (1 << (frame % 64)) != 0
+}
+
+fn set(bitmap: &mut [u64], frame: usize, value: bool) {
+    if value {
+        bitmap[frame / 64] |= 1 << (frame % 64);
+    } else {
+        bitmap[frame / 64] &= !(1 << (frame % 64));
+    }
+}
+
+impl FrameAllocator {
+    //hands frames over to the allocator as free
+    fn release(&mut self, start: usize, end: usize) {
+        for frame in start..end.min(self.frames) {
+            if get(self.reserved, frame) {
+                set(self.reserved, frame, false);
+                set(self.used, frame, false);
+                self.total += 1;
+                self.free += 1;
+            }
+        }
+    }
+
+    //takes frames away from the allocator, whether they were free or not
+    fn reserve(&mut self, start: usize, end: usize) {
+        for frame in start..end.min(self.frames) {
+            if !get(self.reserved, frame) {
+                if !get(self.used, frame) {
+                    self.free -= 1;
+                }
+                set(self.reserved, frame, true);
+                set(self.used, frame, true);
+                self.total -= 1;
+            }
+        }
+    }
+
+    //the first used frame in start..end, or None if they are all free
+    fn first_used(&self, start: usize, end: usize) -> Option<usize> {
+        let mut frame = start;
+        while frame < end {
+            let word = self.used[frame / 64] >> (frame % 64);
+            if word == 0 {
+                frame = (frame / 64 + 1) * 64;
+                continue;
+            }
+
+            let used = frame + word.trailing_zeros() as usize;
+            return (used < end).then_some(used);
+        }
+        None
+    }
+
+    fn mark_used(&mut self, start: usize, count: usize) {
+        for frame in start..start + count {
+            set(self.used, frame, true);
+        }
+        self.free -= count;
+    }
+
+    fn alloc_one(&mut self) -> Option<usize> {
+        let words = self.used.len();
+        let first = self.next / 64;
+
+        for i in 0..words {
+            let index = (first + i) % words;
+            let word = self.used[index];
+            if word == u64::MAX {
+                continue;
+            }
+
+            let frame = index * 64 + word.trailing_ones() as usize;
+            if frame >= self.frames {
+                continue;
+            }
+
+            self.mark_used(frame, 1);
+            self.next = frame + 1;
+            return Some(frame);
+        }
+
+        None
+    }
+
+    fn alloc_run(&mut self, count: usize, constraints: FrameConstraints) -> Option<usize> {
+        let align = (constraints.align.max(FRAME_SIZE) / FRAME_SIZE) as usize;
+        let limit = ((constraints.limit / FRAME_SIZE) as usize).min(self.frames);
+
+        let mut start = 0;
+        while start + count <= limit {
+            match self.first_used(start, start + count) {
+                None => {
+                    self.mark_used(start, count);
+                    return Some(start);
+                }
+                Some(used) => start = (used + 1).next_multiple_of(align),
+            }
+        }
+
+        None
+    }
+
+    fn free_run(&mut self, start: usize, count: usize) -> Result<(), FrameError> {
+        //check every frame first, so a bad free changes nothing
+        for frame in start..start + count {
+            let addr = PhysAddr::new(frame as u64 * FRAME_SIZE);
+            if frame >= self.frames || get(self.reserved, frame) {
+                return Err(FrameError::NotManaged(addr));
+            }
+            if !get(self.used, frame) {
+                return Err(FrameError::DoubleFree(addr));
+            }
+        }
+
+        for frame in start..start + count {
+            set(self.used, frame, false);
+        }
+        self.free += count;
+        Ok(())
+    }
+
+    fn stats(&self) -> FrameStats {
+        let reclaimable = if self.boot_services_reclaimed {
+            0
+        } else {
+            self.memmap.iter().filter(|e| is_boot_services(e.ty)).map(|e| e.pages).sum()
+        };
+
+        FrameStats {
+            total: self.total,
+            free: self.free,
+            used: self.total - self.free,
+            reclaimable,
+        }
+    }
+}
+
+fn frame_of(frame: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE))
+}
+
+/// Builds the allocator from the bootloader's memory map. The kernel image, the kernel stack,
+/// the boot data the kernel still reads, and the first MiB are kept out of it. Boot services
+/// memory stays out as well until `reclaim_boot_services`, as the page tables the kernel runs
+/// on live there.
+pub fn init(args: &'static KernelArgs) -> Result<FrameStats, FrameError> {
+    let memmap = args.memmap();
+    if memmap.is_empty() {
+        return Err(FrameError::NoMemoryMap);
+    }
+
+    let frames = memmap
+        .iter()
+        .filter(|e| is_usable(e.ty) || is_boot_services(e.ty))
+        .map(|e| entry_frames(e).1)
+        .max()
+        .unwrap_or(0);
+    let words = frames.div_ceil(64);
+    let bitmap_bytes = words * 8 * 2;
+
+    //the bitmaps go in the first conventional region above low memory that can hold both
+    let bitmap_base = memmap
+        .iter()
+        .filter(|e| e.ty == memory_type::CONVENTIONAL)
+        .map(|e| (e.base.max(LOW_MEMORY_LIMIT as usize), e.base + e.pages * FRAME_SIZE as usize))
+        .find(|&(start, end)| start < end && end - start >= bitmap_bytes)
+        .map(|(start, _)| start)
+        .ok_or(FrameError::NoBitmapSpace)?;
+
+    let (used, reserved) = unsafe {
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_base as *mut u64, words * 2);
+        bitmap.fill(u64::MAX);
+        bitmap.split_at_mut(words)
+    };
+
+    let mut allocator = FrameAllocator {
+        used,
+        reserved,
+        frames,
+        total: 0,
+        free: 0,
+        next: 0,
+        memmap,
+        boot_services_reclaimed: false,
+    };
+
+    for entry in memmap.iter().filter(|e| is_usable(e.ty)) {
+        let (start, end) = entry_frames(entry);
+        allocator.release(start, end);
+    }
+
+    //everything the kernel is still running on or reading
+    let (kernel_base, kernel_size) = args.get_kernel_image();
+    let (stack_base, stack_size) = args.get_stack();
+    let reservations = [
+        (0, LOW_MEMORY_LIMIT as usize),
+        (kernel_base, kernel_size),
+        (stack_base, stack_size),
+        (args as *const KernelArgs as usize, size_of::<KernelArgs>()),
+        (memmap.as_ptr() as usize, size_of_val(memmap)),
+        (bitmap_base, bitmap_bytes),
+    ];
+    for (base, size) in reservations {
+        let (start, end) = range_frames(base, size);
+        allocator.reserve(start, end);
+    }
+
+    let stats = allocator.stats();
+    without_interrupts(|| *FRAMES.lock() = Some(allocator));
+    Ok(stats)
+}
+
+fn with_allocator<T>(f: impl FnOnce(&mut FrameAllocator) -> Result<T, FrameError>) -> Result<T, FrameError> {
+    without_interrupts(|| match FRAMES.lock().as_mut() {
+        Some(allocator) => f(allocator),
+        None => Err(FrameError::NotInitialized),
+    })
+}
+
+/// Allocates a single frame
+pub fn alloc_frame() -> Result<PhysFrame, FrameError> {
+    with_allocator(|allocator| allocator.alloc_one().map(frame_of).ok_or(FrameError::OutOfMemory))
+}
+
+/// Allocates `count` physically contiguous frames that meet `constraints`, and returns the
+/// first one
+pub fn alloc_frames(count: usize, constraints: FrameConstraints) -> Result<PhysFrame, FrameError> {
+    if count == 0 || !constraints.align.is_power_of_two() {
+        return Err(FrameError::InvalidRequest);
+    }
+
+    with_allocator(|allocator| {
+        allocator.alloc_run(count, constraints).map(frame_of).ok_or(FrameError::OutOfMemory)
+    })
+}
+
+/// Frees a frame from `alloc_frame`
+pub fn free_frame(frame: PhysFrame) -> Result<(), FrameError> {
+    free_frames(frame, 1)
+}
+
+/// Frees `count` frames starting at `frame`, all allocated together with `alloc_frames`
+pub fn free_frames(frame: PhysFrame, count: usize) -> Result<(), FrameError> {
+    let start = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+    with_allocator(|allocator| allocator.free_run(start, count))
+}
+
+/// Hands boot services memory over to the allocator. This must only be called once nothing
+/// the firmware left there is in use, like its page tables. Returns the number of frames added.
+pub fn reclaim_boot_services() -> Result<usize, FrameError> {
+    with_allocator(|allocator| {
+        if allocator.boot_services_reclaimed {
+            return Ok(0);
+        }
+
+        let before = allocator.total;
+        for entry in allocator.memmap.iter().filter(|e| is_boot_services(e.ty)) {
+            let (start, end) = entry_frames(entry);
+            let low = (LOW_MEMORY_LIMIT / FRAME_SIZE) as usize;
+            allocator.release(start.max(low), end.max(low));
+        }
+
+        allocator.boot_services_reclaimed = true;
+        Ok(allocator.total - before)
+    })
+}
+
+/// The frame counts, or all zeroes before `init`
+pub fn stats() -> FrameStats {
+    with_allocator(|allocator| Ok(allocator.stats())).unwrap_or_default()
+}
+
+/// The boot memory map the allocator was built from, or an empty one before `init`
+pub fn memory_map() -> &'static [OSMemEntry] {
+    with_allocator(|allocator| Ok(allocator.memmap)).unwrap_or(&[])
+}
@@ -0,0 +1,349 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt::Write;
+use core::ptr;
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use super::paging::{self, Protection};
+use crate::interrupt::interrupt::without_interrupts;
+use crate::log_ring::LOG_RING;
+
+/*
+ * The kernel heap, behind `alloc`'s Box, Vec and friends.
+ *
+ * Small allocations come from power of two size classes. Each class carves chunks of pages
+ * into equal slots and keeps the free ones on a singly linked list threaded through the slots
+ * themselves. Slots are aligned to their size, so any alignment up to the class size is met.
+ * Anything bigger than the largest class gets its own pages, mapped when it is allocated and
+ * unmapped when it is freed. All heap memory lives in the kernel's virtual address space and
+ * is backed by frames from the frame allocator.
+ *
+ * With HEAP_CHECKS on (debug builds), every allocation is followed by a red zone, freed memory
+ * is filled with a poison pattern, and both are checked: an overwritten red zone is a buffer
+ * overflow, overwritten poison is a write after free, and a slot that is still poisoned when it
+ * is freed is a double free.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/Memory_Allocation
+ * https://os.phil-opp.com/allocator-designs/#fixed-size-block-allocator
+ */
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// The slot sizes of the small allocation classes
+pub const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const CLASS_COUNT: usize = SIZE_CLASSES.len();
+
+//how many pages a size class grabs at once when its free list runs dry
+const CHUNK_PAGES: usize = 16;
+
+/// Whether allocations get red zones and freed memory gets poisoned
+pub const HEAP_CHECKS: bool = cfg!(debug_assertions);
+
+//the smallest red zone after every allocation, when HEAP_CHECKS is on
+const RED_ZONE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xFC;
+
+//what freed memory is filled with, and what new memory is filled with so reads of
+//uninitialized memory stand out
+const FREE_POISON: u8 = 0xDD;
+const ALLOC_POISON: u8 = 0xAA;
+
+//the first word of a free slot is the free list link, which the poison doesn't cover
+const LINK_SIZE: usize = size_of::<usize>();
+
+/// The counters of one size class
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ClassStats {
+    pub allocs: u64,
+    pub frees: u64,
+
+    /// Slots handed out and not yet freed
+    pub active: usize,
+
+    /// Pages the class has taken from the page allocator. They are never given back.
+    pub pages: usize,
+}
+
+/// The counters of allocations too big for any size class
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LargeStats {
+    pub allocs: u64,
+    pub frees: u64,
+
+    /// Pages mapped for large allocations that haven't been freed
+    pub active_pages: usize,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HeapStats {
+    pub classes: [ClassStats; CLASS_COUNT],
+    pub large: LargeStats,
+
+    /// Allocations that couldn't be satisfied
+    pub failures: u64,
+}
+
+impl HeapStats {
+    /// The number of pages the heap has mapped in total
+    pub fn mapped_pages(&self) -> usize {
+        self.classes.iter().map(|c| c.pages).sum::<usize>() + self.large.active_pages
+    }
+}
+
+struct SizeClass {
+    //the first free slot, or null
+    free: *mut u8,
+}
+
+struct Heap {
+    classes: [SizeClass; CLASS_COUNT],
+    stats: HeapStats,
+}
+
+// The free lists only point into heap memory, which only the heap touches while it is locked
+unsafe impl Send for Heap {}
+
+static HEAP: Mutex<Heap> = Mutex::new(Heap {
+    classes: [const { SizeClass { free: ptr::null_mut() } }; CLASS_COUNT],
+    stats: HeapStats {
+        classes: [ClassStats { allocs: 0, frees: 0, active: 0, pages: 0 }; CLASS_COUNT],
+        large: LargeStats { allocs: 0, frees: 0, active_pages: 0 },
+        failures: 0,
+    },
+});
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+/// The `GlobalAlloc` in front of the heap
+pub struct KernelAllocator;
+
+//the bytes an allocation takes up, including its red zone
+fn padded_size(layout: Layout) -> usize {
+    if HEAP_CHECKS { layout.size() + RED_ZONE } else { layout.size() }
+}
+
+//the smallest class that fits the allocation and meets its alignment
+fn class_for(layout: Layout) -> Option<usize> {
+    let size = padded_size(layout).max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= size)
+}
+
+fn large_pages(layout: Layout) -> usize {
+    padded_size(layout).div_ceil(PAGE_SIZE)
+}
+
+//the heap found its own memory corrupted, which means someone else wrote over it. there is no
+//recovering from that.
+fn corrupted(what: &str, ptr: *const u8, layout: Layout) -> ! {
+    panic!("heap: {} at {:p} (size {}, align {})", what, ptr, layout.size(), layout.align());
+}
+
+fn all(slice: &[u8], byte: u8) -> bool {
+    slice.iter().all(|&b| b == byte)
+}
+
+impl Heap {
+    //maps a fresh chunk of pages and puts all of its slots on the class's free list
+    fn refill(&mut self, class: usize) -> bool {
+        let length = (CHUNK_PAGES * PAGE_SIZE) as u64;
+        let Ok(virt) = paging::alloc_virtual(length, PAGE_SIZE as u64) else {
+            return false;
+        };
+        if paging::map_frames(virt, CHUNK_PAGES, Protection::READ_WRITE).is_err() {
+            let _ = paging::free_virtual(virt, length);
+            return false;
+        }
+
+        //link the slots back to front, so they are handed out in address order
+        let size = SIZE_CLASSES[class];
+        let base = virt.as_mut_ptr::<u8>();
+        for offset in (0..CHUNK_PAGES * PAGE_SIZE).step_by(size).rev() {
+            unsafe {
+                let slot = base.add(offset);
+                if HEAP_CHECKS {
+                    ptr::write_bytes(slot.add(LINK_SIZE), FREE_POISON, size - LINK_SIZE);
+                }
+                (slot as *mut *mut u8).write(self.classes[class].free);
+                self.classes[class].free = slot;
+            }
+        }
+
+        self.stats.classes[class].pages += CHUNK_PAGES;
+        true
+    }
+
+    fn alloc_small(&mut self, class: usize, layout: Layout) -> *mut u8 {
+        if self.classes[class].free.is_null() && !self.refill(class) {
+            return ptr::null_mut();
+        }
+
+        let size = SIZE_CLASSES[class];
+        let slot = self.classes[class].free;
+        unsafe {
+            self.classes[class].free = (slot as *const *mut u8).read();
+
+            if HEAP_CHECKS {
+                let slot_bytes = core::slice::from_raw_parts_mut(slot, size);
+                if !all(&slot_bytes[LINK_SIZE..], FREE_POISON) {
+                    corrupted("write after free", slot, layout);
+                }
+                slot_bytes[..layout.size()].fill(ALLOC_POISON);
+                slot_bytes[layout.size()..].fill(RED_ZONE_BYTE);
+            }
+        }
+
+        let stats = &mut self.stats.classes[class];
+        stats.allocs += 1;
+        stats.active += 1;
+        slot
+    }
+
+    fn dealloc_small(&mut self, class: usize, slot: *mut u8, layout: Layout) {
+        let size = SIZE_CLASSES[class];
+        unsafe {
+            if HEAP_CHECKS {
+                let slot_bytes = core::slice::from_raw_parts_mut(slot, size);
+                if all(&slot_bytes[layout.size().max(LINK_SIZE)..], FREE_POISON) {
+                    corrupted("double free", slot, layout);
+                }
+                if !all(&slot_bytes[layout.size()..], RED_ZONE_BYTE) {
+                    corrupted("red zone overwritten", slot, layout);
+                }
+                slot_bytes.fill(FREE_POISON);
+            }
+
+            (slot as *mut *mut u8).write(self.classes[class].free);
+            self.classes[class].free = slot;
+        }
+
+        let stats = &mut self.stats.classes[class];
+        stats.frees += 1;
+        stats.active -= 1;
+    }
+
+    fn alloc_large(&mut self, layout: Layout) -> *mut u8 {
+        let pages = large_pages(layout);
+        let length = (pages * PAGE_SIZE) as u64;
+        let align = layout.align().max(PAGE_SIZE) as u64;
+
+        let Ok(virt) = paging::alloc_virtual(length, align) else {
+            return ptr::null_mut();
+        };
+        if paging::map_frames(virt, pages, Protection::READ_WRITE).is_err() {
+            let _ = paging::free_virtual(virt, length);
+            return ptr::null_mut();
+        }
+
+        let ptr = virt.as_mut_ptr::<u8>();
+        if HEAP_CHECKS {
+            unsafe {
+                ptr::write_bytes(ptr, ALLOC_POISON, layout.size());
+                ptr::write_bytes(ptr.add(layout.size()), RED_ZONE_BYTE, pages * PAGE_SIZE - layout.size());
+            }
+        }
+
+        self.stats.large.allocs += 1;
+        self.stats.large.active_pages += pages;
+        ptr
+    }
+
+    fn dealloc_large(&mut self, ptr: *mut u8, layout: Layout) {
+        let pages = large_pages(layout);
+        if HEAP_CHECKS {
+            let tail = unsafe { core::slice::from_raw_parts(ptr.add(layout.size()), pages * PAGE_SIZE - layout.size()) };
+            if !all(tail, RED_ZONE_BYTE) {
+                corrupted("red zone overwritten", ptr, layout);
+            }
+        }
+
+        //the pages are unmapped, so a use after free faults instead of needing poison
+        let virt = VirtAddr::from_ptr(ptr);
+        paging::unmap_frames(virt, pages);
+        let _ = paging::free_virtual(virt, (pages * PAGE_SIZE) as u64);
+
+        self.stats.large.frees += 1;
+        self.stats.large.active_pages -= pages;
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            let mut heap = HEAP.lock();
+            let ptr = match class_for(layout) {
+                Some(class) => heap.alloc_small(class, layout),
+                None => heap.alloc_large(layout),
+            };
+
+            if ptr.is_null() {
+                heap.stats.failures += 1;
+            }
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| {
+            let mut heap = HEAP.lock();
+            match class_for(layout) {
+                Some(class) => heap.dealloc_small(class, ptr, layout),
+                None => heap.dealloc_large(ptr, layout),
+            }
+        })
+    }
+}
+
+/// Called by `alloc` when the heap can't satisfy an allocation. The kernel has nothing to fall
+/// back on, so the failure is logged and turned into a panic, which saves a crash record.
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let stats = stats();
+    if let Some(mut ring) = LOG_RING.try_lock() {
+        let _ = writeln!(ring, "heap: out of memory allocating {} bytes (align {}), {} pages mapped",
+            layout.size(), layout.align(), stats.mapped_pages()
+        );
+    }
+
+    panic!("heap: out of memory allocating {} bytes (align {})", layout.size(), layout.align());
+}
+
+/// A snapshot of the heap counters
+pub fn stats() -> HeapStats {
+    without_interrupts(|| HEAP.lock().stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn small_and_large_allocations() {
+        let before = stats();
+
+        let small = Box::new(42u64);
+        let mut large = Vec::<u8>::new();
+        large.resize(3 * PAGE_SIZE, 0xA5);
+        assert_eq!(*small, 42);
+        assert!(large.iter().all(|&b| b == 0xA5));
+        assert!(stats().large.active_pages > before.large.active_pages);
+
+        drop(large);
+        drop(small);
+        assert_eq!(stats().large.active_pages, before.large.active_pages);
+    }
+
+    #[test_case]
+    fn alignment_is_met() {
+        for align in [16, 64, 512, PAGE_SIZE, 4 * PAGE_SIZE] {
+            let layout = Layout::from_size_align(24, align).unwrap();
+            let ptr = unsafe { ALLOCATOR.alloc(layout) };
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            unsafe { ALLOCATOR.dealloc(ptr, layout) };
+        }
+    }
+}
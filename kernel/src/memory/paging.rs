@@ -0,0 +1,521 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use spin::Mutex;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr3Flags, Efer, EferFlags};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size1GiB,
+    Size2MiB, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::frame::{self, FrameError};
+use super::vspace::{VirtualSpace, VspaceError, KERNEL_VA_BASE, KERNEL_VA_SIZE};
+use crate::gdt::{self, IstIndex};
+use crate::interrupt::interrupt::without_interrupts;
+use crate::kernel_args::{memory_type, KernelArgs};
+
+/*
+ * The kernel's page tables. `init` replaces the firmware's tables with an identity map of all
+ * physical memory, so physical addresses keep working as pointers, and a region above it that
+ * MMIO mappings and the heap are allocated from.
+ *
+ * The identity map uses the biggest pages it can. The first 2 MiB, the kernel image and the
+ * boot stack are mapped with 4K pages so guard pages can be punched into them, and page 0 is
+ * left out so null pointer accesses fault. The MMIO hole below 4 GiB is mapped uncached.
+ *
+ * PAT is programmed the same way Linux does it, so the PWT and PCD bits alone select WB, WC,
+ * UC- and UC, and the PAT bit is never needed:
+ *   PAT0 WB, PAT1 WC, PAT2 UC-, PAT3 UC, PAT4 WB, PAT5 WP, PAT6 UC-, PAT7 WT
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/Paging
+ * https://wiki.osdev.org/Page_Attribute_Table
+ * Intel SDM Vol. 3A, 4.5 "4-Level Paging" and 11.12 "Page Attribute Table (PAT)"
+ */
+
+const IA32_PAT: u32 = 0x277;
+const PAT_VALUE: u64 = 0x0407_0506_0007_0106;
+
+const SIZE_4K: u64 = 4096;
+const SIZE_2M: u64 = 2 * 1024 * 1024;
+const SIZE_1G: u64 = 1024 * 1024 * 1024;
+const FOUR_GIB: u64 = 4 * SIZE_1G;
+
+//past this many pages, flushing the whole TLB is cheaper than flushing page by page
+const FLUSH_ALL_THRESHOLD: u64 = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    pub const fn bytes(&self) -> u64 {
+        match self {
+            PageSize::Size4K => SIZE_4K,
+            PageSize::Size2M => SIZE_2M,
+            PageSize::Size1G => SIZE_1G,
+        }
+    }
+}
+
+/// The memory type of a mapping
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal memory
+    WriteBack,
+
+    /// Framebuffers and other memory that is written in bursts and never read back
+    WriteCombining,
+
+    /// Device registers
+    Uncached,
+}
+
+/// What a mapping can be used for. Every mapping can be read.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Protection {
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Protection {
+    pub const READ_WRITE: Protection = Protection { write: true, execute: false };
+    pub const READ_WRITE_EXECUTE: Protection = Protection { write: true, execute: true };
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PagingError {
+    /// `init` hasn't run yet
+    NotInitialized,
+
+    /// An address or size isn't a multiple of the page size
+    Misaligned,
+
+    /// The CPU can't map pages of the requested size
+    Unsupported,
+
+    /// Part of the range is already mapped
+    AlreadyMapped,
+
+    /// Part of the range isn't mapped, or is mapped with a different page size
+    NotMapped,
+
+    /// A bigger page already covers part of the range
+    HugePageInTheWay,
+
+    /// There were no frames left for page tables
+    Frame(FrameError),
+
+    /// The kernel virtual address space is used up
+    VirtualSpace(VspaceError),
+}
+
+/// What `init` set up, for the boot log
+#[derive(Copy, Clone, Debug)]
+pub struct PagingInfo {
+    /// The end of the identity map
+    pub identity_end: u64,
+
+    /// Where the uncached MMIO hole below 4 GiB starts
+    pub mmio_hole: u64,
+    pub gigabyte_pages: bool,
+    pub no_execute: bool,
+    pub pat: bool,
+
+    /// Frames of boot services memory freed once the firmware's page tables were dropped
+    pub reclaimed_frames: usize,
+}
+
+struct Paging {
+    pml4: PhysFrame,
+    vspace: VirtualSpace,
+    no_execute: bool,
+}
+
+static PAGING: Mutex<Option<Paging>> = Mutex::new(None);
+
+//page tables come straight from the frame allocator
+struct TableFrames;
+
+unsafe impl FrameAllocator<Size4KiB> for TableFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        frame::alloc_frame().ok()
+    }
+}
+
+//every page table is reachable through the identity map
+fn mapper(pml4: PhysFrame) -> OffsetPageTable<'static> {
+    unsafe {
+        let table = &mut *(pml4.start_address().as_u64() as *mut PageTable);
+        OffsetPageTable::new(table, VirtAddr::new(0))
+    }
+}
+
+fn map_error<S: x86_64::structures::paging::PageSize>(e: MapToError<S>) -> PagingError {
+    match e {
+        MapToError::FrameAllocationFailed => PagingError::Frame(FrameError::OutOfMemory),
+        MapToError::ParentEntryHugePage => PagingError::HugePageInTheWay,
+        MapToError::PageAlreadyMapped(_) => PagingError::AlreadyMapped,
+    }
+}
+
+fn unmap_error(e: UnmapError) -> PagingError {
+    match e {
+        UnmapError::ParentEntryHugePage => PagingError::HugePageInTheWay,
+        UnmapError::PageNotMapped | UnmapError::InvalidFrameAddress(_) => PagingError::NotMapped,
+    }
+}
+
+fn flags(protection: Protection, cache: CacheMode, no_execute: bool) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if protection.write {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !protection.execute && no_execute {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    match cache {
+        CacheMode::WriteBack => {}
+        CacheMode::WriteCombining => flags |= PageTableFlags::WRITE_THROUGH,
+        CacheMode::Uncached => flags |= PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE,
+    }
+
+    flags
+}
+
+fn map_pages<S>(mapper: &mut OffsetPageTable, virt: VirtAddr, phys: PhysAddr, count: u64, flags: PageTableFlags) -> Result<(), PagingError>
+where
+    S: x86_64::structures::paging::PageSize,
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    for i in 0..count {
+        let page = Page::<S>::from_start_address(virt + i * S::SIZE).map_err(|_| PagingError::Misaligned)?;
+        let frame = PhysFrame::<S>::from_start_address(phys + i * S::SIZE).map_err(|_| PagingError::Misaligned)?;
+
+        if let Err(e) = unsafe { mapper.map_to(page, frame, flags, &mut TableFrames) } {
+            //take back what was mapped, so a failed map leaves nothing behind
+            for j in 0..i {
+                if let Ok((_, flush)) = mapper.unmap(Page::<S>::containing_address(virt + j * S::SIZE)) {
+                    flush.ignore();
+                }
+            }
+            return Err(map_error(e));
+        }
+    }
+
+    Ok(())
+}
+
+fn map_sized(mapper: &mut OffsetPageTable, virt: VirtAddr, phys: PhysAddr, count: u64, page_size: PageSize, flags: PageTableFlags) -> Result<(), PagingError> {
+    match page_size {
+        PageSize::Size4K => map_pages::<Size4KiB>(mapper, virt, phys, count, flags),
+        PageSize::Size2M => map_pages::<Size2MiB>(mapper, virt, phys, count, flags),
+        PageSize::Size1G => map_pages::<Size1GiB>(mapper, virt, phys, count, flags),
+    }
+}
+
+fn unmap_pages<S>(mapper: &mut OffsetPageTable, virt: VirtAddr, count: u64) -> Result<(), PagingError>
+where
+    S: x86_64::structures::paging::PageSize,
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    for i in 0..count {
+        let page = Page::<S>::from_start_address(virt + i * S::SIZE).map_err(|_| PagingError::Misaligned)?;
+        let (_, flush) = mapper.unmap(page).map_err(unmap_error)?;
+        flush.ignore();
+    }
+
+    Ok(())
+}
+
+//flushes a range from the TLB. only one CPU runs the kernel, so there is no other TLB to shoot
+//down.
+fn flush(virt: VirtAddr, size: u64) {
+    if size / SIZE_4K > FLUSH_ALL_THRESHOLD {
+        tlb::flush_all();
+    } else {
+        let mut offset = 0;
+        while offset < size {
+            tlb::flush(virt + offset);
+            offset += SIZE_4K;
+        }
+    }
+}
+
+fn with_paging<T>(f: impl FnOnce(&mut Paging) -> Result<T, PagingError>) -> Result<T, PagingError> {
+    without_interrupts(|| match PAGING.lock().as_mut() {
+        Some(paging) => f(paging),
+        None => Err(PagingError::NotInitialized),
+    })
+}
+
+//the number of pages in `size`, which has to be a whole number of them
+fn page_count(virt: u64, size: u64, page_size: PageSize) -> Result<u64, PagingError> {
+    let bytes = page_size.bytes();
+    if !virt.is_multiple_of(bytes) || !size.is_multiple_of(bytes) {
+        return Err(PagingError::Misaligned);
+    }
+    Ok(size / bytes)
+}
+
+fn cpu_features() -> (bool, bool, bool) {
+    let max_extended = __cpuid(0x8000_0000).eax;
+    let extended = if max_extended >= 0x8000_0001 { __cpuid(0x8000_0001).edx } else { 0 };
+    let pat = __cpuid(1).edx & (1 << 16) != 0;
+
+    (extended & (1 << 26) != 0, extended & (1 << 20) != 0, pat)
+}
+
+fn set_pat() {
+    //the caches have to be flushed around a PAT change
+    unsafe {
+        asm!("wbinvd", options(nostack, preserves_flags));
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+        asm!("wbinvd", options(nostack, preserves_flags));
+    }
+    tlb::flush_all();
+}
+
+/// Builds the kernel's page tables and switches to them, then unmaps the guard pages and hands
+/// the firmware's boot services memory to the frame allocator. Has to run after the frame
+/// allocator is set up, and before anything else maps memory.
+pub fn init(args: &'static KernelArgs) -> Result<PagingInfo, PagingError> {
+    let (gigabyte_pages, no_execute, pat) = cpu_features();
+
+    unsafe {
+        if no_execute {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+
+        //make read only pages read only for the kernel too
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    if pat {
+        without_interrupts(set_pat);
+    }
+
+    //all RAM the memory map describes, and at least the whole 32 bit address space. device
+    //memory above 4 GiB is left to map_mmio, so it gets the right cache mode.
+    let memmap = args.memmap();
+    let is_ram = |ty: u32| {
+        !matches!(ty, memory_type::RESERVED | memory_type::UNUSABLE | memory_type::MMIO | memory_type::MMIO_PORT_SPACE)
+    };
+    let identity_end = memmap
+        .iter()
+        .filter(|e| is_ram(e.ty))
+        .map(|e| (e.base + e.pages * SIZE_4K as usize) as u64)
+        .fold(FOUR_GIB, u64::max)
+        .next_multiple_of(SIZE_2M);
+
+    //the MMIO hole starts above the last RAM below 4 GiB
+    let mmio_hole = memmap
+        .iter()
+        .filter(|e| is_ram(e.ty))
+        .map(|e| (e.base + e.pages * SIZE_4K as usize) as u64)
+        .filter(|&end| end <= FOUR_GIB)
+        .max()
+        .unwrap_or(0)
+        .next_multiple_of(SIZE_2M);
+
+    //the ranges that have to be mapped with 4K pages
+    let (kernel_base, kernel_size) = args.get_kernel_image();
+    let (stack_base, stack_size) = args.get_stack();
+    let fine = [
+        (0, SIZE_2M),
+        (kernel_base as u64, kernel_base as u64 + kernel_size as u64),
+        (stack_base as u64, stack_base as u64 + stack_size as u64),
+    ];
+    let is_fine = |start: u64, size: u64| fine.iter().any(|&(s, e)| start < e && s < start + size);
+    let in_hole = |addr: u64| (mmio_hole..FOUR_GIB).contains(&addr);
+
+    let pml4 = frame::alloc_frame().map_err(PagingError::Frame)?;
+    unsafe { (pml4.start_address().as_u64() as *mut PageTable).write(PageTable::new()) };
+    let mut table = mapper(pml4);
+
+    let mut addr = 0;
+    while addr < identity_end {
+        let cache = if in_hole(addr) { CacheMode::Uncached } else { CacheMode::WriteBack };
+        let flags = flags(Protection::READ_WRITE_EXECUTE, cache, no_execute);
+        let phys = PhysAddr::new(addr);
+        let virt = VirtAddr::new(addr);
+
+        //a page can only be big if it is aligned, has one cache mode, and needs no 4K pages
+        let fits = |size: u64| {
+            addr.is_multiple_of(size)
+                && addr + size <= identity_end
+                && in_hole(addr) == in_hole(addr + size - 1)
+                && !is_fine(addr, size)
+        };
+
+        let size = if gigabyte_pages && fits(SIZE_1G) {
+            PageSize::Size1G
+        } else if fits(SIZE_2M) {
+            PageSize::Size2M
+        } else {
+            PageSize::Size4K
+        };
+
+        //page 0 stays unmapped, to catch null pointers
+        if addr != 0 {
+            map_sized(&mut table, virt, phys, 1, size, flags)?;
+        }
+        addr += size.bytes();
+    }
+
+    without_interrupts(|| {
+        unsafe { Cr3::write(pml4, Cr3Flags::empty()) };
+        *PAGING.lock() = Some(Paging {
+            pml4,
+            vspace: VirtualSpace::new(KERNEL_VA_BASE, KERNEL_VA_SIZE),
+            no_execute,
+        });
+    });
+
+    //the IST stacks and the boot stack get their guard pages now that they can be unmapped
+    for index in [IstIndex::DoubleFault, IstIndex::Nmi, IstIndex::MachineCheck] {
+        set_guard_page(VirtAddr::new(gdt::guard_page(index) as u64))?;
+    }
+    set_guard_page(VirtAddr::new(stack_base as u64))?;
+
+    //nothing uses the firmware's page tables any more
+    let reclaimed_frames = frame::reclaim_boot_services().map_err(PagingError::Frame)?;
+
+    Ok(PagingInfo {
+        identity_end,
+        mmio_hole,
+        gigabyte_pages,
+        no_execute,
+        pat,
+        reclaimed_frames,
+    })
+}
+
+/// Maps `size` bytes at `virt` to `phys`, with pages of `page_size`. Both addresses and the
+/// size have to be multiples of the page size.
+pub fn map(virt: VirtAddr, phys: PhysAddr, size: u64, page_size: PageSize, protection: Protection, cache: CacheMode) -> Result<(), PagingError> {
+    let count = page_count(virt.as_u64(), size, page_size)?;
+    if !phys.as_u64().is_multiple_of(page_size.bytes()) {
+        return Err(PagingError::Misaligned);
+    }
+    if page_size == PageSize::Size1G && !cpu_features().0 {
+        return Err(PagingError::Unsupported);
+    }
+
+    with_paging(|paging| {
+        let flags = flags(protection, cache, paging.no_execute);
+        map_sized(&mut mapper(paging.pml4), virt, phys, count, page_size, flags)
+    })?;
+
+    flush(virt, size);
+    Ok(())
+}
+
+/// Unmaps `size` bytes at `virt`, which were mapped with pages of `page_size`. The frames
+/// behind them are not freed.
+pub fn unmap(virt: VirtAddr, size: u64, page_size: PageSize) -> Result<(), PagingError> {
+    let count = page_count(virt.as_u64(), size, page_size)?;
+
+    let result = with_paging(|paging| {
+        let mut table = mapper(paging.pml4);
+        match page_size {
+            PageSize::Size4K => unmap_pages::<Size4KiB>(&mut table, virt, count),
+            PageSize::Size2M => unmap_pages::<Size2MiB>(&mut table, virt, count),
+            PageSize::Size1G => unmap_pages::<Size1GiB>(&mut table, virt, count),
+        }
+    });
+
+    //flush even on an error, some of the pages may have been unmapped already
+    flush(virt, size);
+    result
+}
+
+/// The physical address `virt` is mapped to
+pub fn translate(virt: VirtAddr) -> Option<PhysAddr> {
+    with_paging(|paging| Ok(mapper(paging.pml4).translate_addr(virt))).ok().flatten()
+}
+
+/// Unmaps one 4K page, so any access to it faults. The frame behind it stays with its owner.
+pub fn set_guard_page(virt: VirtAddr) -> Result<(), PagingError> {
+    unmap(virt, SIZE_4K, PageSize::Size4K)
+}
+
+/// Reserves `size` bytes of kernel virtual address space without mapping anything into it
+pub fn alloc_virtual(size: u64, align: u64) -> Result<VirtAddr, PagingError> {
+    with_paging(|paging| paging.vspace.alloc(size, align).map_err(PagingError::VirtualSpace))
+}
+
+/// Gives back address space from `alloc_virtual`. Whatever was mapped into it has to be
+/// unmapped first.
+pub fn free_virtual(virt: VirtAddr, size: u64) -> Result<(), PagingError> {
+    with_paging(|paging| paging.vspace.free(virt, size).map_err(PagingError::VirtualSpace))
+}
+
+/// Backs `pages` 4K pages at `virt` with newly allocated frames. On failure nothing stays
+/// mapped.
+pub fn map_frames(virt: VirtAddr, pages: usize, protection: Protection) -> Result<(), PagingError> {
+    for i in 0..pages as u64 {
+        let page = virt + i * SIZE_4K;
+        let mapped = frame::alloc_frame().map_err(PagingError::Frame).and_then(|frame| {
+            map(page, frame.start_address(), SIZE_4K, PageSize::Size4K, protection, CacheMode::WriteBack)
+                .inspect_err(|_| {
+                    let _ = frame::free_frame(frame);
+                })
+        });
+
+        if let Err(e) = mapped {
+            unmap_frames(virt, i as usize);
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Unmaps `pages` 4K pages at `virt` and frees the frames behind them. Pages that aren't
+/// mapped are skipped.
+pub fn unmap_frames(virt: VirtAddr, pages: usize) {
+    for i in 0..pages as u64 {
+        let page = virt + i * SIZE_4K;
+        if let Some(phys) = translate(page) {
+            let _ = unmap(page, SIZE_4K, PageSize::Size4K);
+            let _ = frame::free_frame(PhysFrame::containing_address(phys));
+        }
+    }
+}
+
+/// Maps device memory into the kernel address space, and returns the virtual address of
+/// `phys`. The range doesn't have to be page aligned.
+pub fn map_mmio(phys: PhysAddr, size: u64, cache: CacheMode) -> Result<VirtAddr, PagingError> {
+    let offset = phys.as_u64() % SIZE_4K;
+    let length = (offset + size).next_multiple_of(SIZE_4K);
+
+    let virt = alloc_virtual(length, SIZE_4K)?;
+    if let Err(e) = map(virt, phys.align_down(SIZE_4K), length, PageSize::Size4K, Protection::READ_WRITE, cache) {
+        let _ = free_virtual(virt, length);
+        return Err(e);
+    }
+
+    Ok(virt + offset)
+}
+
+/// Removes a mapping made by `map_mmio`, with the same address and size it returned and took
+pub fn unmap_mmio(virt: VirtAddr, size: u64) -> Result<(), PagingError> {
+    let offset = virt.as_u64() % SIZE_4K;
+    let length = (offset + size).next_multiple_of(SIZE_4K);
+    let base = virt.align_down(SIZE_4K);
+
+    unmap(base, length, PageSize::Size4K)?;
+    free_virtual(base, length)
+}
+
+/// The number of free bytes left in the kernel virtual address space
+pub fn free_virtual_space() -> u64 {
+    with_paging(|paging| Ok(paging.vspace.free_bytes())).unwrap_or(0)
+}
@@ -0,0 +1,144 @@
+use x86_64::VirtAddr;
+
+/*
+ * Hands out ranges of the kernel's virtual address space, for MMIO mappings and the heap. The
+ * free space is kept as a sorted list of ranges; allocation is first fit, and freed ranges are
+ * merged with their neighbours so the list stays short.
+ */
+
+/// The start of the kernel's dynamically allocated virtual address space. This is the first
+/// address in PML4 entry 384, well away from the identity map.
+pub const KERNEL_VA_BASE: u64 = 0xFFFF_C000_0000_0000;
+
+/// The size of the kernel's dynamically allocated virtual address space, one PML4 entry
+pub const KERNEL_VA_SIZE: u64 = 1 << 39;
+
+//the most separate free ranges that can be tracked. a free that would need more is refused.
+const MAX_FREE_RANGES: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VspaceError {
+    /// No free range is big enough
+    Exhausted,
+
+    /// The free list is full, so the range can't be given back
+    TooFragmented,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Range {
+    start: u64,
+    end: u64,
+}
+
+pub struct VirtualSpace {
+    free: [Range; MAX_FREE_RANGES],
+    count: usize,
+}
+
+impl VirtualSpace {
+    /// A space with all of `start..start + size` free
+    pub const fn new(start: u64, size: u64) -> Self {
+        let mut free = [Range { start: 0, end: 0 }; MAX_FREE_RANGES];
+        free[0] = Range { start, end: start + size };
+        VirtualSpace { free, count: 1 }
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.free.copy_within(index + 1..self.count, index);
+        self.count -= 1;
+    }
+
+    fn insert(&mut self, index: usize, range: Range) -> Result<(), VspaceError> {
+        if self.count == MAX_FREE_RANGES {
+            return Err(VspaceError::TooFragmented);
+        }
+
+        self.free.copy_within(index..self.count, index + 1);
+        self.free[index] = range;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Allocates `size` bytes aligned to `align`, which has to be a power of two
+    pub fn alloc(&mut self, size: u64, align: u64) -> Result<VirtAddr, VspaceError> {
+        for i in 0..self.count {
+            let range = self.free[i];
+            let start = range.start.next_multiple_of(align);
+            if start >= range.end || range.end - start < size {
+                continue;
+            }
+
+            //the allocation can leave a gap on either side of it
+            let before = Range { start: range.start, end: start };
+            let after = Range { start: start + size, end: range.end };
+            match (before.start < before.end, after.start < after.end) {
+                (false, false) => self.remove(i),
+                (true, false) => self.free[i] = before,
+                (false, true) => self.free[i] = after,
+                (true, true) => {
+                    self.free[i] = before;
+                    self.insert(i + 1, after)?;
+                }
+            }
+
+            return Ok(VirtAddr::new(start));
+        }
+
+        Err(VspaceError::Exhausted)
+    }
+
+    /// Gives back a range from `alloc`
+    pub fn free(&mut self, addr: VirtAddr, size: u64) -> Result<(), VspaceError> {
+        let start = addr.as_u64();
+        let end = start + size;
+        let index = self.free[..self.count].partition_point(|r| r.start < start);
+
+        let joins_prev = index > 0 && self.free[index - 1].end == start;
+        let joins_next = index < self.count && self.free[index].start == end;
+        match (joins_prev, joins_next) {
+            (true, true) => {
+                self.free[index - 1].end = self.free[index].end;
+                self.remove(index);
+            }
+            (true, false) => self.free[index - 1].end = end,
+            (false, true) => self.free[index].start = start,
+            (false, false) => self.insert(index, Range { start, end })?,
+        }
+
+        Ok(())
+    }
+
+    /// The number of free bytes
+    pub fn free_bytes(&self) -> u64 {
+        self.free[..self.count].iter().map(|r| r.end - r.start).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn alloc_aligns_and_free_coalesces() {
+        let mut space = VirtualSpace::new(0x1000, 0x10000);
+        let a = space.alloc(0x1000, 0x1000).unwrap();
+        let b = space.alloc(0x2000, 0x4000).unwrap();
+        assert_eq!(a.as_u64(), 0x1000);
+        assert_eq!(b.as_u64(), 0x4000);
+        assert_eq!(space.free_bytes(), 0xD000);
+
+        space.free(a, 0x1000).unwrap();
+        space.free(b, 0x2000).unwrap();
+        assert_eq!(space.free_bytes(), 0x10000);
+        assert_eq!(space.count, 1);
+    }
+
+    #[test_case]
+    fn alloc_fails_once_exhausted() {
+        let mut space = VirtualSpace::new(0x1000, 0x4000);
+        assert_eq!(space.alloc(0x8000, 0x1000), Err(VspaceError::Exhausted));
+        space.alloc(0x4000, 0x1000).unwrap();
+        assert_eq!(space.alloc(0x1000, 0x1000), Err(VspaceError::Exhausted));
+    }
+}
@@ -0,0 +1,97 @@
+use log::{info, warn};
+
+use crate::drivers::net::NetError;
+use packet::PacketError;
+
+pub mod arp;
+pub mod ethernet;
+pub mod icmp;
+pub mod interface;
+pub mod ipv4;
+pub mod packet;
+
+use interface::{Interface, InterfaceEvent};
+
+/*
+ * The network stack. NIC drivers live in drivers::net; this is everything above them, starting
+ * with the interface table. Received frames are polled from the main loop and handed up through
+ * the layers, each of which pulls its header off and passes the rest on.
+ */
+
+//the most frames taken from one interface per poll, so a busy one can't starve the others
+const RX_BUDGET: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// The interface is administratively down or has no link
+    Down,
+
+    /// There is no route to the destination
+    NoRoute,
+
+    /// The next hop's link layer address could not be resolved
+    Unreachable,
+
+    /// The packet is bigger than the MTU, given here, and may not be fragmented
+    TooBig(usize),
+
+    /// The packet has no headroom left, or the pool is out of buffers
+    NoBuffers,
+
+    /// The NIC refused the frame
+    Device(NetError),
+}
+
+impl From<NetError> for SendError {
+    fn from(e: NetError) -> Self {
+        SendError::Device(e)
+    }
+}
+
+impl From<PacketError> for SendError {
+    fn from(_: PacketError) -> Self {
+        SendError::NoBuffers
+    }
+}
+
+//puts every interface event in the log, so link flaps can be traced afterwards
+fn log_event(interface: &'static Interface, event: InterfaceEvent) {
+    info!("net: {}: {:?}", interface.name(), event);
+}
+
+/// Names the NICs the drivers claimed, starts logging interface events and sets up the
+/// protocols. Returns how many interfaces there are.
+pub fn init() -> usize {
+    if let Err(e) = interface::subscribe(log_event) {
+        warn!("net: interface events won't be logged: {:?}", e);
+    }
+    let count = interface::init();
+
+    if let Err(e) = arp::init() {
+        warn!("net: ARP could not subscribe to interface events: {:?}", e);
+    }
+    if let Err(e) = icmp::init() {
+        warn!("net: ICMP could not be registered: {:?}", e);
+    }
+    count
+}
+
+/// The network stack's share of the kernel's main loop
+pub fn poll() {
+    for interface in interface::interfaces() {
+        let device = interface.device();
+        for _ in 0..RX_BUDGET {
+            let Some((frame, info)) = device.receive() else {
+                break;
+            };
+            //a NIC that is down still has to be drained
+            if interface.is_admin_up() {
+                ethernet::receive(interface, frame, info);
+            }
+        }
+    }
+
+    interface::poll_links();
+    arp::poll();
+    ipv4::poll();
+}
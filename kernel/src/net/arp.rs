@@ -0,0 +1,828 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::net::Ipv4Addr;
+use core::time::Duration;
+use log::warn;
+use spin::Mutex;
+
+use super::ethernet::{self, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use super::icmp::{self, ErrorMessage};
+use super::interface::{self, Interface, InterfaceError, InterfaceEvent};
+use super::packet::Packet;
+use super::SendError;
+use crate::drivers::net::{MacAddress, TxChecksum};
+use crate::interrupt::interrupt::without_interrupts;
+use crate::time::Instant;
+
+/*
+ * ARP, which finds the MAC address of an IPv4 neighbor, and the neighbor cache it fills.
+ *
+ * A neighbor starts out incomplete, with requests broadcast once a second and the packets for
+ * it queued. A reply makes it reachable and sends them. A reachable neighbor goes stale after a
+ * while; stale ones are still used, but the first use sends unicast requests to check the
+ * neighbor is still there. Neighbors that never answer are failed for a while, so senders learn
+ * quickly, and then forgotten, as are stale ones nobody used. Static entries stay until removed.
+ *
+ * Every address put on an interface is checked first, by probing for it and giving it up if
+ * another station answers (duplicate address detection). Once it is ours it is announced with
+ * gratuitous ARP, which also happens whenever the link comes back, and defended against
+ * stations that claim it later.
+ *
+ * With proxy ARP on, an interface answers requests for addresses on the networks of the other
+ * interfaces, for hosts that think those are on their own network.
+ *
+ * Built with help from:
+ * RFC 826 "An Ethernet Address Resolution Protocol"
+ * RFC 5227 "IPv4 Address Conflict Detection"
+ * RFC 1027 "Using ARP to Implement Transparent Subnet Gateways"
+ * RFC 4861, 7.3 "Neighbor Unreachability Detection", for the states
+ */
+
+pub const PACKET_SIZE: usize = 28;
+
+const HARDWARE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+
+/// The most neighbors kept, over every interface
+pub const MAX_NEIGHBORS: usize = 1024;
+
+/// The most packets queued for a neighbor being resolved. The oldest goes first.
+pub const PENDING_LIMIT: usize = 3;
+
+const REACHABLE_TIME: Duration = Duration::from_secs(30);
+const STALE_TIME: Duration = Duration::from_secs(10 * 60);
+const FAILED_TIME: Duration = Duration::from_secs(20);
+const RETRANSMIT: Duration = Duration::from_secs(1);
+const MAX_PROBES: u32 = 3;
+
+//RFC 5227 timing, without the random parts
+const PROBE_NUM: u32 = 3;
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+const ANNOUNCE_WAIT: Duration = Duration::from_secs(2);
+const ANNOUNCE_NUM: u32 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+const DEFEND_INTERVAL: Duration = Duration::from_secs(10);
+
+//how often the timers are looked at
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArpError {
+    /// The table is full of static entries
+    TableFull,
+
+    /// There is no entry for that address
+    NoSuchEntry,
+
+    /// A multicast MAC address, or an IPv4 address that can't be a neighbor's
+    InvalidAddress,
+}
+
+/// An ARP packet for Ethernet and IPv4, the only kind there is in practice
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data = data.get(..PACKET_SIZE)?;
+        let field = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+        if field(0) != HARDWARE_ETHERNET || field(2) != ETHERTYPE_IPV4 || data[4] != 6 || data[5] != 4 {
+            return None;
+        }
+
+        let mac = |at: usize| MacAddress(data[at..at + 6].try_into().unwrap());
+        let ip = |at: usize| Ipv4Addr::new(data[at], data[at + 1], data[at + 2], data[at + 3]);
+        Some(ArpPacket {
+            operation: field(6),
+            sender_mac: mac(8),
+            sender_ip: ip(14),
+            target_mac: mac(18),
+            target_ip: ip(24),
+        })
+    }
+
+    pub fn write(&self, out: &mut [u8]) {
+        out[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        out[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        out[4] = 6;
+        out[5] = 4;
+        out[6..8].copy_from_slice(&self.operation.to_be_bytes());
+        out[8..14].copy_from_slice(&self.sender_mac.0);
+        out[14..18].copy_from_slice(&self.sender_ip.octets());
+        out[18..24].copy_from_slice(&self.target_mac.0);
+        out[24..28].copy_from_slice(&self.target_ip.octets());
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NeighborState {
+    /// Being resolved
+    Incomplete,
+
+    /// Heard from recently
+    Reachable,
+
+    /// Not heard from for a while. Still used, but checked on first use.
+    Stale,
+
+    /// Didn't answer. Sends to it fail until it is forgotten.
+    Failed,
+
+    /// Put in by hand, and never aged
+    Static,
+}
+
+impl NeighborState {
+    fn name(&self) -> &'static str {
+        match self {
+            NeighborState::Incomplete => "incomplete",
+            NeighborState::Reachable => "reachable",
+            NeighborState::Stale => "stale",
+            NeighborState::Failed => "failed",
+            NeighborState::Static => "static",
+        }
+    }
+}
+
+struct Neighbor {
+    interface: usize,
+    address: Ipv4Addr,
+    mac: MacAddress,
+    state: NeighborState,
+
+    //when the state last changed
+    updated: Instant,
+
+    //the requests sent since the neighbor was last heard from
+    probes: u32,
+    last_probe: Instant,
+
+    //the packets waiting for the neighbor to be resolved
+    pending: Vec<(Packet, Option<TxChecksum>)>,
+}
+
+impl Neighbor {
+    fn new(interface: usize, address: Ipv4Addr, mac: MacAddress, state: NeighborState) -> Self {
+        let now = Instant::now();
+        Neighbor { interface, address, mac, state, updated: now, probes: 0, last_probe: now, pending: Vec::new() }
+    }
+
+    //takes what an ARP packet from the neighbor says, and returns the packets that were
+    //waiting for it. only a reply to us confirms the neighbor is reachable.
+    fn learn(&mut self, mac: MacAddress, confirmed: bool) -> Vec<(Packet, Option<TxChecksum>)> {
+        if self.state == NeighborState::Static {
+            return Vec::new();
+        }
+
+        let changed = self.mac != mac;
+        self.mac = mac;
+        if confirmed {
+            self.set_state(NeighborState::Reachable);
+        } else if changed || matches!(self.state, NeighborState::Incomplete | NeighborState::Failed) {
+            self.set_state(NeighborState::Stale);
+        }
+        core::mem::take(&mut self.pending)
+    }
+
+    fn set_state(&mut self, state: NeighborState) {
+        self.state = state;
+        self.updated = Instant::now();
+        self.probes = 0;
+    }
+}
+
+/// A neighbor cache entry, as the table dump shows it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NeighborEntry {
+    pub interface: usize,
+    pub address: Ipv4Addr,
+
+    /// None until the neighbor is resolved
+    pub mac: Option<MacAddress>,
+    pub state: NeighborState,
+
+    /// The time since the state last changed
+    pub age: Duration,
+
+    /// The packets waiting for the neighbor
+    pub pending: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ClaimPhase {
+    //probing for the address, with this many probes sent
+    Probing(u32),
+
+    //announcing the address, with this many announcements sent
+    Announcing(u32),
+    Bound,
+}
+
+//one of our addresses, as duplicate address detection sees it
+struct Claim {
+    interface: usize,
+    address: Ipv4Addr,
+    phase: ClaimPhase,
+
+    //when the next probe or announcement is due
+    next: Instant,
+    last_defense: Option<Instant>,
+}
+
+static NEIGHBORS: Mutex<Vec<Neighbor>> = Mutex::new(Vec::new());
+static CLAIMS: Mutex<Vec<Claim>> = Mutex::new(Vec::new());
+static LAST_POLL: Mutex<Option<Instant>> = Mutex::new(None);
+
+//the interfaces that answer for the networks of the others
+static PROXY: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// The MAC address an IPv4 multicast group is sent to: 01:00:5e and the low 23 bits of the
+/// group
+pub fn multicast_mac(group: Ipv4Addr) -> MacAddress {
+    let [_, b, c, d] = group.octets();
+    MacAddress([0x01, 0x00, 0x5E, b & 0x7F, c, d])
+}
+
+fn find(neighbors: &mut [Neighbor], interface: usize, address: Ipv4Addr) -> Option<&mut Neighbor> {
+    neighbors.iter_mut().find(|neighbor| neighbor.interface == interface && neighbor.address == address)
+}
+
+//adds a neighbor, making room by forgetting the one whose state changed longest ago. fails if
+//every entry is static.
+fn insert(neighbors: &mut Vec<Neighbor>, neighbor: Neighbor) -> bool {
+    if neighbors.len() >= MAX_NEIGHBORS {
+        let oldest = neighbors
+            .iter()
+            .enumerate()
+            .filter(|(_, other)| other.state != NeighborState::Static)
+            .min_by_key(|(_, other)| other.updated)
+            .map(|(i, _)| i);
+        match oldest {
+            Some(i) => drop(neighbors.swap_remove(i)),
+            None => return false,
+        }
+    }
+    neighbors.push(neighbor);
+    true
+}
+
+fn send_arp(interface: &Interface, destination: MacAddress, arp: ArpPacket) {
+    let Ok(mut packet) = Packet::new() else {
+        return;
+    };
+    let Ok(data) = packet.put(PACKET_SIZE) else {
+        return;
+    };
+    arp.write(data);
+    let _ = ethernet::send(interface, destination, ETHERTYPE_ARP, packet, None);
+}
+
+fn request(interface: &Interface, destination: MacAddress, sender_ip: Ipv4Addr, target_ip: Ipv4Addr) {
+    send_arp(interface, destination, ArpPacket {
+        operation: OP_REQUEST,
+        sender_mac: interface.device().mac(),
+        sender_ip,
+        target_mac: MacAddress::default(),
+        target_ip,
+    });
+}
+
+//a gratuitous ARP request, which updates every cache that has the address
+fn announce(interface: &Interface, address: Ipv4Addr) {
+    request(interface, MacAddress::BROADCAST, address, address);
+}
+
+//the address to ask from
+fn source_for(interface: &Interface, target: Ipv4Addr) -> Ipv4Addr {
+    interface.ipv4_source(target).unwrap_or(Ipv4Addr::UNSPECIFIED)
+}
+
+fn send_pending(interface: &Interface, mac: MacAddress, pending: Vec<(Packet, Option<TxChecksum>)>) {
+    for (packet, checksum) in pending {
+        let _ = ethernet::send(interface, mac, ETHERTYPE_IPV4, packet, checksum);
+    }
+}
+
+enum Resolution {
+    Send(MacAddress),
+
+    //send, and check the neighbor is still there
+    SendAndProbe(MacAddress),
+    Queued,
+    Request,
+    Unreachable,
+}
+
+/// Sends an IPv4 packet to `next_hop`, a neighbor on `interface`'s link. If the neighbor isn't
+/// resolved yet the packet waits for it, and Ok means only that it was queued.
+pub fn send_ipv4(
+    interface: &'static Interface,
+    next_hop: Ipv4Addr,
+    packet: Packet,
+    checksum: Option<TxChecksum>,
+) -> Result<(), SendError> {
+    if !interface.is_up() {
+        return Err(SendError::Down);
+    }
+
+    let subnet_broadcast = interface.ipv4_addresses().iter().any(|cidr| cidr.prefix_len < 31 && cidr.broadcast() == next_hop);
+    if next_hop.is_broadcast() || subnet_broadcast {
+        return ethernet::send(interface, MacAddress::BROADCAST, ETHERTYPE_IPV4, packet, checksum);
+    }
+    if next_hop.is_multicast() {
+        return ethernet::send(interface, multicast_mac(next_hop), ETHERTYPE_IPV4, packet, checksum);
+    }
+
+    let index = interface.index();
+    let mut packet = Some(packet);
+    let resolution = without_interrupts(|| {
+        let mut neighbors = NEIGHBORS.lock();
+        let Some(neighbor) = find(&mut neighbors, index, next_hop) else {
+            let mut neighbor = Neighbor::new(index, next_hop, MacAddress::default(), NeighborState::Incomplete);
+            neighbor.probes = 1;
+            if !insert(&mut neighbors, neighbor) {
+                return Resolution::Unreachable;
+            }
+
+            //the packet only moves into the entry once it is in the table, so a full table
+            //leaves it here for the Host Unreachable. insert puts new entries at the end.
+            if let Some(neighbor) = neighbors.last_mut() {
+                neighbor.pending.push((packet.take().unwrap(), checksum));
+            }
+            return Resolution::Request;
+        };
+
+        match neighbor.state {
+            NeighborState::Reachable | NeighborState::Static => Resolution::Send(neighbor.mac),
+            NeighborState::Stale if neighbor.probes == 0 => {
+                neighbor.probes = 1;
+                neighbor.last_probe = Instant::now();
+                Resolution::SendAndProbe(neighbor.mac)
+            }
+            NeighborState::Stale => Resolution::Send(neighbor.mac),
+            NeighborState::Incomplete => {
+                if neighbor.pending.len() >= PENDING_LIMIT {
+                    neighbor.pending.remove(0);
+                }
+                neighbor.pending.push((packet.take().unwrap(), checksum));
+                Resolution::Queued
+            }
+            NeighborState::Failed => Resolution::Unreachable,
+        }
+    });
+
+    match resolution {
+        Resolution::Send(mac) => ethernet::send(interface, mac, ETHERTYPE_IPV4, packet.take().unwrap(), checksum),
+        Resolution::SendAndProbe(mac) => {
+            request(interface, mac, source_for(interface, next_hop), next_hop);
+            ethernet::send(interface, mac, ETHERTYPE_IPV4, packet.take().unwrap(), checksum)
+        }
+        Resolution::Queued => Ok(()),
+        Resolution::Request => {
+            request(interface, MacAddress::BROADCAST, source_for(interface, next_hop), next_hop);
+            Ok(())
+        }
+        Resolution::Unreachable => {
+            if let Some(packet) = packet {
+                icmp::send_error(ErrorMessage::HostUnreachable, &packet);
+            }
+            Err(SendError::Unreachable)
+        }
+    }
+}
+
+/// Whether `address` is on `interface` but still being checked for duplicates. Tentative
+/// addresses aren't answered for.
+pub fn is_tentative(interface: &Interface, address: Ipv4Addr) -> bool {
+    without_interrupts(|| {
+        CLAIMS.lock().iter().any(|claim| {
+            claim.interface == interface.index() && claim.address == address && matches!(claim.phase, ClaimPhase::Probing(_))
+        })
+    })
+}
+
+enum Conflict {
+    None,
+
+    //another station had the address before we were done probing for it
+    Lost,
+    Defend,
+
+    //defended too recently to do it again
+    Repeated,
+}
+
+//checks whether the packet shows another station using one of our addresses. returns whether
+//the packet was dealt with.
+fn check_conflict(interface: &'static Interface, arp: &ArpPacket) -> bool {
+    //a probe, from a station checking the address for itself
+    let probe = arp.sender_ip.is_unspecified();
+    let address = if probe { arp.target_ip } else { arp.sender_ip };
+
+    let conflict = without_interrupts(|| {
+        let mut claims = CLAIMS.lock();
+        let Some(claim) = claims.iter_mut().find(|claim| claim.interface == interface.index() && claim.address == address) else {
+            return Conflict::None;
+        };
+        match claim.phase {
+            ClaimPhase::Probing(_) => Conflict::Lost,
+            //a probe for an address we have is answered like any request
+            _ if probe => Conflict::None,
+            _ if claim.last_defense.is_some_and(|at| at.elapsed() < DEFEND_INTERVAL) => Conflict::Repeated,
+            _ => {
+                claim.last_defense = Some(Instant::now());
+                Conflict::Defend
+            }
+        }
+    });
+
+    match conflict {
+        Conflict::None => return false,
+        Conflict::Lost => {
+            warn!("arp: {} is in use on {} by {}, giving it up", address, interface.name(), arp.sender_mac);
+            let _ = interface.remove_ipv4(address);
+        }
+        Conflict::Defend => {
+            warn!("arp: {} on {} is claimed by {}, defending it", address, interface.name(), arp.sender_mac);
+            announce(interface, address);
+        }
+        Conflict::Repeated => {
+            warn!("arp: {} on {} is still claimed by {}", address, interface.name(), arp.sender_mac);
+        }
+    }
+    true
+}
+
+fn proxy_enabled(interface: &Interface) -> bool {
+    without_interrupts(|| PROXY.lock().contains(&interface.index()))
+}
+
+//whether the interface answers for `target` as a proxy: it is on the network of another
+//interface that is up, and not on this one's
+fn proxies(interface: &Interface, target: Ipv4Addr) -> bool {
+    if !proxy_enabled(interface) || interface.ipv4_addresses().iter().any(|cidr| cidr.contains(target)) {
+        return false;
+    }
+    interface::interfaces().into_iter().any(|other| {
+        other.index() != interface.index() && other.is_up() && other.ipv4_addresses().iter().any(|cidr| cidr.contains(target))
+    })
+}
+
+/// Handles an ARP packet received on `interface`
+pub fn receive(interface: &'static Interface, packet: Packet) {
+    let Some(arp) = ArpPacket::parse(packet.data()) else {
+        return;
+    };
+    let mac = interface.device().mac();
+    //our own, looped back, and nonsense
+    if arp.sender_mac == mac || arp.sender_mac.is_multicast() || arp.sender_ip.is_broadcast() || arp.sender_ip.is_multicast() {
+        return;
+    }
+    if check_conflict(interface, &arp) {
+        return;
+    }
+
+    let index = interface.index();
+    let for_us = interface.has_ipv4(arp.target_ip) && !is_tentative(interface, arp.target_ip);
+
+    //RFC 826: update the sender if it is known, and add it if the packet is for us
+    if !arp.sender_ip.is_unspecified() {
+        let confirmed = for_us && arp.operation == OP_REPLY;
+        let pending = without_interrupts(|| {
+            let mut neighbors = NEIGHBORS.lock();
+            match find(&mut neighbors, index, arp.sender_ip) {
+                Some(neighbor) => neighbor.learn(arp.sender_mac, confirmed),
+                None => {
+                    if for_us {
+                        let state = if confirmed { NeighborState::Reachable } else { NeighborState::Stale };
+                        insert(&mut neighbors, Neighbor::new(index, arp.sender_ip, arp.sender_mac, state));
+                    }
+                    Vec::new()
+                }
+            }
+        });
+        send_pending(interface, arp.sender_mac, pending);
+    }
+
+    if arp.operation == OP_REQUEST && (for_us || proxies(interface, arp.target_ip)) {
+        send_arp(interface, arp.sender_mac, ArpPacket {
+            operation: OP_REPLY,
+            sender_mac: mac,
+            sender_ip: arp.target_ip,
+            target_mac: arp.sender_mac,
+            target_ip: arp.sender_ip,
+        });
+    }
+}
+
+//starts checking an address of ours over, from the first probe
+fn claim(interface: &Interface, address: Ipv4Addr) {
+    without_interrupts(|| {
+        let mut claims = CLAIMS.lock();
+        claims.retain(|claim| claim.interface != interface.index() || claim.address != address);
+        claims.push(Claim {
+            interface: interface.index(),
+            address,
+            phase: ClaimPhase::Probing(0),
+            next: Instant::now(),
+            last_defense: None,
+        });
+    })
+}
+
+fn unclaim(interface: &Interface, address: Ipv4Addr) {
+    without_interrupts(|| CLAIMS.lock().retain(|claim| claim.interface != interface.index() || claim.address != address))
+}
+
+fn on_interface_event(interface: &'static Interface, event: InterfaceEvent) {
+    match event {
+        InterfaceEvent::Ipv4Added(cidr) => claim(interface, cidr.address),
+        InterfaceEvent::Ipv4Removed(cidr) => unclaim(interface, cidr.address),
+        InterfaceEvent::LinkUp | InterfaceEvent::AdminUp => {
+            for cidr in interface.ipv4_addresses() {
+                claim(interface, cidr.address);
+            }
+        }
+        InterfaceEvent::LinkDown | InterfaceEvent::AdminDown => flush(interface),
+        _ => {}
+    }
+}
+
+/// Starts listening for interface events, and checks the addresses already on the interfaces
+pub fn init() -> Result<(), InterfaceError> {
+    interface::subscribe(on_interface_event)?;
+    for interface in interface::interfaces() {
+        for cidr in interface.ipv4_addresses() {
+            claim(interface, cidr.address);
+        }
+    }
+    Ok(())
+}
+
+//sends the probes and announcements that are due
+fn poll_claims(now: Instant) {
+    let mut due = Vec::new();
+    without_interrupts(|| {
+        for claim in CLAIMS.lock().iter_mut().filter(|claim| claim.next <= now) {
+            let (probe, phase, wait) = match claim.phase {
+                ClaimPhase::Probing(sent) if sent < PROBE_NUM => {
+                    let wait = if sent + 1 == PROBE_NUM { ANNOUNCE_WAIT } else { PROBE_INTERVAL };
+                    (true, ClaimPhase::Probing(sent + 1), wait)
+                }
+                ClaimPhase::Probing(_) => (false, ClaimPhase::Announcing(1), ANNOUNCE_INTERVAL),
+                ClaimPhase::Announcing(sent) if sent < ANNOUNCE_NUM => (false, ClaimPhase::Announcing(sent + 1), ANNOUNCE_INTERVAL),
+                ClaimPhase::Announcing(_) | ClaimPhase::Bound => {
+                    claim.phase = ClaimPhase::Bound;
+                    continue;
+                }
+            };
+            due.push((claim.interface, claim.address, probe));
+            claim.phase = phase;
+            claim.next = now + wait;
+        }
+    });
+
+    for (index, address, probe) in due {
+        let Some(interface) = interface::by_index(index) else {
+            continue;
+        };
+        if probe {
+            request(interface, MacAddress::BROADCAST, Ipv4Addr::UNSPECIFIED, address);
+        } else {
+            announce(interface, address);
+        }
+    }
+}
+
+//ages the neighbors and sends the requests that are due
+fn poll_neighbors(now: Instant) {
+    let mut requests = Vec::new();
+    let mut undeliverable = Vec::new();
+    without_interrupts(|| {
+        NEIGHBORS.lock().retain_mut(|neighbor| {
+            let age = now.duration_since(neighbor.updated);
+            match neighbor.state {
+                NeighborState::Incomplete | NeighborState::Stale
+                    if neighbor.probes > 0 && now.duration_since(neighbor.last_probe) >= RETRANSMIT =>
+                {
+                    if neighbor.probes >= MAX_PROBES {
+                        neighbor.set_state(NeighborState::Failed);
+                        undeliverable.append(&mut neighbor.pending);
+                    } else {
+                        let destination = if neighbor.state == NeighborState::Incomplete { MacAddress::BROADCAST } else { neighbor.mac };
+                        neighbor.probes += 1;
+                        neighbor.last_probe = now;
+                        requests.push((neighbor.interface, destination, neighbor.address));
+                    }
+                    true
+                }
+                NeighborState::Reachable if age >= REACHABLE_TIME => {
+                    neighbor.set_state(NeighborState::Stale);
+                    true
+                }
+                NeighborState::Stale => neighbor.probes > 0 || age < STALE_TIME,
+                NeighborState::Failed => age < FAILED_TIME,
+                _ => true,
+            }
+        })
+    });
+
+    for (index, destination, target) in requests {
+        if let Some(interface) = interface::by_index(index) {
+            request(interface, destination, source_for(interface, target), target);
+        }
+    }
+    for (packet, _) in undeliverable {
+        icmp::send_error(ErrorMessage::HostUnreachable, &packet);
+    }
+}
+
+/// Runs the timers: resolution retries, aging and duplicate address detection. Called from the
+/// network stack's poll, and does nothing if it ran less than 100 ms ago.
+pub fn poll() {
+    let now = Instant::now();
+    let due = without_interrupts(|| {
+        let mut last = LAST_POLL.lock();
+        if last.is_some_and(|at| now.duration_since(at) < POLL_INTERVAL) {
+            return false;
+        }
+        *last = Some(now);
+        true
+    });
+    if due {
+        poll_neighbors(now);
+        poll_claims(now);
+    }
+}
+
+/// Turns proxy ARP on or off for an interface
+pub fn set_proxy(interface: &Interface, on: bool) {
+    without_interrupts(|| {
+        let mut proxy = PROXY.lock();
+        proxy.retain(|index| *index != interface.index());
+        if on {
+            proxy.push(interface.index());
+        }
+    })
+}
+
+/// Puts in an entry that never ages, replacing any learned one
+pub fn add_static(interface: &Interface, address: Ipv4Addr, mac: MacAddress) -> Result<(), ArpError> {
+    if mac.is_multicast() || address.is_unspecified() || address.is_broadcast() || address.is_multicast() {
+        return Err(ArpError::InvalidAddress);
+    }
+
+    let index = interface.index();
+    let pending = without_interrupts(|| {
+        let mut neighbors = NEIGHBORS.lock();
+        let pending = match find(&mut neighbors, index, address) {
+            Some(neighbor) => {
+                let pending = core::mem::take(&mut neighbor.pending);
+                neighbor.mac = mac;
+                neighbor.set_state(NeighborState::Static);
+                pending
+            }
+            None => {
+                if !insert(&mut neighbors, Neighbor::new(index, address, mac, NeighborState::Static)) {
+                    return Err(ArpError::TableFull);
+                }
+                Vec::new()
+            }
+        };
+        Ok(pending)
+    })?;
+    send_pending(interface, mac, pending);
+    Ok(())
+}
+
+/// Removes an entry, static or not
+pub fn remove(interface: &Interface, address: Ipv4Addr) -> Result<(), ArpError> {
+    without_interrupts(|| {
+        let mut neighbors = NEIGHBORS.lock();
+        let index = neighbors
+            .iter()
+            .position(|neighbor| neighbor.interface == interface.index() && neighbor.address == address)
+            .ok_or(ArpError::NoSuchEntry)?;
+        neighbors.swap_remove(index);
+        Ok(())
+    })
+}
+
+/// Forgets every learned neighbor on an interface, dropping the packets waiting for them
+pub fn flush(interface: &Interface) {
+    without_interrupts(|| {
+        NEIGHBORS
+            .lock()
+            .retain(|neighbor| neighbor.interface != interface.index() || neighbor.state == NeighborState::Static)
+    })
+}
+
+/// Every entry in the neighbor cache, ordered by interface and address
+pub fn entries() -> Vec<NeighborEntry> {
+    let now = Instant::now();
+    let mut entries: Vec<NeighborEntry> = without_interrupts(|| {
+        NEIGHBORS
+            .lock()
+            .iter()
+            .map(|neighbor| NeighborEntry {
+                interface: neighbor.interface,
+                address: neighbor.address,
+                mac: match neighbor.state {
+                    NeighborState::Incomplete | NeighborState::Failed => None,
+                    _ => Some(neighbor.mac),
+                },
+                state: neighbor.state,
+                age: now.duration_since(neighbor.updated),
+                pending: neighbor.pending.len(),
+            })
+            .collect()
+    });
+    entries.sort_by_key(|entry| (entry.interface, entry.address));
+    entries
+}
+
+/// Prints the neighbor cache, one neighbor per line
+pub fn report(out: &mut impl Write) -> fmt::Result {
+    let proxy = without_interrupts(|| PROXY.lock().clone());
+    for index in proxy {
+        if let Some(interface) = interface::by_index(index) {
+            writeln!(out, "proxy ARP on {}", interface.name())?;
+        }
+    }
+
+    writeln!(out, "{:<15} {:<17} {:<10} {:<8} {:>7} {:>7}", "address", "MAC", "state", "iface", "age s", "queued")?;
+    for entry in entries() {
+        let mac = entry.mac.map_or(String::from("-"), |mac| format!("{}", mac));
+        let name = interface::by_index(entry.interface).map_or("?", |interface| interface.name());
+        writeln!(out, "{:<15} {:<17} {:<10} {:<8} {:>7} {:>7}",
+            entry.address, mac, entry.state.name(), name, entry.age.as_secs(), entry.pending
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn packet_round_trip() {
+        let arp = ArpPacket {
+            operation: OP_REQUEST,
+            sender_mac: MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+            sender_ip: Ipv4Addr::new(10, 0, 2, 15),
+            target_mac: MacAddress::default(),
+            target_ip: Ipv4Addr::new(10, 0, 2, 2),
+        };
+        let mut bytes = [0; PACKET_SIZE];
+        arp.write(&mut bytes);
+        assert_eq!(&bytes[..8], &[0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+        assert_eq!(ArpPacket::parse(&bytes), Some(arp));
+
+        //only Ethernet and IPv4
+        bytes[1] = 6;
+        assert_eq!(ArpPacket::parse(&bytes), None);
+        assert_eq!(ArpPacket::parse(&bytes[..PACKET_SIZE - 1]), None);
+    }
+
+    #[test_case]
+    fn multicast_macs() {
+        assert_eq!(multicast_mac(Ipv4Addr::new(224, 0, 0, 1)), MacAddress([0x01, 0x00, 0x5E, 0, 0, 1]));
+        //the top bit of the second byte doesn't make it in
+        assert_eq!(multicast_mac(Ipv4Addr::new(239, 255, 1, 2)), MacAddress([0x01, 0x00, 0x5E, 0x7F, 1, 2]));
+        assert!(multicast_mac(Ipv4Addr::new(224, 0, 0, 251)).is_multicast());
+    }
+
+    #[test_case]
+    fn tentative_addresses_are_not_sources() {
+        let interface = interface::by_index(0).expect("the test kernel has a NIC");
+        let address = Ipv4Addr::new(10, 99, 0, 1);
+        let neighbor = Ipv4Addr::new(10, 99, 0, 2);
+        interface.add_ipv4(interface::Ipv4Cidr::new(address, 24).unwrap()).unwrap();
+
+        //still probing, so requests go out from 0.0.0.0 unless there is another address
+        assert!(is_tentative(interface, address));
+        assert_ne!(interface.ipv4_source(neighbor), Some(address));
+        assert_ne!(source_for(interface, neighbor), address);
+
+        without_interrupts(|| {
+            for claim in CLAIMS.lock().iter_mut().filter(|claim| claim.address == address) {
+                claim.phase = ClaimPhase::Bound;
+            }
+        });
+        assert!(!is_tentative(interface, address));
+        assert_eq!(interface.ipv4_source(neighbor), Some(address));
+        assert_eq!(source_for(interface, neighbor), address);
+
+        interface.remove_ipv4(address).unwrap();
+    }
+}
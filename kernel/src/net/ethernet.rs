@@ -0,0 +1,160 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::interface::Interface;
+use super::packet::Packet;
+use super::{arp, ipv4, SendError};
+use crate::drivers::net::{MacAddress, RxInfo, TxChecksum};
+
+/*
+ * Ethernet II framing. A received frame is checked against the interface's filter, since not
+ * every NIC filters and hash filters let other groups through, then handed to the protocol its
+ * ethertype names with the header pulled off. 802.3 frames, whose type field is a length, are
+ * dropped along with every ethertype nothing handles.
+ *
+ * The FCS is stripped by the NICs, and they pad short frames on the way out.
+ *
+ * Built with help from:
+ * https://en.wikipedia.org/wiki/Ethernet_frame
+ * https://www.iana.org/assignments/ieee-802-numbers/ieee-802-numbers.xhtml
+ */
+
+pub const HEADER_SIZE: usize = 14;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EthernetHeader {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+}
+
+impl EthernetHeader {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data = data.get(..HEADER_SIZE)?;
+        Some(EthernetHeader {
+            destination: MacAddress(data[0..6].try_into().unwrap()),
+            source: MacAddress(data[6..12].try_into().unwrap()),
+            ethertype: u16::from_be_bytes([data[12], data[13]]),
+        })
+    }
+
+    pub fn write(&self, out: &mut [u8]) {
+        out[0..6].copy_from_slice(&self.destination.0);
+        out[6..12].copy_from_slice(&self.source.0);
+        out[12..14].copy_from_slice(&self.ethertype.to_be_bytes());
+    }
+}
+
+/// Frame counters for every interface together
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EthernetStats {
+    pub rx_frames: u64,
+
+    /// Frames too short for the header
+    pub rx_malformed: u64,
+
+    /// Frames for some other station or for a group not joined
+    pub rx_filtered: u64,
+
+    /// 802.3 frames and ethertypes nothing handles
+    pub rx_unknown: u64,
+    pub tx_frames: u64,
+    pub tx_errors: u64,
+}
+
+static RX_FRAMES: AtomicU64 = AtomicU64::new(0);
+static RX_MALFORMED: AtomicU64 = AtomicU64::new(0);
+static RX_FILTERED: AtomicU64 = AtomicU64::new(0);
+static RX_UNKNOWN: AtomicU64 = AtomicU64::new(0);
+static TX_FRAMES: AtomicU64 = AtomicU64::new(0);
+static TX_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+pub fn stats() -> EthernetStats {
+    EthernetStats {
+        rx_frames: RX_FRAMES.load(Ordering::Relaxed),
+        rx_malformed: RX_MALFORMED.load(Ordering::Relaxed),
+        rx_filtered: RX_FILTERED.load(Ordering::Relaxed),
+        rx_unknown: RX_UNKNOWN.load(Ordering::Relaxed),
+        tx_frames: TX_FRAMES.load(Ordering::Relaxed),
+        tx_errors: TX_ERRORS.load(Ordering::Relaxed),
+    }
+}
+
+/// Handles a frame received on `interface`
+pub fn receive(interface: &'static Interface, mut frame: Packet, _info: RxInfo) {
+    RX_FRAMES.fetch_add(1, Ordering::Relaxed);
+    let Some(header) = EthernetHeader::parse(frame.data()) else {
+        RX_MALFORMED.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    if !interface.accepts(header.destination) {
+        RX_FILTERED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let _ = frame.pull(HEADER_SIZE);
+
+    //the broadcast address has the multicast bit set too
+    match header.ethertype {
+        ETHERTYPE_IPV4 => ipv4::receive(interface, frame, header.destination.is_multicast()),
+        ETHERTYPE_ARP => arp::receive(interface, frame),
+        _ => {
+            RX_UNKNOWN.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Puts the header in front of `packet` and sends it to `destination`. `checksum` is relative
+/// to the start of the packet, not of the frame.
+pub fn send(
+    interface: &Interface,
+    destination: MacAddress,
+    ethertype: u16,
+    mut packet: Packet,
+    checksum: Option<TxChecksum>,
+) -> Result<(), SendError> {
+    if !interface.is_up() {
+        return Err(SendError::Down);
+    }
+
+    let header = EthernetHeader { destination, source: interface.device().mac(), ethertype };
+    header.write(packet.push(HEADER_SIZE)?);
+    let checksum = checksum.map(|c| TxChecksum { start: c.start + HEADER_SIZE as u16, offset: c.offset });
+
+    let sent = interface.device().transmit(packet, checksum);
+    match sent {
+        Ok(()) => TX_FRAMES.fetch_add(1, Ordering::Relaxed),
+        Err(_) => TX_ERRORS.fetch_add(1, Ordering::Relaxed),
+    };
+    sent.map_err(SendError::from)
+}
+
+/// Prints the frame counters
+pub fn report(out: &mut impl Write) -> fmt::Result {
+    let stats = stats();
+    writeln!(out, "ethernet: rx {} frames, {} malformed, {} filtered, {} unknown type",
+        stats.rx_frames, stats.rx_malformed, stats.rx_filtered, stats.rx_unknown
+    )?;
+    writeln!(out, "ethernet: tx {} frames, {} errors", stats.tx_frames, stats.tx_errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn header_round_trip() {
+        let header = EthernetHeader {
+            destination: MacAddress::BROADCAST,
+            source: MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+            ethertype: ETHERTYPE_ARP,
+        };
+        let mut bytes = [0; HEADER_SIZE];
+        header.write(&mut bytes);
+        assert_eq!(&bytes[12..], &[0x08, 0x06]);
+        assert_eq!(EthernetHeader::parse(&bytes), Some(header));
+        assert_eq!(EthernetHeader::parse(&bytes[..13]), None);
+    }
+}
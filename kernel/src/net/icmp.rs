@@ -0,0 +1,557 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+
+use super::interface::Interface;
+use super::ipv4::{self, Ipv4Error, Ipv4Header, DEFAULT_TTL, PROTOCOL_ICMP};
+use super::packet::Packet;
+use super::SendError;
+use crate::drivers::watchdog;
+use crate::interrupt::interrupt::without_interrupts;
+use crate::time::Instant;
+
+/*
+ * ICMP for IPv4: answering echo requests, and telling senders why their packets went nowhere.
+ * Errors quote the start of the packet they are about, as much of it as fits in 576 bytes.
+ *
+ * No error is sent about an ICMP error, a fragment other than the first, a packet for a
+ * broadcast or multicast address, or a packet whose source isn't one host. The rest are rate
+ * limited with a token bucket over every destination, so a flood of bad packets can't turn into
+ * a flood of errors.
+ *
+ * ping sends echo requests once a second and prints the replies and errors as they come, then
+ * the round trip times. It runs the network stack's poll itself while it waits, so it must not
+ * be called from inside the stack.
+ *
+ * Built with help from:
+ * RFC 792 "Internet Control Message Protocol"
+ * RFC 1122, 3.2.2 "Internet Control Message Protocol -- ICMP"
+ * RFC 1191 "Path MTU Discovery", for the next hop MTU
+ * RFC 1812, 4.3 "Internet Control Message Protocol"
+ */
+
+pub const HEADER_SIZE: usize = 8;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+const TYPE_SOURCE_QUENCH: u8 = 4;
+const TYPE_REDIRECT: u8 = 5;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const TYPE_TIME_EXCEEDED: u8 = 11;
+const TYPE_PARAMETER_PROBLEM: u8 = 12;
+
+//the most an error is, IP header included
+const MAX_ERROR_SIZE: usize = 576;
+
+/// The errors sent per second, and how many can go at once, until changed
+pub const DEFAULT_RATE: u32 = 100;
+pub const DEFAULT_BURST: u32 = 50;
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+//how long to wait for the reply to the last request
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The ICMP errors that can be sent
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorMessage {
+    NetUnreachable,
+    HostUnreachable,
+    ProtocolUnreachable,
+    PortUnreachable,
+
+    /// The packet needs fragmenting but says not to, with the MTU of the next hop
+    FragmentationNeeded(u16),
+
+    /// The TTL ran out while forwarding
+    TtlExceeded,
+
+    /// The rest of the fragments didn't arrive in time
+    ReassemblyTimeExceeded,
+
+    /// Something is wrong with the header, at this offset
+    ParameterProblem(u8),
+}
+
+impl ErrorMessage {
+    fn type_and_code(&self) -> (u8, u8) {
+        match self {
+            ErrorMessage::NetUnreachable => (TYPE_DESTINATION_UNREACHABLE, 0),
+            ErrorMessage::HostUnreachable => (TYPE_DESTINATION_UNREACHABLE, 1),
+            ErrorMessage::ProtocolUnreachable => (TYPE_DESTINATION_UNREACHABLE, 2),
+            ErrorMessage::PortUnreachable => (TYPE_DESTINATION_UNREACHABLE, 3),
+            ErrorMessage::FragmentationNeeded(_) => (TYPE_DESTINATION_UNREACHABLE, 4),
+            ErrorMessage::TtlExceeded => (TYPE_TIME_EXCEEDED, 0),
+            ErrorMessage::ReassemblyTimeExceeded => (TYPE_TIME_EXCEEDED, 1),
+            ErrorMessage::ParameterProblem(_) => (TYPE_PARAMETER_PROBLEM, 0),
+        }
+    }
+
+    //the second word of the header
+    fn rest(&self) -> [u8; 4] {
+        match self {
+            ErrorMessage::FragmentationNeeded(mtu) => {
+                let [high, low] = mtu.to_be_bytes();
+                [0, 0, high, low]
+            }
+            ErrorMessage::ParameterProblem(pointer) => [*pointer, 0, 0, 0],
+            _ => [0; 4],
+        }
+    }
+}
+
+fn is_error(kind: u8) -> bool {
+    matches!(
+        kind,
+        TYPE_DESTINATION_UNREACHABLE | TYPE_SOURCE_QUENCH | TYPE_REDIRECT | TYPE_TIME_EXCEEDED | TYPE_PARAMETER_PROBLEM
+    )
+}
+
+//what an error says, the way ping prints it
+fn describe(kind: u8, code: u8) -> &'static str {
+    match (kind, code) {
+        (TYPE_DESTINATION_UNREACHABLE, 0) => "Destination Net Unreachable",
+        (TYPE_DESTINATION_UNREACHABLE, 1) => "Destination Host Unreachable",
+        (TYPE_DESTINATION_UNREACHABLE, 2) => "Destination Protocol Unreachable",
+        (TYPE_DESTINATION_UNREACHABLE, 3) => "Destination Port Unreachable",
+        (TYPE_DESTINATION_UNREACHABLE, 4) => "Frag needed and DF set",
+        (TYPE_DESTINATION_UNREACHABLE, _) => "Destination Unreachable",
+        (TYPE_TIME_EXCEEDED, 0) => "Time to live exceeded",
+        (TYPE_TIME_EXCEEDED, _) => "Fragment reassembly time exceeded",
+        (TYPE_PARAMETER_PROBLEM, _) => "Parameter problem",
+        _ => "ICMP error",
+    }
+}
+
+/// A token bucket: tokens come in at `rate` a second up to `burst`, and each message takes one
+#[derive(Copy, Clone, Debug)]
+pub struct TokenBucket {
+    rate: u32,
+    burst: u32,
+
+    //in billionths of a token, so every nanosecond adds exactly `rate` of them and calls in
+    //quick succession don't lose their share of the refill
+    tokens: u64,
+    last: Option<Instant>,
+}
+
+//one token, in the units the bucket counts in
+const TOKEN: u64 = 1_000_000_000;
+
+impl TokenBucket {
+    /// A full bucket
+    pub const fn new(rate: u32, burst: u32) -> Self {
+        TokenBucket { rate, burst, tokens: burst as u64 * TOKEN, last: None }
+    }
+
+    /// Takes a token, if there is one
+    pub fn take(&mut self, now: Instant) -> bool {
+        if let Some(last) = self.last {
+            let added = now.duration_since(last).as_nanos() * self.rate as u128;
+            self.tokens = (self.tokens as u128 + added).min(self.burst as u128 * TOKEN as u128) as u64;
+        }
+        self.last = Some(now);
+
+        if self.tokens < TOKEN {
+            return false;
+        }
+        self.tokens -= TOKEN;
+        true
+    }
+}
+
+static BUCKET: Mutex<TokenBucket> = Mutex::new(TokenBucket::new(DEFAULT_RATE, DEFAULT_BURST));
+
+/// Changes how many errors can be sent a second, and how many at once. The bucket starts full.
+pub fn set_rate_limit(rate: u32, burst: u32) {
+    without_interrupts(|| *BUCKET.lock() = TokenBucket::new(rate, burst))
+}
+
+/// Message counters
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct IcmpStats {
+    pub rx_messages: u64,
+
+    /// Messages too short or with a bad checksum
+    pub rx_errors: u64,
+    pub echo_replies_sent: u64,
+    pub errors_sent: u64,
+
+    /// Errors the token bucket held back
+    pub errors_rate_limited: u64,
+}
+
+static RX_MESSAGES: AtomicU64 = AtomicU64::new(0);
+static RX_ERRORS: AtomicU64 = AtomicU64::new(0);
+static ECHO_REPLIES_SENT: AtomicU64 = AtomicU64::new(0);
+static ERRORS_SENT: AtomicU64 = AtomicU64::new(0);
+static ERRORS_RATE_LIMITED: AtomicU64 = AtomicU64::new(0);
+
+pub fn stats() -> IcmpStats {
+    IcmpStats {
+        rx_messages: RX_MESSAGES.load(Ordering::Relaxed),
+        rx_errors: RX_ERRORS.load(Ordering::Relaxed),
+        echo_replies_sent: ECHO_REPLIES_SENT.load(Ordering::Relaxed),
+        errors_sent: ERRORS_SENT.load(Ordering::Relaxed),
+        errors_rate_limited: ERRORS_RATE_LIMITED.load(Ordering::Relaxed),
+    }
+}
+
+/// Starts answering ICMP
+pub fn init() -> Result<(), Ipv4Error> {
+    ipv4::register(PROTOCOL_ICMP, receive)
+}
+
+/// Sends an error about `original`, a packet starting at its IPv4 header, back to its source,
+/// unless it is a packet no error may be sent about or the rate limit says no
+pub fn send_error(message: ErrorMessage, original: &Packet) {
+    let data = original.data();
+    if data.len() < ipv4::HEADER_SIZE {
+        return;
+    }
+    let header_len = (data[0] & 0xF) as usize * 4;
+    let source = Ipv4Addr::new(data[12], data[13], data[14], data[15]);
+    let destination = Ipv4Addr::new(data[16], data[17], data[18], data[19]);
+    let fragment_offset = u16::from_be_bytes([data[6], data[7]]) & 0x1FFF;
+
+    if fragment_offset != 0 || ipv4::is_broadcast(destination) || destination.is_multicast() {
+        return;
+    }
+    if source.is_unspecified() || source.is_loopback() || source.is_multicast() || ipv4::is_broadcast(source) {
+        return;
+    }
+    if data[9] == PROTOCOL_ICMP && data.get(header_len).is_none_or(|kind| is_error(*kind)) {
+        return;
+    }
+
+    if !without_interrupts(|| BUCKET.lock().take(Instant::now())) {
+        ERRORS_RATE_LIMITED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let quoted = original.len().min(MAX_ERROR_SIZE - ipv4::HEADER_SIZE - HEADER_SIZE);
+    let mut message_data = vec![0; HEADER_SIZE + quoted];
+    let (kind, code) = message.type_and_code();
+    message_data[0] = kind;
+    message_data[1] = code;
+    message_data[4..8].copy_from_slice(&message.rest());
+    original.copy_range(0, &mut message_data[HEADER_SIZE..]);
+
+    if send(source, None, &mut message_data).is_ok() {
+        ERRORS_SENT.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+//fills in the checksum of a message and sends it
+fn send(destination: Ipv4Addr, source: Option<Ipv4Addr>, message: &mut [u8]) -> Result<(), SendError> {
+    message[2..4].fill(0);
+    let checksum = ipv4::header_checksum(message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    ipv4::send(source, destination, PROTOCOL_ICMP, DEFAULT_TTL, Packet::from_slice(message)?, None)
+}
+
+fn receive(_interface: &'static Interface, header: &Ipv4Header, mut packet: Packet) {
+    RX_MESSAGES.fetch_add(1, Ordering::Relaxed);
+    if packet.data().len() < HEADER_SIZE || packet.checksum(0) != 0 {
+        RX_ERRORS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let kind = packet.data()[0];
+    match kind {
+        TYPE_ECHO_REQUEST => {
+            //answering pings to a broadcast address is how smurf attacks work
+            if ipv4::is_broadcast(header.destination) || header.destination.is_multicast() {
+                return;
+            }
+            let Ok(data) = packet.data_mut() else {
+                return;
+            };
+            //only the type changes, so the checksum can be updated rather than summed again
+            let old = u16::from_be_bytes([data[0], data[1]]);
+            data[0] = TYPE_ECHO_REPLY;
+            let checksum = ipv4::update_checksum(u16::from_be_bytes([data[2], data[3]]), old, u16::from_be_bytes([data[0], data[1]]));
+            data[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+            if ipv4::send(Some(header.destination), header.source, PROTOCOL_ICMP, DEFAULT_TTL, packet, None).is_ok() {
+                ECHO_REPLIES_SENT.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        TYPE_ECHO_REPLY => {
+            let data = packet.data();
+            let reply = PingReply {
+                sequence: u16::from_be_bytes([data[6], data[7]]),
+                from: header.source,
+                ttl: header.ttl,
+                len: packet.len(),
+                at: Instant::now(),
+                error: None,
+            };
+            record_reply(u16::from_be_bytes([data[4], data[5]]), reply);
+        }
+        kind if is_error(kind) => record_error(header, packet.data()),
+        _ => {}
+    }
+}
+
+//one answer to a ping's request
+struct PingReply {
+    sequence: u16,
+    from: Ipv4Addr,
+    ttl: u8,
+    len: usize,
+    at: Instant,
+
+    //what an error about the request said, if it was one
+    error: Option<&'static str>,
+}
+
+//the ping running, by the ID in its requests, and the answers not yet printed
+static PING: Mutex<Option<(u16, Vec<PingReply>)>> = Mutex::new(None);
+static NEXT_PING_ID: AtomicU16 = AtomicU16::new(1);
+
+fn record_reply(id: u16, reply: PingReply) {
+    without_interrupts(|| {
+        if let Some((ping_id, replies)) = PING.lock().as_mut()
+            && *ping_id == id
+        {
+            replies.push(reply);
+        }
+    })
+}
+
+//an error about one of ping's requests, which the error quotes the start of
+fn record_error(header: &Ipv4Header, data: &[u8]) {
+    let quoted = &data[HEADER_SIZE..];
+    let Some(quoted_len) = quoted.first().map(|first| (first & 0xF) as usize * 4) else {
+        return;
+    };
+    let Some(request) = quoted.get(quoted_len..quoted_len + HEADER_SIZE) else {
+        return;
+    };
+    if quoted.get(9) != Some(&PROTOCOL_ICMP) || request[0] != TYPE_ECHO_REQUEST {
+        return;
+    }
+
+    record_reply(u16::from_be_bytes([request[4], request[5]]), PingReply {
+        sequence: u16::from_be_bytes([request[6], request[7]]),
+        from: header.source,
+        ttl: header.ttl,
+        len: data.len(),
+        at: Instant::now(),
+        error: Some(describe(data[0], data[1])),
+    });
+}
+
+/// What a ping came to. The times are zero if nothing came back.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PingStats {
+    pub transmitted: u32,
+    pub received: u32,
+    pub errors: u32,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+
+    /// The standard deviation
+    pub mdev: Duration,
+}
+
+//a duration in milliseconds, to the microsecond
+struct Millis(Duration);
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let micros = self.0.as_micros();
+        write!(f, "{}.{:03}", micros / 1000, micros % 1000)
+    }
+}
+
+/// Pings `destination` `count` times with `size` bytes of data, printing every reply and then
+/// the statistics to `out`
+pub fn ping(destination: Ipv4Addr, count: u16, size: usize, out: &mut impl Write) -> PingStats {
+    let mut stats = PingStats::default();
+    let id = NEXT_PING_ID.fetch_add(1, Ordering::Relaxed);
+    let started = without_interrupts(|| {
+        let mut ping = PING.lock();
+        if ping.is_some() {
+            return false;
+        }
+        *ping = Some((id, Vec::new()));
+        true
+    });
+    if !started {
+        let _ = writeln!(out, "ping: another ping is running");
+        return stats;
+    }
+
+    let _ = writeln!(out, "PING {} {} data bytes", destination, size);
+    let mut sent_at = Vec::new();
+    let mut answered = Vec::new();
+    let mut rtts = Vec::new();
+    for sequence in 0..count {
+        let mut request = vec![0; HEADER_SIZE + size];
+        request[0] = TYPE_ECHO_REQUEST;
+        request[4..6].copy_from_slice(&id.to_be_bytes());
+        request[6..8].copy_from_slice(&sequence.to_be_bytes());
+        for (i, byte) in request[HEADER_SIZE..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let sent = Instant::now();
+        sent_at.push(sent);
+        answered.push(false);
+        stats.transmitted += 1;
+        match send(destination, None, &mut request) {
+            //an unreachable neighbor comes back as an error from ARP
+            Ok(()) | Err(SendError::Unreachable) => {}
+            Err(e) => {
+                let _ = writeln!(out, "ping: icmp_seq={} could not be sent: {:?}", sequence, e);
+            }
+        }
+
+        let last = sequence + 1 == count;
+        let deadline = sent + if last { PING_TIMEOUT } else { PING_INTERVAL };
+        //this stands in for the main loop while it waits, so it has to pet the watchdog too
+        while Instant::now() < deadline {
+            watchdog::poll();
+            super::poll();
+            let replies = without_interrupts(|| PING.lock().as_mut().map(|(_, replies)| core::mem::take(replies)));
+            for reply in replies.unwrap_or_default() {
+                let sequence = reply.sequence as usize;
+                if sequence >= sent_at.len() {
+                    continue;
+                }
+                if let Some(error) = reply.error {
+                    stats.errors += 1;
+                    let _ = writeln!(out, "From {} icmp_seq={} {}", reply.from, sequence, error);
+                    continue;
+                }
+
+                let rtt = reply.at.duration_since(sent_at[sequence]);
+                let duplicate = core::mem::replace(&mut answered[sequence], true);
+                let _ = writeln!(out, "{} bytes from {}: icmp_seq={} ttl={} time={} ms{}",
+                    reply.len, reply.from, sequence, reply.ttl, Millis(rtt), if duplicate { " (DUP!)" } else { "" }
+                );
+                if !duplicate {
+                    stats.received += 1;
+                    rtts.push(rtt);
+                }
+            }
+
+            if last && answered.iter().all(|answered| *answered) {
+                break;
+            }
+            x86_64::instructions::hlt();
+        }
+    }
+    without_interrupts(|| *PING.lock() = None);
+
+    if !rtts.is_empty() {
+        let micros: Vec<u64> = rtts.iter().map(|rtt| rtt.as_micros() as u64).collect();
+        let n = micros.len() as u64;
+        let mean = micros.iter().sum::<u64>() / n;
+        let variance = micros.iter().map(|us| us.abs_diff(mean).pow(2)).sum::<u64>() / n;
+        stats.min = rtts.iter().min().copied().unwrap_or_default();
+        stats.max = rtts.iter().max().copied().unwrap_or_default();
+        stats.avg = Duration::from_micros(mean);
+        stats.mdev = Duration::from_micros(variance.isqrt());
+    }
+
+    let loss = (stats.transmitted - stats.received) * 100 / stats.transmitted.max(1);
+    let _ = writeln!(out, "--- {} ping statistics ---", destination);
+    let _ = write!(out, "{} packets transmitted, {} received", stats.transmitted, stats.received);
+    if stats.errors > 0 {
+        let _ = write!(out, ", +{} errors", stats.errors);
+    }
+    let _ = writeln!(out, ", {}% packet loss", loss);
+    if stats.received > 0 {
+        let _ = writeln!(out, "rtt min/avg/max/mdev = {}/{}/{}/{} ms",
+            Millis(stats.min), Millis(stats.avg), Millis(stats.max), Millis(stats.mdev)
+        );
+    }
+    stats
+}
+
+/// Prints the message counters
+pub fn report(out: &mut impl Write) -> fmt::Result {
+    let stats = stats();
+    writeln!(out, "icmp: rx {} messages, {} errors; {} echo replies sent; {} errors sent, {} rate limited",
+        stats.rx_messages, stats.rx_errors, stats.echo_replies_sent, stats.errors_sent, stats.errors_rate_limited
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+    use crate::net::{self, arp, interface};
+
+    #[test_case]
+    fn token_bucket_refills() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2, 2);
+        assert!(bucket.take(now) && bucket.take(now));
+        assert!(!bucket.take(now));
+
+        //half a second is one token at 2 a second
+        assert!(bucket.take(now + Duration::from_millis(500)));
+        assert!(!bucket.take(now + Duration::from_millis(500)));
+
+        //and it never holds more than the burst
+        let later = now + Duration::from_secs(60);
+        assert!(bucket.take(later) && bucket.take(later));
+        assert!(!bucket.take(later));
+    }
+
+    #[test_case]
+    fn token_bucket_refills_in_small_steps() {
+        //at 100 a second a token takes 10ms, which is 2000 calls 5us apart
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100, 1);
+        assert!(bucket.take(start));
+
+        //25ms holds the tokens due at 10ms and 20ms. The instants are kept in TSC cycles, and
+        //the window ends 5ms clear of a refill, so the rounding can't add or lose one.
+        let taken = (1..=5000u64)
+            .filter(|step| bucket.take(start + Duration::from_micros(5 * step)))
+            .count();
+        assert_eq!(taken, 2);
+    }
+
+    #[test_case]
+    fn ping_a_local_address() {
+        let interface = interface::by_index(0).expect("the test kernel has a NIC");
+        let address = Ipv4Addr::new(10, 97, 0, 1);
+        interface.add_ipv4(interface::Ipv4Cidr::new(address, 24).unwrap()).unwrap();
+
+        //an address can't be pinged from until duplicate address detection is done with it
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while arp::is_tentative(interface, address) && Instant::now() < deadline {
+            watchdog::poll();
+            net::poll();
+            x86_64::instructions::hlt();
+        }
+        assert!(!arp::is_tentative(interface, address));
+
+        let mut out = String::new();
+        let stats = ping(address, 2, 56, &mut out);
+        assert_eq!((stats.transmitted, stats.received, stats.errors), (2, 2, 0));
+        assert!(stats.min <= stats.avg && stats.avg <= stats.max);
+        assert!(out.contains("2 packets transmitted, 2 received, 0% packet loss"));
+
+        interface.remove_ipv4(address).unwrap();
+    }
+
+    #[test_case]
+    fn errors_carry_their_fields() {
+        assert_eq!(ErrorMessage::PortUnreachable.type_and_code(), (3, 3));
+        assert_eq!(ErrorMessage::FragmentationNeeded(1400).rest(), [0, 0, 0x05, 0x78]);
+        assert_eq!(ErrorMessage::ParameterProblem(20).rest(), [20, 0, 0, 0]);
+        assert_eq!(ErrorMessage::ReassemblyTimeExceeded.type_and_code(), (11, 1));
+    }
+}
@@ -0,0 +1,417 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::net::{Ipv4Addr, Ipv6Addr};
+use spin::Mutex;
+
+use super::arp;
+use crate::drivers::net::{self as nic, MacAddress, NetDevice};
+use crate::interrupt::interrupt::without_interrupts;
+
+/*
+ * The interface table: one entry per NIC, with the state the network stack keeps about it.
+ * Interfaces are named eth0, eth1, ... in the order of their PCI address. Bus numbers follow
+ * from how the bridges are wired, so the order stays the same from boot to boot as long as the
+ * cards stay in their slots.
+ *
+ * An interface is up when it is administratively up and its link is up. Links are polled from
+ * the main loop, and listeners hear about link changes, administrative changes and address
+ * changes. Listeners run without the table locked, so they can use it.
+ */
+
+/// The most listeners that can subscribe to interface events
+pub const MAX_LISTENERS: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InterfaceError {
+    /// The prefix length is longer than the address
+    InvalidPrefix,
+
+    /// The interface already has the address
+    AddressExists,
+
+    /// The interface doesn't have the address
+    NoSuchAddress,
+
+    /// Every listener slot is taken
+    TooManyListeners,
+}
+
+/// An IPv4 address on an interface, with the length of its network prefix
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Ipv4Cidr {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    pub fn new(address: Ipv4Addr, prefix_len: u8) -> Result<Self, InterfaceError> {
+        if prefix_len > 32 {
+            return Err(InterfaceError::InvalidPrefix);
+        }
+        Ok(Ipv4Cidr { address, prefix_len })
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from_bits(u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0))
+    }
+
+    pub fn network(&self) -> Ipv4Addr {
+        Ipv4Addr::from_bits(self.address.to_bits() & self.netmask().to_bits())
+    }
+
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_bits(self.address.to_bits() | !self.netmask().to_bits())
+    }
+
+    /// Whether `address` is on the same network
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        address.to_bits() & self.netmask().to_bits() == self.network().to_bits()
+    }
+}
+
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+/// An IPv6 address on an interface, with the length of its network prefix
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Ipv6Cidr {
+    pub address: Ipv6Addr,
+    pub prefix_len: u8,
+}
+
+impl Ipv6Cidr {
+    pub fn new(address: Ipv6Addr, prefix_len: u8) -> Result<Self, InterfaceError> {
+        if prefix_len > 128 {
+            return Err(InterfaceError::InvalidPrefix);
+        }
+        Ok(Ipv6Cidr { address, prefix_len })
+    }
+
+    fn mask(&self) -> u128 {
+        u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0)
+    }
+
+    pub fn network(&self) -> Ipv6Addr {
+        Ipv6Addr::from_bits(self.address.to_bits() & self.mask())
+    }
+}
+
+impl fmt::Display for Ipv6Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+/// Something that happened to an interface
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InterfaceEvent {
+    LinkUp,
+    LinkDown,
+    AdminUp,
+    AdminDown,
+    Ipv4Added(Ipv4Cidr),
+    Ipv4Removed(Ipv4Cidr),
+    Ipv6Added(Ipv6Cidr),
+    Ipv6Removed(Ipv6Cidr),
+}
+
+pub type InterfaceListener = fn(&'static Interface, InterfaceEvent);
+
+struct State {
+    admin_up: bool,
+
+    //the link state as of the last poll
+    link_up: bool,
+    ipv4: Vec<Ipv4Cidr>,
+    ipv6: Vec<Ipv6Cidr>,
+
+    //the multicast groups joined, and whether every frame is taken
+    multicast: Vec<MacAddress>,
+    promiscuous: bool,
+}
+
+/// A NIC as the network stack sees it
+pub struct Interface {
+    index: usize,
+    name: String,
+    device: &'static dyn NetDevice,
+    state: Mutex<State>,
+}
+
+impl Interface {
+    /// The position in the table, which is the number in the name
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn device(&self) -> &'static dyn NetDevice {
+        self.device
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    pub fn is_admin_up(&self) -> bool {
+        self.with_state(|state| state.admin_up)
+    }
+
+    /// The link state as of the last poll
+    pub fn link_up(&self) -> bool {
+        self.with_state(|state| state.link_up)
+    }
+
+    /// Whether frames can be sent and received: administratively up with the link up
+    pub fn is_up(&self) -> bool {
+        self.with_state(|state| state.admin_up && state.link_up)
+    }
+
+    pub fn set_admin_up(&'static self, up: bool) {
+        let changed = self.with_state(|state| core::mem::replace(&mut state.admin_up, up) != up);
+        if changed {
+            notify(self, if up { InterfaceEvent::AdminUp } else { InterfaceEvent::AdminDown });
+        }
+    }
+
+    pub fn ipv4_addresses(&self) -> Vec<Ipv4Cidr> {
+        self.with_state(|state| state.ipv4.clone())
+    }
+
+    pub fn ipv6_addresses(&self) -> Vec<Ipv6Cidr> {
+        self.with_state(|state| state.ipv6.clone())
+    }
+
+    /// Whether `address` is one of the interface's own
+    pub fn has_ipv4(&self, address: Ipv4Addr) -> bool {
+        self.with_state(|state| state.ipv4.iter().any(|cidr| cidr.address == address))
+    }
+
+    /// The address to send to `destination` from: one on its network if there is one, or else
+    /// the first. Addresses still being checked for duplicates aren't ours yet, so they're skipped
+    pub fn ipv4_source(&self, destination: Ipv4Addr) -> Option<Ipv4Addr> {
+        //copied out first, as ARP takes its own lock to say what is tentative
+        let usable: Vec<Ipv4Cidr> =
+            self.ipv4_addresses().into_iter().filter(|cidr| !arp::is_tentative(self, cidr.address)).collect();
+        let on_network = usable.iter().find(|cidr| cidr.contains(destination));
+        on_network.or(usable.first()).map(|cidr| cidr.address)
+    }
+
+    pub fn add_ipv4(&'static self, cidr: Ipv4Cidr) -> Result<(), InterfaceError> {
+        self.with_state(|state| {
+            if state.ipv4.iter().any(|other| other.address == cidr.address) {
+                return Err(InterfaceError::AddressExists);
+            }
+            state.ipv4.push(cidr);
+            Ok(())
+        })?;
+        notify(self, InterfaceEvent::Ipv4Added(cidr));
+        Ok(())
+    }
+
+    pub fn remove_ipv4(&'static self, address: Ipv4Addr) -> Result<Ipv4Cidr, InterfaceError> {
+        let cidr = self.with_state(|state| {
+            let index = state.ipv4.iter().position(|cidr| cidr.address == address).ok_or(InterfaceError::NoSuchAddress)?;
+            Ok(state.ipv4.remove(index))
+        })?;
+        notify(self, InterfaceEvent::Ipv4Removed(cidr));
+        Ok(cidr)
+    }
+
+    pub fn add_ipv6(&'static self, cidr: Ipv6Cidr) -> Result<(), InterfaceError> {
+        self.with_state(|state| {
+            if state.ipv6.iter().any(|other| other.address == cidr.address) {
+                return Err(InterfaceError::AddressExists);
+            }
+            state.ipv6.push(cidr);
+            Ok(())
+        })?;
+        notify(self, InterfaceEvent::Ipv6Added(cidr));
+        Ok(())
+    }
+
+    pub fn remove_ipv6(&'static self, address: Ipv6Addr) -> Result<Ipv6Cidr, InterfaceError> {
+        let cidr = self.with_state(|state| {
+            let index = state.ipv6.iter().position(|cidr| cidr.address == address).ok_or(InterfaceError::NoSuchAddress)?;
+            Ok(state.ipv6.remove(index))
+        })?;
+        notify(self, InterfaceEvent::Ipv6Removed(cidr));
+        Ok(cidr)
+    }
+
+    /// Starts taking frames sent to a multicast group
+    pub fn join_multicast(&self, group: MacAddress) {
+        self.with_state(|state| {
+            if !state.multicast.contains(&group) {
+                state.multicast.push(group);
+                self.device.set_multicast(&state.multicast);
+            }
+        })
+    }
+
+    pub fn leave_multicast(&self, group: MacAddress) {
+        self.with_state(|state| {
+            state.multicast.retain(|other| *other != group);
+            self.device.set_multicast(&state.multicast);
+        })
+    }
+
+    /// Takes every frame on the wire, like a bridge port has to
+    pub fn set_promiscuous(&self, on: bool) {
+        self.with_state(|state| {
+            state.promiscuous = on;
+            self.device.set_promiscuous(on);
+        })
+    }
+
+    /// Whether a frame sent to `destination` is for this interface. The NIC filters too, but
+    /// not every NIC can, and a hash filter lets some other groups through.
+    pub fn accepts(&self, destination: MacAddress) -> bool {
+        if destination == self.device.mac() || destination == MacAddress::BROADCAST {
+            return true;
+        }
+        self.with_state(|state| state.promiscuous || (destination.is_multicast() && state.multicast.contains(&destination)))
+    }
+}
+
+static INTERFACES: Mutex<Vec<&'static Interface>> = Mutex::new(Vec::new());
+static LISTENERS: Mutex<[Option<InterfaceListener>; MAX_LISTENERS]> = Mutex::new([None; MAX_LISTENERS]);
+
+fn notify(interface: &'static Interface, event: InterfaceEvent) {
+    let listeners = without_interrupts(|| *LISTENERS.lock());
+    for listener in listeners.into_iter().flatten() {
+        listener(interface, event);
+    }
+}
+
+/// Calls `listener` for every interface event from now on
+pub fn subscribe(listener: InterfaceListener) -> Result<(), InterfaceError> {
+    without_interrupts(|| {
+        let mut listeners = LISTENERS.lock();
+        let slot = listeners.iter_mut().find(|slot| slot.is_none()).ok_or(InterfaceError::TooManyListeners)?;
+        *slot = Some(listener);
+        Ok(())
+    })
+}
+
+/// Adds a NIC to the table under the next free name. It starts out administratively up, with
+/// no addresses.
+pub fn add(device: &'static dyn NetDevice) -> &'static Interface {
+    without_interrupts(|| {
+        let mut interfaces = INTERFACES.lock();
+        let index = interfaces.len();
+        let interface: &'static Interface = Box::leak(Box::new(Interface {
+            index,
+            name: format!("eth{}", index),
+            device,
+            state: Mutex::new(State {
+                admin_up: true,
+                link_up: device.link_up(),
+                ipv4: Vec::new(),
+                ipv6: Vec::new(),
+                multicast: Vec::new(),
+                promiscuous: false,
+            }),
+        }));
+        interfaces.push(interface);
+        interface
+    })
+}
+
+/// Names every NIC the drivers claimed, in the order of their PCI addresses. Returns how many
+/// there are.
+pub fn init() -> usize {
+    let mut devices = nic::devices();
+    devices.sort_by_key(|device| device.pci().address);
+
+    for device in &devices {
+        add(*device);
+    }
+    devices.len()
+}
+
+pub fn interfaces() -> Vec<&'static Interface> {
+    without_interrupts(|| INTERFACES.lock().clone())
+}
+
+pub fn by_index(index: usize) -> Option<&'static Interface> {
+    without_interrupts(|| INTERFACES.lock().get(index).copied())
+}
+
+pub fn by_name(name: &str) -> Option<&'static Interface> {
+    without_interrupts(|| INTERFACES.lock().iter().find(|interface| interface.name == name).copied())
+}
+
+/// Checks every link and tells the listeners about the ones that changed. Called from the
+/// kernel's main loop.
+pub fn poll_links() {
+    for interface in interfaces() {
+        let up = interface.device.link_up();
+        let changed = interface.with_state(|state| core::mem::replace(&mut state.link_up, up) != up);
+        if changed {
+            notify(interface, if up { InterfaceEvent::LinkUp } else { InterfaceEvent::LinkDown });
+        }
+    }
+}
+
+/// Prints the interface table, one interface with its addresses and counters per block
+pub fn report(out: &mut impl Write) -> fmt::Result {
+    for interface in interfaces() {
+        let device = interface.device;
+        let (admin, link) = (interface.is_admin_up(), interface.link_up());
+        writeln!(out, "{}: {}: {} at {}, MAC {}, MTU {}, {}, link {}",
+            interface.index, interface.name, device.name(), device.pci().address, device.mac(), device.mtu(),
+            if admin { "up" } else { "down" }, if link { "up" } else { "down" }
+        )?;
+
+        for cidr in interface.ipv4_addresses() {
+            writeln!(out, "  inet {} network {} broadcast {}", cidr, cidr.network(), cidr.broadcast())?;
+        }
+        for cidr in interface.ipv6_addresses() {
+            writeln!(out, "  inet6 {} network {}", cidr, cidr.network())?;
+        }
+
+        let stats = device.stats();
+        writeln!(out, "  rx {} packets {} bytes, {} dropped, {} errors",
+            stats.rx_packets, stats.rx_bytes, stats.rx_dropped, stats.rx_errors
+        )?;
+        writeln!(out, "  tx {} packets {} bytes, {} dropped", stats.tx_packets, stats.tx_bytes, stats.tx_dropped)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ipv4_networks() {
+        let cidr = Ipv4Cidr::new(Ipv4Addr::new(192, 168, 1, 20), 24).unwrap();
+        assert_eq!(cidr.netmask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(cidr.network(), Ipv4Addr::new(192, 168, 1, 0));
+        assert_eq!(cidr.broadcast(), Ipv4Addr::new(192, 168, 1, 255));
+        assert!(cidr.contains(Ipv4Addr::new(192, 168, 1, 1)));
+        assert!(!cidr.contains(Ipv4Addr::new(192, 168, 2, 1)));
+
+        let any = Ipv4Cidr::new(Ipv4Addr::UNSPECIFIED, 0).unwrap();
+        assert_eq!(any.netmask(), Ipv4Addr::UNSPECIFIED);
+        assert!(any.contains(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(Ipv4Cidr::new(Ipv4Addr::UNSPECIFIED, 33), Err(InterfaceError::InvalidPrefix));
+    }
+
+    #[test_case]
+    fn ipv6_networks() {
+        let cidr = Ipv6Cidr::new(Ipv6Addr::new(0xFE80, 0, 0, 0, 1, 2, 3, 4), 64).unwrap();
+        assert_eq!(cidr.network(), Ipv6Addr::new(0xFE80, 0, 0, 0, 0, 0, 0, 0));
+        assert_eq!(Ipv6Cidr::new(Ipv6Addr::UNSPECIFIED, 129), Err(InterfaceError::InvalidPrefix));
+    }
+}
@@ -0,0 +1,1125 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+
+use super::interface::{self, Interface};
+use super::packet::{fold, Packet, BUFFER_SIZE};
+use super::icmp::{self, ErrorMessage};
+use super::{arp, SendError};
+use crate::drivers::net::{fill_checksum, TxChecksum};
+use crate::interrupt::interrupt::without_interrupts;
+use crate::time::Instant;
+
+/*
+ * IPv4. A received packet is checked (version, lengths, header checksum, options) and then
+ * either delivered, if it is for one of our addresses or a broadcast, or forwarded along the
+ * route to its destination. Delivered packets go to the handler registered for their protocol,
+ * with the header pulled off. Fragments are put back together first.
+ *
+ * The routes are the networks of the interfaces that are up, and a default gateway. A forwarded
+ * packet has its TTL decremented and its checksum updated to match without summing the header
+ * again. Packets bigger than the MTU of the way out are fragmented, unless they say not to be.
+ * Source routed packets are never forwarded, as RFC 7126 recommends.
+ *
+ * Reassembly is bounded: so many datagrams at a time, so many fragments each, so much buffer
+ * memory in all, and 30 seconds for the rest to arrive. A fragment overlapping another one
+ * throws the whole datagram away, which stops overlapping fragment attacks that get something
+ * past a filter by overwriting a header. Exact duplicates are dropped on their own, since they
+ * happen when a fragment is retransmitted.
+ *
+ * Built with help from:
+ * RFC 791 "Internet Protocol"
+ * RFC 815 "IP Datagram Reassembly Algorithms"
+ * RFC 1624 "Computation of the Internet Checksum via Incremental Update"
+ * RFC 1812 "Requirements for IP Version 4 Routers"
+ * RFC 5722 "Handling of Overlapping IPv6 Fragments", whose rule is used for IPv4 as well
+ * RFC 7126 "Recommendations on Filtering of IPv4 Packets Containing IPv4 Options"
+ */
+
+/// The size of a header without options
+pub const HEADER_SIZE: usize = 20;
+
+pub const MAX_OPTIONS_SIZE: usize = 40;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const DEFAULT_TTL: u8 = 64;
+
+/// The most protocol handlers that can be registered
+pub const MAX_HANDLERS: usize = 8;
+
+/// The most datagrams being reassembled at a time
+pub const MAX_REASSEMBLIES: usize = 64;
+
+/// The most fragments one datagram can come in
+pub const MAX_FRAGMENTS: usize = 64;
+
+/// The most buffer memory held by fragments waiting to be reassembled
+pub const REASSEMBLY_MEMORY: usize = 1024 * 1024;
+
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MAX_PACKET_SIZE: usize = 65535;
+
+const FLAG_DF: u16 = 0x4000;
+const FLAG_MF: u16 = 0x2000;
+const OFFSET_MASK: u16 = 0x1FFF;
+
+//option types. the top bit says whether it is copied into every fragment.
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_RECORD_ROUTE: u8 = 7;
+const OPTION_TIMESTAMP: u8 = 68;
+const OPTION_LSRR: u8 = 131;
+const OPTION_SSRR: u8 = 137;
+const OPTION_COPIED: u8 = 0x80;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Ipv4Error {
+    /// Shorter than its header, not version 4, or with lengths that don't add up
+    Malformed,
+
+    /// The header checksum is wrong
+    BadChecksum,
+
+    /// An option is malformed. This is the offset of the bad byte in the header, which is what
+    /// an ICMP parameter problem points at.
+    BadOption(u8),
+
+    /// Every protocol handler slot is taken
+    TooManyHandlers,
+
+    /// Another handler has the protocol
+    ProtocolTaken,
+}
+
+/// The options of a header, as they are on the wire
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    bytes: [u8; MAX_OPTIONS_SIZE],
+    len: u8,
+}
+
+impl Options {
+    pub const NONE: Options = Options { bytes: [0; MAX_OPTIONS_SIZE], len: 0 };
+
+    /// The options, padded to a multiple of 4 bytes
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    //calls `f` with the offset, type and length of every option, and returns the offset of
+    //the first bad byte if they aren't well formed
+    fn walk(&self, mut f: impl FnMut(usize, u8, usize)) -> Result<(), usize> {
+        let bytes = self.as_slice();
+        let mut at = 0;
+        while at < bytes.len() {
+            let kind = bytes[at];
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => {
+                    f(at, kind, 1);
+                    at += 1;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let len = *bytes.get(at + 1).ok_or(at)? as usize;
+            if len < 2 || at + len > bytes.len() {
+                return Err(at + 1);
+            }
+            //the pointer of the options that have one, counted from the option's start at 1
+            let min_pointer = match kind {
+                OPTION_RECORD_ROUTE | OPTION_LSRR | OPTION_SSRR => Some(4),
+                OPTION_TIMESTAMP => Some(5),
+                _ => None,
+            };
+            if let Some(min) = min_pointer
+                && (len < 3 || (bytes[at + 2] as usize) < min)
+            {
+                return Err(at + 2);
+            }
+
+            f(at, kind, len);
+            at += len;
+        }
+        Ok(())
+    }
+
+    fn find(&self, kind: u8) -> Option<(usize, usize)> {
+        let mut found = None;
+        let _ = self.walk(|at, other, len| {
+            if other == kind && found.is_none() {
+                found = Some((at, len));
+            }
+        });
+        found
+    }
+
+    pub fn is_source_routed(&self) -> bool {
+        self.find(OPTION_LSRR).is_some() || self.find(OPTION_SSRR).is_some()
+    }
+
+    /// The options that go into every fragment after the first
+    pub fn copied(&self) -> Options {
+        let mut copied = Options::NONE;
+        let mut len = 0;
+        let _ = self.walk(|at, kind, option_len| {
+            if kind & OPTION_COPIED != 0 {
+                copied.bytes[len..len + option_len].copy_from_slice(&self.bytes[at..at + option_len]);
+                len += option_len;
+            }
+        });
+        //the rest of the padding is END options, which are zeroes
+        copied.len = len.next_multiple_of(4) as u8;
+        copied
+    }
+
+    //adds `address` to a record route option with room left. returns whether it did.
+    fn record_route(&mut self, address: Ipv4Addr) -> bool {
+        let Some((at, len)) = self.find(OPTION_RECORD_ROUTE) else {
+            return false;
+        };
+        let pointer = self.bytes[at + 2] as usize;
+        if pointer + 3 > len {
+            return false;
+        }
+        self.bytes[at + pointer - 1..at + pointer + 3].copy_from_slice(&address.octets());
+        self.bytes[at + 2] += 4;
+        true
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Ipv4Header {
+    /// The DSCP and ECN bits
+    pub tos: u8,
+    pub total_len: u16,
+    pub id: u16,
+    pub dont_fragment: bool,
+    pub more_fragments: bool,
+
+    /// Where the fragment's data goes in the datagram, in bytes
+    pub fragment_offset: u16,
+    pub ttl: u8,
+    pub protocol: u8,
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub options: Options,
+}
+
+impl Ipv4Header {
+    /// Reads and checks the header at the start of `data`. The total length is only checked
+    /// against the header's own length, since `data` may be one buffer of a chain.
+    pub fn parse(data: &[u8]) -> Result<Self, Ipv4Error> {
+        if data.len() < HEADER_SIZE || data[0] >> 4 != 4 {
+            return Err(Ipv4Error::Malformed);
+        }
+        let header_len = (data[0] & 0xF) as usize * 4;
+        let field = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+        if header_len < HEADER_SIZE || header_len > data.len() || (field(2) as usize) < header_len {
+            return Err(Ipv4Error::Malformed);
+        }
+        if header_checksum(&data[..header_len]) != 0 {
+            return Err(Ipv4Error::BadChecksum);
+        }
+
+        let mut options = Options::NONE;
+        options.len = (header_len - HEADER_SIZE) as u8;
+        options.bytes[..options.len as usize].copy_from_slice(&data[HEADER_SIZE..header_len]);
+        options.walk(|_, _, _| {}).map_err(|at| Ipv4Error::BadOption((HEADER_SIZE + at) as u8))?;
+
+        let flags = field(6);
+        Ok(Ipv4Header {
+            tos: data[1],
+            total_len: field(2),
+            id: field(4),
+            dont_fragment: flags & FLAG_DF != 0,
+            more_fragments: flags & FLAG_MF != 0,
+            fragment_offset: (flags & OFFSET_MASK) * 8,
+            ttl: data[8],
+            protocol: data[9],
+            source: Ipv4Addr::new(data[12], data[13], data[14], data[15]),
+            destination: Ipv4Addr::new(data[16], data[17], data[18], data[19]),
+            options,
+        })
+    }
+
+    /// The length of the header, options included
+    pub fn len(&self) -> usize {
+        HEADER_SIZE + self.options.len as usize
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments || self.fragment_offset != 0
+    }
+
+    /// Writes the header, with its checksum, to the start of `out`
+    pub fn write(&self, out: &mut [u8]) {
+        let len = self.len();
+        let mut flags = self.fragment_offset / 8;
+        if self.dont_fragment {
+            flags |= FLAG_DF;
+        }
+        if self.more_fragments {
+            flags |= FLAG_MF;
+        }
+
+        out[0] = 0x40 | (len / 4) as u8;
+        out[1] = self.tos;
+        out[2..4].copy_from_slice(&self.total_len.to_be_bytes());
+        out[4..6].copy_from_slice(&self.id.to_be_bytes());
+        out[6..8].copy_from_slice(&flags.to_be_bytes());
+        out[8] = self.ttl;
+        out[9] = self.protocol;
+        out[10..12].fill(0);
+        out[12..16].copy_from_slice(&self.source.octets());
+        out[16..20].copy_from_slice(&self.destination.octets());
+        out[HEADER_SIZE..len].copy_from_slice(self.options.as_slice());
+
+        let checksum = header_checksum(&out[..len]);
+        out[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+/// The checksum of a header. Over a header with a correct checksum it comes out zero.
+pub fn header_checksum(header: &[u8]) -> u16 {
+    let sum: u64 = header.chunks(2).map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u64).sum();
+    !fold(sum)
+}
+
+/// Updates a checksum for one 16 bit word of what it covers changing from `old` to `new`,
+/// without summing everything again (RFC 1624, equation 3)
+pub fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    !fold(!checksum as u64 + !old as u64 + new as u64)
+}
+
+/// Receives packets of one protocol: the interface they came in on, their header, and their
+/// payload
+pub type ProtocolHandler = fn(&'static Interface, &Ipv4Header, Packet);
+
+static HANDLERS: Mutex<[Option<(u8, ProtocolHandler)>; MAX_HANDLERS]> = Mutex::new([None; MAX_HANDLERS]);
+
+/// Hands every packet for `protocol` that is delivered here to `handler`
+pub fn register(protocol: u8, handler: ProtocolHandler) -> Result<(), Ipv4Error> {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        if handlers.iter().flatten().any(|(other, _)| *other == protocol) {
+            return Err(Ipv4Error::ProtocolTaken);
+        }
+        let slot = handlers.iter_mut().find(|slot| slot.is_none()).ok_or(Ipv4Error::TooManyHandlers)?;
+        *slot = Some((protocol, handler));
+        Ok(())
+    })
+}
+
+pub fn unregister(protocol: u8) {
+    without_interrupts(|| {
+        for slot in HANDLERS.lock().iter_mut() {
+            if slot.is_some_and(|(other, _)| other == protocol) {
+                *slot = None;
+            }
+        }
+    })
+}
+
+fn handler(protocol: u8) -> Option<ProtocolHandler> {
+    without_interrupts(|| HANDLERS.lock().iter().flatten().find(|(other, _)| *other == protocol).map(|(_, handler)| *handler))
+}
+
+/// Packet counters, named after the RFC 4293 ones
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ipv4Stats {
+    pub rx_packets: u64,
+
+    /// Malformed headers, bad checksums and bad options
+    pub rx_header_errors: u64,
+
+    /// Sources that can't be, destinations that aren't forwarded, and link broadcasts that
+    /// would be
+    pub rx_address_errors: u64,
+    pub rx_no_route: u64,
+    pub rx_unknown_protocol: u64,
+    pub rx_delivered: u64,
+    pub forwarded: u64,
+
+    /// Packets dropped because their TTL ran out
+    pub ttl_expired: u64,
+    pub reassembled: u64,
+    pub reassembly_failed: u64,
+    pub reassembly_timeouts: u64,
+    pub fragmented: u64,
+    pub fragments_created: u64,
+    pub fragmentation_failed: u64,
+    pub tx_packets: u64,
+}
+
+struct Counters {
+    rx_packets: AtomicU64,
+    rx_header_errors: AtomicU64,
+    rx_address_errors: AtomicU64,
+    rx_no_route: AtomicU64,
+    rx_unknown_protocol: AtomicU64,
+    rx_delivered: AtomicU64,
+    forwarded: AtomicU64,
+    ttl_expired: AtomicU64,
+    reassembled: AtomicU64,
+    reassembly_failed: AtomicU64,
+    reassembly_timeouts: AtomicU64,
+    fragmented: AtomicU64,
+    fragments_created: AtomicU64,
+    fragmentation_failed: AtomicU64,
+    tx_packets: AtomicU64,
+}
+
+static COUNTERS: Counters = Counters {
+    rx_packets: AtomicU64::new(0),
+    rx_header_errors: AtomicU64::new(0),
+    rx_address_errors: AtomicU64::new(0),
+    rx_no_route: AtomicU64::new(0),
+    rx_unknown_protocol: AtomicU64::new(0),
+    rx_delivered: AtomicU64::new(0),
+    forwarded: AtomicU64::new(0),
+    ttl_expired: AtomicU64::new(0),
+    reassembled: AtomicU64::new(0),
+    reassembly_failed: AtomicU64::new(0),
+    reassembly_timeouts: AtomicU64::new(0),
+    fragmented: AtomicU64::new(0),
+    fragments_created: AtomicU64::new(0),
+    fragmentation_failed: AtomicU64::new(0),
+    tx_packets: AtomicU64::new(0),
+};
+
+fn count(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn stats() -> Ipv4Stats {
+    let c = &COUNTERS;
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    Ipv4Stats {
+        rx_packets: load(&c.rx_packets),
+        rx_header_errors: load(&c.rx_header_errors),
+        rx_address_errors: load(&c.rx_address_errors),
+        rx_no_route: load(&c.rx_no_route),
+        rx_unknown_protocol: load(&c.rx_unknown_protocol),
+        rx_delivered: load(&c.rx_delivered),
+        forwarded: load(&c.forwarded),
+        ttl_expired: load(&c.ttl_expired),
+        reassembled: load(&c.reassembled),
+        reassembly_failed: load(&c.reassembly_failed),
+        reassembly_timeouts: load(&c.reassembly_timeouts),
+        fragmented: load(&c.fragmented),
+        fragments_created: load(&c.fragments_created),
+        fragmentation_failed: load(&c.fragmentation_failed),
+        tx_packets: load(&c.tx_packets),
+    }
+}
+
+static FORWARDING: AtomicBool = AtomicBool::new(true);
+
+//the interface and address of the default gateway
+static GATEWAY: Mutex<Option<(usize, Ipv4Addr)>> = Mutex::new(None);
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Turns forwarding between interfaces on or off. It starts out on.
+pub fn set_forwarding(on: bool) {
+    FORWARDING.store(on, Ordering::Relaxed);
+}
+
+pub fn forwarding() -> bool {
+    FORWARDING.load(Ordering::Relaxed)
+}
+
+/// Sets where packets for destinations on none of the interfaces' networks go
+pub fn set_default_gateway(gateway: Option<(&Interface, Ipv4Addr)>) {
+    without_interrupts(|| *GATEWAY.lock() = gateway.map(|(interface, address)| (interface.index(), address)))
+}
+
+pub fn default_gateway() -> Option<(&'static Interface, Ipv4Addr)> {
+    let (index, address) = without_interrupts(|| *GATEWAY.lock())?;
+    Some((interface::by_index(index)?, address))
+}
+
+/// Where a packet goes next: out of `interface`, to `next_hop` on its link
+#[derive(Copy, Clone)]
+pub struct Route {
+    pub interface: &'static Interface,
+    pub next_hop: Ipv4Addr,
+}
+
+/// Finds the route to `destination`: the longest network of an interface that is up with it
+/// on, or else the default gateway. The limited broadcast address never leaves the link, so it
+/// goes out of the first interface that is up as a link broadcast.
+pub fn route(destination: Ipv4Addr) -> Option<Route> {
+    if destination.is_broadcast() {
+        let interface = interface::interfaces().into_iter().find(|interface| interface.is_up())?;
+        return Some(Route { interface, next_hop: destination });
+    }
+
+    let connected = interface::interfaces()
+        .into_iter()
+        .filter(|interface| interface.is_up())
+        .flat_map(|interface| interface.ipv4_addresses().into_iter().map(move |cidr| (interface, cidr)))
+        .filter(|(_, cidr)| cidr.contains(destination))
+        .max_by_key(|(_, cidr)| cidr.prefix_len);
+    if let Some((interface, _)) = connected {
+        return Some(Route { interface, next_hop: destination });
+    }
+
+    default_gateway()
+        .filter(|(interface, _)| interface.is_up())
+        .map(|(interface, next_hop)| Route { interface, next_hop })
+}
+
+/// What is done with a received packet
+#[derive(Copy, Clone)]
+pub enum Disposition {
+    /// It is for us
+    Local,
+    Forward(Route),
+
+    /// It would be forwarded, but there is no route
+    NoRoute,
+
+    /// It is for somewhere it may not be forwarded to, or forwarding is off
+    NotForwarded,
+}
+
+/// Whether `address` is the limited broadcast address or the broadcast address of an
+/// interface's network
+pub fn is_broadcast(address: Ipv4Addr) -> bool {
+    address.is_broadcast()
+        || interface::interfaces()
+            .iter()
+            .flat_map(|interface| interface.ipv4_addresses())
+            .any(|cidr| cidr.prefix_len < 31 && cidr.broadcast() == address)
+}
+
+//0.0.0.0/8, "this network", which only a host that doesn't know its address yet sends from
+fn is_this_network(address: Ipv4Addr) -> bool {
+    address.octets()[0] == 0
+}
+
+//240.0.0.0/4, the old class E, which is reserved apart from the limited broadcast address
+fn is_class_e(address: Ipv4Addr) -> bool {
+    address.octets()[0] >= 240
+}
+
+//addresses no packet may come from (RFC 1812 5.3.7)
+fn is_martian_source(source: Ipv4Addr) -> bool {
+    source.is_broadcast()
+        || source.is_multicast()
+        || source.is_loopback()
+        || is_this_network(source)
+        || is_class_e(source)
+}
+
+fn is_ours(address: Ipv4Addr) -> bool {
+    interface::interfaces().iter().any(|interface| interface.has_ipv4(address))
+}
+
+/// Decides what to do with a received packet for `destination`. Packets for an address of any
+/// interface are ours, as are broadcasts, which are never forwarded.
+pub fn classify(destination: Ipv4Addr) -> Disposition {
+    if is_ours(destination) || destination.is_multicast() || is_broadcast(destination) {
+        return Disposition::Local;
+    }
+
+    if !forwarding()
+        || is_this_network(destination)
+        || is_class_e(destination)
+        || destination.is_loopback()
+        || destination.is_link_local()
+    {
+        return Disposition::NotForwarded;
+    }
+    route(destination).map_or(Disposition::NoRoute, Disposition::Forward)
+}
+
+/// Handles a packet received on `interface`, with the Ethernet header pulled off.
+/// `link_broadcast` is whether the frame went to a broadcast or multicast MAC address.
+pub fn receive(interface: &'static Interface, packet: Packet, link_broadcast: bool) {
+    input(interface, packet, false, link_broadcast);
+}
+
+//receives a packet, from the wire or, if `looped`, from ourselves through `send`
+fn input(interface: &'static Interface, mut packet: Packet, looped: bool, link_broadcast: bool) {
+    count(&COUNTERS.rx_packets);
+    let header = match Ipv4Header::parse(packet.data()) {
+        Ok(header) if header.total_len as usize <= packet.len() => header,
+        Err(Ipv4Error::BadOption(pointer)) => {
+            count(&COUNTERS.rx_header_errors);
+            icmp::send_error(ErrorMessage::ParameterProblem(pointer), &packet);
+            return;
+        }
+        _ => {
+            count(&COUNTERS.rx_header_errors);
+            return;
+        }
+    };
+    //the Ethernet padding of a short packet
+    if packet.trim(header.total_len as usize).is_err() {
+        return;
+    }
+
+    //only the packets we send ourselves may come from one of our addresses
+    let source = header.source;
+    if is_martian_source(source) || (!looped && is_ours(source)) {
+        count(&COUNTERS.rx_address_errors);
+        return;
+    }
+
+    let disposition = classify(header.destination);
+    //what came to the whole link isn't forwarded, or answered with an error (RFC 1812 5.3.4)
+    if link_broadcast && !matches!(disposition, Disposition::Local) {
+        count(&COUNTERS.rx_address_errors);
+        return;
+    }
+
+    match disposition {
+        Disposition::Local => deliver(interface, header, packet),
+        Disposition::Forward(route) => forward(header, packet, route),
+        Disposition::NoRoute => {
+            count(&COUNTERS.rx_no_route);
+            icmp::send_error(ErrorMessage::NetUnreachable, &packet);
+        }
+        Disposition::NotForwarded => count(&COUNTERS.rx_address_errors),
+    }
+}
+
+//hands a packet for us to its protocol's handler, once it is whole
+fn deliver(interface: &'static Interface, header: Ipv4Header, packet: Packet) {
+    let (header, mut packet) = if header.is_fragment() {
+        match reassemble(&header, packet) {
+            Some(whole) => whole,
+            None => return,
+        }
+    } else {
+        (header, packet)
+    };
+
+    let Some(handler) = handler(header.protocol) else {
+        count(&COUNTERS.rx_unknown_protocol);
+        //there is no UDP, so no port is open
+        let message = if header.protocol == PROTOCOL_UDP { ErrorMessage::PortUnreachable } else { ErrorMessage::ProtocolUnreachable };
+        icmp::send_error(message, &packet);
+        return;
+    };
+    count(&COUNTERS.rx_delivered);
+    let _ = packet.pull(header.len());
+    handler(interface, &header, packet);
+}
+
+fn forward(mut header: Ipv4Header, mut packet: Packet, route: Route) {
+    if header.ttl <= 1 {
+        count(&COUNTERS.ttl_expired);
+        icmp::send_error(ErrorMessage::TtlExceeded, &packet);
+        return;
+    }
+    if header.options.is_source_routed() {
+        count(&COUNTERS.rx_address_errors);
+        return;
+    }
+    let mtu = route.interface.device().mtu();
+    if packet.len() > mtu && header.dont_fragment {
+        count(&COUNTERS.fragmentation_failed);
+        icmp::send_error(ErrorMessage::FragmentationNeeded(mtu as u16), &packet);
+        return;
+    }
+
+    let Ok(data) = packet.data_mut() else {
+        return;
+    };
+    header.ttl -= 1;
+    let recorded = route
+        .interface
+        .ipv4_source(route.next_hop)
+        .is_some_and(|address| header.options.record_route(address));
+    if recorded {
+        header.write(data);
+    } else {
+        //only the TTL changed, which shares a word with the protocol
+        let checksum = u16::from_be_bytes([data[10], data[11]]);
+        let checksum = update_checksum(checksum, u16::from_be_bytes([data[8], data[9]]), u16::from_be_bytes([header.ttl, data[9]]));
+        data[8] = header.ttl;
+        data[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    if output(route, &header, packet, None).is_ok() {
+        count(&COUNTERS.forwarded);
+    }
+}
+
+//sends a packet, header and all, fragmenting it if the MTU calls for it
+fn output(route: Route, header: &Ipv4Header, mut packet: Packet, checksum: Option<TxChecksum>) -> Result<(), SendError> {
+    let mtu = route.interface.device().mtu();
+    if packet.len() <= mtu {
+        return arp::send_ipv4(route.interface, route.next_hop, packet, checksum);
+    }
+    if header.dont_fragment {
+        count(&COUNTERS.fragmentation_failed);
+        return Err(SendError::TooBig(mtu));
+    }
+
+    //the fragments are copies, so the device can't fill in the checksum
+    if let Some(checksum) = checksum {
+        fill_checksum(&mut packet, checksum)?;
+    }
+    let fragments = fragment(header, &packet, mtu).inspect_err(|_| count(&COUNTERS.fragmentation_failed))?;
+    count(&COUNTERS.fragmented);
+
+    let mut result = Ok(());
+    for fragment in fragments {
+        count(&COUNTERS.fragments_created);
+        let sent = arp::send_ipv4(route.interface, route.next_hop, fragment, None);
+        result = result.and(sent);
+    }
+    result
+}
+
+/// Splits a packet, header and all, into fragments that each fit in `mtu`. Options that aren't
+/// copied are only in the first.
+pub fn fragment(header: &Ipv4Header, packet: &Packet, mtu: usize) -> Result<Vec<Packet>, SendError> {
+    let payload_len = packet.len() - header.len();
+    let mut fragments = Vec::new();
+    let mut fragment_header = *header;
+    let mut offset = 0;
+    while offset < payload_len {
+        if offset > 0 {
+            fragment_header.options = header.options.copied();
+        }
+        //every fragment but the last carries a multiple of 8 bytes
+        let room = mtu.saturating_sub(fragment_header.len()) & !7;
+        if room == 0 {
+            return Err(SendError::TooBig(mtu));
+        }
+        let size = room.min(payload_len - offset);
+
+        fragment_header.total_len = (fragment_header.len() + size) as u16;
+        fragment_header.fragment_offset = header.fragment_offset + offset as u16;
+        fragment_header.more_fragments = offset + size < payload_len || header.more_fragments;
+
+        let mut data = vec![0; fragment_header.len() + size];
+        fragment_header.write(&mut data);
+        packet.copy_range(header.len() + offset, &mut data[fragment_header.len()..]);
+        fragments.push(Packet::from_slice(&data)?);
+        offset += size;
+    }
+    Ok(fragments)
+}
+
+/// Sends `payload` to `destination` with an IPv4 header in front. The source is the address of
+/// the way out unless one is given. `checksum` is relative to the payload. Packets for one of
+/// our own addresses are received straight away.
+pub fn send(
+    source: Option<Ipv4Addr>,
+    destination: Ipv4Addr,
+    protocol: u8,
+    ttl: u8,
+    mut payload: Packet,
+    checksum: Option<TxChecksum>,
+) -> Result<(), SendError> {
+    let local = interface::interfaces().into_iter().find(|interface| interface.has_ipv4(destination));
+    let route = match local {
+        Some(interface) => Route { interface, next_hop: destination },
+        None => route(destination).ok_or(SendError::NoRoute)?,
+    };
+    let source = source
+        .or_else(|| route.interface.ipv4_source(route.next_hop))
+        .unwrap_or(Ipv4Addr::UNSPECIFIED);
+
+    if HEADER_SIZE + payload.len() > MAX_PACKET_SIZE {
+        return Err(SendError::TooBig(MAX_PACKET_SIZE));
+    }
+    let header = Ipv4Header {
+        tos: 0,
+        total_len: (HEADER_SIZE + payload.len()) as u16,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        dont_fragment: false,
+        more_fragments: false,
+        fragment_offset: 0,
+        ttl,
+        protocol,
+        source,
+        destination,
+        options: Options::NONE,
+    };
+    header.write(payload.push(header.len())?);
+    let checksum = checksum.map(|c| TxChecksum { start: c.start + header.len() as u16, offset: c.offset });
+    count(&COUNTERS.tx_packets);
+
+    if local.is_some() {
+        if let Some(checksum) = checksum {
+            fill_checksum(&mut payload, checksum)?;
+        }
+        input(route.interface, payload, true, false);
+        return Ok(());
+    }
+    output(route, &header, payload, checksum)
+}
+
+//one fragment waiting to be reassembled, with its header pulled off
+struct Fragment {
+    start: usize,
+    end: usize,
+    payload: Packet,
+}
+
+//a datagram being reassembled. datagrams are told apart by their addresses, ID and protocol.
+struct Reassembly {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    id: u16,
+    protocol: u8,
+
+    //the header of the first fragment, once it is here
+    first: Option<Ipv4Header>,
+
+    //the length of the datagram's data, once the last fragment is here
+    total: Option<usize>,
+
+    //ordered by where they go
+    fragments: Vec<Fragment>,
+
+    //the buffer memory the fragments hold
+    memory: usize,
+    started: Instant,
+}
+
+impl Reassembly {
+    fn matches(&self, header: &Ipv4Header) -> bool {
+        self.source == header.source && self.destination == header.destination && self.id == header.id && self.protocol == header.protocol
+    }
+
+    fn is_complete(&self) -> bool {
+        let Some(total) = self.total else {
+            return false;
+        };
+        let mut covered = 0;
+        for fragment in &self.fragments {
+            if fragment.start != covered {
+                return false;
+            }
+            covered = fragment.end;
+        }
+        self.first.is_some() && covered == total
+    }
+
+    //the datagram, from its fragments, with the header of the first
+    fn assemble(self) -> Option<(Ipv4Header, Packet)> {
+        let mut header = self.first?;
+        let total = self.total?;
+        if header.len() + total > MAX_PACKET_SIZE {
+            return None;
+        }
+        header.total_len = (header.len() + total) as u16;
+        header.more_fragments = false;
+        header.fragment_offset = 0;
+
+        let mut fragments = self.fragments.into_iter();
+        let mut packet = fragments.next()?.payload;
+        for fragment in fragments {
+            packet.append(fragment.payload).ok()?;
+        }
+        //the first fragment's header came off the front of the same buffer, so there is room
+        header.write(packet.push(header.len()).ok()?);
+        Some((header, packet))
+    }
+}
+
+static REASSEMBLIES: Mutex<Vec<Reassembly>> = Mutex::new(Vec::new());
+
+enum Outcome {
+    Waiting,
+    Complete(Reassembly),
+
+    //the fragment was bad, or it made the datagram bad
+    Failed,
+}
+
+//takes one fragment of a datagram for us, and returns the datagram once every fragment of it
+//is here
+fn reassemble(header: &Ipv4Header, mut packet: Packet) -> Option<(Ipv4Header, Packet)> {
+    let _ = packet.pull(header.len());
+    let start = header.fragment_offset as usize;
+    let end = start + packet.len();
+    if packet.is_empty() || (header.more_fragments && !packet.len().is_multiple_of(8)) || header.len() + end > MAX_PACKET_SIZE {
+        count(&COUNTERS.reassembly_failed);
+        return None;
+    }
+    let memory = packet.segments().count() * BUFFER_SIZE;
+
+    let outcome = without_interrupts(|| {
+        let mut reassemblies = REASSEMBLIES.lock();
+        let index = match reassemblies.iter().position(|reassembly| reassembly.matches(header)) {
+            Some(index) => index,
+            None => {
+                if reassemblies.len() >= MAX_REASSEMBLIES {
+                    evict_oldest(&mut reassemblies);
+                }
+                reassemblies.push(Reassembly {
+                    source: header.source,
+                    destination: header.destination,
+                    id: header.id,
+                    protocol: header.protocol,
+                    first: None,
+                    total: None,
+                    fragments: Vec::new(),
+                    memory: 0,
+                    started: Instant::now(),
+                });
+                reassemblies.len() - 1
+            }
+        };
+
+        let reassembly = &mut reassemblies[index];
+        let overlap = reassembly.fragments.iter().find(|other| other.start < end && start < other.end);
+        if let Some(other) = overlap {
+            if other.start == start && other.end == end {
+                return Outcome::Waiting;
+            }
+            reassemblies.swap_remove(index);
+            return Outcome::Failed;
+        }
+
+        //the last fragment says how long the datagram is, and nothing may go past that
+        let beyond_end = if header.more_fragments {
+            reassembly.total.is_some_and(|total| end > total)
+        } else {
+            reassembly.total.is_some_and(|total| total != end) || reassembly.fragments.iter().any(|other| other.end > end)
+        };
+        if beyond_end || reassembly.fragments.len() >= MAX_FRAGMENTS {
+            reassemblies.swap_remove(index);
+            return Outcome::Failed;
+        }
+
+        if !header.more_fragments {
+            reassembly.total = Some(end);
+        }
+        if start == 0 {
+            reassembly.first = Some(*header);
+        }
+        let at = reassembly.fragments.partition_point(|other| other.start < start);
+        reassembly.fragments.insert(at, Fragment { start, end, payload: packet });
+        reassembly.memory += memory;
+
+        if reassembly.is_complete() {
+            return Outcome::Complete(reassemblies.swap_remove(index));
+        }
+
+        //make room by giving up on the oldest datagrams, which may be this one
+        while reassemblies.iter().map(|reassembly| reassembly.memory).sum::<usize>() > REASSEMBLY_MEMORY {
+            evict_oldest(&mut reassemblies);
+        }
+        Outcome::Waiting
+    });
+
+    match outcome {
+        Outcome::Waiting => None,
+        Outcome::Complete(reassembly) => {
+            let whole = reassembly.assemble();
+            count(if whole.is_some() { &COUNTERS.reassembled } else { &COUNTERS.reassembly_failed });
+            whole
+        }
+        Outcome::Failed => {
+            count(&COUNTERS.reassembly_failed);
+            None
+        }
+    }
+}
+
+fn evict_oldest(reassemblies: &mut Vec<Reassembly>) {
+    let oldest = reassemblies.iter().enumerate().min_by_key(|(_, reassembly)| reassembly.started).map(|(i, _)| i);
+    if let Some(i) = oldest {
+        reassemblies.swap_remove(i);
+        count(&COUNTERS.reassembly_failed);
+    }
+}
+
+/// Gives up on the datagrams whose fragments didn't all arrive in time. Called from the
+/// network stack's poll.
+pub fn poll() {
+    //the first fragments of the datagrams given up on, to tell their senders about
+    let mut expired = Vec::new();
+    without_interrupts(|| {
+        REASSEMBLIES.lock().retain(|reassembly| {
+            if reassembly.started.elapsed() < REASSEMBLY_TIMEOUT {
+                return true;
+            }
+            count(&COUNTERS.reassembly_timeouts);
+            if let Some(header) = reassembly.first
+                && let Some(first) = reassembly.fragments.first()
+            {
+                expired.push((header, first.payload.clone()));
+            }
+            false
+        })
+    });
+
+    for (header, payload) in expired {
+        let mut data = vec![0; header.len() + payload.len().min(8)];
+        header.write(&mut data);
+        payload.copy_range(0, &mut data[header.len()..]);
+        if let Ok(original) = Packet::from_slice(&data) {
+            icmp::send_error(ErrorMessage::ReassemblyTimeExceeded, &original);
+        }
+    }
+}
+
+/// Prints the forwarding state and the packet counters
+pub fn report(out: &mut impl Write) -> fmt::Result {
+    let stats = stats();
+    write!(out, "ipv4: forwarding {}", if forwarding() { "on" } else { "off" })?;
+    match default_gateway() {
+        Some((interface, address)) => writeln!(out, ", default gateway {} on {}", address, interface.name())?,
+        None => writeln!(out, ", no default gateway")?,
+    }
+    writeln!(out, "ipv4: rx {} packets, {} delivered, {} header errors, {} address errors, {} no route, {} unknown protocol",
+        stats.rx_packets, stats.rx_delivered, stats.rx_header_errors, stats.rx_address_errors, stats.rx_no_route,
+        stats.rx_unknown_protocol
+    )?;
+    writeln!(out, "ipv4: {} forwarded, {} TTL expired, tx {} packets", stats.forwarded, stats.ttl_expired, stats.tx_packets)?;
+    writeln!(out, "ipv4: reassembled {}, {} failed, {} timed out; fragmented {} into {}, {} failed",
+        stats.reassembled, stats.reassembly_failed, stats.reassembly_timeouts,
+        stats.fragmented, stats.fragments_created, stats.fragmentation_failed
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Ipv4Header {
+        Ipv4Header {
+            tos: 0,
+            total_len: 115,
+            id: 0,
+            dont_fragment: true,
+            more_fragments: false,
+            fragment_offset: 0,
+            ttl: 64,
+            protocol: PROTOCOL_UDP,
+            source: Ipv4Addr::new(192, 168, 0, 1),
+            destination: Ipv4Addr::new(192, 168, 0, 199),
+            options: Options::NONE,
+        }
+    }
+
+    #[test_case]
+    fn martian_addresses_are_dropped() {
+        assert!(is_martian_source(Ipv4Addr::UNSPECIFIED));
+        assert!(is_martian_source(Ipv4Addr::new(0, 1, 2, 3)));
+        assert!(is_martian_source(Ipv4Addr::new(240, 0, 0, 1)));
+        assert!(is_martian_source(Ipv4Addr::new(127, 0, 0, 1)));
+        assert!(is_martian_source(Ipv4Addr::new(224, 0, 0, 1)));
+        assert!(!is_martian_source(Ipv4Addr::new(192, 168, 0, 1)));
+
+        assert!(matches!(classify(Ipv4Addr::new(0, 1, 2, 3)), Disposition::NotForwarded));
+        assert!(matches!(classify(Ipv4Addr::new(250, 1, 2, 3)), Disposition::NotForwarded));
+    }
+
+    #[test_case]
+    fn header_round_trip() {
+        let mut bytes = [0; HEADER_SIZE];
+        header().write(&mut bytes);
+        assert_eq!(&bytes[10..12], &[0xB8, 0x61]);
+        assert_eq!(Ipv4Header::parse(&bytes), Ok(header()));
+
+        bytes[15] ^= 1;
+        assert_eq!(Ipv4Header::parse(&bytes), Err(Ipv4Error::BadChecksum));
+        assert_eq!(Ipv4Header::parse(&bytes[..19]), Err(Ipv4Error::Malformed));
+    }
+
+    #[test_case]
+    fn incremental_update_matches_full_checksum() {
+        let mut bytes = [0; HEADER_SIZE];
+        header().write(&mut bytes);
+        let old = u16::from_be_bytes([bytes[8], bytes[9]]);
+        let updated = update_checksum(u16::from_be_bytes([bytes[10], bytes[11]]), old, old - 0x100);
+
+        let mut decremented = header();
+        decremented.ttl -= 1;
+        decremented.write(&mut bytes);
+        assert_eq!(updated, u16::from_be_bytes([bytes[10], bytes[11]]));
+    }
+
+    #[test_case]
+    fn options_are_checked() {
+        let mut header = header();
+        //record route with room for two addresses, then a router alert
+        header.options.bytes[..12].copy_from_slice(&[7, 11, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        header.options.bytes[11..15].copy_from_slice(&[148, 4, 0, 0]);
+        header.options.len = 16;
+        assert!(header.options.find(148).is_some() && !header.options.is_source_routed());
+        assert!(header.options.record_route(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(header.options.record_route(Ipv4Addr::new(10, 0, 0, 2)));
+        assert!(!header.options.record_route(Ipv4Addr::new(10, 0, 0, 3)));
+        //only the router alert is copied into later fragments
+        assert_eq!(header.options.copied().as_slice(), &[148, 4, 0, 0]);
+
+        let mut bytes = [0; HEADER_SIZE + 16];
+        header.write(&mut bytes);
+        assert_eq!(Ipv4Header::parse(&bytes), Ok(header));
+
+        //an option running past the end of the header
+        header.options.bytes[12] = 40;
+        header.write(&mut bytes);
+        assert_eq!(Ipv4Header::parse(&bytes), Err(Ipv4Error::BadOption(HEADER_SIZE as u8 + 12)));
+    }
+
+    #[test_case]
+    fn packets_are_fragmented() {
+        let mut header = header();
+        header.dont_fragment = false;
+        header.total_len = (HEADER_SIZE + 3000) as u16;
+        let mut data = vec![0; HEADER_SIZE + 3000];
+        header.write(&mut data);
+        let packet = Packet::from_slice(&data).unwrap();
+
+        let fragments = fragment(&header, &packet, 1500).unwrap();
+        let headers: Vec<Ipv4Header> = fragments.iter().map(|f| Ipv4Header::parse(f.data()).unwrap()).collect();
+        assert_eq!(headers.iter().map(|h| h.fragment_offset).collect::<Vec<_>>(), [0, 1480, 2960]);
+        assert_eq!(headers.iter().map(|h| h.more_fragments).collect::<Vec<_>>(), [true, true, false]);
+        assert_eq!(fragments[2].len(), HEADER_SIZE + 40);
+    }
+
+    #[test_case]
+    fn link_broadcasts_are_not_forwarded() {
+        let interface = interface::by_index(0).expect("the test kernel has a NIC");
+        let address = Ipv4Addr::new(10, 98, 0, 1);
+        interface.add_ipv4(interface::Ipv4Cidr::new(address, 24).unwrap()).unwrap();
+        let forwarding = forwarding();
+        set_forwarding(true);
+
+        let mut header = header();
+        header.total_len = HEADER_SIZE as u16;
+        header.source = Ipv4Addr::new(10, 98, 0, 2);
+        header.destination = Ipv4Addr::new(10, 98, 0, 3);
+        let mut data = [0; HEADER_SIZE];
+        header.write(&mut data);
+
+        let before = stats();
+        receive(interface, Packet::from_slice(&data).unwrap(), true);
+        let after = stats();
+        assert_eq!(after.forwarded, before.forwarded);
+        assert_eq!(after.rx_no_route, before.rx_no_route);
+        assert_eq!(after.rx_address_errors, before.rx_address_errors + 1);
+
+        //the limited broadcast stays on the link, whatever the default gateway
+        let gateway = default_gateway();
+        set_default_gateway(Some((interface, Ipv4Addr::new(10, 98, 0, 254))));
+        assert!(route(Ipv4Addr::BROADCAST).is_none_or(|route| route.next_hop == Ipv4Addr::BROADCAST));
+
+        set_default_gateway(gateway);
+        set_forwarding(forwarding);
+        interface.remove_ipv4(address).unwrap();
+    }
+}
@@ -0,0 +1,575 @@
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::acpi::madt::MAX_CPUS;
+use crate::interrupt::apic;
+use crate::interrupt::interrupt::without_interrupts;
+use crate::memory::dma::DmaRegion;
+use crate::memory::frame::{FrameConstraints, FrameError, FRAME_SIZE};
+
+/*
+ * Packet buffers. Every buffer is one physical frame: a small metadata block, then room for
+ * data that devices can DMA to and from directly. A Packet is a handle to a buffer with the
+ * range of it that holds the packet, which starts HEADROOM bytes in so lower layers can push
+ * their headers in front without moving anything.
+ *
+ * Buffers are reference counted. Cloning a Packet shares the buffer, which is how a frame goes
+ * out of several ports at once. The range is kept in the handle, so every holder can pull
+ * headers off independently, but writing needs the buffer to itself: a shared head buffer is
+ * copied first.
+ *
+ * A packet bigger than a buffer, like a jumbo frame, is a chain of buffers. The head buffer is
+ * the handle's, and every buffer after it keeps its own range in its metadata. Headers only ever
+ * go in the head buffer.
+ *
+ * Buffers come from slabs of contiguous frames that are never given back. Freed buffers go to a
+ * small cache per CPU first, which trades batches with the global free list.
+ */
+
+/// The size of a buffer, metadata included
+pub const BUFFER_SIZE: usize = FRAME_SIZE as usize;
+
+//the metadata at the start of every buffer, padded to a cache line
+const META_SIZE: usize = 64;
+
+/// The room left in front of a new packet for headers to be pushed into
+pub const HEADROOM: usize = 128;
+
+/// The most data one buffer holds, headroom included
+pub const BUFFER_CAPACITY: usize = BUFFER_SIZE - META_SIZE;
+
+/// The most buffers the pool grows to
+pub const MAX_BUFFERS: usize = 8192;
+
+const SLAB_BUFFERS: usize = 64;
+const CACHE_SIZE: usize = 64;
+const CACHE_BATCH: usize = CACHE_SIZE / 2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PacketError {
+    /// Every buffer is in use and the pool can't grow any more
+    Exhausted,
+    Memory(FrameError),
+
+    /// There isn't enough room in front of the packet for the header
+    NoHeadroom,
+
+    /// There isn't enough room after the packet, or it is chained
+    NoTailroom,
+
+    /// The packet is shorter than what was asked for
+    TooShort,
+}
+
+#[repr(C)]
+struct Meta {
+    refs: AtomicU32,
+
+    //the range of the buffer a chained buffer covers, as offsets from the start of the buffer
+    start: u16,
+    end: u16,
+
+    //the next buffer of the chain
+    next: Option<NonNull<Meta>>,
+}
+
+const _: () = assert!(size_of::<Meta>() <= META_SIZE);
+
+struct Pool {
+    //the slabs are kept so they are never freed
+    slabs: Vec<DmaRegion>,
+    free: Vec<usize>,
+}
+
+struct Cache {
+    buffers: [usize; CACHE_SIZE],
+    count: usize,
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool { slabs: Vec::new(), free: Vec::new() });
+static CACHES: [Mutex<Cache>; MAX_CPUS] = [const { Mutex::new(Cache { buffers: [0; CACHE_SIZE], count: 0 }) }; MAX_CPUS];
+
+/// How many buffers there are and how many are free
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PoolStats {
+    pub buffers: usize,
+    pub free: usize,
+}
+
+impl Pool {
+    fn grow(&mut self) -> Result<(), PacketError> {
+        if self.slabs.len() * SLAB_BUFFERS >= MAX_BUFFERS {
+            return Err(PacketError::Exhausted);
+        }
+
+        let slab = DmaRegion::alloc(SLAB_BUFFERS * BUFFER_SIZE, FrameConstraints::ANY).map_err(PacketError::Memory)?;
+        let base = slab.as_ptr::<u8>() as usize;
+        self.free.extend((0..SLAB_BUFFERS).rev().map(|i| base + i * BUFFER_SIZE));
+        self.slabs.push(slab);
+        Ok(())
+    }
+}
+
+fn cache() -> &'static Mutex<Cache> {
+    &CACHES[apic::id().unwrap_or(0) as usize % MAX_CPUS]
+}
+
+fn alloc_buffer() -> Result<NonNull<Meta>, PacketError> {
+    let address = without_interrupts(|| {
+        let mut cache = cache().lock();
+        if cache.count == 0 {
+            let mut pool = POOL.lock();
+            if pool.free.is_empty() {
+                pool.grow()?;
+            }
+
+            let take = pool.free.len().min(CACHE_BATCH);
+            let from = pool.free.len() - take;
+            cache.buffers[..take].copy_from_slice(&pool.free[from..]);
+            pool.free.truncate(from);
+            cache.count = take;
+        }
+
+        cache.count -= 1;
+        Ok(cache.buffers[cache.count])
+    })?;
+
+    let meta = address as *mut Meta;
+    unsafe {
+        meta.write(Meta {
+            refs: AtomicU32::new(1),
+            start: 0,
+            end: 0,
+            next: None,
+        });
+        Ok(NonNull::new_unchecked(meta))
+    }
+}
+
+fn free_buffer(meta: NonNull<Meta>) {
+    without_interrupts(|| {
+        let mut cache = cache().lock();
+        if cache.count == CACHE_SIZE {
+            let from = CACHE_SIZE - CACHE_BATCH;
+            POOL.lock().free.extend_from_slice(&cache.buffers[from..]);
+            cache.count = from;
+        }
+
+        let count = cache.count;
+        cache.buffers[count] = meta.as_ptr() as usize;
+        cache.count += 1;
+    })
+}
+
+//drops a reference to a buffer, and frees it and the rest of its chain if it was the last one
+fn release(meta: NonNull<Meta>) {
+    let mut next = Some(meta);
+    while let Some(meta) = next {
+        if unsafe { meta.as_ref() }.refs.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+
+        //whatever the other holders did with the buffer happened before it is reused
+        fence(Ordering::Acquire);
+        next = unsafe { meta.as_ref() }.next;
+        free_buffer(meta);
+    }
+}
+
+/// The pool's buffer counts
+pub fn stats() -> PoolStats {
+    let cached: usize = CACHES.iter().map(|cache| without_interrupts(|| cache.lock().count)).sum();
+    without_interrupts(|| {
+        let pool = POOL.lock();
+        PoolStats { buffers: pool.slabs.len() * SLAB_BUFFERS, free: pool.free.len() + cached }
+    })
+}
+
+/// A packet in one or more pool buffers
+pub struct Packet {
+    buffer: NonNull<Meta>,
+
+    //the range of the head buffer the packet covers, as offsets from the start of the buffer
+    start: u16,
+    end: u16,
+}
+
+// The buffers are only written through a handle that has them to itself
+unsafe impl Send for Packet {}
+unsafe impl Sync for Packet {}
+
+impl Packet {
+    /// An empty packet with HEADROOM bytes of headroom
+    pub fn new() -> Result<Self, PacketError> {
+        Self::with_headroom(HEADROOM)
+    }
+
+    /// An empty packet with `headroom` bytes of headroom, at most BUFFER_CAPACITY
+    pub fn with_headroom(headroom: usize) -> Result<Self, PacketError> {
+        let start = (META_SIZE + headroom.min(BUFFER_CAPACITY)) as u16;
+        Ok(Packet { buffer: alloc_buffer()?, start, end: start })
+    }
+
+    /// A packet holding a copy of `data`, chained over as many buffers as it takes
+    pub fn from_slice(data: &[u8]) -> Result<Self, PacketError> {
+        let first = data.len().min(BUFFER_CAPACITY - HEADROOM);
+        let mut packet = Packet::new()?;
+        packet.put(first)?.copy_from_slice(&data[..first]);
+
+        for chunk in data[first..].chunks(BUFFER_CAPACITY) {
+            let mut segment = Packet::with_headroom(0)?;
+            segment.put(chunk.len())?.copy_from_slice(chunk);
+            packet.append(segment)?;
+        }
+        Ok(packet)
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.buffer.as_ptr() as *mut u8
+    }
+
+    fn meta(&self) -> &Meta {
+        unsafe { self.buffer.as_ref() }
+    }
+
+    //the buffers after the head one, with their ranges
+    fn chain(&self) -> impl Iterator<Item = NonNull<Meta>> + '_ {
+        core::iter::successors(self.meta().next, |meta| unsafe { meta.as_ref() }.next)
+    }
+
+    /// Whether another handle shares the head buffer
+    pub fn is_shared(&self) -> bool {
+        self.meta().refs.load(Ordering::Acquire) != 1
+    }
+
+    pub fn is_chained(&self) -> bool {
+        self.meta().next.is_some()
+    }
+
+    /// The length of the whole packet, every buffer of the chain included
+    pub fn len(&self) -> usize {
+        self.segments().map(<[u8]>::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn headroom(&self) -> usize {
+        self.start as usize - META_SIZE
+    }
+
+    /// The room after the packet. A chained packet has none, since only the last buffer could
+    /// grow.
+    pub fn tailroom(&self) -> usize {
+        if self.is_chained() { 0 } else { BUFFER_SIZE - self.end as usize }
+    }
+
+    /// Where the packet starts for a device. Received data goes here, with tailroom bytes of
+    /// space.
+    pub fn phys(&self) -> PhysAddr {
+        //buffers are identity mapped
+        PhysAddr::new(self.base() as u64 + self.start as u64)
+    }
+
+    /// The part of the packet in the head buffer
+    pub fn data(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.base().add(self.start as usize), (self.end - self.start) as usize) }
+    }
+
+    /// The part of the packet in the head buffer, for writing. A shared head buffer is copied
+    /// first.
+    pub fn data_mut(&mut self) -> Result<&mut [u8], PacketError> {
+        self.make_unique()?;
+        Ok(unsafe { core::slice::from_raw_parts_mut(self.base().add(self.start as usize), (self.end - self.start) as usize) })
+    }
+
+    /// The data of every buffer, in order
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        let chained = self.chain().map(|meta| {
+            let m = unsafe { meta.as_ref() };
+            unsafe { core::slice::from_raw_parts((meta.as_ptr() as *const u8).add(m.start as usize), (m.end - m.start) as usize) }
+        });
+        core::iter::once(self.data()).chain(chained)
+    }
+
+    /// Where each buffer's data is for a device, and how long it is
+    pub fn dma_segments(&self) -> impl Iterator<Item = (PhysAddr, usize)> + '_ {
+        self.segments().map(|data| (PhysAddr::new(data.as_ptr() as u64), data.len()))
+    }
+
+    /// Copies as much of the packet as fits into `buf`, and returns how much that was
+    pub fn copy_to(&self, buf: &mut [u8]) -> usize {
+        self.copy_range(0, buf)
+    }
+
+    /// Copies as much of the packet from `offset` on as fits into `buf`, and returns how much
+    /// that was
+    pub fn copy_range(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut copied = 0;
+        let mut skip = offset;
+        for data in self.segments() {
+            let skipped = skip.min(data.len());
+            skip -= skipped;
+            let n = (data.len() - skipped).min(buf.len() - copied);
+            buf[copied..copied + n].copy_from_slice(&data[skipped..skipped + n]);
+            copied += n;
+        }
+        copied
+    }
+
+    /// Gives the handle a head buffer of its own, copying the shared one. The rest of the
+    /// chain stays shared.
+    pub fn make_unique(&mut self) -> Result<(), PacketError> {
+        if !self.is_shared() {
+            return Ok(());
+        }
+
+        let copy = alloc_buffer()?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.base().add(META_SIZE), (copy.as_ptr() as *mut u8).add(META_SIZE), BUFFER_CAPACITY);
+            let next = self.meta().next;
+            if let Some(next) = next {
+                next.as_ref().refs.fetch_add(1, Ordering::Relaxed);
+            }
+            (*copy.as_ptr()).next = next;
+        }
+
+        release(core::mem::replace(&mut self.buffer, copy));
+        Ok(())
+    }
+
+    //gives every buffer of the chain a single holder, copying shared ones, so the chain can be
+    //changed
+    fn make_chain_unique(&mut self) -> Result<(), PacketError> {
+        self.make_unique()?;
+
+        let mut link = self.buffer;
+        while let Some(next) = unsafe { link.as_ref() }.next {
+            let next = if unsafe { next.as_ref() }.refs.load(Ordering::Acquire) == 1 {
+                next
+            } else {
+                let copy = alloc_buffer()?;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(next.as_ptr() as *const u8, copy.as_ptr() as *mut u8, BUFFER_SIZE);
+                    (*copy.as_ptr()).refs = AtomicU32::new(1);
+                    if let Some(after) = next.as_ref().next {
+                        after.as_ref().refs.fetch_add(1, Ordering::Relaxed);
+                    }
+                    (*link.as_ptr()).next = Some(copy);
+                }
+                release(next);
+                copy
+            };
+            link = next;
+        }
+        Ok(())
+    }
+
+    /// Makes room for `len` bytes in front of the packet, for a header, and returns them
+    pub fn push(&mut self, len: usize) -> Result<&mut [u8], PacketError> {
+        if len > self.headroom() {
+            return Err(PacketError::NoHeadroom);
+        }
+
+        self.make_unique()?;
+        self.start -= len as u16;
+        Ok(unsafe { core::slice::from_raw_parts_mut(self.base().add(self.start as usize), len) })
+    }
+
+    /// Takes `len` bytes off the front of the packet, a header that has been dealt with, and
+    /// returns them. Headers are always in the head buffer.
+    pub fn pull(&mut self, len: usize) -> Result<&[u8], PacketError> {
+        if len > self.data().len() {
+            return Err(PacketError::TooShort);
+        }
+
+        let header = unsafe { core::slice::from_raw_parts(self.base().add(self.start as usize), len) };
+        self.start += len as u16;
+        Ok(header)
+    }
+
+    /// Adds `len` bytes to the end of the packet and returns them
+    pub fn put(&mut self, len: usize) -> Result<&mut [u8], PacketError> {
+        if len > self.tailroom() {
+            return Err(PacketError::NoTailroom);
+        }
+
+        self.make_unique()?;
+        let at = self.end as usize;
+        self.end += len as u16;
+        Ok(unsafe { core::slice::from_raw_parts_mut(self.base().add(at), len) })
+    }
+
+    /// Cuts the packet down to its first `len` bytes, like the padding after an IP packet in
+    /// a minimum size Ethernet frame
+    pub fn trim(&mut self, len: usize) -> Result<(), PacketError> {
+        if len >= self.len() {
+            return Ok(());
+        }
+
+        let head = self.data().len();
+        if !self.is_chained() || len <= head {
+            self.make_unique()?;
+            self.end = self.start + len.min(head) as u16;
+            if let Some(next) = unsafe { (*self.buffer.as_ptr()).next.take() } {
+                release(next);
+            }
+            return Ok(());
+        }
+
+        //the cut is somewhere in the chain, whose ranges are in the buffers
+        self.make_chain_unique()?;
+        let mut left = len - head;
+        let mut link = self.buffer;
+        while let Some(next) = unsafe { link.as_ref() }.next {
+            let meta = unsafe { &mut *next.as_ptr() };
+            let size = (meta.end - meta.start) as usize;
+            if left <= size {
+                meta.end = meta.start + left as u16;
+                if let Some(rest) = meta.next.take() {
+                    release(rest);
+                }
+                break;
+            }
+            left -= size;
+            link = next;
+        }
+        Ok(())
+    }
+
+    /// Adds `tail` to the end of the packet's chain, without copying it
+    pub fn append(&mut self, mut tail: Packet) -> Result<(), PacketError> {
+        self.make_chain_unique()?;
+        tail.make_chain_unique()?;
+
+        //the tail's range moves from its handle into its buffer
+        let tail = core::mem::ManuallyDrop::new(tail);
+        unsafe {
+            (*tail.buffer.as_ptr()).start = tail.start;
+            (*tail.buffer.as_ptr()).end = tail.end;
+        }
+
+        let last = self.chain().last().unwrap_or(self.buffer);
+        unsafe { (*last.as_ptr()).next = Some(tail.buffer) };
+        Ok(())
+    }
+
+    /// The Internet checksum of the packet from `offset` on: the complement of the ones'
+    /// complement sum of its 16 bit words
+    pub fn checksum(&self, offset: usize) -> u16 {
+        let mut sum = 0u64;
+        let mut skip = offset;
+        let mut odd = false;
+        for data in self.segments() {
+            let skipped = skip.min(data.len());
+            skip -= skipped;
+            add_to_sum(&mut sum, &mut odd, &data[skipped..]);
+        }
+        !fold(sum)
+    }
+}
+
+//adds bytes to a ones' complement sum, keeping track of a word split between two segments
+fn add_to_sum(sum: &mut u64, odd: &mut bool, mut data: &[u8]) {
+    if *odd && let Some((&low, rest)) = data.split_first() {
+        *sum += low as u64;
+        data = rest;
+        *odd = false;
+    }
+
+    let (words, rest) = data.as_chunks::<2>();
+    for word in words {
+        *sum += u16::from_be_bytes(*word) as u64;
+    }
+    if let [high] = rest {
+        *sum += (*high as u64) << 8;
+        *odd = true;
+    }
+}
+
+/// Folds a ones' complement sum into 16 bits
+pub fn fold(mut sum: u64) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+impl Clone for Packet {
+    fn clone(&self) -> Self {
+        self.meta().refs.fetch_add(1, Ordering::Relaxed);
+        Packet { buffer: self.buffer, start: self.start, end: self.end }
+    }
+}
+
+impl Drop for Packet {
+    fn drop(&mut self) {
+        release(self.buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn headers_push_and_pull() {
+        let mut packet = Packet::new().unwrap();
+        packet.put(4).unwrap().copy_from_slice(&[1, 2, 3, 4]);
+        packet.push(2).unwrap().copy_from_slice(&[9, 9]);
+        assert_eq!(packet.data(), &[9, 9, 1, 2, 3, 4]);
+        assert_eq!(packet.headroom(), HEADROOM - 2);
+
+        assert_eq!(packet.pull(2).unwrap(), &[9, 9]);
+        assert_eq!(packet.data(), &[1, 2, 3, 4]);
+        assert_eq!(packet.pull(5), Err(PacketError::TooShort));
+    }
+
+    #[test_case]
+    fn shared_buffers_are_copied_on_write() {
+        let mut packet = Packet::from_slice(&[1, 2, 3]).unwrap();
+        let copy = packet.clone();
+        assert!(packet.is_shared());
+
+        packet.data_mut().unwrap()[0] = 7;
+        assert!(!packet.is_shared() && !copy.is_shared());
+        assert_eq!(packet.data(), &[7, 2, 3]);
+        assert_eq!(copy.data(), &[1, 2, 3]);
+    }
+
+    #[test_case]
+    fn jumbo_frames_are_chained() {
+        let data: Vec<u8> = (0..9000u32).map(|i| i as u8).collect();
+        let mut packet = Packet::from_slice(&data).unwrap();
+        assert!(packet.is_chained());
+        assert_eq!(packet.len(), 9000);
+        assert_eq!(packet.segments().count(), 3);
+
+        let mut copy = alloc::vec![0; 9000];
+        assert_eq!(packet.copy_to(&mut copy), 9000);
+        assert_eq!(copy, data);
+
+        let mut middle = [0; 16];
+        assert_eq!(packet.copy_range(4030, &mut middle), 16);
+        assert_eq!(&middle[..], &data[4030..4046]);
+
+        packet.trim(5000).unwrap();
+        assert_eq!(packet.len(), 5000);
+    }
+
+    #[test_case]
+    fn checksum_spans_segments() {
+        //an IPv4 header with the checksum field cleared
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+            0x00, 0x00, 0xC0, 0xA8, 0x00, 0x01, 0xC0, 0xA8, 0x00, 0xC7,
+        ];
+        let mut packet = Packet::from_slice(&header[..7]).unwrap();
+        packet.append(Packet::from_slice(&header[7..]).unwrap()).unwrap();
+        assert_eq!(packet.checksum(0), 0xB861);
+    }
+}
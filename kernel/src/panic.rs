@@ -0,0 +1,159 @@
+use core::arch::asm;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::console::{self, EmergencyConsole};
+use crate::pstore::{self, CrashRegisters};
+use crate::interrupt;
+#[cfg(not(test))]
+use crate::reboot;
+#[cfg(test)]
+use crate::testing::{self, QemuExitCode};
+
+/*
+ * The panic handler. It stops interrupts, saves a crash record, and prints the message,
+ * the registers and a backtrace on every console sink without taking any locks. Then the machine
+ * either halts, or, by default, reboots after a delay so a router comes back on its own.
+ *
+ * A panic inside the panic handler is caught: the second one only prints its message and skips
+ * straight to halting or rebooting, and a third one halts without touching anything.
+ *
+ * The backtrace follows the saved frame pointers, which needs the kernel to be built with them
+ * (`frame-pointer` in the target spec). Addresses can be turned into names with
+ * `addr2line -e kernel.bin`.
+ */
+
+/// How long to wait before rebooting after a panic, unless set otherwise
+pub const DEFAULT_REBOOT_DELAY_SECS: u64 = 10;
+
+//the most frames printed in a backtrace
+const MAX_FRAMES: usize = 32;
+
+//frames are only followed while they stay this close above the panic handler's own stack
+//pointer, so a corrupt frame pointer can't send the walk anywhere else
+const MAX_STACK_SPAN: u64 = 16 * 1024 * 1024;
+
+//`REBOOT_DELAY_SECS` holds this to mean halt instead of rebooting
+const HALT: u64 = u64::MAX;
+
+/// What the panic handler does once it has printed everything
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PanicAction {
+    /// Stop the CPU for good, leaving the output on screen
+    Halt,
+
+    /// Reset the machine after this many seconds
+    Reboot(u64),
+}
+
+static REBOOT_DELAY_SECS: AtomicU64 = AtomicU64::new(DEFAULT_REBOOT_DELAY_SECS);
+static PANIC_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets what happens after a panic
+pub fn set_action(action: PanicAction) {
+    let delay = match action {
+        PanicAction::Halt => HALT,
+        PanicAction::Reboot(secs) => secs.min(HALT - 1),
+    };
+    REBOOT_DELAY_SECS.store(delay, Ordering::Relaxed);
+}
+
+pub fn action() -> PanicAction {
+    match REBOOT_DELAY_SECS.load(Ordering::Relaxed) {
+        HALT => PanicAction::Halt,
+        secs => PanicAction::Reboot(secs),
+    }
+}
+
+fn halt() -> ! {
+    loop {
+        interrupt::disable_interrupts();
+        x86_64::instructions::hlt();
+    }
+}
+
+//follows the saved frame pointers up the stack, printing each return address
+fn backtrace(out: &mut impl Write) {
+    let (mut frame, rsp): (u64, u64);
+    unsafe {
+        asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+
+    let _ = writeln!(out, "backtrace:");
+    for depth in 0..MAX_FRAMES {
+        //each frame has to be above the last one, on the same stack
+        if frame == 0 || frame % 8 != 0 || frame < rsp || frame - rsp > MAX_STACK_SPAN {
+            return;
+        }
+
+        let (next, ret) = unsafe { (*(frame as *const u64), *((frame + 8) as *const u64)) };
+        if ret == 0 {
+            return;
+        }
+        let _ = writeln!(out, "  #{:<2} {:#018x}", depth, ret);
+
+        if next <= frame {
+            return;
+        }
+        frame = next;
+    }
+    let _ = writeln!(out, "  ...");
+}
+
+/// Handles a panic. Called by the `#[panic_handler]`.
+pub fn handle(info: &PanicInfo) -> ! {
+    interrupt::disable_interrupts();
+    let regs = CrashRegisters::capture();
+    let depth = PANIC_DEPTH.fetch_add(1, Ordering::SeqCst);
+
+    //a third panic means even the short path below is broken
+    if depth >= 2 {
+        halt();
+    }
+
+    //whatever was printing when the panic hit may still hold the console lock
+    console::enter_emergency();
+    let mut out = EmergencyConsole;
+
+    if depth == 1 {
+        let _ = writeln!(out, "\nkernel panicked while panicking: {}", info);
+    } else {
+        //save the crash so it can be read back after the reboot
+        pstore::record_crash(info, &regs);
+
+        let _ = writeln!(out, "\n==== kernel panic on cpu{} ====", console::logger::cpu_id());
+        match info.location() {
+            Some(location) => {
+                let _ = writeln!(out, "panicked at {}:{}:{}", location.file(), location.line(), location.column());
+            }
+            None => {
+                let _ = writeln!(out, "panicked at an unknown location");
+            }
+        }
+        let _ = writeln!(out, "{}", info.message());
+        let _ = regs.dump(&mut out);
+        backtrace(&mut out);
+    }
+
+    finish(&mut out)
+}
+
+#[cfg(not(test))]
+fn finish(out: &mut impl Write) -> ! {
+    match action() {
+        PanicAction::Halt => {
+            let _ = writeln!(out, "system halted");
+            halt()
+        }
+        PanicAction::Reboot(secs) => reboot::reboot_after(secs, out),
+    }
+}
+
+//a panic in the test kernel is a failed test, and ends the run
+#[cfg(test)]
+fn finish(out: &mut impl Write) -> ! {
+    let _ = writeln!(out, "FAILED");
+    testing::exit_qemu(QemuExitCode::Failed)
+}
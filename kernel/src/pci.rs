@@ -0,0 +1,346 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+use log::{info, warn};
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::paging::{self, CacheMode, PagingError};
+use crate::time;
+
+pub mod bar;
+pub mod capability;
+pub mod config;
+pub mod driver;
+pub mod msi;
+mod scan;
+
+use bar::{Bar, MAX_BARS};
+use capability::{Capabilities, PowerState};
+
+/*
+ * The PCI bus. `init` walks every bus reachable from the host bridge, through ECAM if the
+ * firmware describes one and the legacy port pair otherwise, and records each function it finds
+ * with its BARs and capabilities. Drivers register with the registry in `driver` and are handed
+ * the functions they match.
+ *
+ * Before `init`, the config space accessors and `find_device` still work through the legacy
+ * ports, which is what the watchdog drivers were written against.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/PCI
+ * https://wiki.osdev.org/PCI_Express
+ */
+
+pub const VENDOR_NONE: u16 = 0xFFFF;
+
+//common header registers
+pub const VENDOR_ID: u8 = 0x00;
+pub const COMMAND: u8 = 0x04;
+pub const STATUS: u8 = 0x06;
+pub const REVISION: u8 = 0x08;
+pub const HEADER_TYPE: u8 = 0x0E;
+pub const SUBSYSTEM_VENDOR_ID: u8 = 0x2C;
+pub const SUBSYSTEM_ID: u8 = 0x2E;
+pub const INTERRUPT_LINE: u8 = 0x3C;
+pub const INTERRUPT_PIN: u8 = 0x3D;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+pub const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+//header types, without the multi-function bit
+pub const HEADER_DEVICE: u8 = 0x00;
+pub const HEADER_BRIDGE: u8 = 0x01;
+pub const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PciError {
+    /// The ECAM region or a BAR couldn't be mapped
+    Map(PagingError),
+
+    /// ECAM reads something different from the legacy ports at 00:00.0, so it isn't used
+    EcamMismatch,
+
+    /// The function has no BAR with that index
+    NoSuchBar,
+
+    /// The BAR is an I/O BAR, or has no address assigned
+    NotMappable,
+
+    /// Every driver slot is taken
+    TooManyDrivers,
+}
+
+/// The location of a PCI function on the bus
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress { bus, device, function }
+    }
+
+    pub fn read32(&self, offset: u8) -> u32 {
+        config::read32(*self, offset)
+    }
+
+    pub fn read16(&self, offset: u8) -> u16 {
+        config::read16(*self, offset)
+    }
+
+    pub fn read8(&self, offset: u8) -> u8 {
+        config::read8(*self, offset)
+    }
+
+    pub fn write32(&self, offset: u8, value: u32) {
+        config::write32(*self, offset, value)
+    }
+
+    pub fn write16(&self, offset: u8, value: u16) {
+        config::write16(*self, offset, value)
+    }
+
+    pub fn write8(&self, offset: u8, value: u8) {
+        config::write8(*self, offset, value)
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read16(VENDOR_ID)
+    }
+}
+
+// The usual bb:dd.f form
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A function found on the bus, and what was learned about it
+#[derive(Debug)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+
+    /// The header type, without the multi-function bit
+    pub header_type: u8,
+    pub subsystem_vendor: u16,
+    pub subsystem: u16,
+
+    /// The legacy INTx pin, 1 to 4 for INTA# to INTD#, or 0 for none
+    pub interrupt_pin: u8,
+
+    /// The IRQ the firmware routed INTx to, 0xFF if it didn't
+    pub interrupt_line: u8,
+    pub bars: [Option<Bar>; MAX_BARS],
+    pub capabilities: Capabilities,
+
+    /// For bridges, the bus on the other side
+    pub secondary_bus: Option<u8>,
+
+    //the name of the driver that claimed the function
+    driver: Once<&'static str>,
+}
+
+impl PciDevice {
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        self.bars.get(index).copied().flatten()
+    }
+
+    /// Maps a memory BAR, and returns the virtual address of its start
+    pub fn map_bar(&self, index: usize, cache: CacheMode) -> Result<VirtAddr, PciError> {
+        match self.bar(index).ok_or(PciError::NoSuchBar)? {
+            Bar::Memory { address, size, .. } if address != 0 => {
+                paging::map_mmio(PhysAddr::new(address), size, cache).map_err(PciError::Map)
+            }
+            _ => Err(PciError::NotMappable),
+        }
+    }
+
+    fn set_command(&self, bits: u16, on: bool) {
+        let command = self.address.read16(COMMAND);
+        let command = if on { command | bits } else { command & !bits };
+        self.address.write16(COMMAND, command);
+    }
+
+    /// Turns on memory and I/O decoding for the BARs the function has
+    pub fn enable(&self) {
+        let mut bits = 0;
+        for bar in self.bars.iter().flatten() {
+            bits |= match bar {
+                Bar::Memory { .. } => COMMAND_MEMORY,
+                Bar::Io { .. } => COMMAND_IO,
+            };
+        }
+        self.set_command(bits, true);
+    }
+
+    /// Lets the function do DMA, which it needs to use descriptor rings
+    pub fn enable_bus_master(&self) {
+        self.set_command(COMMAND_BUS_MASTER, true);
+    }
+
+    /// Turns legacy INTx delivery on or off, for drivers that move to MSI
+    pub fn set_intx(&self, enabled: bool) {
+        self.set_command(COMMAND_INTX_DISABLE, !enabled);
+    }
+
+    /// Brings the function to D0 if it supports power management and is in a lower state
+    pub fn power_on(&self) {
+        if let Some(pm) = self.capabilities.power
+            && pm.state(self.address) != PowerState::D0
+        {
+            pm.set_state(self.address, PowerState::D0);
+            //the function isn't usable for 10 ms after leaving D3hot
+            time::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// The driver that claimed this function
+    pub fn driver(&self) -> Option<&'static str> {
+        self.driver.get().copied()
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type == HEADER_BRIDGE
+    }
+
+    /// A short description of the class, for the boot log
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _) => "storage controller",
+            (0x02, 0x00) => "Ethernet controller",
+            (0x02, _) => "network controller",
+            (0x03, _) => "display controller",
+            (0x04, _) => "multimedia controller",
+            (0x05, _) => "memory controller",
+            (0x06, 0x00) => "host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "bridge",
+            (0x07, _) => "communication controller",
+            (0x08, _) => "system peripheral",
+            (0x0C, 0x03) => "USB controller",
+            (0x0C, 0x05) => "SMBus controller",
+            (0x0C, _) => "serial bus controller",
+            _ => "device",
+        }
+    }
+}
+
+/// What `init` found, for the boot log
+#[derive(Copy, Clone, Debug)]
+pub struct PciInfo {
+    pub mechanism: config::Mechanism,
+    pub functions: usize,
+    pub buses: usize,
+
+    /// BARs the firmware left unassigned that the kernel placed
+    pub assigned_bars: usize,
+
+    /// BARs that stayed unassigned because there was no room for them
+    pub unassigned_bars: usize,
+}
+
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+
+/// Sets up config space access and enumerates the bus. `loader_ecam` is the ECAM base the
+/// bootloader found, used if there is no MCFG.
+pub fn init(loader_ecam: PhysAddr) -> Result<PciInfo, PciError> {
+    //ECAM not working isn't fatal, everything but extended config space works through the ports
+    let mechanism = match config::init(loader_ecam) {
+        Ok(mechanism) => mechanism,
+        Err(e) => {
+            warn!("pci: not using ECAM: {:?}", e);
+            config::Mechanism::Legacy
+        }
+    };
+
+    let result = scan::scan();
+    let info = PciInfo {
+        mechanism,
+        functions: result.devices.len(),
+        buses: result.buses,
+        assigned_bars: result.assigned_bars,
+        unassigned_bars: result.unassigned_bars,
+    };
+
+    let devices = DEVICES.call_once(|| result.devices);
+    for device in devices {
+        info!("pci: {} {:04x}:{:04x} {} (class {:02x}.{:02x}.{:02x})",
+            device.address, device.vendor, device.device, device.class_name(),
+            device.class, device.subclass, device.prog_if
+        );
+    }
+
+    driver::probe_all();
+    Ok(info)
+}
+
+/// Every function found by `init`, in bus order. Empty before it has run.
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.get().map_or(&[], Vec::as_slice)
+}
+
+/// Finds the first function with the given vendor and device ID. Before `init` this searches
+/// every bus through the legacy ports.
+pub fn find_device(vendor: u16, device: u16) -> Option<PciAddress> {
+    if DEVICES.get().is_some() {
+        return devices()
+            .iter()
+            .find(|d| d.vendor == vendor && d.device == device)
+            .map(|d| d.address);
+    }
+
+    for bus in 0..=255u8 {
+        for dev in 0..32u8 {
+            for function in 0..8u8 {
+                let address = PciAddress::new(bus, dev, function);
+                let id = address.read32(VENDOR_ID);
+
+                if id as u16 == VENDOR_NONE {
+                    //function 0 missing means the whole device is missing
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+
+                if id as u16 == vendor && (id >> 16) as u16 == device {
+                    return Some(address);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn host_bridge_is_enumerated() {
+        let host = devices().first().expect("no PCI functions found");
+        assert_eq!(host.address, PciAddress::new(0, 0, 0));
+        assert_eq!((host.class, host.subclass), (0x06, 0x00));
+        assert_eq!(find_device(host.vendor, host.device), Some(host.address));
+    }
+}
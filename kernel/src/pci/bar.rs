@@ -0,0 +1,156 @@
+use super::{config, PciAddress, COMMAND, COMMAND_IO, COMMAND_MEMORY};
+
+/*
+ * Base address registers. A BAR is sized by writing all ones to it and reading back which
+ * address bits stuck: the device hardwires the bits below its size to zero. Memory BARs can be
+ * 64 bit, in which case they take up two slots and the second one holds the high half.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/PCI#Base_Address_Registers
+ * PCI Local Bus Specification 3.0, 6.2.5.1 "Address Maps"
+ */
+
+/// The most BARs a function has, which is what a type 0 header holds
+pub const MAX_BARS: usize = 6;
+
+//where the BARs start in config space
+const BAR0: u8 = 0x10;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+const MEMORY_FLAGS: u32 = 0xF;
+const IO_FLAGS: u32 = 0x3;
+
+/// A decoded, sized BAR
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        /// Zero if neither the firmware nor the kernel assigned one
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+
+        /// Whether it takes up two slots and can be placed above 4 GiB
+        wide: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+
+impl Bar {
+    pub fn address(&self) -> u64 {
+        match *self {
+            Bar::Memory { address, .. } => address,
+            Bar::Io { port, .. } => port as u64,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+
+    pub fn is_assigned(&self) -> bool {
+        self.address() != 0
+    }
+}
+
+fn register(index: usize) -> u8 {
+    BAR0 + index as u8 * 4
+}
+
+//the size of a BAR from the address bits that stuck, which is its lowest set bit
+fn size_from_mask(mask: u64) -> u64 {
+    mask.isolate_lowest_one()
+}
+
+//writes all ones to a BAR and returns what it read back, then puts the original value back
+fn probe_register(address: PciAddress, offset: u8) -> (u32, u32) {
+    let original = config::read32(address, offset);
+    config::write32(address, offset, 0xFFFF_FFFF);
+    let mask = config::read32(address, offset);
+    config::write32(address, offset, original);
+    (original, mask)
+}
+
+/// Reads and sizes the first `count` BARs of a function. Decoding is turned off while the BARs
+/// hold all ones, so the device doesn't answer at whatever address that is.
+pub fn probe(address: PciAddress, count: usize) -> [Option<Bar>; MAX_BARS] {
+    let mut bars = [None; MAX_BARS];
+    let command = config::read16(address, COMMAND);
+    config::write16(address, COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+    let mut index = 0;
+    while index < count.min(MAX_BARS) {
+        let (original, mask) = probe_register(address, register(index));
+        let slot = index;
+        index += 1;
+
+        if original & BAR_IO != 0 {
+            //the high half of an I/O BAR can read back as zero, on x86 ports are only 16 bit
+            let mask = (mask & !IO_FLAGS) | 0xFFFF_0000;
+            let size = size_from_mask(mask as u64) as u32;
+            if mask & 0xFFFF != 0 {
+                bars[slot] = Some(Bar::Io { port: original & !IO_FLAGS & 0xFFFF, size });
+            }
+            continue;
+        }
+
+        let wide = original & BAR_TYPE_MASK == BAR_TYPE_64;
+        let (mut low, mut high_mask) = ((original & !MEMORY_FLAGS) as u64, 0xFFFF_FFFFu64);
+        if wide && index < count.min(MAX_BARS) {
+            let (high, high_bits) = probe_register(address, register(index));
+            low |= (high as u64) << 32;
+            high_mask = high_bits as u64;
+            index += 1;
+        }
+
+        let mask = (high_mask << 32) | (mask & !MEMORY_FLAGS) as u64;
+        if mask as u32 != 0 || (wide && high_mask != 0) {
+            bars[slot] = Some(Bar::Memory {
+                address: low,
+                size: size_from_mask(mask),
+                prefetchable: original & BAR_PREFETCHABLE != 0,
+                wide,
+            });
+        }
+    }
+
+    config::write16(address, COMMAND, command);
+    bars
+}
+
+/// Points BAR `index` at `base`, writing both halves of a 64 bit BAR. Decoding should be off.
+pub fn assign(address: PciAddress, index: usize, bar: &Bar, base: u64) {
+    let offset = register(index);
+    match *bar {
+        Bar::Io { .. } => config::write32(address, offset, base as u32),
+        Bar::Memory { wide, .. } => {
+            let flags = config::read32(address, offset) & MEMORY_FLAGS;
+            config::write32(address, offset, base as u32 | flags);
+            if wide {
+                config::write32(address, offset + 4, (base >> 32) as u32);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn bar_sizes_from_masks() {
+        assert_eq!(size_from_mask(0xFFFF_FFFF_FFFE_0000), 0x2_0000);
+        assert_eq!(size_from_mask(0xFFFF_FFF0_0000_0000), 0x10_0000_0000);
+        assert_eq!(size_from_mask(0xFFFF_FFFF_FFFF_FFE0), 0x20);
+        assert_eq!(size_from_mask(0), 0);
+    }
+}
@@ -0,0 +1,241 @@
+use super::{config, PciAddress, STATUS, STATUS_CAPABILITIES};
+
+/*
+ * The capability list, a linked list through config space that starts at the pointer at 0x34.
+ * Each entry starts with its ID and the offset of the next one. Only the capabilities the
+ * kernel uses are decoded, the rest are just recorded.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/PCI#Capabilities_List
+ * PCI Local Bus Specification 3.0, 6.7 "Capabilities List" and 6.8 "Message Signaled Interrupts"
+ * PCI Bus Power Management Interface Specification 1.2, 3.2 "Power Management Register Block"
+ * PCI Express Base Specification 4.0, 7.5.3 "PCI Express Capability Structure"
+ */
+
+/// The most capabilities recorded per function
+pub const MAX_CAPABILITIES: usize = 16;
+
+const CAPABILITIES_POINTER: u8 = 0x34;
+
+//capabilities all sit in the standard header, so none can run past it
+const CONFIG_SPACE_SIZE: usize = 256;
+
+pub const ID_POWER_MANAGEMENT: u8 = 0x01;
+pub const ID_MSI: u8 = 0x05;
+pub const ID_PCI_EXPRESS: u8 = 0x10;
+pub const ID_MSIX: u8 = 0x11;
+
+//a list longer than config space has room for is a loop
+const MAX_LIST_LENGTH: usize = 48;
+
+//MSI message control bits
+const MSI_64BIT: u16 = 1 << 7;
+const MSI_PER_VECTOR_MASKING: u16 = 1 << 8;
+
+//the sizes of the capability structures, up to the last register that is used
+const MSI_LENGTH: usize = 0x0A;
+const MSI_64BIT_EXTRA: usize = 4;
+const MSI_MASKING_EXTRA: usize = 0x0A;
+const MSIX_LENGTH: usize = 12;
+const PCI_EXPRESS_LENGTH: usize = 4;
+const PM_LENGTH: usize = 8;
+
+//MSI-X message control, table and PBA fields
+const MSIX_TABLE_SIZE_MASK: u16 = 0x7FF;
+const MSIX_BIR_MASK: u32 = 0x7;
+
+//power management control/status
+const PM_CONTROL: u8 = 4;
+const PM_STATE_MASK: u16 = 0b11;
+const PM_D1_SUPPORT: u16 = 1 << 9;
+const PM_D2_SUPPORT: u16 = 1 << 10;
+
+/// The MSI capability
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Msi {
+    pub offset: u8,
+
+    /// Whether the message address has a high half
+    pub wide: bool,
+    pub per_vector_masking: bool,
+
+    /// How many vectors the function can ask for, a power of two up to 32
+    pub max_vectors: u8,
+}
+
+/// The MSI-X capability
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MsiX {
+    pub offset: u8,
+    pub table_size: u16,
+
+    /// The BAR the vector table is in, and where in it
+    pub table_bar: u8,
+    pub table_offset: u32,
+
+    /// The BAR the pending bit array is in, and where in it
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+}
+
+/// The PCI Express capability
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PciExpress {
+    pub offset: u8,
+    pub version: u8,
+
+    /// Endpoint, root port, switch port and so on, see the PCIe spec's device/port type field
+    pub port_type: u8,
+}
+
+/// The power management capability
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PowerManagement {
+    pub offset: u8,
+    pub d1: bool,
+    pub d2: bool,
+}
+
+/// A device power state
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+impl PowerManagement {
+    pub fn state(&self, address: PciAddress) -> PowerState {
+        match config::read16(address, self.offset + PM_CONTROL) & PM_STATE_MASK {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+
+    /// Moves the function to `state`. Coming out of D3hot takes 10 ms before the function can be
+    /// touched again, which is left to the caller.
+    pub fn set_state(&self, address: PciAddress, state: PowerState) {
+        let bits = match state {
+            PowerState::D0 => 0,
+            PowerState::D1 => 1,
+            PowerState::D2 => 2,
+            PowerState::D3Hot => 3,
+        };
+        let control = config::read16(address, self.offset + PM_CONTROL);
+        config::write16(address, self.offset + PM_CONTROL, (control & !PM_STATE_MASK) | bits);
+    }
+}
+
+/// The capabilities of a function: the decoded ones, and the IDs and offsets of all of them
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Capabilities {
+    pub msi: Option<Msi>,
+    pub msix: Option<MsiX>,
+    pub pci_express: Option<PciExpress>,
+    pub power: Option<PowerManagement>,
+    list: [(u8, u8); MAX_CAPABILITIES],
+    count: usize,
+}
+
+impl Capabilities {
+    /// The ID and offset of every capability, in list order
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        self.list[..self.count].iter().copied()
+    }
+}
+
+/// Walks a function's capability list
+pub fn parse(address: PciAddress) -> Capabilities {
+    let mut caps = Capabilities::default();
+    if config::read16(address, STATUS) & STATUS_CAPABILITIES == 0 {
+        return caps;
+    }
+
+    //the bottom two bits of every pointer are reserved
+    let mut offset = config::read8(address, CAPABILITIES_POINTER) & 0xFC;
+    for _ in 0..MAX_LIST_LENGTH {
+        if offset < 0x40 {
+            break;
+        }
+
+        let header = config::read16(address, offset);
+        let id = header as u8;
+        if caps.count < MAX_CAPABILITIES {
+            caps.list[caps.count] = (id, offset);
+            caps.count += 1;
+        }
+
+        //the header is 4 bytes and offset is at most 0xFC, so this always fits. the decoded
+        //capabilities are only kept if all their registers do as well.
+        let control = config::read16(address, offset + 2);
+        let wide = control & MSI_64BIT != 0;
+        let per_vector_masking = control & MSI_PER_VECTOR_MASKING != 0;
+        let msi_length = MSI_LENGTH
+            + if wide { MSI_64BIT_EXTRA } else { 0 }
+            + if per_vector_masking { MSI_MASKING_EXTRA } else { 0 };
+
+        match id {
+            ID_MSI if caps.msi.is_none() && fits(offset, msi_length) => {
+                caps.msi = Some(Msi {
+                    offset,
+                    wide,
+                    per_vector_masking,
+                    max_vectors: 1 << ((control >> 1) & 0b111).min(5),
+                });
+            }
+            ID_MSIX if caps.msix.is_none() && fits(offset, MSIX_LENGTH) => {
+                let table = config::read32(address, offset + 4);
+                let pba = config::read32(address, offset + 8);
+                caps.msix = Some(MsiX {
+                    offset,
+                    table_size: (control & MSIX_TABLE_SIZE_MASK) + 1,
+                    table_bar: (table & MSIX_BIR_MASK) as u8,
+                    table_offset: table & !MSIX_BIR_MASK,
+                    pba_bar: (pba & MSIX_BIR_MASK) as u8,
+                    pba_offset: pba & !MSIX_BIR_MASK,
+                });
+            }
+            ID_PCI_EXPRESS if caps.pci_express.is_none() && fits(offset, PCI_EXPRESS_LENGTH) => {
+                caps.pci_express = Some(PciExpress {
+                    offset,
+                    version: (control & 0xF) as u8,
+                    port_type: ((control >> 4) & 0xF) as u8,
+                });
+            }
+            ID_POWER_MANAGEMENT if caps.power.is_none() && fits(offset, PM_LENGTH) => {
+                caps.power = Some(PowerManagement {
+                    offset,
+                    d1: control & PM_D1_SUPPORT != 0,
+                    d2: control & PM_D2_SUPPORT != 0,
+                });
+            }
+            _ => {}
+        }
+
+        offset = (header >> 8) as u8 & 0xFC;
+    }
+
+    caps
+}
+
+//whether a capability of `length` bytes at `offset` ends inside the header
+fn fits(offset: u8, length: usize) -> bool {
+    offset as usize + length <= CONFIG_SPACE_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn capabilities_must_end_inside_the_header() {
+        assert!(fits(0xF4, MSIX_LENGTH));
+        assert!(!fits(0xF8, MSIX_LENGTH));
+        assert!(fits(0xE8, MSI_LENGTH + MSI_64BIT_EXTRA + MSI_MASKING_EXTRA));
+        assert!(!fits(0xFC, MSI_LENGTH));
+        assert!(fits(0xFC, PCI_EXPRESS_LENGTH));
+    }
+}
@@ -0,0 +1,181 @@
+use spin::{Mutex, Once};
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+use x86_64::PhysAddr;
+
+use super::{PciAddress, PciError};
+use crate::acpi;
+use crate::interrupt::interrupt::without_interrupts;
+use crate::memory::paging::{self, CacheMode};
+
+/*
+ * Configuration space access. Until `init` finds an ECAM region everything goes through the
+ * legacy 0xCF8/0xCFC port pair, which needs a lock because selecting a register and reading it
+ * are two separate port writes. ECAM maps every function's config space into memory, so once it
+ * is up accesses are single loads and stores and need no lock.
+ *
+ * Only PCI segment group 0 is supported, which is the only one on anything but large servers.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/PCI#Configuration_Space_Access_Mechanism_.231
+ * https://wiki.osdev.org/PCI_Express#Enhanced_Configuration_Mechanism
+ * PCI Firmware Specification 3.2, 4.1.2 "MCFG Table Description"
+ */
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+//each bus takes 1 MiB of ECAM space: 32 devices of 8 functions of 4 KiB
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+//the MCFG has 8 reserved bytes after the header, then 16 byte allocation entries
+const MCFG_RESERVED: usize = 8;
+const MCFG_ENTRY_SIZE: usize = 16;
+
+/// How config space is being reached
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mechanism {
+    Legacy,
+    Ecam,
+}
+
+struct Ecam {
+    base: u64,
+    phys: PhysAddr,
+    size: u64,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+static ECAM: Once<Ecam> = Once::new();
+static LEGACY_LOCK: Mutex<()> = Mutex::new(());
+
+//the ECAM region for segment group 0 from the MCFG
+fn find_mcfg() -> Option<(PhysAddr, u8, u8)> {
+    let header = acpi::find_table(b"MCFG").ok()?;
+    let body = header.body();
+
+    body.get(MCFG_RESERVED..)?
+        .as_chunks::<MCFG_ENTRY_SIZE>()
+        .0
+        .iter()
+        .map(|entry| {
+            let base = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+            let segment = u16::from_le_bytes(entry[8..10].try_into().unwrap());
+            (base, segment, entry[10], entry[11])
+        })
+        .find(|&(base, segment, start, end)| segment == 0 && base != 0 && start <= end)
+        .map(|(base, _, start, end)| (PhysAddr::new(base), start, end))
+}
+
+/// Finds the ECAM region, from the MCFG or from the pointer the bootloader passed, and maps it.
+/// Stays on the legacy ports if there is none, or if it doesn't agree with them about what is
+/// at 00:00.0.
+pub fn init(loader_ecam: PhysAddr) -> Result<Mechanism, PciError> {
+    let (phys, start_bus, end_bus) = match find_mcfg() {
+        Some(region) => region,
+        None if !loader_ecam.is_null() => (loader_ecam, 0, 255),
+        None => return Ok(Mechanism::Legacy),
+    };
+
+    let size = (end_bus as u64 - start_bus as u64 + 1) * ECAM_BUS_SIZE;
+    let virt = paging::map_mmio(phys, size, CacheMode::Uncached).map_err(PciError::Map)?;
+    let ecam = Ecam { base: virt.as_u64(), phys, size, start_bus, end_bus };
+
+    //some firmware lists an MCFG for a region the chipset doesn't decode
+    let root = PciAddress::new(start_bus, 0, 0);
+    let through_ports = legacy(root, 0, |port| unsafe { Port::<u32>::new(port).read() });
+    let mapped = unsafe { core::ptr::read_volatile(ecam.register(root, 0).unwrap() as *const u32) };
+    if mapped != through_ports {
+        let _ = paging::unmap_mmio(virt, size);
+        return Err(PciError::EcamMismatch);
+    }
+
+    ECAM.call_once(|| ecam);
+    Ok(Mechanism::Ecam)
+}
+
+pub fn mechanism() -> Mechanism {
+    if ECAM.get().is_some() { Mechanism::Ecam } else { Mechanism::Legacy }
+}
+
+/// The physical address and size of the ECAM region in use, which BARs must stay clear of
+pub fn ecam_region() -> Option<(PhysAddr, u64)> {
+    ECAM.get().map(|ecam| (ecam.phys, ecam.size))
+}
+
+impl Ecam {
+    //the config space address of `offset`, if the bus is inside the region
+    fn register(&self, address: PciAddress, offset: u8) -> Option<u64> {
+        if !(self.start_bus..=self.end_bus).contains(&address.bus) {
+            return None;
+        }
+
+        Some(self.base
+            + (((address.bus - self.start_bus) as u64) << 20
+            | (address.device as u64) << 15
+            | (address.function as u64) << 12
+            | offset as u64))
+    }
+}
+
+//select a dword in a function's config space
+fn legacy_select(address: PciAddress, offset: u8) {
+    let value = 0x8000_0000u32
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset as u32 & 0xFC);
+
+    unsafe { Port::<u32>::new(CONFIG_ADDRESS).write(value) }
+}
+
+//reads or writes through the data port, with the port offset picking the bytes within the dword
+fn legacy<T>(address: PciAddress, offset: u8, access: impl FnOnce(u16) -> T) -> T {
+    without_interrupts(|| {
+        let _guard = LEGACY_LOCK.lock();
+        legacy_select(address, offset);
+        access(CONFIG_DATA + (offset as u16 & 3))
+    })
+}
+
+//each access is done at its own width, since a read-modify-write of the whole dword would clear
+//write-1-to-clear bits next to the ones being written, like the status register's
+fn read<T: PortRead>(address: PciAddress, offset: u8) -> T {
+    let offset = offset & !(size_of::<T>() as u8 - 1);
+    match ECAM.get().and_then(|ecam| ecam.register(address, offset)) {
+        Some(register) => unsafe { core::ptr::read_volatile(register as *const T) },
+        None => legacy(address, offset, |port| unsafe { Port::<T>::new(port).read() }),
+    }
+}
+
+fn write<T: PortWrite>(address: PciAddress, offset: u8, value: T) {
+    let offset = offset & !(size_of::<T>() as u8 - 1);
+    match ECAM.get().and_then(|ecam| ecam.register(address, offset)) {
+        Some(register) => unsafe { core::ptr::write_volatile(register as *mut T, value) },
+        None => legacy(address, offset, |port| unsafe { Port::<T>::new(port).write(value) }),
+    }
+}
+
+pub fn read32(address: PciAddress, offset: u8) -> u32 {
+    read(address, offset)
+}
+
+pub fn read16(address: PciAddress, offset: u8) -> u16 {
+    read(address, offset)
+}
+
+pub fn read8(address: PciAddress, offset: u8) -> u8 {
+    read(address, offset)
+}
+
+pub fn write32(address: PciAddress, offset: u8, value: u32) {
+    write(address, offset, value)
+}
+
+pub fn write16(address: PciAddress, offset: u8, value: u16) {
+    write(address, offset, value)
+}
+
+pub fn write8(address: PciAddress, offset: u8, value: u8) {
+    write(address, offset, value)
+}
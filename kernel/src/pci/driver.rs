@@ -0,0 +1,102 @@
+use log::{info, warn};
+use spin::Mutex;
+
+use super::{devices, PciDevice, PciError};
+use crate::interrupt::interrupt::without_interrupts;
+
+/*
+ * The driver registry. A driver lists the functions it handles by ID or by class, and its probe
+ * function is called for every unclaimed function that matches, whether the driver registers
+ * before the bus is enumerated or after. A function belongs to the first driver whose probe
+ * succeeds on it.
+ */
+
+/// The most drivers that can be registered
+pub const MAX_DRIVERS: usize = 16;
+
+/// Which functions a driver handles
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PciMatch {
+    Id { vendor: u16, device: u16 },
+
+    /// A class and subclass, and the programming interface too if it is given
+    Class { class: u8, subclass: u8, prog_if: Option<u8> },
+}
+
+impl PciMatch {
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            PciMatch::Id { vendor, device: id } => device.vendor == vendor && device.device == id,
+            PciMatch::Class { class, subclass, prog_if } => {
+                device.class == class
+                    && device.subclass == subclass
+                    && prog_if.is_none_or(|prog_if| device.prog_if == prog_if)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProbeError {
+    /// The function matched, but it is a model or revision the driver can't drive
+    Unsupported,
+
+    /// Memory, mappings or interrupt vectors ran out while setting it up
+    NoResources,
+
+    /// The function didn't behave the way the driver expected
+    Device,
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [PciMatch],
+
+    /// Sets up a matching function. Returning `Ok` claims it, so no other driver is offered it.
+    pub probe: fn(&'static PciDevice) -> Result<(), ProbeError>,
+}
+
+static DRIVERS: Mutex<[Option<&'static PciDriver>; MAX_DRIVERS]> = Mutex::new([None; MAX_DRIVERS]);
+
+//offers every unclaimed function the driver matches to its probe, and returns how many it took
+fn bind(driver: &'static PciDriver) -> usize {
+    let mut claimed = 0;
+    for device in devices() {
+        if device.driver().is_some() || !driver.matches.iter().any(|m| m.matches(device)) {
+            continue;
+        }
+
+        match (driver.probe)(device) {
+            Ok(()) => {
+                device.driver.call_once(|| driver.name);
+                info!("pci: {} bound to {}", device.address, driver.name);
+                claimed += 1;
+            }
+            Err(e) => warn!("pci: {} could not set up {}: {:?}", driver.name, device.address, e),
+        }
+    }
+    claimed
+}
+
+/// Adds a driver, and probes the functions it matches if the bus has been enumerated. Returns
+/// how many functions it claimed.
+pub fn register(driver: &'static PciDriver) -> Result<usize, PciError> {
+    without_interrupts(|| {
+        let mut drivers = DRIVERS.lock();
+        let slot = drivers.iter_mut().find(|slot| slot.is_none()).ok_or(PciError::TooManyDrivers)?;
+        *slot = Some(driver);
+        Ok(())
+    })?;
+
+    //probes can sleep, so they run without the lock
+    Ok(bind(driver))
+}
+
+/// Offers every function to the drivers registered so far. Called by `pci::init` once the bus
+/// has been enumerated.
+pub(super) fn probe_all() {
+    let drivers = without_interrupts(|| *DRIVERS.lock());
+    for driver in drivers.into_iter().flatten() {
+        bind(driver);
+    }
+}
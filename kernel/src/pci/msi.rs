@@ -0,0 +1,381 @@
+use alloc::vec::Vec;
+use log::warn;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::bar::Bar;
+use super::capability::{Msi, MsiX};
+use super::{PciDevice, PciError};
+use crate::interrupt::apic;
+use crate::interrupt::irq::{self, Irq};
+use crate::interrupt::manager::{self, InterruptError, InterruptHandler};
+use crate::memory::paging::{self, CacheMode};
+
+/*
+ * Interrupt vectors for PCI functions. `allocate` picks the best mechanism the function has:
+ * MSI-X, where every vector has its own table entry with its own address, data and mask bit;
+ * then MSI, which gives a block of consecutive vectors sharing one address; and finally the
+ * legacy INTx line, which is shared with other functions.
+ *
+ * Message signalled interrupts go straight to a local APIC, so they need the APIC to be in use.
+ * The INTx fallback routes the line the firmware wrote into the interrupt line register, taken
+ * as a GSI. The real routing is in the ACPI _PRT method, which needs an AML interpreter, but
+ * the firmware's value holds on the PIC and on chipsets that wire PCI interrupts to the I/O
+ * APIC pins of the same number.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/PCI#Message_Signaled_Interrupts
+ * PCI Local Bus Specification 3.0, 6.8 "Message Signaled Interrupts"
+ */
+
+//MSI message control and registers, as offsets from the capability
+const MSI_CONTROL: u8 = 2;
+const MSI_ADDRESS: u8 = 4;
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_ENABLED_VECTORS_SHIFT: u16 = 4;
+const MSI_ENABLED_VECTORS_MASK: u16 = 0b111 << MSI_ENABLED_VECTORS_SHIFT;
+
+//MSI-X message control, and the layout of a table entry
+const MSIX_CONTROL: u8 = 2;
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_ADDRESS_LOW: u64 = 0;
+const MSIX_ENTRY_ADDRESS_HIGH: u64 = 4;
+const MSIX_ENTRY_DATA: u64 = 8;
+const MSIX_ENTRY_CONTROL: u64 = 12;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+//the most vectors one MSI capability can have
+const MSI_MAX_VECTORS: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MsiError {
+    /// The function has no MSI or MSI-X capability and no INTx pin
+    NoInterrupt,
+
+    /// The MSI-X table's BAR is missing, or couldn't be mapped
+    Pci(PciError),
+    Interrupt(InterruptError),
+
+    /// There is no vector with that index
+    NoSuchVector,
+
+    /// The interrupt mode can't do it: INTx lines can't be masked per function or moved to
+    /// another CPU, and plain MSI vectors can only be masked with per-vector masking
+    Unsupported,
+}
+
+impl From<InterruptError> for MsiError {
+    fn from(e: InterruptError) -> Self {
+        MsiError::Interrupt(e)
+    }
+}
+
+/// How a function's interrupts are delivered
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InterruptMode {
+    MsiX,
+    Msi,
+    Intx,
+}
+
+/// The interrupt vectors of one function. Give them back with `free`.
+pub struct PciInterrupts {
+    device: &'static PciDevice,
+    mode: InterruptMode,
+    handler: InterruptHandler,
+
+    /// The vector of each message, in message order. INTx has the one vector of its line.
+    vectors: Vec<u8>,
+
+    //the mapped MSI-X table, and the length of the mapping
+    table: Option<(VirtAddr, u64)>,
+}
+
+impl PciInterrupts {
+    pub fn mode(&self) -> InterruptMode {
+        self.mode
+    }
+
+    pub fn vectors(&self) -> &[u8] {
+        &self.vectors
+    }
+
+    /// The message index a vector belongs to, for handlers shared between queues
+    pub fn index_of(&self, vector: u8) -> Option<usize> {
+        self.vectors.iter().position(|&v| v == vector)
+    }
+
+    fn entry(&self, index: usize) -> Option<*mut u32> {
+        let (table, _) = self.table?;
+        Some((table + index as u64 * MSIX_ENTRY_SIZE).as_mut_ptr())
+    }
+
+    fn write_entry(&self, index: usize, register: u64, value: u32) {
+        if let Some(entry) = self.entry(index) {
+            unsafe { core::ptr::write_volatile(entry.byte_add(register as usize), value) }
+        }
+    }
+
+    fn read_entry(&self, index: usize, register: u64) -> u32 {
+        self.entry(index).map_or(0, |entry| unsafe { core::ptr::read_volatile(entry.byte_add(register as usize)) })
+    }
+
+    /// Sends the interrupt with message index `index` to the CPU with APIC ID `destination`.
+    /// With plain MSI every message shares one address, so this moves all of them.
+    pub fn set_affinity(&self, index: usize, destination: u32) -> Result<(), MsiError> {
+        let vector = *self.vectors.get(index).ok_or(MsiError::NoSuchVector)?;
+        match self.mode {
+            InterruptMode::MsiX => {
+                let (address, data) = apic::msi_message(destination, vector)?;
+                //an entry is only changed while it is masked, so the device never sends half
+                //of an old message and half of a new one
+                let control = self.read_entry(index, MSIX_ENTRY_CONTROL);
+                self.write_entry(index, MSIX_ENTRY_CONTROL, control | MSIX_ENTRY_MASKED);
+                self.write_entry(index, MSIX_ENTRY_ADDRESS_LOW, address as u32);
+                self.write_entry(index, MSIX_ENTRY_ADDRESS_HIGH, (address >> 32) as u32);
+                self.write_entry(index, MSIX_ENTRY_DATA, data);
+                self.write_entry(index, MSIX_ENTRY_CONTROL, control);
+                Ok(())
+            }
+            InterruptMode::Msi => {
+                let msi = self.device.capabilities.msi.ok_or(MsiError::NoInterrupt)?;
+                let (address, data) = apic::msi_message(destination, self.vectors[0])?;
+                write_msi_message(self.device, &msi, address, data);
+                Ok(())
+            }
+            InterruptMode::Intx => Err(MsiError::Unsupported),
+        }
+    }
+
+    fn set_masked(&self, index: usize, masked: bool) -> Result<(), MsiError> {
+        if index >= self.vectors.len() {
+            return Err(MsiError::NoSuchVector);
+        }
+
+        match self.mode {
+            InterruptMode::MsiX => {
+                let control = self.read_entry(index, MSIX_ENTRY_CONTROL);
+                let control = if masked { control | MSIX_ENTRY_MASKED } else { control & !MSIX_ENTRY_MASKED };
+                self.write_entry(index, MSIX_ENTRY_CONTROL, control);
+                Ok(())
+            }
+            InterruptMode::Msi => {
+                let msi = self.device.capabilities.msi.ok_or(MsiError::NoInterrupt)?;
+                if !msi.per_vector_masking {
+                    return Err(MsiError::Unsupported);
+                }
+
+                let offset = msi_mask_offset(&msi);
+                let bits = self.device.address.read32(offset);
+                let bits = if masked { bits | 1 << index } else { bits & !(1 << index) };
+                self.device.address.write32(offset, bits);
+                Ok(())
+            }
+            InterruptMode::Intx => Err(MsiError::Unsupported),
+        }
+    }
+
+    /// Stops the message with index `index` from being sent. A device that raises it while it
+    /// is masked sets its pending bit instead, and sends it once it is unmasked.
+    pub fn mask(&self, index: usize) -> Result<(), MsiError> {
+        self.set_masked(index, true)
+    }
+
+    pub fn unmask(&self, index: usize) -> Result<(), MsiError> {
+        self.set_masked(index, false)
+    }
+
+    /// Turns the function's interrupts off and gives the vectors back
+    pub fn free(self) {
+        let address = self.device.address;
+        match self.mode {
+            InterruptMode::MsiX => {
+                if let Some(msix) = self.device.capabilities.msix {
+                    let control = address.read16(msix.offset + MSIX_CONTROL);
+                    address.write16(msix.offset + MSIX_CONTROL, control & !MSIX_ENABLE);
+                }
+                if let Some((table, length)) = self.table {
+                    let _ = paging::unmap_mmio(table, length);
+                }
+            }
+            InterruptMode::Msi => {
+                if let Some(msi) = self.device.capabilities.msi {
+                    let control = address.read16(msi.offset + MSI_CONTROL);
+                    address.write16(msi.offset + MSI_CONTROL, control & !MSI_ENABLE);
+                }
+            }
+            InterruptMode::Intx => {
+                self.device.set_intx(false);
+                let _ = irq::unroute_irq(Irq::pci(self.device.interrupt_line as u32), self.handler);
+                return;
+            }
+        }
+
+        for &vector in &self.vectors {
+            manager::free_vector(vector);
+        }
+    }
+}
+
+//where the mask bits are, which depends on whether the address has a high half
+fn msi_mask_offset(msi: &Msi) -> u8 {
+    msi.offset + if msi.wide { 0x10 } else { 0x0C }
+}
+
+fn write_msi_message(device: &PciDevice, msi: &Msi, address: u64, data: u32) {
+    let pci = device.address;
+    pci.write32(msi.offset + MSI_ADDRESS, address as u32);
+    if msi.wide {
+        pci.write32(msi.offset + MSI_ADDRESS + 4, (address >> 32) as u32);
+        pci.write16(msi.offset + MSI_ADDRESS + 8, data as u16);
+    } else {
+        pci.write16(msi.offset + MSI_ADDRESS + 4, data as u16);
+    }
+}
+
+//allocates one vector per message and registers the handler on each, undoing it all on failure
+fn allocate_single_vectors(count: usize, handler: InterruptHandler) -> Result<Vec<u8>, MsiError> {
+    let mut vectors = Vec::with_capacity(count);
+    for _ in 0..count {
+        let registered = manager::allocate_vector().and_then(|vector| {
+            manager::register_handler(vector, handler).inspect_err(|_| manager::free_vector(vector))?;
+            Ok(vector)
+        });
+
+        match registered {
+            Ok(vector) => vectors.push(vector),
+            Err(e) => {
+                vectors.into_iter().for_each(manager::free_vector);
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(vectors)
+}
+
+fn setup_msix(device: &'static PciDevice, msix: &MsiX, count: usize, handler: InterruptHandler) -> Result<PciInterrupts, MsiError> {
+    let count = count.min(msix.table_size as usize);
+    let Some(Bar::Memory { address: bar, .. }) = device.bar(msix.table_bar as usize).filter(Bar::is_assigned) else {
+        return Err(MsiError::Pci(PciError::NotMappable));
+    };
+
+    let length = msix.table_size as u64 * MSIX_ENTRY_SIZE;
+    let table = paging::map_mmio(PhysAddr::new(bar + msix.table_offset as u64), length, CacheMode::Uncached)
+        .map_err(|e| MsiError::Pci(PciError::Map(e)))?;
+
+    let vectors = match allocate_single_vectors(count, handler) {
+        Ok(vectors) => vectors,
+        Err(e) => {
+            let _ = paging::unmap_mmio(table, length);
+            return Err(e);
+        }
+    };
+
+    let interrupts = PciInterrupts {
+        device,
+        mode: InterruptMode::MsiX,
+        handler,
+        vectors,
+        table: Some((table, length)),
+    };
+
+    //the whole function stays masked while the table is written, and every entry past the
+    //ones in use stays masked for good
+    let pci = device.address;
+    device.enable();
+    device.enable_bus_master();
+    let control = pci.read16(msix.offset + MSIX_CONTROL);
+    pci.write16(msix.offset + MSIX_CONTROL, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+
+    for index in 0..msix.table_size as usize {
+        interrupts.write_entry(index, MSIX_ENTRY_CONTROL, MSIX_ENTRY_MASKED);
+    }
+
+    let destination = apic::id().unwrap_or(0);
+    for index in 0..count {
+        if let Err(e) = interrupts.set_affinity(index, destination) {
+            interrupts.free();
+            return Err(e);
+        }
+        interrupts.write_entry(index, MSIX_ENTRY_CONTROL, 0);
+    }
+
+    device.set_intx(false);
+    let control = pci.read16(msix.offset + MSIX_CONTROL);
+    pci.write16(msix.offset + MSIX_CONTROL, control & !MSIX_FUNCTION_MASK);
+    Ok(interrupts)
+}
+
+fn setup_msi(device: &'static PciDevice, msi: &Msi, count: usize, handler: InterruptHandler) -> Result<PciInterrupts, MsiError> {
+    //the vectors have to be a power of two sized, aligned block
+    let limit = count.min(msi.max_vectors as usize).min(MSI_MAX_VECTORS);
+    let count = 1 << limit.ilog2();
+
+    let base = manager::allocate_vectors(count)?;
+    let vectors: Vec<u8> = (base..base + count as u8).collect();
+    //freeing a vector removes its handlers too
+    for &vector in &vectors {
+        if let Err(e) = manager::register_handler(vector, handler) {
+            vectors.iter().copied().for_each(manager::free_vector);
+            return Err(e.into());
+        }
+    }
+
+    let destination = apic::id().unwrap_or(0);
+    let (address, data) = match apic::msi_message(destination, base) {
+        Ok(message) => message,
+        Err(e) => {
+            vectors.iter().copied().for_each(manager::free_vector);
+            return Err(e.into());
+        }
+    };
+
+    let pci = device.address;
+    device.enable_bus_master();
+    write_msi_message(device, msi, address, data);
+    if msi.per_vector_masking {
+        pci.write32(msi_mask_offset(msi), 0);
+    }
+
+    let control = pci.read16(msi.offset + MSI_CONTROL) & !MSI_ENABLED_VECTORS_MASK;
+    let enabled = (count.ilog2() as u16) << MSI_ENABLED_VECTORS_SHIFT;
+    pci.write16(msi.offset + MSI_CONTROL, control | enabled | MSI_ENABLE);
+    device.set_intx(false);
+
+    Ok(PciInterrupts { device, mode: InterruptMode::Msi, handler, vectors, table: None })
+}
+
+fn setup_intx(device: &'static PciDevice, handler: InterruptHandler) -> Result<PciInterrupts, MsiError> {
+    if device.interrupt_pin == 0 || device.interrupt_line == 0xFF {
+        return Err(MsiError::NoInterrupt);
+    }
+
+    let vector = irq::route_irq(Irq::pci(device.interrupt_line as u32), handler)?;
+    device.set_intx(true);
+    Ok(PciInterrupts { device, mode: InterruptMode::Intx, handler, vectors: alloc::vec![vector], table: None })
+}
+
+/// Sets up to `count` interrupt vectors for a function, all with `handler` registered, aimed at
+/// the current CPU. Uses MSI-X if the function has it, MSI if not, and INTx as a last resort, so
+/// fewer vectors than asked for can come back: MSI rounds down to a power of two, and INTx is
+/// always one shared vector.
+pub fn allocate(device: &'static PciDevice, count: usize, handler: InterruptHandler) -> Result<PciInterrupts, MsiError> {
+    let count = count.max(1);
+    if apic::is_enabled() {
+        if let Some(msix) = device.capabilities.msix {
+            match setup_msix(device, &msix, count, handler) {
+                Ok(interrupts) => return Ok(interrupts),
+                Err(e) => warn!("pci: {} MSI-X setup failed, trying MSI: {:?}", device.address, e),
+            }
+        }
+        if let Some(msi) = device.capabilities.msi {
+            match setup_msi(device, &msi, count, handler) {
+                Ok(interrupts) => return Ok(interrupts),
+                Err(e) => warn!("pci: {} MSI setup failed, falling back to INTx: {:?}", device.address, e),
+            }
+        }
+    }
+
+    setup_intx(device, handler)
+}
@@ -0,0 +1,277 @@
+use alloc::vec::Vec;
+use log::warn;
+use spin::Once;
+
+use super::bar::{self, Bar};
+use super::{capability, config, PciAddress, PciDevice};
+use super::{COMMAND, COMMAND_IO, COMMAND_MEMORY, HEADER_BRIDGE, HEADER_DEVICE, HEADER_MULTI_FUNCTION, HEADER_TYPE};
+use super::{INTERRUPT_LINE, INTERRUPT_PIN, REVISION, SUBSYSTEM_ID, SUBSYSTEM_VENDOR_ID, VENDOR_NONE};
+
+/*
+ * Bus enumeration. Every bus is scanned depth first from the root buses, following PCI-to-PCI
+ * bridges to the buses behind them. The firmware normally numbers every bridge, but one it left
+ * at bus 0 is given the next free bus number here, as long as every bridge above it was too:
+ * a bridge the firmware numbered only forwards config cycles for the range it was given.
+ *
+ * Then BARs the firmware didn't assign are given space above everything it did, which is only
+ * done on the root buses, since behind a bridge they'd also have to fit the bridge's window.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/PCI#Recursive_Scan
+ * PCI-to-PCI Bridge Architecture Specification 1.2, 3.2.5.3 "Bus Number Registers"
+ */
+
+//type 1 header registers
+const PRIMARY_BUS: u8 = 0x18;
+const SECONDARY_BUS: u8 = 0x19;
+const SUBORDINATE_BUS: u8 = 0x1A;
+
+//the class code, subclass and programming interface are the top three bytes of this dword
+const CLASS_REVISION: u8 = REVISION;
+
+//how many BARs each header type has
+const DEVICE_BARS: usize = 6;
+const BRIDGE_BARS: usize = 2;
+
+//where new 32 bit memory BARs go if the firmware assigned none to start above, which is where
+//QEMU and most chipsets start their PCI window
+const MEMORY_WINDOW_START: u64 = 0xC000_0000;
+
+//the I/O APIC, HPET and local APIC live above this
+const MEMORY_WINDOW_END: u64 = 0xFEC0_0000;
+
+//I/O ports below this belong to legacy ISA devices
+const IO_WINDOW_START: u64 = 0x1000;
+const IO_WINDOW_END: u64 = 0x1_0000;
+
+pub struct ScanResult {
+    pub devices: Vec<PciDevice>,
+    pub buses: usize,
+    pub assigned_bars: usize,
+    pub unassigned_bars: usize,
+}
+
+struct Scanner {
+    devices: Vec<PciDevice>,
+    seen: [bool; 256],
+    roots: [bool; 256],
+
+    //the highest bus number in use
+    highest: u8,
+
+    //bridges on a root bus the firmware left unnumbered
+    unnumbered: Vec<usize>,
+}
+
+impl Scanner {
+    fn scan_bus(&mut self, bus: u8, numbering: bool) {
+        if self.seen[bus as usize] {
+            return;
+        }
+        self.seen[bus as usize] = true;
+        self.highest = self.highest.max(bus);
+
+        for device in 0..32 {
+            let address = PciAddress::new(bus, device, 0);
+            if address.vendor_id() == VENDOR_NONE {
+                continue;
+            }
+
+            let functions = if address.read8(HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 { 8 } else { 1 };
+            for function in 0..functions {
+                let address = PciAddress::new(bus, device, function);
+                if address.vendor_id() != VENDOR_NONE {
+                    self.scan_function(address, numbering);
+                }
+            }
+        }
+    }
+
+    fn scan_function(&mut self, address: PciAddress, numbering: bool) {
+        let id = address.read32(0x00);
+        let class = address.read32(CLASS_REVISION);
+        let header_type = address.read8(HEADER_TYPE) & !HEADER_MULTI_FUNCTION;
+        let bar_count = match header_type {
+            HEADER_DEVICE => DEVICE_BARS,
+            HEADER_BRIDGE => BRIDGE_BARS,
+            _ => 0,
+        };
+
+        //a bridge has no subsystem IDs at these offsets
+        let (subsystem_vendor, subsystem) = match header_type {
+            HEADER_DEVICE => (address.read16(SUBSYSTEM_VENDOR_ID), address.read16(SUBSYSTEM_ID)),
+            _ => (0, 0),
+        };
+
+        let index = self.devices.len();
+        self.devices.push(PciDevice {
+            address,
+            vendor: id as u16,
+            device: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            subsystem_vendor,
+            subsystem,
+            interrupt_pin: address.read8(INTERRUPT_PIN),
+            interrupt_line: address.read8(INTERRUPT_LINE),
+            bars: bar::probe(address, bar_count),
+            capabilities: capability::parse(address),
+            secondary_bus: None,
+            driver: Once::new(),
+        });
+
+        if header_type != HEADER_BRIDGE {
+            return;
+        }
+
+        let secondary = address.read8(SECONDARY_BUS);
+        if secondary > address.bus && !self.seen[secondary as usize] {
+            self.devices[index].secondary_bus = Some(secondary);
+            self.scan_bus(secondary, numbering);
+        } else if numbering {
+            self.number_bridge(index);
+        } else if self.roots[address.bus as usize] {
+            self.unnumbered.push(index);
+        } else {
+            warn!("pci: bridge {} behind a firmware numbered bridge has no bus number, skipping it", address);
+        }
+    }
+
+    //gives a bridge the next free bus number and scans behind it. the subordinate bus stays at
+    //the maximum while scanning, so config cycles reach bridges further down being numbered.
+    fn number_bridge(&mut self, index: usize) {
+        let address = self.devices[index].address;
+        let Some(secondary) = self.highest.checked_add(1) else {
+            warn!("pci: out of bus numbers for bridge {}", address);
+            return;
+        };
+
+        address.write8(PRIMARY_BUS, address.bus);
+        address.write8(SECONDARY_BUS, secondary);
+        address.write8(SUBORDINATE_BUS, 0xFF);
+        self.devices[index].secondary_bus = Some(secondary);
+
+        self.scan_bus(secondary, true);
+        address.write8(SUBORDINATE_BUS, self.highest);
+    }
+
+    //the end of the highest BAR of each kind the firmware assigned, so new ones go above them
+    fn windows(&self) -> (u64, u64) {
+        let mut memory = MEMORY_WINDOW_START;
+        let mut io = IO_WINDOW_START;
+        if let Some((ecam, size)) = config::ecam_region()
+            && ecam.as_u64() < MEMORY_WINDOW_END
+        {
+            memory = memory.max(ecam.as_u64() + size);
+        }
+
+        for bar in self.devices.iter().flat_map(|d| d.bars.iter().flatten()) {
+            let end = bar.address() + bar.size();
+            match bar {
+                Bar::Memory { .. } if bar.is_assigned() && end <= MEMORY_WINDOW_END => memory = memory.max(end),
+                Bar::Io { .. } if bar.is_assigned() => io = io.max(end),
+                _ => {}
+            }
+        }
+
+        (memory, io)
+    }
+
+    //places the BARs the firmware left unassigned on the root buses, biggest first so the
+    //alignment padding stays small. returns how many were placed and how many didn't fit.
+    fn assign_bars(&mut self) -> (usize, usize) {
+        let (mut memory, mut io) = self.windows();
+        let mut pending = Vec::new();
+        for (index, device) in self.devices.iter().enumerate() {
+            if !self.roots[device.address.bus as usize] {
+                continue;
+            }
+            for (slot, bar) in device.bars.iter().enumerate() {
+                if let Some(bar) = bar
+                    && !bar.is_assigned()
+                {
+                    pending.push((index, slot, *bar));
+                }
+            }
+        }
+        pending.sort_unstable_by_key(|&(_, _, bar)| core::cmp::Reverse(bar.size()));
+
+        let (mut assigned, mut unassigned) = (0, 0);
+        for (index, slot, bar) in pending {
+            let (next, end) = match bar {
+                Bar::Memory { .. } => (&mut memory, MEMORY_WINDOW_END),
+                Bar::Io { .. } => (&mut io, IO_WINDOW_END),
+            };
+
+            let base = next.next_multiple_of(bar.size());
+            let device = &mut self.devices[index];
+            if base + bar.size() > end {
+                warn!("pci: no room for BAR{} of {} ({} bytes)", slot, device.address, bar.size());
+                unassigned += 1;
+                continue;
+            }
+
+            let address = device.address;
+            let command = address.read16(COMMAND);
+            address.write16(COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+            bar::assign(address, slot, &bar, base);
+            address.write16(COMMAND, command);
+
+            device.bars[slot] = Some(match bar {
+                Bar::Memory { size, prefetchable, wide, .. } => Bar::Memory { address: base, size, prefetchable, wide },
+                Bar::Io { size, .. } => Bar::Io { port: base as u32, size },
+            });
+            *next = base + bar.size();
+            assigned += 1;
+        }
+
+        (assigned, unassigned)
+    }
+}
+
+/// Finds every function, numbers the bridges the firmware didn't and assigns the BARs it didn't
+pub fn scan() -> ScanResult {
+    let mut scanner = Scanner {
+        devices: Vec::new(),
+        seen: [false; 256],
+        roots: [false; 256],
+        highest: 0,
+        unnumbered: Vec::new(),
+    };
+
+    //a multi-function host bridge is several host bridges, each with the root bus of its number
+    let host = PciAddress::new(0, 0, 0);
+    if host.read8(HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 {
+        for function in 0..8 {
+            if PciAddress::new(0, 0, function).vendor_id() != VENDOR_NONE {
+                scanner.roots[function as usize] = true;
+            }
+        }
+    } else {
+        scanner.roots[0] = true;
+    }
+
+    for bus in 0..8 {
+        if scanner.roots[bus] {
+            scanner.scan_bus(bus as u8, false);
+        }
+    }
+
+    //numbering only starts once the firmware's numbers are all known, so they don't collide
+    for index in core::mem::take(&mut scanner.unnumbered) {
+        scanner.number_bridge(index);
+    }
+
+    let (assigned_bars, unassigned_bars) = scanner.assign_bars();
+    scanner.devices.sort_unstable_by_key(|device| device.address);
+
+    ScanResult {
+        buses: scanner.seen.iter().filter(|&&seen| seen).count(),
+        devices: scanner.devices,
+        assigned_bars,
+        unassigned_bars,
+    }
+}
@@ -0,0 +1,293 @@
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::log_ring::LOG_RING;
+
+// The persistent crash store. The panic handler writes a `CrashRecord` into a physical region
+// that the bootloader reserves at the same address on every boot, so the record survives a warm
+// reboot. The layout here has to match bootloader/src/pstore.rs.
+
+pub const CRASH_MAGIC: u64 = u64::from_le_bytes(*b"RTRCRASH");
+pub const CRASH_VERSION: u32 = 1;
+
+pub const CRASH_FILE_LEN: usize = 128;
+pub const CRASH_MESSAGE_LEN: usize = 512;
+pub const CRASH_LOG_LEN: usize = 4096;
+
+/// The register state at the time of the crash
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CrashRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl CrashRegisters {
+    /// Takes a snapshot of the registers of the calling code
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut regs = CrashRegisters::default();
+
+        unsafe {
+            //the general purpose registers are stored first, before rax is used as scratch
+            asm!(
+                "mov [{regs} + 0x00], rax",
+                "mov [{regs} + 0x08], rbx",
+                "mov [{regs} + 0x10], rcx",
+                "mov [{regs} + 0x18], rdx",
+                "mov [{regs} + 0x20], rsi",
+                "mov [{regs} + 0x28], rdi",
+                "mov [{regs} + 0x30], rbp",
+                "mov [{regs} + 0x38], rsp",
+                "mov [{regs} + 0x40], r8",
+                "mov [{regs} + 0x48], r9",
+                "mov [{regs} + 0x50], r10",
+                "mov [{regs} + 0x58], r11",
+                "mov [{regs} + 0x60], r12",
+                "mov [{regs} + 0x68], r13",
+                "mov [{regs} + 0x70], r14",
+                "mov [{regs} + 0x78], r15",
+                "lea rax, [rip]",
+                "mov [{regs} + 0x80], rax",
+                "pushfq",
+                "pop rax",
+                "mov [{regs} + 0x88], rax",
+                "mov rax, cr0",
+                "mov [{regs} + 0x90], rax",
+                "mov rax, cr2",
+                "mov [{regs} + 0x98], rax",
+                "mov rax, cr3",
+                "mov [{regs} + 0xa0], rax",
+                "mov rax, cr4",
+                "mov [{regs} + 0xa8], rax",
+                regs = in(reg) &mut regs as *mut CrashRegisters,
+                out("rax") _,
+            );
+        }
+
+        regs
+    }
+
+    /// Writes the registers, four to a line
+    pub fn dump(&self, out: &mut impl Write) -> fmt::Result {
+        writeln!(out, "RIP {:#018x} RSP {:#018x} RBP {:#018x} RFLAGS {:#018x}", self.rip, self.rsp, self.rbp, self.rflags)?;
+        writeln!(out, "RAX {:#018x} RBX {:#018x} RCX {:#018x} RDX {:#018x}", self.rax, self.rbx, self.rcx, self.rdx)?;
+        writeln!(out, "RSI {:#018x} RDI {:#018x} R8  {:#018x} R9  {:#018x}", self.rsi, self.rdi, self.r8, self.r9)?;
+        writeln!(out, "R10 {:#018x} R11 {:#018x} R12 {:#018x} R13 {:#018x}", self.r10, self.r11, self.r12, self.r13)?;
+        writeln!(out, "R14 {:#018x} R15 {:#018x}", self.r14, self.r15)?;
+        writeln!(out, "CR0 {:#018x} CR2 {:#018x} CR3 {:#018x} CR4 {:#018x}", self.cr0, self.cr2, self.cr3, self.cr4)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct CrashRecord {
+    pub magic: u64,
+    pub version: u32,
+
+    /// FNV-1a checksum of the whole record, calculated with this field set to 0
+    pub checksum: u32,
+
+    /// The TSC value when the crash was recorded
+    pub tsc: u64,
+
+    pub regs: CrashRegisters,
+    pub line: u32,
+    pub column: u32,
+    pub file_len: u32,
+    pub message_len: u32,
+    pub log_len: u32,
+    pub file: [u8; CRASH_FILE_LEN],
+    pub message: [u8; CRASH_MESSAGE_LEN],
+
+    /// The last bytes of the log ring, oldest first
+    pub log: [u8; CRASH_LOG_LEN],
+}
+
+impl CrashRecord {
+    /// Checks the magic, version and checksum of the record
+    pub fn is_valid(&self) -> bool {
+        self.magic == CRASH_MAGIC
+            && self.version == CRASH_VERSION
+            && self.file_len as usize <= CRASH_FILE_LEN
+            && self.message_len as usize <= CRASH_MESSAGE_LEN
+            && self.log_len as usize <= CRASH_LOG_LEN
+            && self.checksum == self.calculate_checksum()
+    }
+
+    pub fn calculate_checksum(&self) -> u32 {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                core::mem::size_of::<Self>()
+            )
+        };
+
+        //the checksum field is skipped, so the value can be stored inside the data it covers
+        let checksum_start = core::mem::offset_of!(CrashRecord, checksum);
+        let checksum_end = checksum_start + core::mem::size_of::<u32>();
+
+        let mut hash: u32 = 0x811c9dc5;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let byte = if (checksum_start..checksum_end).contains(&i) { 0 } else { byte };
+            hash ^= byte as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+
+        hash
+    }
+
+    pub fn file(&self) -> &str {
+        as_str(&self.file[..self.file_len as usize])
+    }
+
+    pub fn message(&self) -> &str {
+        as_str(&self.message[..self.message_len as usize])
+    }
+
+    pub fn log(&self) -> &str {
+        as_str(&self.log[..self.log_len as usize])
+    }
+
+    /// Writes the record in the same format the bootloader uses on the serial console
+    pub fn dump(&self, out: &mut impl Write) -> fmt::Result {
+        writeln!(out, "panicked at {}:{}:{}: {}", self.file(), self.line, self.column, self.message())?;
+        self.regs.dump(out)?;
+        writeln!(out, "---- log tail ----")?;
+        writeln!(out, "{}", self.log())
+    }
+}
+
+//strings are truncated at byte boundaries, so cut back to the last valid character
+fn as_str(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => unsafe { core::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) },
+    }
+}
+
+/// A `fmt::Write` into a fixed buffer that silently drops whatever does not fit
+struct TruncatingWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+//the crash store handed over by the bootloader, or null if there is none
+static REGION: AtomicPtr<CrashRecord> = AtomicPtr::new(core::ptr::null_mut());
+
+//the size of the crash store, so it can be wiped when the record is cleared
+static REGION_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+//the record found in the crash store at boot, kept until it is cleared
+static LAST_CRASH: Mutex<Option<CrashRecord>> = Mutex::new(None);
+
+/// Takes over the crash store region. A record left by an earlier boot is copied out so it can
+/// be read with `with_last_crash`. It is left in the region as well, so it survives further
+/// reboots until a new crash overwrites it or `clear_last_crash` is called.
+pub fn init(region: *mut u8, size: usize) {
+    if region.is_null() || size < core::mem::size_of::<CrashRecord>() {
+        return;
+    }
+
+    let record = region as *mut CrashRecord;
+
+    unsafe {
+        if (*record).is_valid() {
+            *LAST_CRASH.lock() = Some(*record);
+        }
+    }
+
+    REGION_SIZE.store(size, Ordering::Relaxed);
+    REGION.store(record, Ordering::Release);
+}
+
+/// Calls `f` with the crash record from the previous boot, if there was one
+pub fn with_last_crash<R>(f: impl FnOnce(Option<&CrashRecord>) -> R) -> R {
+    f(LAST_CRASH.lock().as_ref())
+}
+
+/// Forgets the crash record from an earlier boot, and wipes it from the crash store so the next
+/// boot doesn't report it again
+pub fn clear_last_crash() {
+    let mut last_crash = LAST_CRASH.lock();
+    *last_crash = None;
+
+    let record = REGION.load(Ordering::Acquire);
+    if !record.is_null() {
+        unsafe {
+            core::ptr::write_bytes(record as *mut u8, 0, REGION_SIZE.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Writes a crash record for a panic into the crash store. This is called from the panic
+/// handler, so it does not wait on any lock: if the log ring is busy its tail is left out.
+pub fn record_crash(info: &PanicInfo, regs: &CrashRegisters) {
+    let record = REGION.load(Ordering::Acquire);
+    if record.is_null() {
+        return;
+    }
+
+    let record = unsafe { &mut *record };
+
+    //invalidate the old contents first, so a crash while writing never leaves a valid record
+    record.magic = 0;
+    record.version = CRASH_VERSION;
+    record.tsc = crate::boot_time::rdtsc();
+    record.regs = *regs;
+
+    let (file, line, column) = match info.location() {
+        Some(location) => (location.file(), location.line(), location.column()),
+        None => ("<unknown>", 0, 0),
+    };
+    record.line = line;
+    record.column = column;
+
+    let file_len = file.len().min(CRASH_FILE_LEN);
+    record.file[..file_len].copy_from_slice(&file.as_bytes()[..file_len]);
+    record.file_len = file_len as u32;
+
+    let mut message = TruncatingWriter { buf: &mut record.message, len: 0 };
+    let _ = write!(message, "{}", info.message());
+    record.message_len = message.len as u32;
+
+    record.log_len = match LOG_RING.try_lock() {
+        Some(ring) => ring.tail(&mut record.log) as u32,
+        None => 0,
+    };
+
+    record.magic = CRASH_MAGIC;
+    record.checksum = record.calculate_checksum();
+}
@@ -0,0 +1,94 @@
+use core::arch::asm;
+use core::fmt::Write;
+use x86_64::instructions::port::Port;
+
+use crate::{boot_time, interrupt, time};
+
+/*
+ * Resetting the machine. There is no single reset mechanism every board honors, so the common
+ * ones are tried in turn, each given a moment to take effect: the PCI reset control register,
+ * the keyboard controller's reset line, and finally a triple fault, which every x86 CPU turns
+ * into a reset.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/Reboot
+ * Intel 300 Series Chipset PCH datasheet, "Reset Control Register (RST_CNT)"
+ */
+
+const RESET_CONTROL: u16 = 0xCF9;
+const RESET_CPU: u8 = 1 << 2;
+const FULL_RESET: u8 = 1 << 3;
+const SYSTEM_RESET: u8 = 1 << 1;
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+//how many port reads to wait for a reset method to work before trying the next
+const SETTLE_READS: usize = 100_000;
+
+//the TSC frequency assumed when nothing better is known, only used to time a reboot delay
+const FALLBACK_TSC_HZ: u64 = 2_000_000_000;
+
+fn settle() {
+    let mut status: Port<u8> = Port::new(KBC_STATUS);
+    for _ in 0..SETTLE_READS {
+        unsafe { status.read() };
+    }
+}
+
+/// Resets the machine. Interrupts are disabled first, and this never returns.
+pub fn reboot() -> ! {
+    interrupt::disable_interrupts();
+
+    unsafe {
+        //the reset happens on the 0 to 1 edge of RESET_CPU, so the other bits are set first
+        let mut reset_control: Port<u8> = Port::new(RESET_CONTROL);
+        reset_control.write(SYSTEM_RESET | FULL_RESET);
+        reset_control.write(SYSTEM_RESET | FULL_RESET | RESET_CPU);
+        settle();
+
+        //wait for the keyboard controller to take commands, then pulse the reset line
+        let mut kbc: Port<u8> = Port::new(KBC_STATUS);
+        for _ in 0..SETTLE_READS {
+            if kbc.read() & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        kbc.write(KBC_PULSE_RESET);
+        settle();
+
+        //an empty IDT turns the next exception into a triple fault
+        let idt = x86_64::structures::DescriptorTablePointer {
+            limit: 0,
+            base: x86_64::VirtAddr::new(0),
+        };
+        x86_64::instructions::tables::lidt(&idt);
+        asm!("int3", options(nomem, nostack));
+    }
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Counts down `secs` seconds on `out`, then resets the machine. The wait spins on the TSC, so it
+/// works with interrupts off, like on the panic path.
+pub fn reboot_after(secs: u64, out: &mut impl Write) -> ! {
+    //the bootloader's calibration sits behind a lock, so it isn't used here
+    let hz = match time::tsc_hz() {
+        0 => FALLBACK_TSC_HZ,
+        hz => hz,
+    };
+
+    for remaining in (1..=secs).rev() {
+        let _ = write!(out, "\rrebooting in {:>3} s", remaining);
+        let start = boot_time::rdtsc();
+        while boot_time::rdtsc().wrapping_sub(start) < hz {
+            core::hint::spin_loop();
+        }
+    }
+
+    let _ = writeln!(out, "\rrebooting now      ");
+    reboot()
+}
@@ -0,0 +1,79 @@
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+use crate::{interrupt, time};
+
+/*
+ * The kernel test harness, for `cargo test`. Tests are `#[test_case]` functions anywhere in the
+ * kernel; the test build runs them once the kernel has finished booting, prints a line for
+ * each on the console, and ends the run through QEMU's isa-debug-exit device with a pass or fail
+ * code. run-qemu.sh turns that code into cargo's verdict. A panic fails the running test, after
+ * the usual panic report with registers and a backtrace.
+ *
+ * Every test gets TEST_TIMEOUT to finish. The timeout runs off the tick, so a test that hangs
+ * with interrupts off is only caught by run-qemu.sh's timeout for the whole run.
+ *
+ * Built with help from:
+ * https://os.phil-opp.com/testing/
+ * QEMU's hw/misc/debugexit.c, for the isa-debug-exit device
+ */
+
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const ISA_DEBUG_EXIT: u16 = 0xF4;
+
+/// What the test kernel tells QEMU to exit with. These have to differ from 0 and 1, which QEMU
+/// uses for its own exits.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Exits QEMU through the isa-debug-exit device
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    unsafe { Port::<u32>::new(ISA_DEBUG_EXIT).write(code as u32) };
+
+    //only reached without the device, like under plain `cargo run`
+    loop {
+        interrupt::disable_interrupts();
+        x86_64::instructions::hlt();
+    }
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        crate::print!("{} ... ", core::any::type_name::<T>());
+
+        let timeout = time::add_timer(TEST_TIMEOUT, timed_out, 0).ok();
+        self();
+        if let Some(timeout) = timeout {
+            time::cancel_timer(timeout);
+        }
+
+        crate::println!("ok");
+    }
+}
+
+//runs from the tick when a test overstays TEST_TIMEOUT
+fn timed_out(_: usize) {
+    crate::println!("timed out after {:?}", TEST_TIMEOUT);
+    exit_qemu(QemuExitCode::Failed);
+}
+
+/// Runs every `#[test_case]`. Called through `test_main` at the end of `_start`.
+pub fn test_runner(tests: &[&dyn Testable]) {
+    crate::println!("running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+
+    crate::println!("test result: ok. {} passed", tests.len());
+    exit_qemu(QemuExitCode::Success);
+}
+
@@ -0,0 +1,326 @@
+use core::arch::x86_64::__cpuid;
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+
+use crate::boot_time::{self, rdtsc};
+use crate::interrupt::apic::{self, TimerMode};
+use crate::interrupt::entry::InterruptContext;
+use crate::interrupt::interrupt::{interrupts_enabled, without_interrupts};
+use crate::interrupt::irq::{self, Irq};
+use crate::interrupt::manager::{self, InterruptError};
+
+pub mod hpet;
+pub mod pit;
+pub mod wheel;
+
+use wheel::{TimerCallback, TimerError, TimerHandle, TimerWheel};
+
+/*
+ * Timekeeping. The TSC is the clocksource, calibrated against the HPET when ACPI lists one and
+ * the PIT otherwise. A periodic tick from the local APIC timer (or the PIT, when the APIC isn't
+ * in use) drives the timer wheel.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/TSC
+ * https://wiki.osdev.org/APIC_Timer
+ * Intel SDM Vol. 3B, 18.17 "Time-Stamp Counter"
+ */
+
+/// How many times a second the tick fires. This is the resolution of the timer wheel.
+pub const TICK_HZ: u64 = 1000;
+
+//how long each calibration measures for
+const CALIBRATION_MS: u64 = 10;
+
+//how many expired timers are taken off the wheel at a time, before their callbacks run
+const TIMER_BATCH: usize = 32;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// What the TSC was calibrated against
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Reference {
+    Hpet,
+    Pit,
+}
+
+/// What drives the tick
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TickSource {
+    None = 0,
+    ApicTimer,
+    Pit,
+}
+
+/// What `init` set up, for the boot log
+#[derive(Copy, Clone, Debug)]
+pub struct ClockInfo {
+    pub reference: Reference,
+    pub tsc_hz: u64,
+
+    /// Whether the TSC keeps a constant rate through power state changes
+    pub invariant_tsc: bool,
+    pub tick: TickSource,
+}
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TICK_SOURCE: AtomicU8 = AtomicU8::new(TickSource::None as u8);
+static WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+
+/// A point in time, measured with the TSC. It only ever goes forward.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(rdtsc())
+    }
+
+    /// The time from `earlier` to this one, or zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        tsc_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// The time since this instant
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_tsc(duration)).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration_to_tsc(duration)).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+fn tsc_to_duration(tsc: u64) -> Duration {
+    let hz = tsc_hz();
+    if hz == 0 {
+        return Duration::ZERO;
+    }
+
+    let nanos = tsc as u128 * NANOS_PER_SEC / hz as u128;
+    Duration::new((nanos / NANOS_PER_SEC) as u64, (nanos % NANOS_PER_SEC) as u32)
+}
+
+fn duration_to_tsc(duration: Duration) -> u64 {
+    (duration.as_nanos() * tsc_hz() as u128 / NANOS_PER_SEC).min(u64::MAX as u128) as u64
+}
+
+//the number of ticks that covers `duration`, rounded up
+fn duration_to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * TICK_HZ as u128).div_ceil(NANOS_PER_SEC).min(u64::MAX as u128) as u64
+}
+
+fn invariant_tsc() -> bool {
+    let max_extended = __cpuid(0x8000_0000).eax;
+    max_extended >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+fn tick(_ctx: &InterruptContext) {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    run_timers(now);
+}
+
+//runs every timer that is due by `now`. the wheel is unlocked while the callbacks run, so they
+//can add and cancel timers themselves.
+fn run_timers(now: u64) {
+    loop {
+        let mut batch: [Option<(TimerCallback, usize)>; TIMER_BATCH] = [None; TIMER_BATCH];
+        let mut count = 0;
+        {
+            let mut wheel = WHEEL.lock();
+            while count < TIMER_BATCH {
+                match wheel.pop_expired(now) {
+                    Some(timer) => batch[count] = Some(timer),
+                    None => break,
+                }
+                count += 1;
+            }
+        }
+
+        for (callback, arg) in batch.iter().flatten() {
+            callback(*arg);
+        }
+
+        if count < TIMER_BATCH {
+            break;
+        }
+    }
+}
+
+//starts the tick on the local APIC timer if the APIC is in use, and on the PIT otherwise
+fn start_tick() -> Result<TickSource, InterruptError> {
+    if !apic::is_enabled() {
+        pit::start_periodic(TICK_HZ);
+        irq::route_irq(Irq::isa(pit::PIT_IRQ), tick)?;
+        return Ok(TickSource::Pit);
+    }
+
+    let vector = manager::allocate_vector()?;
+    manager::register_handler(vector, tick)?;
+
+    //count how far the APIC timer gets in a known amount of TSC time
+    let calibration = duration_to_tsc(Duration::from_millis(CALIBRATION_MS));
+    apic::set_timer(vector, TimerMode::OneShot, u32::MAX)?;
+    let start = rdtsc();
+    while rdtsc() - start < calibration {
+        core::hint::spin_loop();
+    }
+    let counted = u32::MAX - apic::timer_count();
+    apic::stop_timer();
+
+    let initial = (counted as u64 * 1000 / CALIBRATION_MS / TICK_HZ).max(1) as u32;
+    apic::set_timer(vector, TimerMode::Periodic, initial)?;
+    Ok(TickSource::ApicTimer)
+}
+
+/// Calibrates the TSC and starts the tick. Has to run after `irq::init`, so the tick is set up
+/// on whichever interrupt controller is in use.
+pub fn init() -> Result<ClockInfo, InterruptError> {
+    //the bootloader's calibration is good enough until the kernel's own is done
+    TSC_HZ.store(boot_time::tsc_hz(), Ordering::Relaxed);
+
+    let (reference, tsc_hz) = without_interrupts(|| match hpet::init() {
+        Ok(_) => (Reference::Hpet, hpet::calibrate_tsc(CALIBRATION_MS)),
+        Err(_) => (Reference::Pit, pit::calibrate_tsc(CALIBRATION_MS)),
+    });
+    TSC_HZ.store(tsc_hz, Ordering::Relaxed);
+
+    let tick = without_interrupts(start_tick)?;
+    TICK_SOURCE.store(tick as u8, Ordering::Relaxed);
+
+    Ok(ClockInfo {
+        reference,
+        tsc_hz,
+        invariant_tsc: invariant_tsc(),
+        tick,
+    })
+}
+
+/// TSC ticks per second, or 0 before anything calibrated it
+pub fn tsc_hz() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+/// The number of ticks since `init`
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// What drives the tick, or `TickSource::None` before `init`
+pub fn tick_source() -> TickSource {
+    match TICK_SOURCE.load(Ordering::Relaxed) {
+        1 => TickSource::ApicTimer,
+        2 => TickSource::Pit,
+        _ => TickSource::None,
+    }
+}
+
+/// Waits for `duration`. The CPU halts between ticks when it can, and spins otherwise.
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    let tick = Duration::from_nanos((NANOS_PER_SEC / TICK_HZ as u128) as u64);
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+
+        //halting is only safe if a tick is coming to wake the CPU before the deadline passes
+        if tick_source() != TickSource::None && interrupts_enabled() && deadline - now > tick {
+            x86_64::instructions::hlt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Runs `callback(arg)` once, `delay` from now, rounded up to the next tick. Callbacks run in
+/// the tick interrupt, so they have to be short and must not wait on anything.
+pub fn add_timer(delay: Duration, callback: TimerCallback, arg: usize) -> Result<TimerHandle, TimerError> {
+    let expires = ticks().saturating_add(duration_to_ticks(delay));
+    without_interrupts(|| WHEEL.lock().add(expires, callback, arg))
+}
+
+/// Cancels a timer added with `add_timer`. Returns false if it already ran or was cancelled.
+pub fn cancel_timer(handle: TimerHandle) -> bool {
+    without_interrupts(|| WHEEL.lock().cancel(handle))
+}
+
+/// The number of timers waiting to run
+pub fn active_timers() -> usize {
+    without_interrupts(|| WHEEL.lock().active())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicBool;
+
+    #[test_case]
+    fn instant_arithmetic() {
+        let now = Instant::now();
+        let later = now + Duration::from_millis(5);
+        assert!(later > now);
+        //converting to TSC ticks and back can round down by a tick
+        let elapsed = later.duration_since(now);
+        assert!(elapsed <= Duration::from_millis(5) && elapsed > Duration::from_micros(4_990));
+    }
+
+    #[test_case]
+    fn timer_fires_after_its_delay() {
+        static FIRED: AtomicBool = AtomicBool::new(false);
+
+        add_timer(Duration::from_millis(5), |_| FIRED.store(true, Ordering::Relaxed), 0).unwrap();
+        sleep(Duration::from_millis(50));
+        assert!(FIRED.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn cancelled_timer_does_not_fire() {
+        static FIRED: AtomicBool = AtomicBool::new(false);
+
+        let handle = add_timer(Duration::from_millis(5), |_| FIRED.store(true, Ordering::Relaxed), 0).unwrap();
+        assert!(cancel_timer(handle));
+        sleep(Duration::from_millis(50));
+        assert!(!FIRED.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,112 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::PhysAddr;
+
+use crate::acpi::{self, AcpiError};
+use crate::boot_time::rdtsc;
+use crate::memory::paging::{self, CacheMode, PagingError};
+
+/*
+ * The HPET main counter, found through the ACPI "HPET" table. Only the free running counter is
+ * used, as a reference to calibrate the TSC against.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/HPET
+ * IA-PC HPET Specification 1.0a, 2.3 "Register Set"
+ */
+
+const REG_CAPABILITIES: usize = 0x00;
+const REG_CONFIG: usize = 0x10;
+const REG_MAIN_COUNTER: usize = 0xF0;
+
+//the size of the register block
+const REGISTERS_SIZE: u64 = 1024;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+
+//the spec caps the counter period at 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+//the generic address structure's address space for memory
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HpetError {
+    /// There is no usable HPET table
+    Acpi(AcpiError),
+
+    /// The registers are not in memory space, or report a counter period the spec doesn't allow
+    Unusable,
+
+    /// The registers couldn't be mapped
+    Map(PagingError),
+}
+
+static BASE: AtomicUsize = AtomicUsize::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+fn read(reg: usize) -> u64 {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe { ((base + reg) as *const u64).read_volatile() }
+}
+
+fn write(reg: usize, value: u64) {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe { ((base + reg) as *mut u64).write_volatile(value) }
+}
+
+/// Finds the HPET and starts its main counter. Returns the counter frequency in Hz.
+pub fn init() -> Result<u64, HpetError> {
+    let table = acpi::find_table(b"HPET").map_err(HpetError::Acpi)?;
+    let body = table.body();
+    if body.len() < 16 {
+        return Err(HpetError::Acpi(AcpiError::Truncated));
+    }
+
+    //the base address is a generic address structure at offset 4
+    if body[4] != ADDRESS_SPACE_MEMORY {
+        return Err(HpetError::Unusable);
+    }
+    let address = PhysAddr::new(u64::from_le_bytes(body[8..16].try_into().unwrap()));
+
+    let base = paging::map_mmio(address, REGISTERS_SIZE, CacheMode::Uncached).map_err(HpetError::Map)?;
+    BASE.store(base.as_u64() as usize, Ordering::Relaxed);
+    let period = read(REG_CAPABILITIES) >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        BASE.store(0, Ordering::Relaxed);
+        let _ = paging::unmap_mmio(base, REGISTERS_SIZE);
+        return Err(HpetError::Unusable);
+    }
+    PERIOD_FS.store(period, Ordering::Relaxed);
+
+    write(REG_CONFIG, read(REG_CONFIG) | CONFIG_ENABLE);
+    Ok(FS_PER_SEC / period)
+}
+
+/// Whether `init` found a usable HPET
+pub fn is_present() -> bool {
+    PERIOD_FS.load(Ordering::Relaxed) != 0
+}
+
+/// The main counter
+pub fn counter() -> u64 {
+    read(REG_MAIN_COUNTER)
+}
+
+/// Counts TSC ticks over `ms` milliseconds of the HPET, and returns the TSC frequency
+pub fn calibrate_tsc(ms: u64) -> u64 {
+    let period = PERIOD_FS.load(Ordering::Relaxed);
+    let target = ms * (FS_PER_SEC / 1000) / period;
+
+    let start_counter = counter();
+    let start = rdtsc();
+    let (end, elapsed) = loop {
+        let elapsed = counter().wrapping_sub(start_counter);
+        if elapsed >= target {
+            break (rdtsc(), elapsed);
+        }
+        core::hint::spin_loop();
+    };
+
+    ((end - start) as u128 * FS_PER_SEC as u128 / (elapsed as u128 * period as u128)) as u64
+}
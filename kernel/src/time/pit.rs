@@ -0,0 +1,75 @@
+use x86_64::instructions::port::Port;
+
+use crate::boot_time::rdtsc;
+
+/*
+ * The 8253/8254 programmable interval timer. Channel 0 is wired to ISA IRQ 0 and can be used
+ * as the tick, and channel 2 can be polled through port 0x61 without any interrupt, which makes
+ * it usable for calibrating the TSC before interrupts are set up.
+ *
+ * Built with help from:
+ * https://wiki.osdev.org/Programmable_Interval_Timer
+ * Linux arch/x86/kernel/tsc.c, pit_calibrate_tsc()
+ */
+
+/// The frequency of the PIT's input clock
+pub const PIT_HZ: u64 = 1_193_182;
+
+/// The ISA IRQ channel 0 raises
+pub const PIT_IRQ: u8 = 0;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const PORT_B: u16 = 0x61;
+
+//command register values: channel in bits 6-7, access mode 3 (low byte then high byte) in bits
+//4-5, and the operating mode in bits 1-3
+const CHANNEL0_RATE_GENERATOR: u8 = 0x34; //channel 0, mode 2
+const CHANNEL2_ONE_SHOT: u8 = 0xB0; //channel 2, mode 0
+
+const PORT_B_GATE2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT2: u8 = 1 << 5;
+
+/// Makes channel 0 fire IRQ 0 `hz` times a second. Returns the rate it actually runs at.
+pub fn start_periodic(hz: u64) -> u64 {
+    let divisor = (PIT_HZ / hz).clamp(1, 0xFFFF) as u16;
+
+    unsafe {
+        Port::<u8>::new(COMMAND).write(CHANNEL0_RATE_GENERATOR);
+        let mut data = Port::<u8>::new(CHANNEL0_DATA);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+
+    PIT_HZ / divisor as u64
+}
+
+/// Counts TSC ticks over `ms` milliseconds of channel 2, and returns the TSC frequency. `ms`
+/// can be at most 54, the longest the 16 bit counter can run.
+pub fn calibrate_tsc(ms: u64) -> u64 {
+    let count = (PIT_HZ * ms / 1000).min(0xFFFF) as u16;
+
+    unsafe {
+        //open the gate for channel 2, with the speaker kept off
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let value = port_b.read();
+        port_b.write((value & !PORT_B_SPEAKER) | PORT_B_GATE2);
+
+        Port::<u8>::new(COMMAND).write(CHANNEL2_ONE_SHOT);
+        let mut data = Port::<u8>::new(CHANNEL2_DATA);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        //OUT2 goes high when the count reaches zero
+        let start = rdtsc();
+        while port_b.read() & PORT_B_OUT2 == 0 {
+            core::hint::spin_loop();
+        }
+        let end = rdtsc();
+
+        port_b.write(value);
+        (end - start) * PIT_HZ / count as u64
+    }
+}
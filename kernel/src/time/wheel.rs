@@ -0,0 +1,265 @@
+/*
+ * A hierarchical timer wheel. Level 0 has a slot for each of the next 64 ticks, and each level
+ * above covers 64 times the range of the one below it with the same number of slots. Adding and
+ * cancelling a timer is O(1); a timer on a higher level is moved down ("cascaded") when the
+ * wheel below it wraps around, so every timer is only touched a few times before it fires.
+ *
+ * The timers live in a fixed pool and are linked into their slots by index, so no allocation is
+ * needed. The pool is all zeroes until it is used, so it costs nothing in the kernel image.
+ *
+ * Built with help from:
+ * Varghese & Lauck, "Hashed and Hierarchical Timing Wheels" (1987)
+ * Linux kernel/time/timer.c, before the 4.8 rewrite
+ */
+
+pub const MAX_TIMERS: usize = 1 << 18;
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+const LEVELS: usize = 4;
+
+//the furthest ahead a timer can be placed. later timers are parked at the top and re-cascaded.
+const MAX_DELTA: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1;
+
+//index 0 is never handed out, so 0 can mean "no node" and the pool can start out zeroed
+const NIL: u32 = 0;
+
+/// A function that runs when a timer expires, with the argument it was added with
+pub type TimerCallback = fn(usize);
+
+/// Identifies an added timer. A handle goes stale once its timer fires or is cancelled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimerHandle {
+    index: u32,
+    generation: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TimerError {
+    /// Every timer in the pool is in use
+    Full,
+}
+
+#[derive(Copy, Clone)]
+struct Node {
+    next: u32,
+    prev: u32,
+
+    //the slot the node is linked into, plus one. 0 means it isn't in the wheel.
+    slot: u16,
+    generation: u32,
+    expires: u64,
+    callback: Option<TimerCallback>,
+    arg: usize,
+}
+
+impl Node {
+    const EMPTY: Node = Node {
+        next: NIL,
+        prev: NIL,
+        slot: 0,
+        generation: 0,
+        expires: 0,
+        callback: None,
+        arg: 0,
+    };
+}
+
+pub struct TimerWheel {
+    nodes: [Node; MAX_TIMERS],
+
+    //freed nodes, linked through `next`
+    free: u32,
+
+    //nodes above this have never been used
+    high_water: u32,
+
+    //the first node in each slot, level by level
+    heads: [u32; SLOTS * LEVELS],
+
+    //the next tick to be processed
+    now: u64,
+
+    //the level 0 slot whose timers are being handed out, plus one
+    draining: u16,
+    active: usize,
+}
+
+impl TimerWheel {
+    pub const fn new() -> Self {
+        TimerWheel {
+            nodes: [Node::EMPTY; MAX_TIMERS],
+            free: NIL,
+            high_water: 0,
+            heads: [NIL; SLOTS * LEVELS],
+            now: 0,
+            draining: 0,
+            active: 0,
+        }
+    }
+
+    /// The number of timers waiting to fire
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    fn node(&mut self, index: u32) -> &mut Node {
+        &mut self.nodes[index as usize - 1]
+    }
+
+    fn alloc_node(&mut self) -> Option<u32> {
+        if self.free != NIL {
+            let index = self.free;
+            self.free = self.node(index).next;
+            Some(index)
+        } else if (self.high_water as usize) < MAX_TIMERS {
+            self.high_water += 1;
+            Some(self.high_water)
+        } else {
+            None
+        }
+    }
+
+    fn free_node(&mut self, index: u32) {
+        let free = self.free;
+        let node = self.node(index);
+        node.generation = node.generation.wrapping_add(1);
+        node.callback = None;
+        node.slot = 0;
+        node.next = free;
+        self.free = index;
+    }
+
+    fn link(&mut self, index: u32, slot: usize) {
+        let head = self.heads[slot];
+        {
+            let node = self.node(index);
+            node.prev = NIL;
+            node.next = head;
+            node.slot = slot as u16 + 1;
+        }
+        if head != NIL {
+            self.node(head).prev = index;
+        }
+        self.heads[slot] = index;
+    }
+
+    fn unlink(&mut self, index: u32) {
+        let Node { next, prev, slot, .. } = *self.node(index);
+        if prev != NIL {
+            self.node(prev).next = next;
+        } else {
+            self.heads[slot as usize - 1] = next;
+        }
+        if next != NIL {
+            self.node(next).prev = prev;
+        }
+        self.node(index).slot = 0;
+    }
+
+    //picks the slot for a node from how far away it expires
+    fn place(&mut self, index: u32) {
+        let mut expires = self.node(index).expires;
+
+        //timers that are already due go in the slot being processed next
+        if expires < self.now {
+            expires = self.now;
+        }
+        if expires - self.now > MAX_DELTA {
+            expires = self.now + MAX_DELTA;
+        }
+
+        let delta = expires - self.now;
+        let mut level = 0;
+        while level < LEVELS - 1 && delta >= 1 << (SLOT_BITS * (level as u32 + 1)) {
+            level += 1;
+        }
+
+        let slot = ((expires >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize;
+        self.link(index, level * SLOTS + slot);
+    }
+
+    /// Adds a timer that fires on tick `expires`. A tick in the past fires on the next one.
+    pub fn add(&mut self, expires: u64, callback: TimerCallback, arg: usize) -> Result<TimerHandle, TimerError> {
+        let index = self.alloc_node().ok_or(TimerError::Full)?;
+        let generation = {
+            let node = self.node(index);
+            node.expires = expires;
+            node.callback = Some(callback);
+            node.arg = arg;
+            node.generation
+        };
+
+        self.place(index);
+        self.active += 1;
+        Ok(TimerHandle { index, generation })
+    }
+
+    /// Cancels a timer. Returns false if it already fired or was cancelled.
+    pub fn cancel(&mut self, handle: TimerHandle) -> bool {
+        if handle.index == NIL || handle.index as usize > MAX_TIMERS {
+            return false;
+        }
+
+        let node = *self.node(handle.index);
+        if node.generation != handle.generation || node.slot == 0 {
+            return false;
+        }
+
+        self.unlink(handle.index);
+        self.free_node(handle.index);
+        self.active -= 1;
+        true
+    }
+
+    //moves every timer in a higher level slot down to where it belongs now
+    fn cascade(&mut self, level: usize) -> usize {
+        let slot = ((self.now >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize;
+        let mut index = core::mem::replace(&mut self.heads[level * SLOTS + slot], NIL);
+
+        while index != NIL {
+            let next = self.node(index).next;
+            self.place(index);
+            index = next;
+        }
+
+        slot
+    }
+
+    /// Takes the next timer that is due by tick `until`, removing it from the wheel. The caller
+    /// runs the callback, so it can do so without holding the wheel.
+    pub fn pop_expired(&mut self, until: u64) -> Option<(TimerCallback, usize)> {
+        loop {
+            if self.draining != 0 {
+                let slot = self.draining as usize - 1;
+                let index = self.heads[slot];
+
+                if index != NIL {
+                    self.unlink(index);
+                    let node = *self.node(index);
+                    self.free_node(index);
+                    self.active -= 1;
+                    return node.callback.map(|callback| (callback, node.arg));
+                }
+                self.draining = 0;
+            }
+
+            if self.now > until {
+                return None;
+            }
+
+            //when level 0 wraps, pull down the next slot of level 1, and so on up
+            let slot = (self.now & SLOT_MASK) as usize;
+            if slot == 0 {
+                let mut level = 1;
+                while level < LEVELS && self.cascade(level) == 0 {
+                    level += 1;
+                }
+            }
+
+            self.now += 1;
+            self.draining = slot as u16 + 1;
+        }
+    }
+}